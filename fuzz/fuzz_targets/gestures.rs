@@ -0,0 +1,6 @@
+#![no_main]
+
+use banglejs_emu::fuzz;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| fuzz::run_gestures(data));
@@ -0,0 +1,17 @@
+#![no_main]
+
+use std::sync::OnceLock;
+
+use banglejs_emu::{fuzz, pool::EmulatorPool};
+use libfuzzer_sys::fuzz_target;
+
+static POOL: OnceLock<EmulatorPool> = OnceLock::new();
+
+fuzz_target!(|data: &[u8]| {
+    let pool = POOL.get_or_init(|| {
+        let path = std::env::var(fuzz::WASM_PATH_VAR)
+            .unwrap_or_else(|_| panic!("set {} to the firmware to fuzz", fuzz::WASM_PATH_VAR));
+        EmulatorPool::new(path).expect("failed to load firmware")
+    });
+    fuzz::run_one(pool, data);
+});
@@ -0,0 +1,129 @@
+use std::fmt;
+
+/// A field of a Bluetooth LE advertisement, decoded as far as beacon-style
+/// apps usually care about.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AdField {
+    Flags(u8),
+    /// 16-bit service UUIDs, and whether the list is complete.
+    Uuids16(Vec<u16>, bool),
+    /// 128-bit service UUIDs, as sent (least significant byte first), and
+    /// whether the list is complete.
+    Uuids128(Vec<[u8; 16]>, bool),
+    /// The device's name, and whether it's complete rather than shortened.
+    Name(String, bool),
+    TxPower(i8),
+    ServiceData16 {
+        uuid: u16,
+        data: Vec<u8>,
+    },
+    Manufacturer {
+        company: u16,
+        data: Vec<u8>,
+    },
+    /// A field of any other type, or one too short for its type.
+    Other {
+        kind: u8,
+        data: Vec<u8>,
+    },
+    /// Bytes left over at the end that don't make up a whole field.
+    Truncated(Vec<u8>),
+}
+
+/// Splits an advertising payload into its fields, each a length byte (which
+/// counts the type byte), a type byte, and the data. A zero length ends the
+/// payload early, as the spec allows for padding.
+pub fn parse(payload: &[u8]) -> Vec<AdField> {
+    let mut fields = vec![];
+    let mut rest = payload;
+    while let Some((&len, after)) = rest.split_first() {
+        let len = usize::from(len);
+        if len == 0 {
+            break;
+        }
+        if after.len() < len {
+            fields.push(AdField::Truncated(rest.to_vec()));
+            break;
+        }
+        let (field, after) = after.split_at(len);
+        fields.push(decode(field[0], &field[1..]));
+        rest = after;
+    }
+    fields
+}
+
+fn decode(kind: u8, data: &[u8]) -> AdField {
+    let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+    match kind {
+        0x01 if data.len() == 1 => AdField::Flags(data[0]),
+        0x02 | 0x03 if data.len().is_multiple_of(2) => AdField::Uuids16(
+            (0..data.len()).step_by(2).map(u16_at).collect(),
+            kind == 0x03,
+        ),
+        0x06 | 0x07 if data.len().is_multiple_of(16) => AdField::Uuids128(
+            data.chunks(16).map(|c| c.try_into().unwrap()).collect(),
+            kind == 0x07,
+        ),
+        0x08 | 0x09 => AdField::Name(String::from_utf8_lossy(data).into_owned(), kind == 0x09),
+        0x0a if data.len() == 1 => AdField::TxPower(data[0] as i8),
+        0x16 if data.len() >= 2 => AdField::ServiceData16 {
+            uuid: u16_at(0),
+            data: data[2..].to_vec(),
+        },
+        0xff if data.len() >= 2 => AdField::Manufacturer {
+            company: u16_at(0),
+            data: data[2..].to_vec(),
+        },
+        _ => AdField::Other {
+            kind,
+            data: data.to_vec(),
+        },
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl fmt::Display for AdField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let partial = |complete: &bool| if *complete { "" } else { " (incomplete)" };
+        match self {
+            Self::Flags(flags) => write!(f, "flags {flags:#04x}"),
+            Self::Uuids16(uuids, complete) => {
+                let uuids: Vec<_> = uuids.iter().map(|u| format!("{u:04x}")).collect();
+                write!(f, "services {}{}", uuids.join(", "), partial(complete))
+            }
+            Self::Uuids128(uuids, complete) => {
+                let uuids: Vec<_> = uuids.iter().map(uuid128).collect();
+                write!(f, "services {}{}", uuids.join(", "), partial(complete))
+            }
+            Self::Name(name, complete) => write!(f, "name {name:?}{}", partial(complete)),
+            Self::TxPower(dbm) => write!(f, "tx power {dbm} dBm"),
+            Self::ServiceData16 { uuid, data } => {
+                write!(f, "service data {uuid:04x}: {}", hex(data))
+            }
+            Self::Manufacturer { company, data } => {
+                write!(f, "manufacturer {company:#06x}: {}", hex(data))
+            }
+            Self::Other { kind, data } => write!(f, "type {kind:#04x}: {}", hex(data)),
+            Self::Truncated(data) => write!(f, "truncated: {}", hex(data)),
+        }
+    }
+}
+
+/// Formats a 128-bit UUID, sent least significant byte first, the usual way.
+fn uuid128(uuid: &[u8; 16]) -> String {
+    let b: Vec<_> = uuid.iter().rev().map(|b| format!("{b:02x}")).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        b[..4].concat(),
+        b[4..6].concat(),
+        b[6..8].concat(),
+        b[8..10].concat(),
+        b[10..].concat()
+    )
+}
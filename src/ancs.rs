@@ -0,0 +1,91 @@
+use serde_derive::{Deserialize, Serialize};
+
+/// A notification from an emulated iPhone, as the Apple Notification Center
+/// Service would describe it. Anything left out of an `ancs notify` control
+/// command comes from a canned text message.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AncsNotification {
+    pub app_id: String,
+    pub app_name: String,
+    /// The ANCS category ID: 1 for an incoming call, 4 for social (which
+    /// includes messages), 6 for email, and so on.
+    pub category: u8,
+    pub title: String,
+    pub subtitle: String,
+    pub message: String,
+    /// The label of the positive action (such as "Answer"), if there is one.
+    pub positive: Option<String>,
+    /// The label of the negative action (such as "Clear"), if there is one.
+    pub negative: Option<String>,
+    pub silent: bool,
+    pub important: bool,
+}
+
+impl Default for AncsNotification {
+    fn default() -> Self {
+        Self {
+            app_id: "com.apple.MobileSMS".to_owned(),
+            app_name: "Messages".to_owned(),
+            category: 4,
+            title: "Alice".to_owned(),
+            subtitle: String::new(),
+            message: "Are we still on for lunch?".to_owned(),
+            positive: None,
+            negative: Some("Clear".to_owned()),
+            silent: false,
+            important: false,
+        }
+    }
+}
+
+/// Installs stand-ins for the firmware's ANCS functions, answering from the
+/// notifications the emulator has sent, and recording the actions the watch
+/// takes in `global.__ancs`. It's reinstalled each time, since reloading the
+/// firmware undoes it.
+const SETUP: &str = "var N=global.NRF||(global.NRF={}),\
+     A=global.__ancs||(global.__ancs={uid:0,list:{},actions:[]});\
+     N.ancsIsActive=function(){return true;};\
+     N.ancsGetNotificationInfo=function(u){var n=A.list[u];\
+     if(!n)return Promise.reject('Not found');\
+     return Promise.resolve({uid:u,appId:n.appId,title:n.title,subtitle:n.subtitle,\
+     message:n.message,messageSize:n.message.length,date:n.date,\
+     posAction:n.positive||'',negAction:n.negative||''});};\
+     N.ancsGetAppInfo=function(id){for(var u in A.list)if(A.list[u].appId==id)\
+     return Promise.resolve({appId:id,appName:A.list[u].appName});\
+     return Promise.reject('Not found');};\
+     N.ancsAction=function(u,p){var n=A.list[u];if(!n)return;\
+     A.actions.push({uid:u,positive:!!p});\
+     setTimeout(function(){delete A.list[u];\
+     E.emit('ANCS',{event:'remove',uid:u,category:n.category,categoryCnt:0});},0);};";
+
+/// Builds a JavaScript expression that sends a notification the way the
+/// firmware reports one from an iPhone, with an `ANCS` event on `E` for the
+/// watch to fetch the rest through `NRF.ancsGetNotificationInfo` and
+/// `NRF.ancsGetAppInfo`. It evaluates to the notification's UID.
+pub fn notify_expr(notification: &AncsNotification) -> String {
+    format!(
+        "(function(n){{{SETUP}\
+         var d=new Date(),p=function(x){{return ('0'+x).substr(-2);}};\
+         n.date=d.getFullYear()+p(d.getMonth()+1)+p(d.getDate())+'T'+p(d.getHours())+p(d.getMinutes())+p(d.getSeconds());\
+         var u=++A.uid;A.list[u]=n;\
+         E.emit('ANCS',{{event:'add',uid:u,category:n.category,categoryCnt:1,silent:n.silent,important:n.important,\
+         preExisting:false,positive:!!n.positive,negative:!!n.negative}});return u;}})({})",
+        serde_json::to_string(notification).unwrap()
+    )
+}
+
+/// Builds a JavaScript expression that removes a notification, as happens when
+/// it's dismissed on the phone, evaluating to whether it was there.
+pub fn remove_expr(uid: u32) -> String {
+    format!(
+        "(function(u){{{SETUP}var n=A.list[u];if(!n)return false;delete A.list[u];\
+         E.emit('ANCS',{{event:'remove',uid:u,category:n.category,categoryCnt:0}});return true;}})({uid})"
+    )
+}
+
+/// A JavaScript expression that evaluates to the actions the watch has taken
+/// on notifications since the last time, each a UID and whether it was the
+/// positive action, and forgets them.
+pub const ACTIONS_EXPR: &str = "(function(){var A=global.__ancs;if(!A)return [];\
+     var a=A.actions;A.actions=[];return a;})()";
@@ -0,0 +1,247 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{device::Board, storage::Storage};
+
+/// The name of the file describing an app in a BangleApps-style app directory.
+pub const MANIFEST_FILE: &str = "metadata.json";
+/// The file the watch keeps its settings in, including which clock to show.
+const SETTINGS_FILE: &str = "setting.json";
+
+/// A file listed in an app's manifest, either one to install or one the app
+/// keeps its data in.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifestFile {
+    name: Option<String>,
+    /// Where the file's contents are, relative to the app's directory, if not
+    /// at its name.
+    url: Option<String>,
+    content: Option<String>,
+    #[serde(default)]
+    evaluate: bool,
+    /// The devices the file is for, if not all of them.
+    supports: Option<Vec<String>>,
+    /// A pattern matching the data files the app creates.
+    wildcard: Option<String>,
+    /// Whether the data file is a `StorageFile`, which is kept in pieces.
+    #[serde(default)]
+    storage_file: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    id: String,
+    name: String,
+    short_name: Option<String>,
+    version: Option<String>,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    tags: Option<String>,
+    sortorder: Option<i64>,
+    #[serde(default)]
+    storage: Vec<ManifestFile>,
+    data: Option<Vec<ManifestFile>>,
+}
+
+/// The `<id>.info` file the App Loader writes for an installed app, with the
+/// fields in the same order.
+#[derive(Serialize)]
+struct AppInfo<'a> {
+    id: &'a str,
+    name: &'a str,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    kind: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    src: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sortorder: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<&'a str>,
+    files: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<String>,
+}
+
+pub enum AppContents {
+    Path(PathBuf),
+    Text(String),
+}
+
+/// A file to write into storage to install an app.
+pub struct AppFile {
+    pub name: String,
+    pub contents: AppContents,
+    /// Whether the contents are JavaScript to evaluate, with the result being
+    /// what's stored.
+    pub evaluate: bool,
+}
+
+/// Works out the files the App Loader would write to install the app in
+/// `dir` on a board: the files from its manifest that the board supports,
+/// followed by the app's `.info` file.
+pub fn app_files(dir: &Path, board: Board) -> anyhow::Result<Vec<AppFile>> {
+    let path = dir.join(MANIFEST_FILE);
+    let manifest: Manifest = serde_json::from_slice(
+        &fs::read(&path).with_context(|| format!("Failed to read {path:?}"))?,
+    )
+    .with_context(|| format!("Failed to parse {path:?}"))?;
+
+    let device = board.app_loader_id();
+    let mut files = vec![];
+    for file in &manifest.storage {
+        if file
+            .supports
+            .as_ref()
+            .is_some_and(|s| !s.iter().any(|d| d == device))
+        {
+            continue;
+        }
+        let Some(name) = &file.name else {
+            anyhow::bail!("a storage file in {path:?} has no name");
+        };
+        let contents = match &file.content {
+            Some(text) => AppContents::Text(text.clone()),
+            None => AppContents::Path(dir.join(file.url.as_deref().unwrap_or(name))),
+        };
+        files.push(AppFile {
+            name: name.clone(),
+            contents,
+            evaluate: file.evaluate,
+        });
+    }
+
+    let id = &manifest.id;
+    let info_name = format!("{id}.info");
+    let has_file = |name: &str| files.iter().any(|f| f.name == name);
+    let src = format!("{id}.app.js");
+    let icon = format!("{id}.img");
+    let names: Vec<_> = [info_name.as_str()]
+        .into_iter()
+        .chain(files.iter().map(|f| f.name.as_str()))
+        .collect();
+    let data = manifest.data.as_ref().map(|data| {
+        let names = |storage_file| {
+            data.iter()
+                .filter(|d| d.storage_file == storage_file)
+                .filter_map(|d| d.name.as_deref().or(d.wildcard.as_deref()))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let (data_files, storage_files) = (names(false), names(true));
+        if storage_files.is_empty() {
+            data_files
+        } else {
+            format!("{data_files};{storage_files}")
+        }
+    });
+    let info = AppInfo {
+        id,
+        name: manifest.short_name.as_deref().unwrap_or(&manifest.name),
+        kind: manifest.kind.as_deref().filter(|&k| k != "app"),
+        src: has_file(&src).then_some(src),
+        icon: has_file(&icon).then_some(icon),
+        sortorder: manifest.sortorder,
+        version: manifest.version.as_deref(),
+        tags: manifest.tags.as_deref(),
+        files: names.join(","),
+        data: data.filter(|d| !d.is_empty()),
+    };
+    let info = serde_json::to_string(&info)?;
+    files.push(AppFile {
+        name: info_name,
+        contents: AppContents::Text(info),
+        evaluate: false,
+    });
+    Ok(files)
+}
+
+/// A JavaScript expression that evaluates to the installed apps, from their
+/// `.info` files: each one's id, name, type, and version.
+pub const LIST_EXPR: &str = "require('Storage').list(/\\.info$/).map(function(f){\
+     var i=require('Storage').readJSON(f,1)||{};\
+     return {id:i.id||f.slice(0,-5),name:i.name,type:i.type,version:i.version};})";
+
+/// A JavaScript expression that removes an app as the App Loader does: every
+/// file its `.info` file lists, including its data files (matching any
+/// wildcards, and with every chunk of those kept as `StorageFile`s), and then
+/// the `.info` file itself. It evaluates to whether the app was installed.
+pub fn remove_expr(id: &str) -> String {
+    format!(
+        "(function(id){{var S=require('Storage'),i=S.readJSON(id+'.info',1);if(!i)return false;\
+         function each(l,sf){{(l||'').split(',').forEach(function(f){{if(!f)return;\
+         var p='^'+f.replace(/[.+?^${{}}()|[\\]\\\\]/g,'\\\\$&').replace(/\\*/g,'.*')+(sf?'[\\x01-\\xff]?':'')+'$';\
+         S.list(new RegExp(p)).forEach(function(n){{S.erase(n);}});}});}}\
+         each(i.files);var d=(i.data||'').split(';');each(d[0]);each(d[1],1);\
+         S.erase(id+'.info');return true;}})({})",
+        serde_json::to_string(id).unwrap()
+    )
+}
+
+/// A JavaScript expression that makes an installed clock the one the watch
+/// shows, as the settings app does, evaluating to whether there was such a
+/// clock.
+pub fn set_clock_expr(id: &str) -> String {
+    format!(
+        "(function(id){{var S=require('Storage'),i=S.readJSON(id+'.info',1);\
+         if(!i||i.type!='clock'||!i.src)return false;var s=S.readJSON('setting.json',1)||{{}};\
+         s.clock=i.src;s.clockHasWidgets=(S.read(i.src)||'').includes('Bangle.loadWidgets');\
+         S.writeJSON('setting.json',s);return true;}})({})",
+        serde_json::to_string(id).unwrap()
+    )
+}
+
+/// A JavaScript expression that switches the watch to the standard dark theme
+/// (or light, if `dark` is false, or whichever it isn't using, if `None`) in
+/// its settings, as the settings app does, and reloads the app to show it. It
+/// evaluates to whether the new theme is dark.
+pub fn set_theme_expr(dark: Option<bool>) -> String {
+    format!(
+        "(function(dark){{var S=require('Storage'),s=S.readJSON('setting.json',1)||{{}};\
+         if(dark===null)dark=!(s.theme?s.theme.dark:g.theme.dark);\
+         function cl(x){{return g.setColor(x).getColor();}}\
+         s.theme=dark?{{fg:cl('#fff'),bg:cl('#000'),fg2:cl('#fff'),bg2:cl('#004'),fgH:cl('#fff'),bgH:cl('#00f'),dark:true}}\
+         :{{fg:cl('#000'),bg:cl('#fff'),fg2:cl('#000'),bg2:cl('#cff'),fgH:cl('#000'),bgH:cl('#0ff'),dark:false}};\
+         S.writeJSON('setting.json',s);load();return dark;}})({})",
+        match dark {
+            Some(dark) => dark.to_string(),
+            None => "null".to_owned(),
+        }
+    )
+}
+
+/// Makes an installed clock the one the watch shows by editing its settings in
+/// a flash image, as [`set_clock_expr`] does on a running watch, returning
+/// whether the clock's `.info` file was there to find it from.
+pub fn set_clock_in_storage(storage: &mut Storage, id: &str) -> anyhow::Result<bool> {
+    let Some(info) = storage.read(&format!("{id}.info")) else {
+        return Ok(false);
+    };
+    let info: serde_json::Value = serde_json::from_slice(info)
+        .with_context(|| format!("Failed to parse the .info file of {id:?}"))?;
+    let (Some("clock"), Some(src)) = (info["type"].as_str(), info["src"].as_str()) else {
+        anyhow::bail!("app {id:?} isn't a clock");
+    };
+    let widgets = b"Bangle.loadWidgets";
+    let has_widgets = storage
+        .read(src)
+        .is_some_and(|code| code.windows(widgets.len()).any(|w| w == widgets));
+    let mut settings: serde_json::Map<String, serde_json::Value> = storage
+        .read(SETTINGS_FILE)
+        .and_then(|s| serde_json::from_slice(s).ok())
+        .unwrap_or_default();
+    settings.insert("clock".to_owned(), src.into());
+    settings.insert("clockHasWidgets".to_owned(), has_widgets.into());
+    storage.write(SETTINGS_FILE, serde_json::to_string(&settings)?.as_bytes())?;
+    Ok(true)
+}
@@ -0,0 +1,124 @@
+use std::sync::{Arc, Mutex};
+
+use log::info;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::power::Powered;
+
+/// How fast things on the watch drain its battery, in percent of a full charge
+/// per hour, for comparing how power-hungry apps are. The defaults are rough
+/// figures for a Bangle.js 2.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BatteryModel {
+    /// The charge to start with, in percent.
+    pub initial: f64,
+    /// The drain with everything off and the CPU asleep.
+    pub idle: f64,
+    /// The extra drain while the backlight is on.
+    pub backlight: f64,
+    /// The extra drain while the GPS is powered.
+    pub gps: f64,
+    /// The extra drain while the heart rate monitor is powered.
+    pub hrm: f64,
+    /// The extra drain per hour of the firmware running rather than sleeping,
+    /// going by how long its idle calls take.
+    pub cpu: f64,
+    /// How fast the battery charges while the firmware says it's charging.
+    pub charge: f64,
+}
+
+impl Default for BatteryModel {
+    fn default() -> Self {
+        Self {
+            initial: 100.0,
+            idle: 0.15,
+            backlight: 6.0,
+            gps: 12.0,
+            hrm: 1.5,
+            cpu: 2.0,
+            charge: 50.0,
+        }
+    }
+}
+
+/// How much of the battery each thing has drained so far, in percent.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Drained {
+    pub idle: f64,
+    pub backlight: f64,
+    pub gps: f64,
+    pub hrm: f64,
+    pub cpu: f64,
+}
+
+impl Drained {
+    pub fn total(&self) -> f64 {
+        self.idle + self.backlight + self.gps + self.hrm + self.cpu
+    }
+}
+
+/// The reply to the `battery` control command.
+#[derive(Clone, Serialize)]
+pub struct BatteryReport {
+    /// The charge left, in percent.
+    pub level: f64,
+    pub drained: Drained,
+    /// How much has been put back by charging, in percent.
+    pub charged: f64,
+}
+
+/// A battery being drained by a running emulator, shared between the
+/// [`PowerMonitor`] draining it and anything reporting on it.
+///
+/// [`PowerMonitor`]: crate::power::PowerMonitor
+#[derive(Clone)]
+pub struct Battery {
+    model: BatteryModel,
+    state: Arc<Mutex<BatteryReport>>,
+}
+
+impl Battery {
+    pub fn new(model: BatteryModel) -> Self {
+        let state = BatteryReport {
+            level: model.initial.clamp(0.0, 100.0),
+            drained: Drained::default(),
+            charged: 0.0,
+        };
+        Self {
+            model,
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    pub fn report(&self) -> BatteryReport {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Drains the battery over some hours on the watch's clock, with the given
+    /// things powered throughout, and some hours of the CPU running, and
+    /// charges it over that time if it was charging.
+    pub fn drain(&self, hours: f64, on: Powered, charging: bool, cpu_hours: f64) {
+        let rate = |rate, on| if on { rate * hours } else { 0.0 };
+        let drained = Drained {
+            idle: self.model.idle * hours,
+            backlight: rate(self.model.backlight, on.backlight),
+            gps: rate(self.model.gps, on.gps),
+            hrm: rate(self.model.hrm, on.hrm),
+            cpu: self.model.cpu * cpu_hours,
+        };
+        let charged = rate(self.model.charge, charging);
+        let mut state = self.state.lock().unwrap();
+        let was_flat = state.level <= 0.0;
+        state.drained.idle += drained.idle;
+        state.drained.backlight += drained.backlight;
+        state.drained.gps += drained.gps;
+        state.drained.hrm += drained.hrm;
+        state.drained.cpu += drained.cpu;
+        state.charged += charged;
+        state.level = (state.level - drained.total() + charged).clamp(0.0, 100.0);
+        if !was_flat && state.level <= 0.0 {
+            info!("emulated battery is flat");
+        }
+    }
+}
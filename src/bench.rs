@@ -0,0 +1,196 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use log::info;
+
+use crate::{
+    control::{Command, TAP_DURATION},
+    emu::{Clock, Emulator, Input, Screen, BTN1},
+    runner::{apply_input, TICK_MS},
+    script::ScriptLine,
+};
+
+/// What a benchmark measured.
+pub struct BenchReport {
+    /// How long setting up the emulator took, including any storage uploads.
+    pub boot: Duration,
+    pub virtual_ms: f64,
+    pub wall: Duration,
+    /// How long each idle call took, in order.
+    pub idles: Vec<Duration>,
+    pub frames: usize,
+    pub console_bytes: usize,
+}
+
+/// Picks a percentile out of sorted durations.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let ind = ((sorted.len() - 1) as f64 * p).round() as usize;
+    sorted[ind]
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.virtual_ms / 1000.0;
+        let wall = self.wall.as_secs_f64();
+        writeln!(f, "boot:          {:.3}s", self.boot.as_secs_f64())?;
+        writeln!(
+            f,
+            "ran:           {secs:.1}s virtual in {wall:.3}s wall ({:.1}x real time)",
+            secs / wall
+        )?;
+
+        let mut idles = self.idles.clone();
+        idles.sort();
+        let total: Duration = idles.iter().sum();
+        let us = |d: Duration| d.as_secs_f64() * 1e6;
+        writeln!(
+            f,
+            "idle calls:    {} taking {:.3}s ({:.0}% of wall time)",
+            idles.len(),
+            total.as_secs_f64(),
+            100.0 * total.as_secs_f64() / wall
+        )?;
+        writeln!(
+            f,
+            "idle time:     p50 {:.0}us, p90 {:.0}us, p99 {:.0}us, max {:.0}us",
+            us(percentile(&idles, 0.5)),
+            us(percentile(&idles, 0.9)),
+            us(percentile(&idles, 0.99)),
+            us(idles.last().copied().unwrap_or_default())
+        )?;
+        writeln!(
+            f,
+            "frames:        {} ({:.1}/s virtual)",
+            self.frames,
+            self.frames as f64 / secs
+        )?;
+        write!(f, "console:       {} bytes", self.console_bytes)
+    }
+}
+
+/// Runs an emulator as fast as it will go on a virtual clock, feeding it
+/// scheduled and scripted inputs, to measure how much it costs to emulate.
+pub struct Bench {
+    emu: Emulator,
+    clock: Clock,
+    schedule: VecDeque<(f64, Input)>,
+    elapsed_ms: f64,
+    end_ms: f64,
+    screen: Screen,
+    report: BenchReport,
+}
+
+impl Bench {
+    /// Sets up a benchmark lasting `ms` of virtual time, with the inputs from
+    /// the config's schedule. `boot` is how long building the emulator took.
+    pub fn new(
+        mut emu: Emulator,
+        boot: Duration,
+        mut schedule: Vec<(Duration, Input)>,
+        ms: f64,
+    ) -> anyhow::Result<Self> {
+        let clock = Clock::stopped_at(emu.clock().now_ms());
+        emu.set_clock(clock.clone());
+        emu.send_pin_watch_event(BTN1)?;
+        schedule.sort_by_key(|(at, _)| *at);
+        Ok(Self {
+            emu,
+            clock,
+            schedule: schedule
+                .into_iter()
+                .map(|(at, input)| (at.as_secs_f64() * 1000.0, input))
+                .collect(),
+            elapsed_ms: 0.0,
+            end_ms: ms,
+            screen: Screen::default(),
+            report: BenchReport {
+                boot,
+                virtual_ms: 0.0,
+                wall: Duration::ZERO,
+                idles: vec![],
+                frames: 0,
+                console_bytes: 0,
+            },
+        })
+    }
+
+    fn done(&self) -> bool {
+        self.elapsed_ms >= self.end_ms
+    }
+
+    fn tick(&mut self) -> anyhow::Result<()> {
+        while self
+            .schedule
+            .front()
+            .is_some_and(|(at, _)| *at <= self.elapsed_ms)
+        {
+            let (_, input) = self.schedule.pop_front().unwrap();
+            apply_input(&mut self.emu, input)?;
+        }
+        self.clock.advance(TICK_MS);
+        self.elapsed_ms += TICK_MS;
+
+        let start = Instant::now();
+        self.emu.idle()?;
+        self.report.idles.push(start.elapsed());
+        // Grabbing frames and pumping the console are part of the cost of
+        // emulation too, so they're done just as the runner would.
+        if self.emu.gfx_changed()? {
+            self.emu.get_screen_into(&mut self.screen)?;
+            self.report.frames += 1;
+        }
+        for (_, chars) in self.emu.handle_io()? {
+            self.report.console_bytes += chars.len();
+        }
+        Ok(())
+    }
+
+    /// Moves the emulator forward by the given amount of virtual time, or
+    /// until the benchmark is over.
+    fn run_for(&mut self, ms: f64) -> anyhow::Result<()> {
+        let end = (self.elapsed_ms + ms).min(self.end_ms);
+        while self.elapsed_ms < end {
+            self.tick()?;
+        }
+        Ok(())
+    }
+
+    /// Runs the script as the workload, then keeps going until the benchmark's
+    /// time is up. A script that runs longer than that is cut short.
+    pub fn run(mut self, script: &[ScriptLine]) -> anyhow::Result<BenchReport> {
+        let start = Instant::now();
+        for line in script {
+            if self.done() {
+                info!("benchmark time ran out before script line {}", line.lineno);
+                break;
+            }
+            info!("bench script line {}: {}", line.lineno, line.text);
+            match &line.command {
+                Command::Wait(d) => self.run_for(d.as_secs_f64() * 1000.0)?,
+                Command::Tap(x, y) => {
+                    apply_input(&mut self.emu, Input::Touch(*x, *y, true, None))?;
+                    self.run_for(TAP_DURATION.as_secs_f64() * 1000.0)?;
+                    apply_input(&mut self.emu, Input::Touch(*x, *y, false, None))?;
+                }
+                c => match c.firmware_input() {
+                    Some(input) => apply_input(&mut self.emu, input)?,
+                    None => anyhow::bail!(
+                        "line {}: `{}` isn't supported when benchmarking",
+                        line.lineno,
+                        line.text
+                    ),
+                },
+            }
+        }
+        self.run_for(f64::INFINITY)?;
+        self.report.wall = start.elapsed();
+        self.report.virtual_ms = self.elapsed_ms;
+        Ok(self.report)
+    }
+}
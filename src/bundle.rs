@@ -0,0 +1,241 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use base64::{engine::general_purpose, Engine};
+use log::info;
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::{mpsc::Sender, oneshot};
+
+use crate::{
+    emu::{ClockAdjustment, Device, Input},
+    flash,
+    machine::Machine,
+    screenshot::encode_png,
+    touch::TouchMode,
+};
+
+/// The name of the config file in a bundle, which starts the firmware from the
+/// bundle's flash image.
+pub const CONFIG_FILE: &str = "config.toml";
+/// The flash as it was when the emulator started.
+pub const FLASH_FILE: &str = "flash.bin.zst";
+const FINAL_FLASH_FILE: &str = "flash-final.bin.zst";
+const INPUTS_FILE: &str = "inputs.jsonl";
+const CONSOLE_FILE: &str = "console.log";
+const SCREENSHOT_FILE: &str = "screenshot.png";
+const REASON_FILE: &str = "reason.txt";
+/// How much of the most recent console output goes in a bundle.
+const CONSOLE_LIMIT: usize = 1 << 20;
+
+/// Presses of BTN1 are recorded without a button number, as they were before
+/// there could be other buttons.
+fn first_button() -> usize {
+    1
+}
+
+fn is_first_button(button: &usize) -> bool {
+    *button == 1
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "type")]
+enum RecordedInput {
+    Console {
+        device: i32,
+        data: String,
+    },
+    Touch {
+        x: u8,
+        y: u8,
+        on: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pressure: Option<u8>,
+    },
+    Button {
+        #[serde(default = "first_button", skip_serializing_if = "is_first_button")]
+        button: usize,
+        on: bool,
+    },
+    TouchMode {
+        mode: TouchMode,
+    },
+    Pin {
+        pin: i32,
+        value: Option<bool>,
+    },
+    Charger {
+        on: Option<bool>,
+    },
+    Bluetooth {
+        connected: Option<bool>,
+    },
+    ClockDrift {
+        ppm: f64,
+    },
+    ClockSkew {
+        ms: f64,
+    },
+    /// Replayed against the host's clock at the time of the replay.
+    TimeSync {
+        offset_ms: f64,
+        timezone: Option<f64>,
+    },
+}
+
+/// An input, and when it arrived on the firmware's clock relative to startup.
+#[derive(Debug, Deserialize, Serialize)]
+struct Record {
+    at_ms: f64,
+    #[serde(flatten)]
+    input: RecordedInput,
+}
+
+/// Keeps what's needed to reproduce a run of the emulator: the flash it
+/// started from and every input since, along with the console output and
+/// screen for context, and writes them out as a bundle when something goes
+/// wrong.
+pub struct Recorder {
+    dir: PathBuf,
+    config: String,
+    boot_flash: Vec<u8>,
+    start_ms: f64,
+    inputs: Vec<Record>,
+    console: VecDeque<u8>,
+}
+
+impl Recorder {
+    /// Starts recording an emulator that was set up by `config` (the text of
+    /// a config file that reproduces its setup from [`FLASH_FILE`]), to write a
+    /// bundle to `dir`.
+    pub fn new(dir: PathBuf, config: String, emu: &impl Machine) -> Self {
+        Self {
+            dir,
+            config,
+            boot_flash: emu.flash().to_vec(),
+            start_ms: emu.clock().now_ms(),
+            inputs: vec![],
+            console: VecDeque::new(),
+        }
+    }
+
+    /// Records an input delivered at `now_ms` on the firmware's clock. Inputs
+    /// that only observe or control the emulator aren't recorded.
+    pub fn record_input(&mut self, now_ms: f64, input: &Input) {
+        let input = match input {
+            Input::Console(device, data) => RecordedInput::Console {
+                device: device.0,
+                data: general_purpose::STANDARD.encode(data),
+            },
+            Input::Touch(x, y, on, pressure) => RecordedInput::Touch {
+                x: *x,
+                y: *y,
+                on: *on,
+                pressure: *pressure,
+            },
+            Input::Button(button, on) => RecordedInput::Button {
+                button: *button,
+                on: *on,
+            },
+            Input::TouchMode(mode) => RecordedInput::TouchMode { mode: *mode },
+            Input::Pin(pin, value) => RecordedInput::Pin {
+                pin: *pin,
+                value: *value,
+            },
+            Input::Charger(on) => RecordedInput::Charger { on: *on },
+            Input::Bluetooth(connected) => RecordedInput::Bluetooth {
+                connected: *connected,
+            },
+            Input::AdjustClock(ClockAdjustment::Drift(ppm)) => {
+                RecordedInput::ClockDrift { ppm: *ppm }
+            }
+            Input::AdjustClock(ClockAdjustment::Skew(ms)) => RecordedInput::ClockSkew { ms: *ms },
+            Input::TimeSync(offset_ms, timezone) => RecordedInput::TimeSync {
+                offset_ms: *offset_ms,
+                timezone: *timezone,
+            },
+            _ => return,
+        };
+        self.inputs.push(Record {
+            at_ms: now_ms - self.start_ms,
+            input,
+        });
+    }
+
+    pub fn record_console(&mut self, data: &[u8]) {
+        self.console.extend(data);
+        let excess = self.console.len().saturating_sub(CONSOLE_LIMIT);
+        self.console.drain(..excess);
+    }
+
+    /// Writes the bundle, explaining why in its `reason.txt`.
+    pub fn write(&self, emu: &mut impl Machine, reason: &str) -> anyhow::Result<()> {
+        let dir = &self.dir;
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {dir:?}"))?;
+        fs::write(dir.join(REASON_FILE), format!("{reason}\n"))?;
+        fs::write(dir.join(CONFIG_FILE), &self.config)?;
+        flash::write_image(&dir.join(FLASH_FILE), &self.boot_flash)?;
+        flash::write_image(&dir.join(FINAL_FLASH_FILE), emu.flash())?;
+        let mut inputs = fs::File::create(dir.join(INPUTS_FILE))?;
+        for record in &self.inputs {
+            writeln!(inputs, "{}", serde_json::to_string(record)?)?;
+        }
+        fs::write(
+            dir.join(CONSOLE_FILE),
+            self.console.iter().copied().collect::<Vec<_>>(),
+        )?;
+        // The firmware may be in no state to give up its screen after a trap.
+        if let Ok(screen) = emu.get_screen() {
+            fs::write(dir.join(SCREENSHOT_FILE), encode_png(&screen)?)?;
+        }
+        info!("wrote reproducer bundle to {dir:?}");
+        Ok(())
+    }
+}
+
+/// Reads the inputs recorded in a bundle, for delivering on the same schedule.
+pub fn load_inputs(dir: &Path) -> anyhow::Result<Vec<(Duration, Input)>> {
+    let path = dir.join(INPUTS_FILE);
+    let f = fs::File::open(&path).with_context(|| format!("Failed to open {path:?}"))?;
+    let mut inputs = vec![];
+    for line in BufReader::new(f).lines() {
+        let record: Record = serde_json::from_str(&line?)?;
+        let input = match record.input {
+            RecordedInput::Console { device, data } => {
+                Input::Console(Device(device), general_purpose::STANDARD.decode(data)?)
+            }
+            RecordedInput::Touch { x, y, on, pressure } => Input::Touch(x, y, on, pressure),
+            RecordedInput::Button { button, on } => Input::Button(button, on),
+            RecordedInput::Pin { pin, value } => Input::Pin(pin, value),
+            RecordedInput::Charger { on } => Input::Charger(on),
+            RecordedInput::Bluetooth { connected } => Input::Bluetooth(connected),
+            RecordedInput::ClockDrift { ppm } => Input::AdjustClock(ClockAdjustment::Drift(ppm)),
+            RecordedInput::ClockSkew { ms } => Input::AdjustClock(ClockAdjustment::Skew(ms)),
+            RecordedInput::TimeSync {
+                offset_ms,
+                timezone,
+            } => Input::TimeSync(offset_ms, timezone),
+            RecordedInput::TouchMode { mode } => Input::TouchMode(mode),
+        };
+        inputs.push((
+            Duration::from_secs_f64(record.at_ms.max(0.0) / 1000.0),
+            input,
+        ));
+    }
+    Ok(inputs)
+}
+
+/// Asks a runner to write its reproducer bundle, if it's recording one.
+pub async fn request(to_emu: &Sender<Input>, reason: String) -> anyhow::Result<()> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    to_emu
+        .send(Input::WriteBundle(reason, reply_tx))
+        .await
+        .map_err(|_| anyhow::format_err!("emulator is not running"))?;
+    reply_rx.await?
+}
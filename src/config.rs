@@ -0,0 +1,1037 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{BufRead, Read},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use anyhow::Context;
+use base64::{engine::general_purpose, Engine};
+use log::info;
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    app::{self, AppContents},
+    battery::BatteryModel,
+    bundle,
+    control::TAP_DURATION,
+    device::{timezone_expr, DeviceProfile},
+    emu::{ClockAdjustment, Device, Emulator, Input, FLASH_SIZE},
+    eval, flash, heatshrink,
+    http_proxy::{HttpProxy, HttpProxyConfig},
+    peripheral::Peripheral,
+    plugin::WasmPlugin,
+    pool::EmulatorPool,
+    responder::Response,
+    storage::{Storage, NAME_LEN},
+    tui_extras::Palette,
+    widgets,
+};
+
+/// How long the button is held for a short and a long press.
+const SHORT_PRESS: Duration = Duration::from_millis(100);
+const LONG_PRESS: Duration = Duration::from_millis(1000);
+
+/// How much console input to send between progress reports while writing
+/// files into storage or uploading them to RAM.
+const PROGRESS_STEP: usize = 1 << 12;
+
+/// How far along writing a file into storage (or uploading it to RAM) is, in
+/// bytes of the console input that sends it.
+#[derive(Clone, Copy, Debug)]
+pub struct UploadProgress<'a> {
+    pub file: &'a str,
+    pub sent: usize,
+    pub total: usize,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+enum FileContents {
+    #[serde(rename = "path")]
+    Path(PathBuf),
+    #[serde(rename = "contents")]
+    Contents(String),
+}
+
+impl FileContents {
+    fn load(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(match self {
+            Self::Path(p) => fs::read(p).with_context(|| format!("Failed to load file {p:?}"))?,
+            Self::Contents(s) => s.clone().into_bytes(),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct FileSpec {
+    #[serde(default)]
+    evaluate: bool,
+
+    /// Whether the contents are compressed with heatshrink, as in an App
+    /// Loader package, to be decompressed before they're stored.
+    #[serde(default)]
+    heatshrink: bool,
+
+    #[serde(flatten)]
+    contents: FileContents,
+}
+
+impl FileSpec {
+    fn load(&self) -> anyhow::Result<Vec<u8>> {
+        let contents = self.contents.load()?;
+        Ok(if self.heatshrink {
+            heatshrink::decompress(&contents)
+        } else {
+            contents
+        })
+    }
+}
+
+/// Gets what an expression the App Loader stores compressed files as,
+/// `require("heatshrink").decompress(atob("..."))`, evaluates to, so that the
+/// file can be written without the firmware evaluating it.
+fn decompressed_value(expr: &[u8]) -> Option<Vec<u8>> {
+    static EXPR: OnceLock<Regex> = OnceLock::new();
+    let re = EXPR.get_or_init(|| {
+        Regex::new(
+            r#"^\s*require\(\s*["']heatshrink["']\s*\)\.decompress\(\s*atob\(\s*["']([A-Za-z0-9+/=]*)["']\s*\)\s*\)\s*;?\s*$"#,
+        )
+        .unwrap()
+    });
+    let captures = re.captures(std::str::from_utf8(expr).ok()?)?;
+    let data = general_purpose::STANDARD.decode(&captures[1]).ok()?;
+    Some(heatshrink::decompress(&data))
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum ButtonPress {
+    Short,
+    Long,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum ScheduledAction {
+    /// Tap the screen at a point.
+    Touch([u8; 2]),
+    Button(ButtonPress),
+    /// Send a line of text to the console.
+    Send(String),
+    /// Jump the watch's clock by a number of milliseconds, as a time sync
+    /// would.
+    ClockSkew(f64),
+    /// Sync the watch's time to the host's clock plus a number of
+    /// milliseconds, as Gadgetbridge does.
+    TimeSync(f64),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ScheduleEntry {
+    /// When to act, relative to startup, e.g. `"5s"` or `"250ms"`.
+    at: String,
+    #[serde(flatten)]
+    action: ScheduledAction,
+}
+
+/// A canned reply to messages the watch sends Gadgetbridge.
+#[derive(Clone, Debug, Deserialize)]
+struct ResponseRule {
+    /// The fields a message has to have, with these values, such as
+    /// `{ t = "http" }`.
+    when: serde_json::Map<String, serde_json::Value>,
+    /// How long to take to reply, e.g. `"200ms"`.
+    after: Option<String>,
+    reply: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Parses a duration given as a number with a unit of `ms`, `s`, or `m`.
+fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let num: f64 = num
+        .parse()
+        .with_context(|| format!("invalid duration {s:?}"))?;
+    let secs = match unit.trim() {
+        "ms" => num / 1000.0,
+        "s" => num,
+        "m" => num * 60.0,
+        _ => anyhow::bail!("invalid duration {s:?}: expected a unit of ms, s, or m"),
+    };
+    Duration::try_from_secs_f64(secs).with_context(|| format!("invalid duration {s:?}"))
+}
+
+/// Expands `${NAME}` references to environment variables in a string.
+fn interpolate_env(s: &str) -> anyhow::Result<String> {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let Some(len) = rest[start + 2..].find('}') else {
+            anyhow::bail!("unterminated variable reference in {s:?}");
+        };
+        let name = &rest[start + 2..start + 2 + len];
+        let value = std::env::var(name)
+            .with_context(|| format!("Failed to expand ${{{name}}} in {s:?}"))?;
+        out.push_str(&value);
+        rest = &rest[start + 3 + len..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Expands environment variables in a path from a config file, and makes it
+/// relative to the directory containing the file rather than the working
+/// directory.
+fn resolve_path(path: &str, dir: &Path) -> anyhow::Result<String> {
+    let path = interpolate_env(path)?;
+    Ok(dir.join(path).to_string_lossy().into_owned())
+}
+
+/// Resolves every path in a config table (or scenario table) read from a file
+/// in `dir`.
+fn resolve_paths(table: &mut toml::Table, dir: &Path) -> anyhow::Result<()> {
+    if let Some(toml::Value::String(path)) = table.get_mut("flash_initial_contents_file") {
+        *path = resolve_path(path, dir)?;
+    }
+    if let Some(toml::Value::Array(plugins)) = table.get_mut("plugins") {
+        for plugin in plugins {
+            if let toml::Value::String(path) = plugin {
+                *path = resolve_path(path, dir)?;
+            }
+        }
+    }
+    for key in ["apps", "upload"] {
+        if let Some(toml::Value::Array(paths)) = table.get_mut(key) {
+            for path in paths {
+                if let toml::Value::String(path) = path {
+                    *path = resolve_path(path, dir)?;
+                }
+            }
+        }
+    }
+    if let Some(toml::Value::Table(storage)) = table.get_mut("storage") {
+        for (_, spec) in storage.iter_mut() {
+            if let Some(toml::Value::String(path)) = spec.get_mut("path") {
+                *path = resolve_path(path, dir)?;
+            }
+        }
+    }
+    if let Some(toml::Value::Table(scenarios)) = table.get_mut("scenario") {
+        for (_, scenario) in scenarios.iter_mut() {
+            if let toml::Value::Table(scenario) = scenario {
+                resolve_paths(scenario, dir)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Merges `overlay` into `base`: tables are merged key by key, and any other
+/// value in `overlay` replaces the one in `base`. Storage files are replaced
+/// whole, since a file's contents can't come from both a path and a string.
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) if key == "storage" => {
+                base.extend(overlay)
+            }
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge_tables(base, overlay)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Reads a config file as a table with its paths resolved, layered on top of
+/// the files it includes. `stack` holds the files currently being read, to
+/// catch include cycles.
+fn read_table(path: &Path, stack: &mut Vec<PathBuf>) -> anyhow::Result<toml::Table> {
+    let canonical = fs::canonicalize(path).with_context(|| format!("Failed to open {path:?}"))?;
+    if stack.contains(&canonical) {
+        anyhow::bail!("config {path:?} includes itself");
+    }
+    let mut f = File::open(path)?;
+    let mut buf = String::new();
+    f.read_to_string(&mut buf)?;
+    // JSON and YAML configs have the same structure as TOML ones; reading them
+    // into a TOML table lets them go through the same path handling.
+    let parsed: anyhow::Result<toml::Table> = match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => serde_json::from_str(&buf).map_err(Into::into),
+        Some("yaml" | "yml") => serde_yaml::from_str(&buf).map_err(Into::into),
+        _ => toml::from_str(&buf).map_err(Into::into),
+    };
+    let mut table = parsed.with_context(|| format!("Failed to parse {path:?}"))?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    resolve_paths(&mut table, dir)?;
+
+    let includes: Vec<String> = match table.remove("include") {
+        Some(includes) => includes
+            .try_into()
+            .with_context(|| format!("include in {path:?} must be a list of paths"))?,
+        None => vec![],
+    };
+    stack.push(canonical);
+    let mut merged = toml::Table::new();
+    for include in includes {
+        let include = resolve_path(&include, dir)?;
+        merge_tables(&mut merged, read_table(Path::new(&include), stack)?);
+    }
+    stack.pop();
+    merge_tables(&mut merged, table);
+    Ok(merged)
+}
+
+/// Settings that override the rest of a config when its scenario is selected.
+/// Its storage files are added to the config's, replacing any with the same
+/// names. Settings that scenarios can't override are rejected rather than
+/// ignored.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Scenario {
+    factory_reset: Option<bool>,
+    flash_initial_contents_file: Option<String>,
+    #[serde(default)]
+    storage: HashMap<String, FileSpec>,
+    startup: Option<String>,
+    default_clock: Option<String>,
+    boot_app: Option<String>,
+    device: Option<DeviceProfile>,
+    schedule: Option<Vec<ScheduleEntry>>,
+    respond: Option<Vec<ResponseRule>>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    factory_reset: bool,
+    flash_initial_contents_file: Option<String>,
+    /// The SHA-256 the firmware has to have, as hex.
+    firmware_sha256: Option<String>,
+    #[serde(default)]
+    storage: HashMap<String, FileSpec>,
+    /// BangleApps-style app directories to install, as the App Loader would,
+    /// before the storage files, which can replace their files.
+    #[serde(default)]
+    apps: Vec<String>,
+    /// Canned widgets to install, by fixture name, after the apps.
+    #[serde(default)]
+    widgets: Vec<String>,
+    /// Whether to write storage files through the console after startup, like
+    /// the Espruino IDE, rather than directly into the flash beforehand.
+    #[serde(default)]
+    console_storage: bool,
+    startup: Option<String>,
+    /// The id of an installed clock app to make the one the watch shows, so
+    /// that it boots straight into it.
+    default_clock: Option<String>,
+    /// A file in storage to load after the startup string, so that the watch
+    /// lands in the app under test without going through the launcher.
+    boot_app: Option<String>,
+    /// JavaScript files to send to the console after the startup string, as
+    /// the Espruino IDE does when uploading to RAM, so that they run without
+    /// being written to storage.
+    #[serde(default)]
+    upload: Vec<String>,
+    #[serde(default)]
+    pub device: DeviceProfile,
+    /// WebAssembly modules simulating peripherals to attach.
+    #[serde(default)]
+    plugins: Vec<String>,
+    /// The colors to show the screen in the TUI with.
+    pub palette: Option<Palette>,
+    /// A model of the battery draining as the watch runs, which the firmware
+    /// reports the level of.
+    pub battery: Option<BatteryModel>,
+    #[serde(default)]
+    schedule: Vec<ScheduleEntry>,
+    /// Replies to send, as an emulated phone, to messages the watch sends
+    /// Gadgetbridge.
+    #[serde(default)]
+    respond: Vec<ResponseRule>,
+    /// Makes the watch's requests to Gadgetbridge's HTTP proxy from the host,
+    /// for the URLs it allows, when no reply in `respond` matches.
+    pub http_proxy: Option<HttpProxyConfig>,
+    #[serde(default)]
+    scenario: HashMap<String, Scenario>,
+}
+
+impl Config {
+    /// Reads a config file. Paths in it may refer to environment variables as
+    /// `${NAME}`, and relative ones are taken relative to the file's directory.
+    /// The file may list other config files to `include`, which it's layered
+    /// on top of in order. Files ending in `.json`, `.yaml`, or `.yml` are read
+    /// as JSON or YAML, and anything else as TOML.
+    pub fn read<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let table = read_table(path.as_ref(), &mut vec![])?;
+        let config: Config = table.try_into()?;
+        Ok(config)
+    }
+
+    /// Applies the settings of one of the config's named scenarios.
+    pub fn select_scenario(&mut self, name: &str) -> anyhow::Result<()> {
+        let Some(scenario) = self.scenario.remove(name) else {
+            let mut names: Vec<_> = self.scenario.keys().map(String::as_str).collect();
+            names.sort_unstable();
+            anyhow::bail!(
+                "no scenario named {name:?} in config (available: {})",
+                if names.is_empty() {
+                    "none".to_owned()
+                } else {
+                    names.join(", ")
+                }
+            );
+        };
+        if let Some(factory_reset) = scenario.factory_reset {
+            self.factory_reset = factory_reset;
+        }
+        if scenario.flash_initial_contents_file.is_some() {
+            self.flash_initial_contents_file = scenario.flash_initial_contents_file;
+        }
+        self.storage.extend(scenario.storage);
+        if scenario.startup.is_some() {
+            self.startup = scenario.startup;
+        }
+        if scenario.default_clock.is_some() {
+            self.default_clock = scenario.default_clock;
+        }
+        if scenario.boot_app.is_some() {
+            self.boot_app = scenario.boot_app;
+        }
+        if let Some(device) = scenario.device {
+            self.device = device;
+        }
+        if let Some(schedule) = scenario.schedule {
+            self.schedule = schedule;
+        }
+        if let Some(respond) = scenario.respond {
+            self.respond = respond;
+        }
+        Ok(())
+    }
+
+    /// Gets every file to write into storage: those of the apps to install,
+    /// those of the widget fixtures, and then the config's own storage files.
+    fn storage(&self) -> anyhow::Result<HashMap<String, FileSpec>> {
+        let mut storage = HashMap::new();
+        for dir in &self.apps {
+            for file in app::app_files(Path::new(dir), self.device.board)? {
+                let contents = match file.contents {
+                    AppContents::Path(p) => FileContents::Path(p),
+                    AppContents::Text(s) => FileContents::Contents(s),
+                };
+                let spec = FileSpec {
+                    evaluate: file.evaluate,
+                    heatshrink: false,
+                    contents,
+                };
+                storage.insert(file.name, spec);
+            }
+        }
+        for name in &self.widgets {
+            for (file, contents) in widgets::fixture(name)?.files {
+                let spec = FileSpec {
+                    evaluate: false,
+                    heatshrink: false,
+                    contents: FileContents::Contents((*contents).to_owned()),
+                };
+                storage.insert((*file).to_owned(), spec);
+            }
+        }
+        storage.extend(self.storage.clone());
+        Ok(storage)
+    }
+
+    /// Sets the file in storage to load after the startup string.
+    pub fn set_boot_app(&mut self, file: String) {
+        self.boot_app = Some(file);
+    }
+
+    /// Adds a JavaScript file to upload to RAM after the config's own.
+    pub fn add_upload(&mut self, path: &Path) {
+        self.upload.push(path.to_string_lossy().into_owned());
+    }
+
+    /// Gets the inputs from the config's schedule, along with when to deliver
+    /// them relative to startup.
+    pub fn schedule(&self) -> anyhow::Result<Vec<(Duration, Input)>> {
+        let mut inputs = vec![];
+        for entry in &self.schedule {
+            let at = parse_duration(&entry.at)?;
+            match &entry.action {
+                ScheduledAction::Touch([x, y]) => {
+                    let spec = self.device.screen_spec();
+                    if !self.device.board.has_touchscreen() {
+                        anyhow::bail!("scheduled touch, but the device has no touchscreen");
+                    }
+                    if usize::from(*x) >= spec.width || usize::from(*y) >= spec.height {
+                        anyhow::bail!("scheduled touch at ({x}, {y}) is off the screen");
+                    }
+                    inputs.push((at, Input::Touch(*x, *y, true, None)));
+                    inputs.push((at + TAP_DURATION, Input::Touch(*x, *y, false, None)));
+                }
+                ScheduledAction::Button(press) => {
+                    let hold = match press {
+                        ButtonPress::Short => SHORT_PRESS,
+                        ButtonPress::Long => LONG_PRESS,
+                    };
+                    inputs.push((at, Input::Button(1, true)));
+                    inputs.push((at + hold, Input::Button(1, false)));
+                }
+                ScheduledAction::Send(text) => inputs.push((
+                    at,
+                    Input::Console(Device::CONSOLE, format!("{text}\n").into_bytes()),
+                )),
+                ScheduledAction::ClockSkew(ms) => {
+                    inputs.push((at, Input::AdjustClock(ClockAdjustment::Skew(*ms))))
+                }
+                ScheduledAction::TimeSync(ms) => inputs.push((at, Input::TimeSync(*ms, None))),
+            }
+        }
+        Ok(inputs)
+    }
+
+    /// Checks the config, and each of its scenarios, for problems that would
+    /// otherwise only come up partway through setting up an emulator, and
+    /// describes all of them.
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = self.check_own();
+        let mut names: Vec<_> = self.scenario.keys().collect();
+        names.sort_unstable();
+        for name in names {
+            let mut config = self.clone();
+            config.select_scenario(name).unwrap();
+            for problem in config.check_own() {
+                // Problems in the base config show up in every scenario.
+                if !problems.contains(&problem) {
+                    problems.push(format!("scenario {name:?}: {problem}"));
+                }
+            }
+        }
+        problems
+    }
+
+    fn check_own(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if let Some(hex) = &self.firmware_sha256 {
+            if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                problems.push(format!("firmware_sha256 {hex:?} isn't a SHA-256 in hex"));
+            }
+        }
+        for plugin in &self.plugins {
+            if !Path::new(plugin).is_file() {
+                problems.push(format!("plugin {plugin:?} doesn't exist"));
+            }
+        }
+        if let Some(f) = &self.flash_initial_contents_file {
+            if let Err(err) = get_flash_initial_contents(f) {
+                problems.push(format!("can't read flash contents file {f:?}: {err}"));
+            }
+        }
+        let storage = self.storage().unwrap_or_else(|err| {
+            problems.push(format!("can't install apps or widgets: {err:#}"));
+            self.storage.clone()
+        });
+        let mut names: Vec<_> = storage.keys().collect();
+        names.sort_unstable();
+        for name in names {
+            if name.is_empty() || name.len() > NAME_LEN {
+                problems.push(format!(
+                    "storage file name {name:?} must be 1 to {NAME_LEN} bytes long"
+                ));
+            }
+            if let FileContents::Path(p) = &storage[name].contents {
+                if let Err(err) = fs::metadata(p) {
+                    problems.push(format!("can't read {p:?} for storage file {name:?}: {err}"));
+                }
+            }
+        }
+        for path in &self.upload {
+            if let Err(err) = fs::metadata(path) {
+                problems.push(format!("can't read upload {path:?}: {err}"));
+            }
+        }
+        if let Err(err) = self.schedule() {
+            problems.push(format!("bad schedule: {err:#}"));
+        }
+        if let Err(err) = self.responses() {
+            problems.push(format!("bad reply: {err:#}"));
+        }
+        if let Some(Err(err)) = self.http_proxy.as_ref().map(HttpProxy::new) {
+            problems.push(format!("bad http_proxy: {err:#}"));
+        }
+        problems
+    }
+
+    /// Evaluates the files the config (and its scenarios) would store the
+    /// results of evaluating on a freshly booted emulator, describing any
+    /// that fail. Files that can't be read are skipped, since [`Config::check`]
+    /// reports them.
+    pub fn check_evaluated(&self, pool: &EmulatorPool) -> anyhow::Result<Vec<String>> {
+        if let Err(err) = self.check_firmware(pool) {
+            return Ok(vec![err.to_string()]);
+        }
+        let mut emu = pool.instantiate_with_peripherals(self.peripherals()?)?;
+        emu.init()?;
+        // Problems installing apps are reported by `check`.
+        let storage = self.storage().unwrap_or_else(|_| self.storage.clone());
+        let mut files: Vec<_> = storage
+            .iter()
+            .map(|(name, spec)| (name.clone(), spec))
+            .collect();
+        for (scenario, config) in &self.scenario {
+            files.extend(
+                config
+                    .storage
+                    .iter()
+                    .map(|(name, spec)| (format!("{name} (scenario {scenario:?})"), spec)),
+            );
+        }
+        files.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+        let mut problems = vec![];
+        for (name, spec) in files {
+            if !spec.evaluate {
+                continue;
+            }
+            let Ok(contents) = spec.load() else {
+                continue;
+            };
+            let expr = format!(
+                "eval(atob('{}')),true",
+                general_purpose::STANDARD.encode(contents)
+            );
+            if let Err(err) = eval::eval_sync(&mut emu, &expr)? {
+                problems.push(format!("evaluating storage file {name:?} failed: {err}"));
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Gets the config's canned replies to messages from the watch, in the
+    /// order they're tried.
+    pub fn responses(&self) -> anyhow::Result<Vec<Response>> {
+        self.respond
+            .iter()
+            .map(|rule| {
+                Ok(Response {
+                    when: rule.when.clone(),
+                    after: match &rule.after {
+                        Some(after) => parse_duration(after)?,
+                        None => Duration::ZERO,
+                    },
+                    reply: rule.reply.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// The text of a config that starts the emulator the way this one does
+    /// from the reproducer bundle's flash image. That already has the storage
+    /// files that were written straight into the flash in it, which writing
+    /// again leaves as they are, so only those sent through the console are
+    /// sent again; it's not reset, for the same reason. Paths are made
+    /// absolute, so that the bundle can be replayed from anywhere.
+    pub fn bundle_config(&self) -> anyhow::Result<String> {
+        #[derive(Serialize)]
+        struct BundleConfig<'a> {
+            flash_initial_contents_file: &'a str,
+            firmware_sha256: Option<&'a str>,
+            storage: BTreeMap<&'a str, FileSpec>,
+            apps: Vec<String>,
+            widgets: &'a [String],
+            console_storage: bool,
+            startup: Option<&'a str>,
+            default_clock: Option<&'a str>,
+            boot_app: Option<&'a str>,
+            upload: Vec<String>,
+            device: &'a DeviceProfile,
+            plugins: Vec<String>,
+        }
+        let absolute = |path: &str| -> anyhow::Result<String> {
+            Ok(std::path::absolute(path)
+                .with_context(|| format!("Failed to resolve {path:?}"))?
+                .to_string_lossy()
+                .into_owned())
+        };
+        let absolute_all = |paths: &[String]| -> anyhow::Result<Vec<String>> {
+            paths.iter().map(|p| absolute(p)).collect()
+        };
+        let storage = self
+            .storage
+            .iter()
+            .map(|(name, spec)| {
+                let mut spec = spec.clone();
+                if let FileContents::Path(path) = &spec.contents {
+                    spec.contents = FileContents::Path(absolute(&path.to_string_lossy())?.into());
+                }
+                Ok((name.as_str(), spec))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(toml::to_string(&BundleConfig {
+            flash_initial_contents_file: bundle::FLASH_FILE,
+            firmware_sha256: self.firmware_sha256.as_deref(),
+            storage,
+            apps: absolute_all(&self.apps)?,
+            widgets: &self.widgets,
+            console_storage: self.console_storage,
+            startup: self.startup.as_deref(),
+            default_clock: self.default_clock.as_deref(),
+            boot_app: self.boot_app.as_deref(),
+            upload: absolute_all(&self.upload)?,
+            device: &self.device,
+            plugins: absolute_all(&self.plugins)?,
+        })?)
+    }
+
+    pub fn build<P: AsRef<Path>>(&self, wasm_path: P) -> anyhow::Result<Emulator> {
+        self.build_from(&EmulatorPool::new(wasm_path)?)
+    }
+
+    /// Like [`Config::build`], but using an already-compiled firmware.
+    pub fn build_from(&self, pool: &EmulatorPool) -> anyhow::Result<Emulator> {
+        self.build_with_progress(pool, |_| {})
+    }
+
+    /// Fails unless the firmware is the one the config pins with
+    /// `firmware_sha256`, if any, so that results can't silently change when
+    /// the firmware file is replaced.
+    pub fn check_firmware(&self, pool: &EmulatorPool) -> anyhow::Result<()> {
+        if let Some(expected) = &self.firmware_sha256 {
+            let actual = pool.sha256();
+            if !actual.eq_ignore_ascii_case(expected) {
+                anyhow::bail!("firmware has SHA-256 {actual}, but the config requires {expected}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Loads the config's peripheral plugins.
+    fn peripherals(&self) -> anyhow::Result<Vec<Box<dyn Peripheral>>> {
+        self.plugins
+            .iter()
+            .map(|plugin| Ok(Box::new(WasmPlugin::load(Path::new(plugin))?) as Box<dyn Peripheral>))
+            .collect()
+    }
+
+    /// Like [`Config::build_from`], but reporting progress through writing
+    /// each storage file, which can take a while for large ones.
+    pub fn build_with_progress(
+        &self,
+        pool: &EmulatorPool,
+        mut progress: impl FnMut(UploadProgress),
+    ) -> anyhow::Result<Emulator> {
+        self.check_firmware(pool)?;
+        let mut emu = pool.instantiate_with_peripherals(self.peripherals()?)?;
+        if let Some(f) = &self.flash_initial_contents_file {
+            emu.write_flash(&get_flash_initial_contents(f)?);
+        }
+        emu.set_profile(&self.device);
+
+        if self.factory_reset {
+            emu.reset_storage()?;
+        }
+
+        // Files that are stored as-is can go straight into the flash, which is
+        // much faster than sending them through the console; the rest need the
+        // firmware to evaluate them first, unless they only decompress data.
+        let storage = self.storage()?;
+        let mut console = vec![];
+        for (path, spec) in &storage {
+            let contents = spec.load()?;
+            let stored = if self.console_storage {
+                Err(contents)
+            } else if spec.evaluate {
+                decompressed_value(&contents).ok_or(contents)
+            } else {
+                Ok(contents)
+            };
+            match stored {
+                Ok(contents) => {
+                    info!("writing {} bytes to {} in flash", contents.len(), path);
+                    Storage::new(emu.flash_mut()).write(path, &contents)?;
+                }
+                Err(contents) => console.push((path, spec.evaluate, contents)),
+            }
+        }
+
+        // The clock is picked from the settings when the firmware boots, so
+        // it's set in the flash if the clock's there already; otherwise it's
+        // set through the console once its files are, for the startup string
+        // to load.
+        let console_clock = match &self.default_clock {
+            Some(id) => !app::set_clock_in_storage(&mut Storage::new(emu.flash_mut()), id)?,
+            None => false,
+        };
+
+        emu.init()?;
+
+        // Set up initial emulator state as specified by config.
+        fn b64(b: &[u8]) -> String {
+            general_purpose::STANDARD_NO_PAD.encode(b)
+        }
+
+        for (path, evaluate, contents) in console {
+            info!("writing {} bytes to {}", contents.len(), path);
+            let s = if evaluate {
+                format!(
+                    "\x10require('Storage').write(atob('{}'), eval(atob('{}')));\n",
+                    b64(path.as_bytes()),
+                    b64(&contents),
+                )
+            } else {
+                const CHUNK_SIZE: usize = 1 << 15;
+                contents
+                    .chunks(CHUNK_SIZE)
+                    .enumerate()
+                    .map(|(ind, chunk)| {
+                        format!(
+                            "\x10require('Storage').write(atob('{}'), atob('{}'), {}, {});\n",
+                            b64(path.as_bytes()),
+                            b64(chunk),
+                            ind * CHUNK_SIZE,
+                            contents.len(),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("")
+            };
+            push_with_progress(&mut emu, path, &s, &mut progress)?;
+        }
+
+        if let Some(hours) = self.device.timezone {
+            emu.push_string(format!("\x10{};\n", timezone_expr(hours)).as_bytes())?;
+        }
+
+        if let (true, Some(id)) = (console_clock, &self.default_clock) {
+            emu.push_string(format!("\x10{};\n", app::set_clock_expr(id)).as_bytes())?;
+        }
+
+        if let Some(s) = &self.startup {
+            emu.push_string(s.as_bytes())?;
+        }
+
+        if let Some(file) = &self.boot_app {
+            let file = serde_json::to_string(file)?;
+            emu.push_string(format!("\x10load({file});\n").as_bytes())?;
+        }
+
+        // Code uploaded to RAM is evaluated at the top level, so that its
+        // declarations end up global, as they would from the IDE.
+        for path in &self.upload {
+            let contents =
+                fs::read(path).with_context(|| format!("Failed to load upload {path:?}"))?;
+            info!("uploading {} bytes from {}", contents.len(), path);
+            let s = format!("\x10eval(atob('{}'));\n", b64(&contents));
+            push_with_progress(&mut emu, path, &s, &mut progress)?;
+        }
+
+        Ok(emu)
+    }
+}
+
+/// Sends console input to the emulator a piece at a time, reporting progress
+/// through it as sending `file`.
+fn push_with_progress(
+    emu: &mut Emulator,
+    file: &str,
+    s: &str,
+    progress: &mut impl FnMut(UploadProgress),
+) -> anyhow::Result<()> {
+    let mut sent = 0;
+    for piece in s.as_bytes().chunks(PROGRESS_STEP) {
+        emu.push_string(piece)?;
+        sent += piece.len();
+        progress(UploadProgress {
+            file,
+            sent,
+            total: s.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Where the Bangle.js 2 maps its external flash, which Intel HEX images of the
+/// storage are usually addressed from.
+const FLASH_BASE: usize = 0x6000_0000;
+
+/// Reads a flash image, which may be raw binary, Intel HEX, or rows of
+/// comma-separated decimal bytes, and may be compressed with gzip or zstd.
+fn get_flash_initial_contents<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<u8>> {
+    let data = flash::decompress(fs::read(path)?)?;
+    if data.first() == Some(&b':') {
+        parse_intel_hex(&data)
+    } else if data
+        .iter()
+        .all(|b| b.is_ascii_digit() || b", \t\r\n".contains(b))
+    {
+        parse_decimal_rows(&data)
+    } else {
+        Ok(data)
+    }
+}
+
+/// Parses an Intel HEX image, filling any gaps with erased bytes. Addresses from
+/// [`FLASH_BASE`] up are taken relative to it, and must then be within the
+/// flash.
+fn parse_intel_hex(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut ret = vec![];
+    let mut base = 0;
+    for (ind, line) in data.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let bad = || anyhow::format_err!("invalid Intel HEX record on line {}", ind + 1);
+        let hex = line.strip_prefix(':').ok_or_else(bad)?;
+        if hex.len() % 2 != 0 || hex.len() < 10 {
+            return Err(bad());
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| bad())?;
+        if bytes.iter().fold(0u8, |a, b| a.wrapping_add(*b)) != 0 {
+            anyhow::bail!("bad checksum in Intel HEX record on line {}", ind + 1);
+        }
+        let (len, record) = (bytes[0] as usize, &bytes[4..bytes.len() - 1]);
+        if record.len() != len {
+            return Err(bad());
+        }
+        let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as usize;
+        match bytes[3] {
+            0 => {
+                let mut addr = base + offset;
+                if addr >= FLASH_BASE {
+                    addr -= FLASH_BASE;
+                }
+                if addr + len > FLASH_SIZE {
+                    anyhow::bail!(
+                        "Intel HEX record on line {} is beyond the end of the flash",
+                        ind + 1
+                    );
+                }
+                if ret.len() < addr + len {
+                    ret.resize(addr + len, 0xff);
+                }
+                ret[addr..addr + len].copy_from_slice(record);
+            }
+            1 => break,
+            2 if len == 2 => base = (u16::from_be_bytes([record[0], record[1]]) as usize) << 4,
+            4 if len == 2 => base = (u16::from_be_bytes([record[0], record[1]]) as usize) << 16,
+            // Start addresses don't matter for a storage image.
+            3 | 5 => {}
+            _ => return Err(bad()),
+        }
+    }
+    Ok(ret)
+}
+
+fn parse_decimal_rows(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut ret = vec![];
+
+    for line in data.lines() {
+        let line = line?;
+        let fields = line.split(',');
+        let row: Result<Vec<u8>, _> = fields
+            .filter(|f| !f.is_empty())
+            .map(|f| f.parse())
+            .collect();
+        if let Ok(row) = row {
+            ret.extend(row);
+        }
+    }
+
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_too_long_to_hold_fail_to_parse() {
+        assert_eq!(parse_duration("1.5s").unwrap(), Duration::from_millis(1500));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+        let huge = format!("1{}s", "0".repeat(400));
+        assert!(parse_duration(&huge).is_err());
+        assert!(parse_duration("1e400s").is_err());
+    }
+
+    #[test]
+    fn intel_hex_must_stay_in_the_flash() {
+        // The last two bytes of the flash fit, but not the two just past it or
+        // two straddling its end, whether addressed from the flash's base or
+        // from 0.
+        let image = parse_intel_hex(b":02000004607F1B\n:02FFFE00ABCD89\n:00000001FF\n").unwrap();
+        assert_eq!(image.len(), FLASH_SIZE);
+        assert_eq!(image[FLASH_SIZE - 2..], [0xab, 0xcd]);
+        assert!(parse_intel_hex(b":0200000460801A\n:02000000ABCD86\n:00000001FF\n").is_err());
+        assert!(parse_intel_hex(b":02000004007F7B\n:02FFFF00ABCD88\n:00000001FF\n").is_err());
+    }
+
+    #[test]
+    fn scenarios_reject_settings_they_cant_override() {
+        let config = toml::from_str::<Config>("[scenario.a]\nboot_app = \"app.js\"\n");
+        assert!(config.is_ok());
+        let config = toml::from_str::<Config>("[scenario.a]\napps = [\"apps/clock\"]\n");
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn bundle_config_round_trips() {
+        let config: Config = toml::from_str(
+            r#"
+            factory_reset = true
+            firmware_sha256 = "abcd"
+            apps = ["apps/clock"]
+            widgets = ["battery"]
+            startup = "1;\n"
+            default_clock = "clock"
+            boot_app = "app.js"
+            upload = ["test.js"]
+            plugins = ["/plugins/i2c.wasm"]
+            storage = { "a.txt" = { path = "a.txt" }, "b.txt" = { contents = "b" } }
+            device = { board = "pixljs" }
+            "#,
+        )
+        .unwrap();
+        let bundle: Config = toml::from_str(&config.bundle_config().unwrap()).unwrap();
+        let cwd = std::env::current_dir().unwrap();
+        let under_cwd = |path| cwd.join(path).to_string_lossy().into_owned();
+
+        assert_eq!(
+            bundle.flash_initial_contents_file.as_deref(),
+            Some(bundle::FLASH_FILE)
+        );
+        assert!(!bundle.factory_reset);
+        assert_eq!(bundle.firmware_sha256.as_deref(), Some("abcd"));
+        assert_eq!(bundle.apps, [under_cwd("apps/clock")]);
+        assert_eq!(bundle.widgets, ["battery"]);
+        assert_eq!(bundle.startup.as_deref(), Some("1;\n"));
+        assert_eq!(bundle.default_clock.as_deref(), Some("clock"));
+        assert_eq!(bundle.boot_app.as_deref(), Some("app.js"));
+        assert_eq!(bundle.upload, [under_cwd("test.js")]);
+        assert_eq!(bundle.plugins, ["/plugins/i2c.wasm"]);
+        assert_eq!(bundle.device.board, config.device.board);
+        match &bundle.storage["a.txt"].contents {
+            FileContents::Path(path) => assert_eq!(path, &cwd.join("a.txt")),
+            contents => panic!("{contents:?}"),
+        }
+        match &bundle.storage["b.txt"].contents {
+            FileContents::Contents(s) => assert_eq!(s, "b"),
+            contents => panic!("{contents:?}"),
+        }
+    }
+}
@@ -0,0 +1,1037 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context;
+use base64::{engine::general_purpose, Engine};
+use log::{error, info};
+use regex::Regex;
+use serde_derive::Serialize;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    select,
+    sync::{broadcast::Receiver, mpsc::Sender, oneshot},
+    time::{sleep, timeout},
+};
+
+use crate::{
+    ancs::{self, AncsNotification},
+    app,
+    device::{timezone_expr, Board, DeviceProfile},
+    emu::{
+        Buzz, ClockAdjustment, Color, Device, Input, PixelFormat, Screen, ScreenSpec, ScreenWatch,
+        Step, WaitResult,
+    },
+    eval::{eval, parse_watched_pins, WATCHED_PINS_EXPR},
+    flash,
+    overlay::{self, Overlay},
+    power::PowerMonitor,
+    screenshot::{decode_png, encode_png},
+    text::{read_text, Font},
+    touch::{Gesture, TouchMode},
+};
+
+const EVAL_TIMEOUT: Duration = Duration::from_secs(10);
+pub const TAP_DURATION: Duration = Duration::from_millis(50);
+const SETTLE_DURATION: Duration = Duration::from_millis(300);
+/// How long to give an app to settle after it's reloaded in a new theme, as
+/// for `wait-idle`: how far off its next timer has to be, and how long to
+/// wait for that at most, in milliseconds of virtual time.
+const THEME_QUIET_MS: f64 = 200.0;
+const THEME_TIMEOUT_MS: f64 = 5000.0;
+
+/// Fonts that menus are tried in, largest first, since that's what the
+/// standard menus on the Bangle.js 2 use.
+const MENU_FONTS: [&str; 3] = ["12x20", "6x8:2", "6x8"];
+const MENU_MAX_SCROLLS: usize = 20;
+
+/// A JavaScript expression that evaluates to whether the LCD is on, taking
+/// devices without `Bangle.isLCDOn` to always have it on.
+const LCD_ON_EXPR: &str = "typeof Bangle=='undefined'||!Bangle.isLCDOn||Bangle.isLCDOn()";
+
+/// A JavaScript expression that evaluates to whether the firmware thinks it's
+/// charging.
+const CHARGING_EXPR: &str = "typeof Bangle!='undefined'&&!!Bangle.isCharging&&Bangle.isCharging()";
+
+/// A JavaScript expression that evaluates to whether the firmware thinks it's
+/// connected over Bluetooth.
+const BLUETOOTH_EXPR: &str =
+    "!!(global.NRF&&NRF.getSecurityStatus&&NRF.getSecurityStatus().connected)";
+
+/// The reply to `framebuffer`.
+#[derive(Serialize)]
+struct Framebuffer {
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+    frame: u64,
+    lcd_on: bool,
+    /// The framebuffer's bytes in base64.
+    data: String,
+}
+
+/// A rectangular region of the screen, checked to lie within its bounds when
+/// it's used, since screens differ in size.
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub w: usize,
+    pub h: usize,
+}
+
+/// A command accepted by the control interface, one per line.
+#[derive(Clone, Debug)]
+pub enum Command {
+    /// `eval <expression>`: evaluate JavaScript without echoing it to the
+    /// console and reply with the JSON-encoded result.
+    Eval(String),
+    /// `read-text [font]`: reply with the runs of text drawn on the screen in
+    /// the given font (default `6x8`) as JSON.
+    ReadText(String),
+    /// `send <text>`: send a line of text to the console.
+    Send(String),
+    /// `wait <ms>`: do nothing for a while.
+    Wait(Duration),
+    /// `touch <x> <y> [pressure]` and `release <x> <y>`: start or continue a
+    /// touch, optionally with a pressure from 0 to 255, or end it.
+    Touch(u8, u8, bool, Option<u8>),
+    /// `tap <x> <y>`: touch and release at a point.
+    Tap(u8, u8),
+    /// `button [<n>] down` and `button [<n>] up`: press or release a button,
+    /// BTN1 unless another is given.
+    Button(usize, bool),
+    /// `touch-mode gestures` and `touch-mode raw`: switch between recognizing
+    /// taps and swipes in the emulator and passing raw touches through.
+    TouchMode(TouchMode),
+    /// `pause` and `resume`: stop running the firmware, freezing its clock, or
+    /// start it again.
+    Pause(bool),
+    /// `step [ms]`: while paused, run the firmware for one idle call or for the
+    /// given amount of virtual time.
+    Step(Step),
+    /// `run-until /<regex>/ <ms>`: wait until console output matches the
+    /// regex or the given amount of virtual time passes, replying with
+    /// `console <matched text>` or `timeout`.
+    RunUntil(Regex, f64),
+    /// `wait-idle <quiet-ms> <ms>`: wait until the firmware has no timers due
+    /// within the first number of milliseconds and no evaluations outstanding,
+    /// or until the second number of milliseconds of virtual time pass,
+    /// replying with `idle` or `timeout`.
+    WaitIdle(f64, f64),
+    /// `expect-pixel <x> <y> <color>`: fail unless the pixel has the given
+    /// color, specified by name or 3-bit value.
+    ExpectPixel(usize, usize, Color),
+    /// `region-hash <x> <y> <w> <h>`: reply with the hash of a region, for use
+    /// with `expect-region`.
+    RegionHash(Region),
+    /// `expect-region <x> <y> <w> <h> <hash>`: fail unless the region has the
+    /// given hash.
+    ExpectRegion(Region, u64),
+    /// `expect-text <text>`: fail unless the text appears on the screen in
+    /// the default font.
+    ExpectText(String),
+    /// `menu-select <label>`: scroll through the menu on screen until an item
+    /// containing the label is visible, then tap it.
+    MenuSelect(String),
+    /// `screenshot <path>`: save the screen as a PNG file.
+    Screenshot(PathBuf),
+    /// `overlay-screenshot <path>`: save just the overlay layer as a PNG file,
+    /// failing if there's no overlay.
+    OverlayScreenshot(PathBuf),
+    /// `expect-screenshot <path>`: fail unless the screen matches a PNG file
+    /// (saving the actual screen next to it as `<name>.actual.png`) or, when
+    /// updating goldens, save the screen to the file.
+    ExpectScreenshot(PathBuf),
+    /// `pin <n>`: reply with a pin's raw value, `0` or `1`.
+    ReadPin(i32),
+    /// `set-pin <n> <0|1>`: set a pin's raw value and send the firmware a pin
+    /// watch event for it.
+    SetPin(i32, bool),
+    /// `pin-event <n>`: send the firmware a pin watch event without changing
+    /// the pin.
+    PinEvent(i32),
+    /// `charger [on|off]`: connect or disconnect the charger (or toggle it),
+    /// replying with whether the firmware now says it's charging.
+    Charger(Option<bool>),
+    /// `bluetooth [on|off]`: connect or disconnect an emulated phone (or
+    /// toggle it), replying with whether the firmware now says it's connected.
+    Bluetooth(Option<bool>),
+    /// `timezone <hours>`: set the watch's timezone as an offset from UTC.
+    Timezone(f64),
+    /// `clock-drift <ppm>`: make the watch's clock gain (or, if negative, lose)
+    /// time at the given rate from now on.
+    ClockDrift(f64),
+    /// `clock-skew <ms>`: jump the watch's clock forward (or, if negative,
+    /// back) by the given number of milliseconds.
+    ClockSkew(f64),
+    /// `time-sync [offset-ms] [timezone]`: sync the watch's time to the host's
+    /// clock plus an offset, and its timezone if given, as Gadgetbridge does.
+    TimeSync(f64, Option<f64>),
+    /// `buzzes`: reply with the buzzes of the vibration motor since the last
+    /// `buzzes` or `expect-buzz` as JSON.
+    Buzzes,
+    /// `expect-buzz [count]`: fail unless the vibration motor has buzzed at
+    /// least the given number of times (default 1) since the last `buzzes` or
+    /// `expect-buzz`, replying with the buzzes as JSON.
+    ExpectBuzz(usize),
+    /// `gestures`: reply with a JSON list of the names of the gestures
+    /// recognized in touches since the last `gestures` or `expect-gesture`.
+    Gestures,
+    /// `expect-gesture <name>`: fail unless the given gesture (such as `tap` or
+    /// `swipe left`) has been recognized since the last `gestures` or
+    /// `expect-gesture`, replying with the gestures as JSON.
+    ExpectGesture(Gesture),
+    /// `framebuffer`: reply with the firmware's framebuffer, without the
+    /// overlay layer, as JSON: its size and pixel format, the number of the
+    /// frame, whether the LCD is on, and the packed pixels in base64.
+    Framebuffer,
+    /// `watched-pins`: reply with a JSON list of the pins the firmware has
+    /// watches on.
+    WatchedPins,
+    /// `app ls`: reply with a JSON list of the installed apps, each with its
+    /// id, name, type, and version.
+    AppList,
+    /// `app rm <id>`: remove an app and all its files, as the App Loader does,
+    /// failing if it isn't installed.
+    AppRemove(String),
+    /// `app set-clock <id>`: make an installed clock the one the watch shows.
+    AppSetClock(String),
+    /// `ancs notify [json]`: send a notification the way an iPhone does, with
+    /// any fields given as a JSON object overriding a canned message, replying
+    /// with its UID.
+    AncsNotify(AncsNotification),
+    /// `ancs rm <uid>`: remove a notification, as dismissing it on the phone
+    /// does, failing if there's no such notification.
+    AncsRemove(u32),
+    /// `ancs actions`: reply with a JSON list of the actions the watch has
+    /// taken on notifications since the last `ancs actions`.
+    AncsActions,
+    /// `theme [light|dark]`: switch the watch to the standard light or dark
+    /// theme (or whichever it isn't using) in its settings and reload the app,
+    /// replying with the new theme.
+    Theme(Option<bool>),
+    /// `theme-screenshots <path>`: save the screen in both themes, as
+    /// `<name>.light.png` and `<name>.dark.png`, reloading the app in each and
+    /// waiting for it to settle, finishing in the theme the watch was in.
+    ThemeScreenshots(PathBuf),
+    /// `power`: reply with how long each part of the watch has been powered,
+    /// and the CPU running, in seconds, as JSON, failing if power isn't being
+    /// monitored.
+    Power,
+    /// `battery`: reply with the battery model's level and how much each thing
+    /// has drained, in percent, as JSON, failing if there's no model.
+    Battery,
+    /// `save-flash <path>`: save the whole flash as a raw image, compressed if
+    /// the path ends in `.gz` or `.zst`, for use as a config's initial flash
+    /// contents.
+    SaveFlash(PathBuf),
+}
+
+/// Removes one pair of surrounding double quotes, if present.
+fn unquote(s: &str) -> &str {
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .unwrap_or(s)
+}
+
+fn parse_args<T: FromStr>(rest: &str, n: usize) -> anyhow::Result<Vec<T>>
+where
+    T::Err: Into<anyhow::Error>,
+{
+    let args = rest
+        .split_whitespace()
+        .map(|a| a.parse().map_err(Into::into))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if args.len() != n {
+        anyhow::bail!("expected {n} arguments, got {}", args.len());
+    }
+    Ok(args)
+}
+
+/// Parses an optional `on` or `off`, with neither meaning to toggle.
+fn parse_on_off(rest: &str) -> anyhow::Result<Option<bool>> {
+    match rest {
+        "" => Ok(None),
+        "on" => Ok(Some(true)),
+        "off" => Ok(Some(false)),
+        _ => anyhow::bail!("expected `on` or `off`"),
+    }
+}
+
+/// Parses a point, which is checked to lie on the screen when it's used, since
+/// screens differ in size.
+fn parse_point(rest: &str) -> anyhow::Result<(u8, u8)> {
+    match parse_args::<usize>(rest, 2)?[..] {
+        [x, y] => match (x.try_into(), y.try_into()) {
+            (Ok(x), Ok(y)) => Ok((x, y)),
+            _ => anyhow::bail!("point is off the screen"),
+        },
+        _ => unreachable!(),
+    }
+}
+
+impl Region {
+    fn parse(args: &[usize]) -> anyhow::Result<Self> {
+        match *args {
+            [x, y, w, h] => Ok(Self { x, y, w, h }),
+            _ => unreachable!(),
+        }
+    }
+
+    fn hash(&self, screen: &Screen) -> anyhow::Result<u64> {
+        let Self { x, y, w, h } = *self;
+        if !screen.contains(x, y, w, h) {
+            anyhow::bail!("region is off the screen");
+        }
+        Ok(screen.region_hash(x, y, w, h))
+    }
+}
+
+impl Command {
+    pub fn parse(line: &str) -> anyhow::Result<Self> {
+        let line = line.trim();
+        let (name, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim();
+        match name {
+            "eval" => Ok(Self::Eval(rest.to_owned())),
+            "read-text" => Ok(Self::ReadText(match rest {
+                "" => "6x8".to_owned(),
+                font => font.to_owned(),
+            })),
+            "send" => Ok(Self::Send(rest.to_owned())),
+            "wait" => Ok(Self::Wait(Duration::from_millis(parse_args(rest, 1)?[0]))),
+            "touch" => {
+                let (point, pressure) = match rest.rsplit_once(' ') {
+                    Some((point, pressure)) if point.split_whitespace().count() == 2 => {
+                        (point, Some(pressure.parse().context("invalid pressure")?))
+                    }
+                    _ => (rest, None),
+                };
+                let (x, y) = parse_point(point)?;
+                Ok(Self::Touch(x, y, true, pressure))
+            }
+            "release" => {
+                let (x, y) = parse_point(rest)?;
+                Ok(Self::Touch(x, y, false, None))
+            }
+            "tap" => {
+                let (x, y) = parse_point(rest)?;
+                Ok(Self::Tap(x, y))
+            }
+            "button" => {
+                let (button, action) = match rest.split_once(' ') {
+                    Some((n, action)) => (n.parse().context("invalid button")?, action),
+                    None => (1, rest),
+                };
+                match action {
+                    "down" => Ok(Self::Button(button, true)),
+                    "up" => Ok(Self::Button(button, false)),
+                    _ => anyhow::bail!("expected `down` or `up`"),
+                }
+            }
+            "touch-mode" => Ok(Self::TouchMode(rest.parse()?)),
+            "pause" => Ok(Self::Pause(true)),
+            "resume" => Ok(Self::Pause(false)),
+            "step" => Ok(Self::Step(match rest {
+                "" => Step::Idle,
+                ms => Step::Millis(parse_args(ms, 1)?[0]),
+            })),
+            "run-until" => {
+                let (pattern, ms) = rest
+                    .rsplit_once(' ')
+                    .ok_or_else(|| anyhow::format_err!("expected 2 arguments"))?;
+                let pattern = pattern
+                    .trim()
+                    .strip_prefix('/')
+                    .and_then(|p| p.strip_suffix('/'))
+                    .ok_or_else(|| anyhow::format_err!("expected a /regex/"))?;
+                Ok(Self::RunUntil(Regex::new(pattern)?, ms.parse()?))
+            }
+            "wait-idle" => {
+                let args = parse_args(rest, 2)?;
+                Ok(Self::WaitIdle(args[0], args[1]))
+            }
+            "expect-pixel" => {
+                let (point, color) = rest
+                    .rsplit_once(' ')
+                    .ok_or_else(|| anyhow::format_err!("expected 3 arguments"))?;
+                let [x, y] = parse_args(point, 2)?[..] else {
+                    unreachable!()
+                };
+                Ok(Self::ExpectPixel(x, y, color.parse()?))
+            }
+            "region-hash" => Ok(Self::RegionHash(Region::parse(&parse_args(rest, 4)?)?)),
+            "expect-region" => {
+                let (region, hash) = rest
+                    .rsplit_once(' ')
+                    .ok_or_else(|| anyhow::format_err!("expected 5 arguments"))?;
+                let hash = u64::from_str_radix(hash, 16)
+                    .with_context(|| format!("invalid hash {hash:?}"))?;
+                Ok(Self::ExpectRegion(
+                    Region::parse(&parse_args(region, 4)?)?,
+                    hash,
+                ))
+            }
+            "expect-text" => Ok(Self::ExpectText(unquote(rest).to_owned())),
+            "menu-select" => Ok(Self::MenuSelect(unquote(rest).to_owned())),
+            "screenshot" => Ok(Self::Screenshot(unquote(rest).into())),
+            "overlay-screenshot" => Ok(Self::OverlayScreenshot(unquote(rest).into())),
+            "expect-screenshot" => Ok(Self::ExpectScreenshot(unquote(rest).into())),
+            "save-flash" => Ok(Self::SaveFlash(unquote(rest).into())),
+            "theme" => Ok(Self::Theme(match rest {
+                "" => None,
+                "light" => Some(false),
+                "dark" => Some(true),
+                _ => anyhow::bail!("expected `light` or `dark`"),
+            })),
+            "theme-screenshots" => Ok(Self::ThemeScreenshots(unquote(rest).into())),
+            "pin" => Ok(Self::ReadPin(parse_args(rest, 1)?[0])),
+            "set-pin" => match parse_args::<i32>(rest, 2)?[..] {
+                [pin, value @ (0 | 1)] => Ok(Self::SetPin(pin, value == 1)),
+                _ => anyhow::bail!("expected a value of 0 or 1"),
+            },
+            "pin-event" => Ok(Self::PinEvent(parse_args(rest, 1)?[0])),
+            "watched-pins" => Ok(Self::WatchedPins),
+            "framebuffer" => Ok(Self::Framebuffer),
+            "app" => {
+                let (op, id) = rest.split_once(' ').unwrap_or((rest, ""));
+                match (op, id.trim()) {
+                    ("ls", "") => Ok(Self::AppList),
+                    ("rm", id) if !id.is_empty() => Ok(Self::AppRemove(id.to_owned())),
+                    ("set-clock", id) if !id.is_empty() => Ok(Self::AppSetClock(id.to_owned())),
+                    _ => anyhow::bail!("expected `ls`, `rm <id>`, or `set-clock <id>`"),
+                }
+            }
+            "ancs" => {
+                let (op, arg) = rest.split_once(' ').unwrap_or((rest, ""));
+                match (op, arg.trim()) {
+                    ("notify", "") => Ok(Self::AncsNotify(AncsNotification::default())),
+                    ("notify", json) => Ok(Self::AncsNotify(
+                        serde_json::from_str(json).context("Failed to parse notification")?,
+                    )),
+                    ("rm", uid) if !uid.is_empty() => Ok(Self::AncsRemove(uid.parse()?)),
+                    ("actions", "") => Ok(Self::AncsActions),
+                    _ => anyhow::bail!("expected `notify [json]`, `rm <uid>`, or `actions`"),
+                }
+            }
+            "power" => Ok(Self::Power),
+            "battery" => Ok(Self::Battery),
+            "buzzes" => Ok(Self::Buzzes),
+            "expect-buzz" => Ok(Self::ExpectBuzz(match rest {
+                "" => 1,
+                count => parse_args(count, 1)?[0],
+            })),
+            "gestures" => Ok(Self::Gestures),
+            "expect-gesture" => Ok(Self::ExpectGesture(rest.parse()?)),
+            "charger" => Ok(Self::Charger(parse_on_off(rest)?)),
+            "bluetooth" => Ok(Self::Bluetooth(parse_on_off(rest)?)),
+            "timezone" => Ok(Self::Timezone(parse_args(rest, 1)?[0])),
+            "clock-drift" => Ok(Self::ClockDrift(parse_args(rest, 1)?[0])),
+            "clock-skew" => Ok(Self::ClockSkew(parse_args(rest, 1)?[0])),
+            "time-sync" => {
+                let args = rest
+                    .split_whitespace()
+                    .map(str::parse)
+                    .collect::<Result<Vec<f64>, _>>()?;
+                match args[..] {
+                    [] => Ok(Self::TimeSync(0.0, None)),
+                    [ms] => Ok(Self::TimeSync(ms, None)),
+                    [ms, hours] => Ok(Self::TimeSync(ms, Some(hours))),
+                    _ => anyhow::bail!("expected at most 2 arguments, got {}", args.len()),
+                }
+            }
+            "" => anyhow::bail!("empty command"),
+            _ => anyhow::bail!("unknown command {name:?}"),
+        }
+    }
+
+    /// Makes relative paths in the command relative to `base` rather than the
+    /// working directory, except for the files it only writes, which are
+    /// relative to `output_base`.
+    pub fn resolve_paths(&mut self, base: &Path, output_base: &Path) {
+        match self {
+            Self::ExpectScreenshot(p) => *p = base.join(&*p),
+            Self::Screenshot(p)
+            | Self::OverlayScreenshot(p)
+            | Self::ThemeScreenshots(p)
+            | Self::SaveFlash(p) => *p = output_base.join(&*p),
+            _ => {}
+        }
+    }
+
+    /// The input that carries out the command, for the commands that only
+    /// deliver something straight to the firmware.
+    pub fn firmware_input(&self) -> Option<Input> {
+        match self {
+            Self::Send(text) => Some(Input::Console(
+                Device::CONSOLE,
+                format!("{text}\n").into_bytes(),
+            )),
+            Self::Touch(x, y, on, pressure) => Some(Input::Touch(*x, *y, *on, *pressure)),
+            Self::Button(button, on) => Some(Input::Button(*button, *on)),
+            Self::TouchMode(mode) => Some(Input::TouchMode(*mode)),
+            _ => None,
+        }
+    }
+}
+
+fn theme_name(dark: bool) -> &'static str {
+    if dark {
+        "dark"
+    } else {
+        "light"
+    }
+}
+
+/// Executes control commands against a running emulator.
+#[derive(Clone)]
+pub struct Controller {
+    emu_tx: Sender<Input>,
+    screens: ScreenWatch,
+    fonts: Arc<Mutex<HashMap<String, Arc<Font>>>>,
+    update_goldens: bool,
+    output_dir: Option<PathBuf>,
+    power: Option<PowerMonitor>,
+    board: Board,
+    screen_spec: ScreenSpec,
+}
+
+impl Controller {
+    pub fn new(emu_tx: Sender<Input>, screens: ScreenWatch) -> Self {
+        Self {
+            emu_tx,
+            screens,
+            fonts: Default::default(),
+            update_goldens: false,
+            output_dir: None,
+            power: None,
+            board: Board::default(),
+            screen_spec: ScreenSpec::default(),
+        }
+    }
+
+    /// Sets the device the emulator is running as, which decides the touches
+    /// and buttons commands can send it.
+    pub fn device(mut self, device: &DeviceProfile) -> Self {
+        self.board = device.board;
+        self.screen_spec = device.screen_spec();
+        self
+    }
+
+    /// Gives the `power` and `battery` commands a power monitor to report on.
+    pub fn power(mut self, power: Option<PowerMonitor>) -> Self {
+        self.power = power;
+        self
+    }
+
+    /// Makes commands write files with relative paths (like screenshots) into
+    /// this directory.
+    pub fn outputs_in(mut self, dir: Option<PathBuf>) -> Self {
+        self.output_dir = dir;
+        self
+    }
+
+    pub fn output_dir(&self) -> Option<&Path> {
+        self.output_dir.as_deref()
+    }
+
+    /// Makes `expect-screenshot` save the screen instead of comparing it.
+    pub fn update_goldens(mut self, update: bool) -> Self {
+        self.update_goldens = update;
+        self
+    }
+
+    pub async fn eval(&self, expr: &str) -> anyhow::Result<serde_json::Value> {
+        timeout(EVAL_TIMEOUT, eval(&self.emu_tx, expr))
+            .await
+            .context("timed out waiting for eval result")??
+            .map_err(|e| anyhow::format_err!("{e}"))
+    }
+
+    /// Gets the glyphs of a firmware font, probing the firmware the first
+    /// time each font is used.
+    pub async fn font(&self, name: &str) -> anyhow::Result<Arc<Font>> {
+        if let Some(font) = self.fonts.lock().unwrap().get(name) {
+            return Ok(Arc::clone(font));
+        }
+        let probe = self
+            .eval(&Font::probe_expr(name))
+            .await
+            .with_context(|| format!("Failed to load font {name:?}"))?;
+        let font = Arc::new(Font::from_probe(&probe)?);
+        self.fonts
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), Arc::clone(&font));
+        Ok(font)
+    }
+
+    /// Whether the emulator is still there to take commands.
+    pub fn is_running(&self) -> bool {
+        !self.emu_tx.is_closed()
+    }
+
+    async fn send(&self, input: Input) -> anyhow::Result<()> {
+        self.emu_tx
+            .send(input)
+            .await
+            .map_err(|_| anyhow::format_err!("emulator is not running"))
+    }
+
+    fn screen(&self) -> anyhow::Result<Arc<Screen>> {
+        self.screens
+            .borrow()
+            .clone()
+            .ok_or_else(|| anyhow::format_err!("nothing has been drawn yet"))
+    }
+
+    /// Wraps `Bangle.setLCDOverlay` so that overlays set from then on can be
+    /// read back, until the firmware next resets its JavaScript state.
+    pub async fn install_overlay_hook(&self) -> anyhow::Result<()> {
+        self.eval(overlay::HOOK_EXPR).await?;
+        Ok(())
+    }
+
+    /// Gets the current overlay, reinstalling the hook in the same evaluation
+    /// in case the firmware has reset since it was last installed.
+    async fn overlay(&self) -> anyhow::Result<Option<Overlay>> {
+        let expr = format!("{},{}", overlay::HOOK_EXPR, overlay::CAPTURE_EXPR);
+        Overlay::from_capture(&self.eval(&expr).await?)
+    }
+
+    /// Gets the screen as it would appear on the LCD, with the overlay layer
+    /// drawn on top of the framebuffer.
+    async fn lcd_screen(&self) -> anyhow::Result<Arc<Screen>> {
+        let screen = self.screen()?;
+        Ok(match self.overlay().await? {
+            Some(overlay) => {
+                let mut screen = (*screen).clone();
+                overlay.compose(&mut screen);
+                Arc::new(screen)
+            }
+            None => screen,
+        })
+    }
+
+    async fn take_buzzes(&self) -> anyhow::Result<Vec<Buzz>> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.send(Input::TakeBuzzes(reply_tx)).await?;
+        reply_rx.await.context("emulator stopped")
+    }
+
+    /// Switches the theme and waits for the reloaded app to settle, returning
+    /// whether the new theme is dark.
+    async fn set_theme(&self, dark: Option<bool>) -> anyhow::Result<bool> {
+        let dark = self
+            .eval(&app::set_theme_expr(dark))
+            .await?
+            .as_bool()
+            .ok_or_else(|| anyhow::format_err!("couldn't set the theme"))?;
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.send(Input::WaitIdle(THEME_QUIET_MS, THEME_TIMEOUT_MS, reply_tx))
+            .await?;
+        reply_rx.await.context("emulator stopped while waiting")?;
+        Ok(dark)
+    }
+
+    async fn take_gestures(&self) -> anyhow::Result<Vec<&'static str>> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.send(Input::TakeGestures(reply_tx)).await?;
+        let gestures = reply_rx.await.context("emulator stopped")?;
+        Ok(gestures.into_iter().map(Gesture::name).collect())
+    }
+
+    /// Reads a pin's raw value, failing if there's no such pin.
+    async fn read_pin(&self, pin: i32) -> anyhow::Result<bool> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.send(Input::ReadPin(pin, reply_tx)).await?;
+        reply_rx
+            .await
+            .context("emulator stopped")?
+            .ok_or_else(|| anyhow::format_err!("no such pin {pin}"))
+    }
+
+    /// Checks that the device can be touched at a point.
+    fn check_touch(&self, x: u8, y: u8) -> anyhow::Result<()> {
+        if !self.board.has_touchscreen() {
+            anyhow::bail!("the device has no touchscreen");
+        }
+        if usize::from(x) >= self.screen_spec.width || usize::from(y) >= self.screen_spec.height {
+            anyhow::bail!("point is off the screen");
+        }
+        Ok(())
+    }
+
+    async fn tap(&self, x: u8, y: u8) -> anyhow::Result<()> {
+        self.send(Input::Touch(x, y, true, None)).await?;
+        sleep(TAP_DURATION).await;
+        self.send(Input::Touch(x, y, false, None)).await
+    }
+
+    /// Drags upwards in the middle of the screen, which scrolls menus down.
+    /// The distance is kept short enough to not count as a swipe.
+    async fn scroll_down(&self) -> anyhow::Result<()> {
+        for y in (80..=140).rev().step_by(10) {
+            self.send(Input::Touch(88, y, true, None)).await?;
+            sleep(Duration::from_millis(10)).await;
+        }
+        self.send(Input::Touch(88, 80, false, None)).await
+    }
+
+    async fn menu_select(&self, label: &str) -> anyhow::Result<String> {
+        for _ in 0..MENU_MAX_SCROLLS {
+            let screen = self.screen()?;
+            for name in MENU_FONTS {
+                // Not every firmware build includes every font.
+                let Ok(font) = self.font(name).await else {
+                    continue;
+                };
+                if let Some(run) = read_text(&screen, &font)
+                    .into_iter()
+                    .find(|r| r.text.contains(label))
+                {
+                    let (x, y) = (run.x + run.width / 2, run.y + run.height / 2);
+                    self.tap(x as u8, y as u8).await?;
+                    return Ok(format!("{x} {y}"));
+                }
+            }
+
+            self.scroll_down().await?;
+            sleep(SETTLE_DURATION).await;
+            if self.screen()?.hash() == screen.hash() {
+                break;
+            }
+        }
+        anyhow::bail!("menu item {label:?} not found")
+    }
+
+    /// Runs a command, returning the text of a successful reply.
+    pub async fn execute(&self, command: Command) -> anyhow::Result<String> {
+        match command {
+            Command::Eval(expr) => Ok(self.eval(&expr).await?.to_string()),
+            Command::ReadText(font) => {
+                let font = self.font(&font).await?;
+                let runs = if self.screens.borrow().is_some() {
+                    read_text(&*self.lcd_screen().await?, &font)
+                } else {
+                    vec![]
+                };
+                Ok(serde_json::to_string(&runs)?)
+            }
+            Command::Send(_) | Command::TouchMode(_) => {
+                self.send(command.firmware_input().unwrap()).await?;
+                Ok(String::new())
+            }
+            Command::Wait(d) => {
+                sleep(d).await;
+                Ok(String::new())
+            }
+            Command::Touch(x, y, on, pressure) => {
+                self.check_touch(x, y)?;
+                self.send(Input::Touch(x, y, on, pressure)).await?;
+                Ok(String::new())
+            }
+            Command::Tap(x, y) => {
+                self.check_touch(x, y)?;
+                self.tap(x, y).await?;
+                Ok(String::new())
+            }
+            Command::Button(button, on) => {
+                if button == 0 || button > self.board.buttons().len() {
+                    anyhow::bail!("the device has no button {button}");
+                }
+                self.send(Input::Button(button, on)).await?;
+                Ok(String::new())
+            }
+            Command::Pause(paused) => {
+                self.send(Input::Pause(paused)).await?;
+                Ok(String::new())
+            }
+            Command::Step(step) => {
+                self.send(Input::Step(step)).await?;
+                Ok(String::new())
+            }
+            Command::RunUntil(regex, ms) => {
+                let (reply_tx, reply_rx) = oneshot::channel();
+                self.send(Input::WaitFor(regex, ms, reply_tx)).await?;
+                match reply_rx.await.context("emulator stopped while waiting")? {
+                    WaitResult::Matched(text) => Ok(format!("console {text}")),
+                    WaitResult::Elapsed => Ok("timeout".to_owned()),
+                }
+            }
+            Command::WaitIdle(quiet_ms, ms) => {
+                let (reply_tx, reply_rx) = oneshot::channel();
+                self.send(Input::WaitIdle(quiet_ms, ms, reply_tx)).await?;
+                match reply_rx.await.context("emulator stopped while waiting")? {
+                    true => Ok("idle".to_owned()),
+                    false => Ok("timeout".to_owned()),
+                }
+            }
+            Command::ExpectPixel(x, y, color) => {
+                let actual = self
+                    .lcd_screen()
+                    .await?
+                    .get(x, y)
+                    .ok_or_else(|| anyhow::format_err!("point is off the screen"))?;
+                if actual != color {
+                    anyhow::bail!("pixel ({x}, {y}) is {actual}, expected {color}");
+                }
+                Ok(String::new())
+            }
+            Command::RegionHash(r) => Ok(format!("{:016x}", r.hash(&*self.lcd_screen().await?)?)),
+            Command::ExpectRegion(r, hash) => {
+                let actual = r.hash(&*self.lcd_screen().await?)?;
+                if actual != hash {
+                    anyhow::bail!("region hash is {actual:016x}, expected {hash:016x}");
+                }
+                Ok(String::new())
+            }
+            Command::ExpectText(text) => {
+                let font = self.font("6x8").await?;
+                let runs = read_text(&*self.lcd_screen().await?, &font);
+                if !runs.iter().any(|r| r.text.contains(&text)) {
+                    anyhow::bail!("text {text:?} not found on screen");
+                }
+                Ok(String::new())
+            }
+            Command::MenuSelect(label) => self.menu_select(&label).await,
+            Command::Screenshot(path) => {
+                fs::write(&path, encode_png(&*self.lcd_screen().await?)?)
+                    .with_context(|| format!("Failed to write {path:?}"))?;
+                Ok(String::new())
+            }
+            Command::OverlayScreenshot(path) => {
+                let overlay = self
+                    .overlay()
+                    .await?
+                    .ok_or_else(|| anyhow::format_err!("no overlay is being shown"))?;
+                fs::write(&path, encode_png(&overlay.layer(self.screen()?.spec()))?)
+                    .with_context(|| format!("Failed to write {path:?}"))?;
+                Ok(format!(
+                    "{} {} {} {}",
+                    overlay.x, overlay.y, overlay.width, overlay.height
+                ))
+            }
+            Command::ExpectScreenshot(path) => {
+                let screen = self.lcd_screen().await?;
+                if self.update_goldens {
+                    fs::write(&path, encode_png(&screen)?)
+                        .with_context(|| format!("Failed to write {path:?}"))?;
+                    return Ok(String::new());
+                }
+                let golden = fs::File::open(&path)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|file| decode_png(file, screen.format()))
+                    .with_context(|| format!("Failed to read golden screenshot {path:?}"))?;
+                let diff = screen.differing_pixels(&golden);
+                if diff > 0 {
+                    let actual = path.with_extension("actual.png");
+                    fs::write(&actual, encode_png(&screen)?)?;
+                    anyhow::bail!(
+                        "{diff} pixels differ from {path:?} (actual screen saved to {actual:?})"
+                    );
+                }
+                Ok(String::new())
+            }
+            Command::SaveFlash(path) => {
+                let (reply_tx, reply_rx) = oneshot::channel();
+                self.send(Input::ReadFlash(reply_tx)).await?;
+                let data = reply_rx.await.context("emulator stopped")?;
+                tokio::task::spawn_blocking(move || flash::write_image(&path, &data)).await??;
+                Ok(String::new())
+            }
+            Command::ReadPin(pin) => Ok((self.read_pin(pin).await? as u8).to_string()),
+            Command::SetPin(pin, value) => {
+                self.read_pin(pin).await?;
+                self.send(Input::Pin(pin, Some(value))).await?;
+                Ok(String::new())
+            }
+            Command::PinEvent(pin) => {
+                self.read_pin(pin).await?;
+                self.send(Input::Pin(pin, None)).await?;
+                Ok(String::new())
+            }
+            Command::Charger(on) => {
+                self.send(Input::Charger(on)).await?;
+                let charging = self.eval(CHARGING_EXPR).await?;
+                Ok(if charging == true {
+                    "charging"
+                } else {
+                    "not charging"
+                }
+                .to_owned())
+            }
+            Command::Bluetooth(connected) => {
+                self.send(Input::Bluetooth(connected)).await?;
+                let connected = self.eval(BLUETOOTH_EXPR).await?;
+                Ok(if connected == true {
+                    "connected"
+                } else {
+                    "disconnected"
+                }
+                .to_owned())
+            }
+            Command::Timezone(hours) => {
+                self.eval(&timezone_expr(hours)).await?;
+                Ok(String::new())
+            }
+            Command::ClockDrift(ppm) => {
+                self.send(Input::AdjustClock(ClockAdjustment::Drift(ppm)))
+                    .await?;
+                Ok(String::new())
+            }
+            Command::ClockSkew(ms) => {
+                self.send(Input::AdjustClock(ClockAdjustment::Skew(ms)))
+                    .await?;
+                Ok(String::new())
+            }
+            Command::TimeSync(ms, timezone) => {
+                self.send(Input::TimeSync(ms, timezone)).await?;
+                Ok(String::new())
+            }
+            Command::Buzzes => Ok(serde_json::to_string(&self.take_buzzes().await?)?),
+            Command::ExpectBuzz(count) => {
+                let buzzes = self.take_buzzes().await?;
+                if buzzes.len() < count {
+                    anyhow::bail!("expected {count} buzzes, got {}", buzzes.len());
+                }
+                Ok(serde_json::to_string(&buzzes)?)
+            }
+            Command::Gestures => Ok(serde_json::to_string(&self.take_gestures().await?)?),
+            Command::ExpectGesture(gesture) => {
+                let gestures = self.take_gestures().await?;
+                if !gestures.contains(&gesture.name()) {
+                    anyhow::bail!("expected a {}, got {gestures:?}", gesture.name());
+                }
+                Ok(serde_json::to_string(&gestures)?)
+            }
+            Command::Framebuffer => {
+                let screen = self.screen()?;
+                let lcd_on = self.eval(LCD_ON_EXPR).await?;
+                Ok(serde_json::to_string(&Framebuffer {
+                    width: screen.width(),
+                    height: screen.height(),
+                    format: screen.format(),
+                    frame: screen.frame(),
+                    lcd_on: lcd_on.as_bool().unwrap_or(true),
+                    data: general_purpose::STANDARD.encode(screen.framebuffer()),
+                })?)
+            }
+            Command::AppList => Ok(self.eval(app::LIST_EXPR).await?.to_string()),
+            Command::AppRemove(id) => {
+                if self.eval(&app::remove_expr(&id)).await? != true {
+                    anyhow::bail!("no app {id:?} is installed");
+                }
+                Ok(String::new())
+            }
+            Command::AppSetClock(id) => {
+                if self.eval(&app::set_clock_expr(&id)).await? != true {
+                    anyhow::bail!("no clock {id:?} is installed");
+                }
+                Ok(String::new())
+            }
+            Command::AncsNotify(notification) => Ok(self
+                .eval(&ancs::notify_expr(&notification))
+                .await?
+                .to_string()),
+            Command::AncsRemove(uid) => {
+                if self.eval(&ancs::remove_expr(uid)).await? != true {
+                    anyhow::bail!("no notification {uid}");
+                }
+                Ok(String::new())
+            }
+            Command::AncsActions => Ok(self.eval(ancs::ACTIONS_EXPR).await?.to_string()),
+            Command::Theme(dark) => Ok(theme_name(self.set_theme(dark).await?).to_owned()),
+            Command::ThemeScreenshots(path) => {
+                // Ending in the theme the watch started in saves a reload.
+                let was_dark = self.eval("g.theme.dark").await? == true;
+                for dark in [!was_dark, was_dark] {
+                    self.set_theme(Some(dark)).await?;
+                    let file = path.with_extension(format!("{}.png", theme_name(dark)));
+                    fs::write(&file, encode_png(&*self.lcd_screen().await?)?)
+                        .with_context(|| format!("Failed to write {file:?}"))?;
+                }
+                Ok(String::new())
+            }
+            Command::Power => {
+                let power = self.power.as_ref().ok_or_else(|| {
+                    anyhow::format_err!("power isn't being monitored (see --power-report)")
+                })?;
+                Ok(serde_json::to_string(&power.usage())?)
+            }
+            Command::Battery => {
+                let battery = self
+                    .power
+                    .as_ref()
+                    .and_then(PowerMonitor::battery)
+                    .ok_or_else(|| anyhow::format_err!("no battery model is configured"))?;
+                Ok(serde_json::to_string(&battery.report())?)
+            }
+            Command::WatchedPins => {
+                let pins = parse_watched_pins(self.eval(WATCHED_PINS_EXPR).await?)?;
+                Ok(serde_json::to_string(&pins)?)
+            }
+        }
+    }
+}
+
+async fn handle_connection(socket: TcpStream, controller: Controller) -> anyhow::Result<()> {
+    let (read, mut write) = socket.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        let reply = match Command::parse(&line) {
+            Ok(mut command) => {
+                if let Some(dir) = controller.output_dir() {
+                    command.resolve_paths(Path::new(""), dir);
+                }
+                controller.execute(command).await
+            }
+            Err(err) => Err(err),
+        };
+        let reply = match reply {
+            Ok(s) if s.is_empty() => "ok\n".to_owned(),
+            Ok(s) => format!("ok {s}\n"),
+            Err(err) => format!("err {err:#}\n"),
+        };
+        write.write_all(reply.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Serves the line-based control interface: each line received is a command,
+/// answered with a line starting with `ok` or `err`.
+pub async fn run_control(
+    bind: impl ToSocketAddrs + Debug,
+    controller: Controller,
+    mut quit: Receiver<()>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(&bind)
+        .await
+        .with_context(|| format!("Failed to bind {bind:?}"))?;
+
+    loop {
+        select! {
+            _ = quit.recv() => break,
+            conn = listener.accept() => {
+                let (socket, addr) = conn?;
+                info!("control connection from {addr}");
+                let controller = controller.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = handle_connection(socket, controller).await {
+                        error!("control connection from {addr} failed: {err}");
+                    }
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
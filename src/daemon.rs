@@ -0,0 +1,137 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+
+/// The flag the detached copy of the emulator is started with.
+pub const DETACHED_FLAG: &str = "--detached";
+
+/// How long `stop` waits for the emulator to exit.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Starts a copy of the emulator with the same arguments in the background,
+/// detached from the terminal, records its pid in the pidfile, and returns the
+/// pid.
+#[cfg(unix)]
+pub fn detach(pidfile: &Path) -> anyhow::Result<u32> {
+    use std::{os::unix::process::CommandExt, process::Stdio};
+
+    if let Some(pid) = running_pid(pidfile)? {
+        anyhow::bail!("already running with pid {pid} (according to {pidfile:?})");
+    }
+    let args = std::env::args_os().skip(1).filter(|arg| arg != "--daemon");
+    let mut command = std::process::Command::new(std::env::current_exe()?);
+    command
+        .args(args)
+        .arg(DETACHED_FLAG)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    // Start a new session so that closing the terminal doesn't take the
+    // emulator down with it.
+    unsafe {
+        command.pre_exec(|| {
+            libc::setsid();
+            Ok(())
+        });
+    }
+    let pid = command
+        .spawn()
+        .context("Failed to start the emulator")?
+        .id();
+    fs::write(pidfile, format!("{pid}\n"))
+        .with_context(|| format!("Failed to write pidfile {pidfile:?}"))?;
+    Ok(pid)
+}
+
+#[cfg(not(unix))]
+pub fn detach(_pidfile: &Path) -> anyhow::Result<u32> {
+    anyhow::bail!("running as a daemon is only supported on Unix")
+}
+
+#[cfg(unix)]
+fn is_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Signal 0 only checks whether the process exists; a permission error
+    // still means it does.
+    let alive = unsafe { libc::kill(pid, 0) } == 0;
+    alive || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_alive(_pid: u32) -> bool {
+    false
+}
+
+/// Gets the pid from the pidfile if the process is still running.
+pub fn running_pid(pidfile: &Path) -> anyhow::Result<Option<u32>> {
+    let contents = match fs::read_to_string(pidfile) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("Failed to read {pidfile:?}")),
+    };
+    let pid: u32 = contents
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid pidfile {pidfile:?}"))?;
+    Ok(is_alive(pid).then_some(pid))
+}
+
+/// Asks the emulator in the pidfile to exit and waits for it to.
+#[cfg(unix)]
+pub fn stop(pidfile: &Path) -> anyhow::Result<()> {
+    let Some(pid) = running_pid(pidfile)? else {
+        anyhow::bail!("not running (according to {pidfile:?})");
+    };
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to signal the emulator");
+    }
+    let start = Instant::now();
+    while is_alive(pid) {
+        if start.elapsed() > STOP_TIMEOUT {
+            anyhow::bail!("pid {pid} is still running after {STOP_TIMEOUT:?}");
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    // The emulator normally removes it itself on the way out.
+    let _ = fs::remove_file(pidfile);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn stop(_pidfile: &Path) -> anyhow::Result<()> {
+    anyhow::bail!("running as a daemon is only supported on Unix")
+}
+
+/// Waits for the process to be asked to terminate (with `SIGTERM`, as `stop`
+/// and service managers do).
+pub async fn terminated() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut signal) = signal(SignalKind::terminate()) {
+            signal.recv().await;
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+/// Removes the pidfile when the detached emulator exits, if it's still ours.
+pub struct PidfileGuard(pub PathBuf);
+
+impl Drop for PidfileGuard {
+    fn drop(&mut self) {
+        let ours = fs::read_to_string(&self.0)
+            .is_ok_and(|contents| contents.trim() == std::process::id().to_string());
+        if ours {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    emu::{PixelFormat, ScreenSpec, BTN1, CHARGING},
+    touch::{GestureThresholds, TouchMode},
+};
+
+/// A board an emulator firmware can be built for, which determines the
+/// emulated device's screen and buttons.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Board {
+    #[default]
+    Banglejs2,
+    /// The Pixl.js, with a 128×64 monochrome LCD and four buttons.
+    Pixljs,
+}
+
+impl Board {
+    pub fn screen(&self) -> ScreenSpec {
+        match self {
+            Self::Banglejs2 => ScreenSpec::default(),
+            Self::Pixljs => ScreenSpec {
+                width: 128,
+                height: 64,
+                format: PixelFormat::Mono,
+            },
+        }
+    }
+
+    /// The pins the buttons are on, from BTN1.
+    pub fn buttons(&self) -> &'static [i32] {
+        match self {
+            Self::Banglejs2 => &[BTN1],
+            Self::Pixljs => &[29, 28, 4, 3],
+        }
+    }
+
+    /// Where the buttons sit around the screen, from BTN1.
+    pub fn button_positions(&self) -> &'static [ButtonPosition] {
+        const LEFT_TOP: ButtonPosition = ButtonPosition {
+            right: false,
+            height: 0.0,
+        };
+        const RIGHT_TOP: ButtonPosition = ButtonPosition {
+            right: true,
+            height: 0.0,
+        };
+        const RIGHT_MIDDLE: ButtonPosition = ButtonPosition {
+            right: true,
+            height: 0.5,
+        };
+        const RIGHT_BOTTOM: ButtonPosition = ButtonPosition {
+            right: true,
+            height: 1.0,
+        };
+        const LEFT_BOTTOM: ButtonPosition = ButtonPosition {
+            right: false,
+            height: 1.0,
+        };
+        match self {
+            Self::Banglejs2 => &[RIGHT_MIDDLE],
+            Self::Pixljs => &[LEFT_TOP, RIGHT_TOP, RIGHT_BOTTOM, LEFT_BOTTOM],
+        }
+    }
+
+    /// The pin that tells the firmware whether the charger is connected, and
+    /// whether it reads low while it is, for boards that can charge.
+    pub fn charge_pin(&self) -> Option<(i32, bool)> {
+        match self {
+            Self::Banglejs2 => Some((CHARGING, true)),
+            Self::Pixljs => None,
+        }
+    }
+
+    /// Whether the board has a touchscreen.
+    pub fn has_touchscreen(&self) -> bool {
+        match self {
+            Self::Banglejs2 => true,
+            Self::Pixljs => false,
+        }
+    }
+
+    /// Whether the buttons' pins read low while they're pressed.
+    pub fn buttons_active_low(&self) -> bool {
+        match self {
+            Self::Banglejs2 => true,
+            Self::Pixljs => false,
+        }
+    }
+
+    /// What the App Loader calls the device, as in the `supports` lists of
+    /// app manifests.
+    pub fn app_loader_id(&self) -> &'static str {
+        match self {
+            Self::Banglejs2 => "BANGLEJS2",
+            Self::Pixljs => "PIXLJS",
+        }
+    }
+}
+
+/// Where a button sits on the device, for drawing it beside the screen.
+#[derive(Clone, Copy, Debug)]
+pub struct ButtonPosition {
+    /// Whether it's on the right side of the screen rather than the left.
+    pub right: bool,
+    /// How far down that side it is, from 0 at the top of the screen to 1 at
+    /// the bottom.
+    pub height: f32,
+}
+
+/// The characteristics of the emulated device that aren't determined by the
+/// firmware itself. The defaults match a Bangle.js 2.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DeviceProfile {
+    pub board: Board,
+    pub touch_mode: TouchMode,
+    pub gestures: GestureThresholds,
+    /// How many parts per million the watch's clock gains (or, if negative,
+    /// loses); real crystals are off by tens of ppm.
+    pub clock_drift_ppm: f64,
+    /// The timezone to set on the watch, as an offset from UTC in hours.
+    pub timezone: Option<f64>,
+    /// The size and pixel format of the firmware's framebuffer, if not the
+    /// board's.
+    pub screen: Option<ScreenSpec>,
+}
+
+impl DeviceProfile {
+    pub fn screen_spec(&self) -> ScreenSpec {
+        self.screen.unwrap_or_else(|| self.board.screen())
+    }
+}
+
+/// The boot file that keeps an emulated Bluetooth connection across the
+/// firmware reloading, since it runs before every app.
+const BLUETOOTH_BOOT_FILE: &str = ".boot3";
+
+/// Builds a JavaScript expression that connects or disconnects an emulated
+/// phone over Bluetooth (or toggles it, if `None`), whatever the console is
+/// connected to: `NRF.getSecurityStatus()` reports the new state, `NRF` emits
+/// `connect` or `disconnect` if it changed, and a boot file keeps the state
+/// over reloads while connected. It evaluates to whether it's now connected.
+pub fn bluetooth_expr(connected: Option<bool>) -> String {
+    format!(
+        "(function(c){{var N=global.NRF||(global.NRF={{}}),f=N.getSecurityStatus,w=!!(f&&f().connected);\
+         if(c===null)c=!w;\
+         var P=function(c){{var N=global.NRF||(global.NRF={{}}),f=N.getSecurityStatus,\
+         o=f&&f.orig||f||function(){{return {{advertising:true,encrypted:false,mitm_protected:false,bonded:false}};}};\
+         N.getSecurityStatus=function(){{var s=o.call(N);s.connected=c;\
+         if(c)s.connected_addr='e7:5b:3d:1c:09:a2 random';else delete s.connected_addr;return s;}};\
+         N.getSecurityStatus.orig=o;}};P(c);var S=require('Storage');\
+         if(c)S.write('{BLUETOOTH_BOOT_FILE}','('+P+')(true);');else S.erase('{BLUETOOTH_BOOT_FILE}');\
+         if(c!=w)N.emit(c?'connect':'disconnect',c?N.getSecurityStatus().connected_addr:19);\
+         return c;}})({})",
+        match connected {
+            Some(connected) => connected.to_string(),
+            None => "null".to_owned(),
+        }
+    )
+}
+
+/// Builds the console line Gadgetbridge sends to set the watch's time, in
+/// milliseconds since the epoch, and, if given, its timezone, both for now and
+/// in its settings.
+pub fn gb_set_time_line(unix_ms: f64, timezone: Option<f64>) -> String {
+    let mut line = format!("\x10setTime({});", unix_ms / 1000.0);
+    if let Some(hours) = timezone {
+        line.push_str(&format!(
+            "E.setTimeZone({hours});(s=>s&&(s.timezone={hours},require('Storage').write('setting.json',s)))\
+             (require('Storage').readJSON('setting.json',1))"
+        ));
+    }
+    line.push('\n');
+    line
+}
+
+/// Builds the console line Gadgetbridge sends the watch a message with.
+pub fn gb_message_line(message: &Value) -> Vec<u8> {
+    format!("\x10GB({message})\n").into_bytes()
+}
+
+/// Picks out a line of console output in the form the watch sends Gadgetbridge
+/// messages, a JSON object with its type in `t`.
+pub fn gb_watch_message(line: &str) -> Option<Map<String, Value>> {
+    let line = line.trim();
+    if !line.starts_with('{') {
+        return None;
+    }
+    match serde_json::from_str(line).ok()? {
+        Value::Object(message) if message.get("t").is_some_and(Value::is_string) => Some(message),
+        _ => None,
+    }
+}
+
+/// Builds a JavaScript expression that sets the watch's timezone, both for
+/// now and in its settings, so that it survives the firmware reloading.
+pub fn timezone_expr(hours: f64) -> String {
+    format!(
+        "(function(h){{var S=require('Storage'),s=S.readJSON('setting.json',1)||{{}};\
+         if(s.timezone!==h){{s.timezone=h;S.writeJSON('setting.json',s);}}\
+         E.setTimeZone(h);}})({hours})"
+    )
+}
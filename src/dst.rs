@@ -0,0 +1,218 @@
+use std::{fs, path::Path, str::FromStr};
+
+use anyhow::Context;
+
+use crate::{
+    emu::{Clock, Emulator, Screen, BTN1},
+    eval::eval_sync,
+    runner::TICK_MS,
+    screenshot::encode_png,
+};
+
+const MINUTE_MS: f64 = 60_000.0;
+const DAY_MS: f64 = 24.0 * 60.0 * MINUTE_MS;
+
+/// When daylight saving time starts and ends.
+#[derive(Clone, Copy, Debug)]
+enum DstRule {
+    /// From the last Sunday of March to the last Sunday of October, changing
+    /// at 01:00 UTC.
+    Eu,
+    /// From the second Sunday of March to the first Sunday of November,
+    /// changing at 02:00 local time.
+    Us,
+}
+
+/// A timezone with daylight saving time, as far as Espruino's `E.setDST`
+/// can describe one.
+#[derive(Clone, Copy, Debug)]
+pub struct DstZone {
+    /// The standard offset from UTC, in minutes.
+    offset_mins: i32,
+    rule: DstRule,
+}
+
+const ZONES: &[(&str, i32, DstRule)] = &[
+    ("Europe/London", 0, DstRule::Eu),
+    ("Europe/Paris", 60, DstRule::Eu),
+    ("Europe/Berlin", 60, DstRule::Eu),
+    ("Europe/Helsinki", 120, DstRule::Eu),
+    ("America/New_York", -300, DstRule::Us),
+    ("America/Chicago", -360, DstRule::Us),
+    ("America/Denver", -420, DstRule::Us),
+    ("America/Los_Angeles", -480, DstRule::Us),
+];
+
+/// Parses a zone name, or `eu:<hours>` or `us:<hours>` for the EU or US rules
+/// with any standard offset.
+impl FromStr for DstZone {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if let Some(&(_, offset_mins, rule)) = ZONES.iter().find(|(name, ..)| *name == s) {
+            return Ok(Self { offset_mins, rule });
+        }
+        let (rule, hours) = match s.split_once(':') {
+            Some(("eu", hours)) => (DstRule::Eu, hours),
+            Some(("us", hours)) => (DstRule::Us, hours),
+            _ => {
+                let names: Vec<_> = ZONES.iter().map(|(name, ..)| *name).collect();
+                anyhow::bail!(
+                    "unknown zone {s:?} (expected one of {}, eu:<hours>, or us:<hours>)",
+                    names.join(", ")
+                )
+            }
+        };
+        let hours: f64 = hours
+            .parse()
+            .with_context(|| format!("invalid offset {hours:?}"))?;
+        Ok(Self {
+            offset_mins: (hours * 60.0).round() as i32,
+            rule,
+        })
+    }
+}
+
+/// Counts days since the Unix epoch (from Howard Hinnant's `days_from_civil`).
+fn days_from_civil(year: i32, month: u32, day: u32) -> i64 {
+    let year = i64::from(year) - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * i64::from((month + 9) % 12) + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// The inverse of [`days_from_civil`].
+fn civil_from_days(days: i64) -> (i32, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    ((yoe + era * 400 + i64::from(month <= 2)) as i32, month, day)
+}
+
+/// Finds the day of the `n`th Sunday of a month, counting from 1, or the last
+/// one if `n` is 0.
+fn sunday(year: i32, month: u32, n: u32) -> i64 {
+    // The Unix epoch was a Thursday.
+    let weekday = |days: i64| (days + 4).rem_euclid(7);
+    if n == 0 {
+        let (next_year, next_month) = if month == 12 {
+            (year + 1, 1)
+        } else {
+            (year, month + 1)
+        };
+        let last = days_from_civil(next_year, next_month, 1) - 1;
+        last - weekday(last)
+    } else {
+        let first = days_from_civil(year, month, 1);
+        first + (7 - weekday(first)) % 7 + 7 * i64::from(n - 1)
+    }
+}
+
+/// Formats a time in milliseconds since the epoch as a UTC date and time.
+pub fn format_utc(ms: f64) -> String {
+    let days = (ms / DAY_MS).floor();
+    let (year, month, day) = civil_from_days(days as i64);
+    let mins = ((ms - days * DAY_MS) / MINUTE_MS) as u32;
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02} UTC",
+        mins / 60,
+        mins % 60
+    )
+}
+
+/// Formats a time in milliseconds since the epoch as a compact UTC timestamp,
+/// e.g. `20250330T010000Z`, for naming files.
+pub fn format_utc_stamp(ms: f64) -> String {
+    let days = (ms / DAY_MS).floor();
+    let (year, month, day) = civil_from_days(days as i64);
+    let secs = ((ms - days * DAY_MS) / 1000.0) as u32;
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+impl DstZone {
+    /// Builds a JavaScript expression that sets up the zone with `E.setDST`.
+    pub fn set_dst_expr(&self) -> String {
+        let offset = self.offset_mins;
+        // The start is given in standard time and the end in daylight saving
+        // time, each as what the day's local time would be.
+        let params = match self.rule {
+            DstRule::Eu => format!("4,0,2,0,{},4,0,9,0,{}", 60 + offset, 120 + offset),
+            DstRule::Us => "1,0,2,0,120,0,0,10,0,120".to_owned(),
+        };
+        format!(
+            "(function(){{if(!E.setDST)throw new Error('firmware has no E.setDST');\
+             E.setDST(60,{offset},{params});}})()"
+        )
+    }
+
+    /// When daylight saving time starts and ends in a year, in milliseconds
+    /// since the epoch.
+    pub fn transitions(&self, year: i32) -> [f64; 2] {
+        let at = |day: i64, utc_mins: i32| day as f64 * DAY_MS + f64::from(utc_mins) * MINUTE_MS;
+        match self.rule {
+            DstRule::Eu => [at(sunday(year, 3, 0), 60), at(sunday(year, 10, 0), 60)],
+            DstRule::Us => [
+                at(sunday(year, 3, 2), 120 - self.offset_mins),
+                at(sunday(year, 11, 1), 60 - self.offset_mins),
+            ],
+        }
+    }
+}
+
+/// The year it currently is in UTC, by a clock.
+pub fn current_year(clock: &Clock) -> i32 {
+    civil_from_days((clock.now_ms() / DAY_MS) as i64).0
+}
+
+/// Runs an emulator across both of a year's DST transitions in a zone on
+/// virtual time, saving screenshots a second before each one and `margin_ms`
+/// after it into `dir`, and printing what the firmware thinks the time is
+/// each time.
+pub fn capture_transitions(
+    mut emu: Emulator,
+    zone: &DstZone,
+    year: i32,
+    margin_ms: f64,
+    dir: &Path,
+) -> anyhow::Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {dir:?}"))?;
+    let [start, end] = zone.transitions(year);
+    let clock = Clock::stopped_at(start - margin_ms);
+    emu.set_clock(clock.clone());
+    emu.send_pin_watch_event(BTN1)?;
+    eval_sync(&mut emu, &zone.set_dst_expr())?.map_err(|e| anyhow::format_err!("{e}"))?;
+
+    let mut screen = Screen::default();
+    for (name, at) in [("dst-start", start), ("dst-end", end)] {
+        println!("{name}: {}", format_utc(at));
+        clock.set_ms(at - margin_ms);
+        for (suffix, until) in [("before", at - 1000.0), ("after", at + margin_ms)] {
+            while clock.now_ms() < until {
+                clock.advance(TICK_MS);
+                emu.idle()?;
+                emu.handle_io()?;
+            }
+            emu.get_screen_into(&mut screen)?;
+            let path = dir.join(format!("{name}-{suffix}.png"));
+            fs::write(&path, encode_png(&screen)?)
+                .with_context(|| format!("Failed to write {path:?}"))?;
+            let time = eval_sync(&mut emu, "Date().toString()")?
+                .map_err(|e| anyhow::format_err!("{e}"))?;
+            let time = time.as_str().unwrap_or_default();
+            println!("  {suffix:<6} {time} -> {}", path.display());
+        }
+    }
+    Ok(())
+}
@@ -1,61 +1,402 @@
+#[cfg(feature = "native")]
+use std::path::Path;
 use std::{
     borrow::Borrow,
+    collections::VecDeque,
     fmt::Display,
     mem,
-    path::Path,
+    str::FromStr,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Mutex,
     },
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use log::{debug, trace};
-use wasmtime::{AsContextMut, Caller, Engine, Instance, Linker, Module, Store, TypedFunc};
-use wasmtime_wasi::{sync::WasiCtxBuilder, WasiCtx};
+#[cfg(feature = "native")]
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+#[cfg(feature = "native")]
+use tokio::sync::{oneshot, watch};
+#[cfg(feature = "native")]
+use wasmtime::Module;
+
+use crate::{
+    device::{Board, DeviceProfile},
+    engine::{transmit, Engine, Export, Val},
+    peripheral::Peripheral,
+    profile::SharedCallProfile,
+    touch::{Gesture, TouchMode, TouchTracker},
+};
+#[cfg(feature = "native")]
+use crate::{eval::EvalResult, snapshot::Snapshot, wasmtime_engine::WasmtimeEngine};
 
 pub const BTN1: i32 = 17;
+/// The pressure of touches that don't give one, about that of a firm tap.
+pub const DEFAULT_TOUCH_PRESSURE: u8 = 128;
+pub const VIBRATE: i32 = 19;
+/// The Bangle.js 2's charge-detect pin, which reads low while charging.
+pub const CHARGING: i32 = 23;
+/// The size of the watch's external SPI flash.
+pub(crate) const FLASH_SIZE: usize = 1 << 23;
+/// How many GPIO pins the emulated boards have.
+pub(crate) const PIN_COUNT: usize = 48;
+
+/// Finds the byte of flash an address refers to. Like the real SPI flash, only
+/// the low bits of the address are decoded, so addresses in the range the watch
+/// maps the flash to (from 0x60000000), which backup tools read and write
+/// through `require("Flash")`, land on the same bytes as on a real watch.
+fn flash_index(addr: i32) -> usize {
+    addr as u32 as usize & (FLASH_SIZE - 1)
+}
+
+/// How many hardware events are kept for [`Emulator::take_events`] before the
+/// oldest are dropped.
+const MAX_PENDING_EVENTS: usize = 256;
+
+/// An Espruino IO device (`IOEventFlags`) that console traffic can be sent on.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Device(pub i32);
+
+impl Device {
+    /// The device that the emulator's console input is pushed from, and which
+    /// the firmware transmits its console output on by default.
+    pub const CONSOLE: Self = Self(21);
+}
 
-#[derive(Clone, Copy, Default, Eq, PartialEq)]
-pub struct Color(u8);
+/// A pixel's color, kept as 16-bit RGB565, which every supported screen format
+/// fits in exactly.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Color(u16);
 
 impl Color {
+    pub const BLACK: Self = Self(0);
+    pub const WHITE: Self = Self(0xffff);
+
+    /// Makes one of the 3-bit colors of the Bangle.js 2's screen (1 is red, 2
+    /// green, and 4 blue).
     pub fn new(val: u8) -> Self {
-        Self(val & 7)
+        let channel = |n: u8, full: u16| if val & (1 << n) != 0 { full } else { 0 };
+        Self(channel(0, 0xf800) | channel(1, 0x07e0) | channel(2, 0x001f))
+    }
+
+    pub fn from_rgb565(val: u16) -> Self {
+        Self(val)
+    }
+
+    pub fn rgb565(&self) -> u16 {
+        self.0
     }
 
     pub fn fg(&self) -> u8 {
-        30 + self.0
+        30 + self.value()
     }
 
     pub fn bg(&self) -> u8 {
-        40 + self.0
+        40 + self.value()
     }
 
+    /// The nearest 3-bit color's value.
+    pub fn value(&self) -> u8 {
+        let (r, g, b) = self.rgb();
+        u8::from(r) | u8::from(g) << 1 | u8::from(b) << 2
+    }
+
+    /// Whether each channel is at least half on.
     pub fn rgb(&self) -> (bool, bool, bool) {
-        (self.0 & 1 != 0, self.0 & 2 != 0, self.0 & 4 != 0)
+        (
+            self.0 & 0x8000 != 0,
+            self.0 & 0x0400 != 0,
+            self.0 & 0x0010 != 0,
+        )
+    }
+
+    /// The channels scaled to 8 bits each.
+    pub fn rgb8(&self) -> [u8; 3] {
+        let (r, g, b) = (self.0 >> 11, (self.0 >> 5) & 0x3f, self.0 & 0x1f);
+        [
+            (r << 3 | r >> 2) as u8,
+            (g << 2 | g >> 4) as u8,
+            (b << 3 | b >> 2) as u8,
+        ]
+    }
+}
+
+impl Display for Color {
+    /// Shows a 3-bit color as its value, and any other as `#rrggbb`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if Self::new(self.value()) == *self {
+            write!(f, "{}", self.value())
+        } else {
+            let [r, g, b] = self.rgb8();
+            write!(f, "#{r:02x}{g:02x}{b:02x}")
+        }
+    }
+}
+
+impl FromStr for Color {
+    type Err = anyhow::Error;
+
+    /// Parses a color given either as its 3-bit value, by name, or as
+    /// `#rrggbb` (taken to the nearest RGB565 color).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const NAMES: [&str; 8] = [
+            "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+        ];
+        if let Some(ind) = NAMES.iter().position(|&n| n.eq_ignore_ascii_case(s)) {
+            return Ok(Self::new(ind as u8));
+        }
+        if let Some(hex) = s.strip_prefix('#') {
+            if let (6, Ok(val)) = (hex.len(), u32::from_str_radix(hex, 16)) {
+                let [_, r, g, b] = val.to_be_bytes();
+                return Ok(PixelFormat::Rgb565.quantize([r, g, b]));
+            }
+        }
+        match s.parse::<u8>() {
+            Ok(val) if val < 8 => Ok(Self::new(val)),
+            _ => anyhow::bail!("invalid color {s:?}"),
+        }
+    }
+}
+
+/// How the firmware stores pixels in its framebuffer. Pixels are packed into
+/// each row starting from the least significant bit of its first byte, so a
+/// 16-bit pixel is a little-endian `u16`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PixelFormat {
+    /// 1 bit per pixel, with set pixels dark, as on monochrome LCDs.
+    Mono,
+    /// 3 bits per pixel: red, green, and blue, each on or off.
+    #[default]
+    Rgb3,
+    /// 16 bits per pixel.
+    Rgb565,
+}
+
+impl PixelFormat {
+    pub fn bits(&self) -> usize {
+        match self {
+            Self::Mono => 1,
+            Self::Rgb3 => 3,
+            Self::Rgb565 => 16,
+        }
+    }
+
+    /// Gets the color of a pixel from its value in the framebuffer.
+    pub fn color(&self, val: u32) -> Color {
+        match self {
+            Self::Mono if val != 0 => Color::BLACK,
+            Self::Mono => Color::WHITE,
+            Self::Rgb3 => Color::new(val as u8),
+            Self::Rgb565 => Color::from_rgb565(val as u16),
+        }
+    }
+
+    /// Gets the value a color is stored as in the framebuffer, taking it to
+    /// the nearest color the format can show.
+    pub fn value(&self, color: Color) -> u32 {
+        match self {
+            Self::Mono => u32::from(self.quantize(color.rgb8()) == Color::BLACK),
+            Self::Rgb3 => color.value().into(),
+            Self::Rgb565 => color.rgb565().into(),
+        }
+    }
+
+    /// Finds the nearest color the format can show to an 8-bit RGB color.
+    pub fn quantize(&self, [r, g, b]: [u8; 3]) -> Color {
+        match self {
+            Self::Mono => {
+                let luma = (u32::from(r) * 299 + u32::from(g) * 587 + u32::from(b) * 114) / 1000;
+                self.color(u32::from(luma < 128))
+            }
+            Self::Rgb3 => {
+                let bit = |v: u8, n: u8| u8::from(v >= 128) << n;
+                Color::new(bit(r, 0) | bit(g, 1) | bit(b, 2))
+            }
+            Self::Rgb565 => {
+                let scale = |v: u8, max: u16| (u16::from(v) * max + 127) / 255;
+                Color::from_rgb565(scale(r, 31) << 11 | scale(g, 63) << 5 | scale(b, 31))
+            }
+        }
+    }
+}
+
+/// The size and pixel format of a device's screen.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(default)]
+pub struct ScreenSpec {
+    pub width: usize,
+    pub height: usize,
+    pub format: PixelFormat,
+}
+
+impl Default for ScreenSpec {
+    /// The Bangle.js 2's screen.
+    fn default() -> Self {
+        Self {
+            width: 176,
+            height: 176,
+            format: PixelFormat::Rgb3,
+        }
+    }
+}
+
+impl ScreenSpec {
+    /// How many bytes each row of the framebuffer takes.
+    fn row_len(&self) -> usize {
+        (self.width * self.format.bits()).div_ceil(8)
     }
 }
 
 #[derive(Clone)]
-pub struct Screen(pub [[Color; 176]; 176]);
+pub struct Screen {
+    spec: ScreenSpec,
+    /// The pixels by rows.
+    pixels: Vec<Color>,
+    /// How many screen updates the runner had sent when this one was grabbed,
+    /// counting it, or 0 for a screen that didn't come from a runner.
+    frame: u64,
+}
+
+impl Screen {
+    /// Makes a screen with every pixel black.
+    pub fn new(spec: ScreenSpec) -> Self {
+        Self {
+            spec,
+            pixels: vec![Color::BLACK; spec.width * spec.height],
+            frame: 0,
+        }
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn set_frame(&mut self, frame: u64) {
+        self.frame = frame;
+    }
+
+    pub fn spec(&self) -> ScreenSpec {
+        self.spec
+    }
+
+    pub fn width(&self) -> usize {
+        self.spec.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.spec.height
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.spec.format
+    }
+
+    /// Gets a pixel, panicking if it's off the screen.
+    pub fn pixel(&self, x: usize, y: usize) -> Color {
+        assert!(x < self.width(), "pixel ({x}, {y}) is off the screen");
+        self.pixels[y * self.width() + x]
+    }
+
+    /// Gets a pixel, or `None` if it's off the screen.
+    pub fn get(&self, x: usize, y: usize) -> Option<Color> {
+        (x < self.width() && y < self.height()).then(|| self.pixel(x, y))
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, color: Color) {
+        assert!(x < self.width(), "pixel ({x}, {y}) is off the screen");
+        let width = self.width();
+        self.pixels[y * width + x] = color;
+    }
+
+    /// The rows of pixels, from the top.
+    pub fn rows(&self) -> impl Iterator<Item = &[Color]> {
+        self.pixels.chunks_exact(self.width())
+    }
+
+    /// Whether a rectangular region lies entirely on the screen.
+    pub fn contains(&self, x: usize, y: usize, w: usize, h: usize) -> bool {
+        x.checked_add(w).is_some_and(|r| r <= self.width())
+            && y.checked_add(h).is_some_and(|b| b <= self.height())
+    }
+
+    /// Computes a hash (64-bit FNV-1a) of the colors in a rectangular region,
+    /// for cheaply checking that a region is unchanged.
+    pub fn region_hash(&self, x: usize, y: usize, w: usize, h: usize) -> u64 {
+        let mut hash = 0xcbf29ce484222325u64;
+        for row in self.rows().skip(y).take(h) {
+            for c in &row[x..x + w] {
+                // Colors from the 3-bit palette hash as they always have, so
+                // that saved hashes stay valid.
+                let val = if self.format() == PixelFormat::Rgb3 {
+                    u64::from(c.value())
+                } else {
+                    u64::from(c.0)
+                };
+                hash ^= val;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+        hash
+    }
+
+    /// Packs the pixels as the firmware stores them in its framebuffer (see
+    /// [`PixelFormat`]), row by row.
+    pub fn framebuffer(&self) -> Vec<u8> {
+        let bits = self.format().bits();
+        let row_len = self.spec.row_len();
+        let mut buf = vec![0u8; row_len * self.height()];
+        for (row, out) in self.rows().zip(buf.chunks_exact_mut(row_len)) {
+            for (x, &color) in row.iter().enumerate() {
+                let val = self.format().value(color);
+                for i in 0..bits {
+                    let bit = x * bits + i;
+                    out[bit >> 3] |= (((val >> i) & 1) as u8) << (bit & 7);
+                }
+            }
+        }
+        buf
+    }
+
+    /// Hashes the whole screen, as [`Screen::region_hash`] does.
+    pub fn hash(&self) -> u64 {
+        self.region_hash(0, 0, self.width(), self.height())
+    }
+
+    /// Counts the pixels that differ between two screens, taking every pixel
+    /// to differ if their sizes don't match.
+    pub fn differing_pixels(&self, other: &Screen) -> usize {
+        if (self.width(), self.height()) != (other.width(), other.height()) {
+            return self.pixels.len().max(other.pixels.len());
+        }
+        self.pixels
+            .iter()
+            .zip(&other.pixels)
+            .filter(|(a, b)| a != b)
+            .count()
+    }
+}
+
+/// The most recent screen contents, if the firmware has drawn anything yet.
+#[cfg(feature = "native")]
+pub type ScreenWatch = watch::Receiver<Option<Arc<Screen>>>;
 
 impl Default for Screen {
+    /// A blank Bangle.js 2 screen.
     fn default() -> Self {
-        Self([[Default::default(); 176]; 176])
+        Self::new(ScreenSpec::default())
     }
 }
 
 impl Display for Screen {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for y in (0..176).step_by(2) {
-            for x in 0..176 {
-                write!(
-                    f,
-                    "\x1b[{};{}m\u{2584}",
-                    self.0[y][x].bg(),
-                    self.0[y + 1][x].fg()
-                )?;
+        for y in (0..self.height()).step_by(2) {
+            for x in 0..self.width() {
+                // An odd last row is drawn over black.
+                let below = self.get(x, y + 1).unwrap_or_default();
+                write!(f, "\x1b[{};{}m\u{2584}", self.pixel(x, y).bg(), below.fg())?;
             }
             writeln!(f, "\x1b[m")?;
         }
@@ -63,17 +404,177 @@ impl Display for Screen {
     }
 }
 
+#[cfg(feature = "native")]
 #[derive(Debug)]
 pub enum Input {
-    Console(Vec<u8>),
+    Console(Device, Vec<u8>),
+    /// A touch or its release at a point, with its pressure if given (see
+    /// [`Emulator::send_touch_with_pressure`]).
+    Touch(u8, u8, bool, Option<u8>),
+    /// A press or release of a button, numbered from 1 like `BTN1`.
+    Button(usize, bool),
+    /// Change the firmware's clock.
+    AdjustClock(ClockAdjustment),
+    /// Sync the watch's time to the host's clock plus some milliseconds, and
+    /// its timezone to some hours from UTC if given, as Gadgetbridge does from
+    /// a phone.
+    TimeSync(f64, Option<f64>),
+    /// Set a pin's raw value, if given, and tell the firmware it may have
+    /// changed, as a pin watch would.
+    Pin(i32, Option<bool>),
+    /// Connect or disconnect the charger, or toggle it if not given, through
+    /// the board's charge-detect pin.
+    Charger(Option<bool>),
+    /// Connect or disconnect an emulated phone over Bluetooth, or toggle it if
+    /// not given (see [`bluetooth_expr`]).
+    ///
+    /// [`bluetooth_expr`]: crate::device::bluetooth_expr
+    Bluetooth(Option<bool>),
+    /// Read a pin's raw value, or `None` if there's no such pin.
+    ReadPin(i32, oneshot::Sender<Option<bool>>),
+    /// Read the raw values of all the pins.
+    ReadPins(oneshot::Sender<Vec<bool>>),
+    /// Take the buzzes of the vibration motor that have finished since the
+    /// last time.
+    TakeBuzzes(oneshot::Sender<Vec<Buzz>>),
+    /// Take the gestures recognized in touches since the last time.
+    TakeGestures(oneshot::Sender<Vec<Gesture>>),
+    TouchMode(TouchMode),
+    /// Stop or resume running the firmware, freezing its clock while stopped.
+    Pause(bool),
+    /// While paused, run the firmware a little.
+    Step(Step),
+    /// Wait until console output matches a pattern or the given number of
+    /// milliseconds of virtual time pass.
+    WaitFor(Regex, f64, oneshot::Sender<WaitResult>),
+    /// Wait until the firmware has nothing to do for at least the first number
+    /// of milliseconds (no timers due and no evaluations outstanding),
+    /// replying `true`, or until the second number of milliseconds of virtual
+    /// time pass, replying `false`.
+    WaitIdle(f64, f64, oneshot::Sender<bool>),
+    /// Evaluate an expression through the hidden eval channel.
+    Eval(String, oneshot::Sender<EvalResult>),
+    /// Get a copy of the whole flash.
+    ReadFlash(oneshot::Sender<Vec<u8>>),
+    /// Capture the emulator's whole state.
+    Snapshot(oneshot::Sender<Box<Snapshot>>),
+    /// Go back to a captured state.
+    Restore(Box<Snapshot>),
+    /// Write a reproducer bundle of the run so far, if it's being recorded,
+    /// giving the reason.
+    WriteBundle(String, oneshot::Sender<anyhow::Result<()>>),
+}
+
+/// Something that happened in the emulator, reported for display.
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// An input was delivered to the firmware, with a short description.
+    Input(String),
+    /// Something happened to the emulator itself rather than in the firmware,
+    /// like a restart or saving its state, with a short description.
+    Status(String),
+    /// The firmware changed the value of a pin.
+    Pin(i32, bool),
+    /// A button, numbered from 1, was pressed or released.
+    Button(usize, bool),
+    /// The screen was touched at a point, or released there.
     Touch(u8, u8, bool),
-    Button(bool),
+    /// The firmware wrote to a range of flash.
+    FlashWrite { addr: usize, len: usize },
+    /// The firmware changed what it advertises over Bluetooth, to this
+    /// payload (see [`advertising::parse`]).
+    ///
+    /// [`advertising::parse`]: crate::advertising::parse
+    Advertising(Vec<u8>),
+}
+
+/// A buzz of the vibration motor, timed by the firmware's clock to the
+/// nearest idle call.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Buzz {
+    pub start_ms: f64,
+    pub duration_ms: f64,
+}
+
+/// A change to the firmware's clock, as from a drifting crystal or a time sync.
+#[derive(Clone, Copy, Debug)]
+pub enum ClockAdjustment {
+    /// Make the clock gain (or, if negative, lose) this many parts per million.
+    Drift(f64),
+    /// Jump the clock forward (or, if negative, back) by this many milliseconds.
+    Skew(f64),
+}
+
+/// How far to run the firmware when stepping while paused.
+#[derive(Clone, Copy, Debug)]
+pub enum Step {
+    /// A single idle call, without moving the clock.
+    Idle,
+    /// Move the clock forward by this many milliseconds, idling as it goes.
+    Millis(f64),
+}
+
+/// How a wait for console output ended.
+#[derive(Debug)]
+pub enum WaitResult {
+    /// Output matched, with this text.
+    Matched(String),
+    /// Time ran out first.
+    Elapsed,
+}
+
+/// How the firmware's idle calls have been going since the emulator started.
+/// An app that always has more work for the firmware to do hides busy loops
+/// that would drain a real watch's battery, so that's tracked as well.
+#[derive(Clone, Debug, Default)]
+pub struct IdleStats {
+    pub calls: u64,
+    pub total: Duration,
+    pub max: Duration,
+    /// The longest idle call since the previous report.
+    pub recent_max: Duration,
+    /// How many times the runner has idled the firmware back to back until it
+    /// had nothing left to do.
+    pub bursts: u64,
+    /// How many of those bursts ran out of time with the firmware still having
+    /// more to do.
+    pub busy_bursts: u64,
+}
+
+impl IdleStats {
+    pub fn mean(&self) -> Duration {
+        if self.calls == 0 {
+            return Duration::ZERO;
+        }
+        self.total / self.calls as u32
+    }
+}
+
+impl Display for IdleStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "idle {:.0}us avg, {:.1}ms max, busy {}/{}",
+            self.mean().as_secs_f64() * 1e6,
+            self.recent_max.as_secs_f64() * 1e3,
+            self.busy_bursts,
+            self.bursts
+        )
+    }
 }
 
 #[derive(Clone)]
 pub enum Output {
-    Console(Vec<u8>),
+    Console(Device, Vec<u8>),
     Screen(Box<Screen>),
+    Event(Event),
+    /// The emulator recognized a gesture other than a drag in the touches it
+    /// was given and sent it to the firmware.
+    Gesture(Gesture),
+    /// The emulator was paused or resumed.
+    Paused(bool),
+    /// The latest idle statistics, reported periodically.
+    IdleStats(IdleStats),
 }
 
 #[derive(Clone, Default)]
@@ -99,367 +600,753 @@ pub struct Flags {
     pub reset: Flag,
 }
 
-struct State {
-    wasi_ctx: WasiCtx,
-    pins: Vec<bool>,
-    flash: Vec<u8>,
-    char_q: Vec<u8>,
-    instance: Option<Instance>,
-    flags: Flags,
+struct ClockState {
+    /// The virtual time in milliseconds since the epoch as of `anchor`.
+    base: f64,
+    /// When the clock was last started, if it's running.
+    anchor: Option<Instant>,
+    /// How fast the clock runs compared to real (or advanced) time.
+    rate: f64,
 }
 
-impl State {
-    fn init_banglejs2() -> Self {
-        let mut pins = vec![false; 48];
-        pins[BTN1 as usize] = true;
-
-        Self {
-            wasi_ctx: WasiCtxBuilder::new().build(),
-            pins,
-            flash: vec![255u8; 1 << 23],
-            instance: None,
-            char_q: vec![],
-            flags: Flags::default(),
+impl ClockState {
+    /// Folds the time since the anchor into the base, restarting from now.
+    fn reanchor(&mut self) {
+        if let Some(anchor) = &mut self.anchor {
+            self.base += anchor.elapsed().as_secs_f64() * 1000.0 * self.rate;
+            *anchor = Instant::now();
         }
     }
 }
 
-struct ModuleFuncs {
-    get_gfx_ptr: TypedFunc<i32, i32>,
-    js_gfx_changed: TypedFunc<(), i32>,
-    js_idle: TypedFunc<(), i32>,
-    js_init: TypedFunc<(), ()>,
-    js_push_char: TypedFunc<(i32, i32), ()>,
-    js_reset_storage: TypedFunc<(), ()>,
-    js_send_pin_watch_event: TypedFunc<i32, ()>,
-    js_send_touch_event: TypedFunc<(i32, i32, i32, i32), ()>,
+/// The time seen by the firmware. It normally follows the host's clock, but can
+/// be created stopped and moved forward explicitly to run on virtual time. Like
+/// a real watch's crystal, it can be made to drift, gaining or losing time at a
+/// steady rate.
+#[derive(Clone)]
+pub struct Clock(Arc<Mutex<ClockState>>);
+
+impl Default for Clock {
+    fn default() -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64()
+            * 1000.0;
+        Self(Arc::new(Mutex::new(ClockState {
+            base: now,
+            anchor: Some(Instant::now()),
+            rate: 1.0,
+        })))
+    }
 }
 
-#[repr(u8)]
-enum Gesture {
-    Drag = 0,
-    Down = 1,
-    Up = 2,
-    Left = 3,
-    Right = 4,
-    Touch = 5,
+impl Clock {
+    /// Creates a stopped clock, which only moves when advanced.
+    pub fn stopped_at(ms: f64) -> Self {
+        Self(Arc::new(Mutex::new(ClockState {
+            base: ms,
+            anchor: None,
+            rate: 1.0,
+        })))
+    }
+
+    pub fn now_ms(&self) -> f64 {
+        let state = self.0.lock().unwrap();
+        state.base
+            + state
+                .anchor
+                .map_or(0.0, |a| a.elapsed().as_secs_f64() * 1000.0 * state.rate)
+    }
+
+    /// Moves the clock forward by the given amount of real time (which it
+    /// counts as more or less than that if it drifts).
+    pub fn advance(&self, ms: f64) {
+        let mut state = self.0.lock().unwrap();
+        state.base += ms * state.rate;
+    }
+
+    /// Jumps the clock forward (or, if negative, back) by the given number of
+    /// milliseconds, as if it had been set.
+    pub fn skew(&self, ms: f64) {
+        self.0.lock().unwrap().base += ms;
+    }
+
+    /// How many parts per million the clock gains (or, if negative, loses).
+    pub fn drift_ppm(&self) -> f64 {
+        (self.0.lock().unwrap().rate - 1.0) * 1e6
+    }
+
+    pub fn set_drift_ppm(&self, ppm: f64) {
+        let mut state = self.0.lock().unwrap();
+        state.reanchor();
+        state.rate = 1.0 + ppm / 1e6;
+    }
+
+    /// Stops the clock where it is, until it's started again.
+    pub fn stop(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.reanchor();
+        state.anchor = None;
+    }
+
+    /// Moves the clock to a time, leaving it running or stopped.
+    pub fn set_ms(&self, ms: f64) {
+        let mut state = self.0.lock().unwrap();
+        state.base = ms;
+        if state.anchor.is_some() {
+            state.anchor = Some(Instant::now());
+        }
+    }
+
+    /// Starts a stopped clock following the host's clock again from where it
+    /// stopped.
+    pub fn start(&self) {
+        let mut state = self.0.lock().unwrap();
+        if state.anchor.is_none() {
+            state.anchor = Some(Instant::now());
+        }
+    }
 }
 
-#[derive(Debug, Default)]
-struct TouchTracker {
-    start_last: Option<((u8, u8), (u8, u8))>,
-    dist: (u64, u64),
+/// The emulator's side of the host functions the firmware imports: the
+/// watch's pins, flash, and clock, and the peripherals attached to it. The
+/// [`Engine`] running the firmware owns it, calling its methods when the
+/// firmware calls the host.
+pub struct Host {
+    pins: Vec<bool>,
+    flash: Vec<u8>,
+    char_q: Vec<(Device, u8)>,
+    flags: Flags,
+    clock: Clock,
+    events: VecDeque<Event>,
+    call_profile: Option<SharedCallProfile>,
+    peripherals: Vec<Box<dyn Peripheral>>,
 }
 
-impl TouchTracker {
-    fn add_touch(&mut self, pt: (u8, u8), on: bool) -> Vec<Gesture> {
-        match (self.start_last, on) {
-            // Start new touch -- record start and emit a drag.
-            (None, true) => {
-                self.start_last = Some((pt, pt));
-                self.dist = (0, 0);
-                vec![Gesture::Drag]
+impl Host {
+    /// Starts timing a call, if profiling.
+    pub fn profile_start(&self) -> Option<Instant> {
+        self.call_profile.as_ref().map(|_| Instant::now())
+    }
+
+    pub fn profile_finish(&self, name: &'static str, start: Option<Instant>) {
+        if let (Some(profile), Some(start)) = (&self.call_profile, start) {
+            profile.lock().unwrap().record(name, start.elapsed());
+        }
+    }
+
+    fn push_event(&mut self, event: Event) {
+        // Coalesce contiguous flash writes, which arrive a chunk at a time.
+        if let (
+            Some(Event::FlashWrite { addr, len }),
+            Event::FlashWrite {
+                addr: new_addr,
+                len: new_len,
+            },
+        ) = (self.events.back_mut(), &event)
+        {
+            if *addr + *len == *new_addr {
+                *len += new_len;
+                return;
+            }
+        }
+        if self.events.len() == MAX_PENDING_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+
+    /// The console output collected from the firmware but not yet taken.
+    pub fn char_q(&mut self) -> &mut Vec<(Device, u8)> {
+        &mut self.char_q
+    }
+
+    /// `hostIsInterrupted`
+    pub fn is_interrupted(&self) -> bool {
+        let ret = self.flags.interrupt.get();
+        if ret {
+            log::info!("is interrupted!");
+        }
+        ret
+    }
+
+    /// `hostClearInterrupted`
+    pub fn clear_interrupted(&self) {
+        self.flags.interrupt.clear();
+    }
+
+    /// `hostIsReset`
+    pub fn is_reset(&self) -> bool {
+        let ret = self.flags.reset.get();
+        if ret {
+            log::info!("is reset!");
+        }
+        ret
+    }
+
+    /// `hostClearReset`
+    pub fn clear_reset(&self) {
+        self.flags.reset.clear();
+    }
+
+    /// `hwFlashRead`
+    pub fn flash_read(&self, addr: i32) -> u8 {
+        trace!("hwFlashRead {addr}");
+        let start = self.profile_start();
+        let ret = self.flash[flash_index(addr)];
+        self.profile_finish("hwFlashRead", start);
+        ret
+    }
+
+    /// `hwFlashWritePtr`, given the data from the firmware's memory.
+    pub fn flash_write(&mut self, addr: i32, data: &[u8]) {
+        trace!("writing at {addr}: {data:?}");
+        let start = self.profile_start();
+        let addr = flash_index(addr);
+        // A write running off the end wraps around to the start, as it would
+        // on the chip.
+        for (i, &b) in data.iter().enumerate() {
+            self.flash[(addr + i) % FLASH_SIZE] = b;
+        }
+        self.push_event(Event::FlashWrite {
+            addr,
+            len: data.len(),
+        });
+        self.profile_finish("hwFlashWritePtr", start);
+    }
+
+    /// `hwGetPinValue`
+    pub fn read_pin(&mut self, pin: i32) -> anyhow::Result<bool> {
+        debug!("hwGetPinValue {pin}");
+        let start = self.profile_start();
+        let mut ret = None;
+        for peripheral in &mut self.peripherals {
+            ret = peripheral.read_pin(pin)?;
+            if ret.is_some() {
+                break;
             }
-            // Continue existing touch -- update state and emit a drag.
-            (Some((start, last)), true) => {
-                self.dist.0 += u64::from(pt.0.abs_diff(last.0));
-                self.dist.1 += u64::from(pt.1.abs_diff(last.1));
-                self.start_last = Some((start, pt));
-                vec![Gesture::Drag]
+        }
+        let ret = ret.unwrap_or(self.pins[pin as usize]);
+        self.profile_finish("hwGetPinValue", start);
+        Ok(ret)
+    }
+
+    /// `hwSetPinValue`
+    pub fn write_pin(&mut self, pin: i32, value: bool) -> anyhow::Result<()> {
+        debug!("hwSetPinValue {pin} {value}");
+        let start = self.profile_start();
+        if self.pins[pin as usize] != value {
+            self.push_event(Event::Pin(pin, value));
+        }
+        self.pins[pin as usize] = value;
+        for peripheral in &mut self.peripherals {
+            peripheral.pin_written(pin, value)?;
+        }
+        self.profile_finish("hwSetPinValue", start);
+        Ok(())
+    }
+
+    /// `hwI2CWrite`, returning whether some peripheral acknowledged it.
+    pub fn i2c_write(&mut self, bus: i32, addr: i32, data: &[u8]) -> anyhow::Result<bool> {
+        for peripheral in &mut self.peripherals {
+            if peripheral.i2c_write(bus, addr, data)? {
+                return Ok(true);
             }
-            // Release existing touch -- check stats and see what to emit in
-            // addition to a drag.
-            (Some((start, last)), false) => {
-                self.dist.0 += u64::from(pt.0.abs_diff(last.0));
-                self.dist.1 += u64::from(pt.1.abs_diff(last.1));
+        }
+        Ok(false)
+    }
 
-                let mut ret = vec![Gesture::Drag];
+    /// `hwI2CRead`, returning whether some peripheral acknowledged it (and
+    /// filled `buf`).
+    pub fn i2c_read(&mut self, bus: i32, addr: i32, buf: &mut [u8]) -> anyhow::Result<bool> {
+        for peripheral in &mut self.peripherals {
+            if peripheral.i2c_read(bus, addr, buf)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
 
-                if self.dist.0 < 5 && self.dist.1 < 5 {
-                    ret.push(Gesture::Touch);
-                }
-                if self.dist.0 > 80 && self.dist.1 < 20 {
-                    ret.push(if pt.0 > start.0 {
-                        Gesture::Right
-                    } else {
-                        Gesture::Left
-                    });
-                }
-                if self.dist.0 < 20 && self.dist.1 > 80 {
-                    ret.push(if pt.1 > start.1 {
-                        Gesture::Down
-                    } else {
-                        Gesture::Up
-                    });
-                }
+    /// `hwBleSetAdvertising`
+    pub fn set_advertising(&mut self, data: Vec<u8>) {
+        self.push_event(Event::Advertising(data));
+    }
 
-                self.start_last = None;
-                ret
+    /// `nowMillis`
+    pub fn now_ms(&self) -> f64 {
+        trace!("nowMillis");
+        let start = self.profile_start();
+        let ret = self.clock.now_ms();
+        self.profile_finish("nowMillis", start);
+        ret
+    }
+
+    /// Whether some peripheral provides a host function the emulator doesn't.
+    pub fn provides(&self, name: &str) -> bool {
+        self.peripherals.iter().any(|p| p.provides(name))
+    }
+
+    /// Calls a host function that one of the peripherals provides.
+    pub fn call(&mut self, name: &str, params: &[Val], results: &mut [Val]) -> anyhow::Result<()> {
+        for peripheral in &mut self.peripherals {
+            if peripheral.call(name, params, results)? {
+                return Ok(());
             }
-            // Supposedly end touch when already ended -- ignore.
-            (None, false) => vec![],
+        }
+        anyhow::bail!("firmware called {name}, which no peripheral handled")
+    }
+}
+
+impl Default for Host {
+    /// A Bangle.js 2 with its flash erased.
+    fn default() -> Self {
+        let mut pins = vec![false; PIN_COUNT];
+        pins[BTN1 as usize] = true;
+        pins[CHARGING as usize] = true;
+
+        Self {
+            pins,
+            flash: vec![255u8; FLASH_SIZE],
+            char_q: vec![],
+            flags: Flags::default(),
+            clock: Clock::default(),
+            events: VecDeque::new(),
+            call_profile: None,
+            peripherals: vec![],
         }
     }
 }
 
 pub struct Emulator {
-    store: Store<State>,
-    instance: Instance,
-    funcs: ModuleFuncs,
+    engine: Box<dyn Engine>,
 
     touch: TouchTracker,
+    touch_mode: TouchMode,
+    /// Gestures recognized in touches since they were last taken.
+    gestures: Vec<Gesture>,
     flags: Flags,
+    screen_spec: ScreenSpec,
+    /// The pins of the buttons, from BTN1.
+    buttons: &'static [i32],
+    buttons_active_low: bool,
+    charge_pin: Option<(i32, bool)>,
+    /// Kept between reads of the screen to avoid reallocating it.
+    screen_buf: Vec<u8>,
 }
 
+#[cfg(feature = "native")]
 impl Emulator {
     pub fn new<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
-        let engine = Engine::default();
-
-        let mut linker = Linker::new(&engine);
-
-        wasmtime_wasi::add_to_linker(&mut linker, |s: &mut State| &mut s.wasi_ctx)?;
-
-        linker.func_wrap("env", "jsHandleIO", |mut caller: Caller<'_, State>| {
-            let instance = caller.data().instance.unwrap();
-            let mut char_q = mem::take(&mut caller.data_mut().char_q);
-            Self::js_handle_io(&mut caller, &instance, &mut char_q).unwrap();
-            caller.data_mut().char_q = char_q;
-        })?;
-
-        linker.func_wrap(
-            "env",
-            "hostIsInterrupted",
-            |caller: Caller<'_, State>| -> i32 {
-                let ret = caller.data().flags.interrupt.get();
-                if ret {
-                    log::info!("is interrupted!");
-                }
-                ret.into()
-            },
-        )?;
-
-        linker.func_wrap(
-            "env",
-            "hostClearInterrupted",
-            |caller: Caller<'_, State>| {
-                caller.data().flags.interrupt.clear();
-            },
-        )?;
+        let engine = wasmtime::Engine::default();
+        let module = Module::from_file(&engine, path)?;
+        Self::from_module(&engine, &module)
+    }
 
-        linker.func_wrap("env", "hostIsReset", |caller: Caller<'_, State>| -> i32 {
-            let ret = caller.data().flags.reset.get();
-            if ret {
-                log::info!("is reset!");
-            }
-            ret.into()
-        })?;
+    /// Creates an emulator from an already-compiled firmware module, which can
+    /// be shared between any number of emulators.
+    pub fn from_module(engine: &wasmtime::Engine, module: &Module) -> anyhow::Result<Self> {
+        Self::from_module_with(engine, module, vec![])
+    }
 
-        linker.func_wrap("env", "hostClearReset", |caller: Caller<'_, State>| {
-            caller.data().flags.reset.clear();
-        })?;
+    /// Like [`Emulator::from_module`], but with peripherals attached from the
+    /// start, which can provide host functions the firmware imports that the
+    /// emulator doesn't. A firmware importing one that nothing provides fails
+    /// to load, since it's most likely not a build for the emulator.
+    pub fn from_module_with(
+        engine: &wasmtime::Engine,
+        module: &Module,
+        peripherals: Vec<Box<dyn Peripheral>>,
+    ) -> anyhow::Result<Self> {
+        let host = Host {
+            peripherals,
+            ..Default::default()
+        };
+        let engine = WasmtimeEngine::new(engine, module, host)?;
+        Ok(Self::with_engine(Box::new(engine)))
+    }
 
-        linker.func_wrap(
-            "env",
-            "hwFlashRead",
-            |caller: Caller<'_, State>, ind: i32| -> i32 {
-                trace!("hwFlashRead {ind}");
-                caller.data().flash[ind as usize] as i32
-            },
-        )?;
-
-        linker.func_wrap(
-            "env",
-            "hwFlashWritePtr",
-            |mut caller: Caller<'_, State>, flash_addr: i32, base: i32, len: i32| {
-                debug!("hwFlashWritePtr {flash_addr} {base} {len}");
-                let memory = caller.get_export("memory").unwrap().into_memory().unwrap();
-                let mut flash = mem::take(&mut caller.data_mut().flash);
-                let dst = &mut flash[flash_addr as usize..][..len as usize];
-                memory.read(&caller, base as usize, dst).unwrap();
-                trace!("writing at {flash_addr}: {dst:?}");
-                caller.data_mut().flash = flash;
-            },
-        )?;
-
-        linker.func_wrap(
-            "env",
-            "hwGetPinValue",
-            |caller: Caller<'_, State>, ind: i32| -> i32 {
-                debug!("hwGetPinValue {ind}");
-                caller.data().pins[ind as usize] as i32
-            },
-        )?;
-
-        linker.func_wrap(
-            "env",
-            "hwSetPinValue",
-            |mut caller: Caller<'_, State>, ind: i32, val: i32| {
-                debug!("hwSetPinValue {ind} {val}");
-                caller.data_mut().pins[ind as usize] = val != 0
-            },
-        )?;
-
-        linker.func_wrap("env", "nowMillis", || -> f64 {
-            trace!("nowMillis");
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs_f64()
-                * 1000.0
-        })?;
+    pub fn new_with_flash<P: AsRef<Path>>(path: P, data: &[u8]) -> anyhow::Result<Self> {
+        let mut emu = Self::new(path)?;
+        emu.write_flash(data);
+        Ok(emu)
+    }
+}
 
-        let mut store = Store::new(&engine, State::init_banglejs2());
-        let module = Module::from_file(&engine, path)?;
-        let instance = linker.instantiate(&mut store, &module)?;
-
-        store.data_mut().instance = Some(instance);
-        let flags = store.data().flags.clone();
-
-        let funcs = ModuleFuncs {
-            get_gfx_ptr: instance.get_typed_func(&mut store, "jsGfxGetPtr")?,
-            js_gfx_changed: instance.get_typed_func(&mut store, "jsGfxChanged")?,
-            js_idle: instance.get_typed_func(&mut store, "jsIdle")?,
-            js_init: instance.get_typed_func(&mut store, "jsInit")?,
-            js_push_char: instance.get_typed_func(&mut store, "jshPushIOCharEvent")?,
-            js_reset_storage: instance.get_typed_func(&mut store, "jsfResetStorage")?,
-            js_send_pin_watch_event: instance.get_typed_func(&mut store, "jsSendPinWatchEvent")?,
-            js_send_touch_event: instance.get_typed_func(&mut store, "jsSendTouchEvent")?,
-        };
-        Ok(Self {
-            store,
-            instance,
-            funcs,
+impl Emulator {
+    /// Creates an emulator around firmware that an engine has already loaded.
+    pub fn with_engine(engine: Box<dyn Engine>) -> Self {
+        let flags = engine.host().flags.clone();
+        Self {
+            engine,
             touch: Default::default(),
+            touch_mode: Default::default(),
+            gestures: vec![],
             flags,
-        })
+            screen_spec: ScreenSpec::default(),
+            buttons: Board::Banglejs2.buttons(),
+            buttons_active_low: Board::Banglejs2.buttons_active_low(),
+            charge_pin: Board::Banglejs2.charge_pin(),
+            screen_buf: vec![],
+        }
     }
 
-    pub fn new_with_flash<P: AsRef<Path>>(path: P, data: &[u8]) -> anyhow::Result<Self> {
-        let mut emu = Self::new(path)?;
-        let flash = &mut emu.store.data_mut().flash;
+    /// Overwrites the start of the flash with `data` (truncated to the size of
+    /// the flash).
+    pub fn write_flash(&mut self, data: &[u8]) {
+        let flash = &mut self.engine.host_mut().flash;
         let n = flash.len().min(data.len());
         flash[..n].copy_from_slice(&data[..n]);
-        Ok(emu)
+    }
+
+    pub fn flash(&self) -> &[u8] {
+        &self.engine.host().flash
+    }
+
+    pub fn flash_mut(&mut self) -> &mut [u8] {
+        &mut self.engine.host_mut().flash
+    }
+
+    /// Starts counting and timing host function calls and calls into the
+    /// firmware in the given profile.
+    pub fn set_call_profile(&mut self, profile: SharedCallProfile) {
+        self.engine.host_mut().call_profile = Some(profile);
+    }
+
+    fn profiled<T>(&mut self, name: &'static str, f: impl FnOnce(&mut Self) -> T) -> T {
+        let start = self.engine.host().profile_start();
+        let ret = f(self);
+        self.engine.host().profile_finish(name, start);
+        ret
     }
 
     pub fn init(&mut self) -> anyhow::Result<()> {
-        self.funcs.js_init.call(&mut self.store, ())
+        self.profiled("jsInit", |emu| emu.engine.call(Export::Init, &[]))?;
+        Ok(())
     }
 
     pub fn idle(&mut self) -> anyhow::Result<i32> {
-        self.funcs.js_idle.call(&mut self.store, ())
+        self.profiled("jsIdle", |emu| emu.engine.call(Export::Idle, &[]))
     }
 
     pub fn gfx_changed(&mut self) -> anyhow::Result<bool> {
-        Ok(self.funcs.js_gfx_changed.call(&mut self.store, ())? != 0)
+        let changed = self.profiled("jsGfxChanged", |emu| {
+            emu.engine.call(Export::GfxChanged, &[])
+        })?;
+        Ok(changed != 0)
     }
 
-    fn js_handle_io(
-        context: &mut impl AsContextMut<Data = State>,
-        instance: &Instance,
-        char_q: &mut Vec<u8>,
-    ) -> anyhow::Result<()> {
-        trace!("jsHandleIO");
-        let mut context = context.as_context_mut();
-        let get_device =
-            instance.get_typed_func::<(), i32>(&mut context, "jshGetDeviceToTransmit")?;
-        let get_char = instance.get_typed_func::<i32, i32>(&mut context, "jshGetCharToTransmit")?;
-
-        loop {
-            let device = get_device.call(&mut context, ()).unwrap();
-            if device == 0 {
-                break Ok(());
-            }
-            let ch = get_char.call(&mut context, device)?;
-            if let Ok(ch) = ch.try_into() {
-                char_q.push(ch);
-            } else {
-                return Ok(());
+    /// Collects pending console output, grouped into runs of bytes sent to the
+    /// same device.
+    pub fn handle_io(&mut self) -> anyhow::Result<Vec<(Device, Vec<u8>)>> {
+        let mut char_q = mem::take(&mut self.engine.host_mut().char_q);
+        self.profiled("handleIO", |emu| {
+            transmit(&mut char_q, |export, args| emu.engine.call(export, args))
+        })?;
+
+        let mut ret: Vec<(Device, Vec<u8>)> = vec![];
+        for (device, ch) in char_q {
+            match ret.last_mut() {
+                Some((d, chars)) if *d == device => chars.push(ch),
+                _ => ret.push((device, vec![ch])),
             }
         }
+        Ok(ret)
+    }
+
+    /// Takes the pin changes and flash writes since the last call.
+    pub fn take_events(&mut self) -> Vec<Event> {
+        self.engine.host_mut().events.drain(..).collect()
     }
 
-    pub fn handle_io(&mut self) -> anyhow::Result<Vec<u8>> {
-        let mut char_q = mem::take(&mut self.store.data_mut().char_q);
-        Self::js_handle_io(&mut self.store, &self.instance, &mut char_q)?;
-        Ok(char_q)
+    /// Takes the gestures, other than drags, recognized in touches since the
+    /// last call.
+    pub fn take_gestures(&mut self) -> Vec<Gesture> {
+        std::mem::take(&mut self.gestures)
+    }
+
+    /// Captures the emulator's state. This has to happen between calls into
+    /// the firmware, so that nothing is on its stack (whose pointer isn't
+    /// necessarily exported to be saved).
+    #[cfg(feature = "native")]
+    pub fn snapshot(&mut self) -> anyhow::Result<Snapshot> {
+        let globals = self.engine.globals();
+        let host = self.engine.host();
+        Ok(Snapshot {
+            memory: self.engine.memory().to_vec(),
+            globals,
+            pins: host.pins.clone(),
+            flash: host.flash.clone(),
+            clock_ms: host.clock.now_ms(),
+        })
+    }
+
+    /// Goes back to a state captured with [`Emulator::snapshot`] from an
+    /// emulator running the same firmware, abandoning any touch in progress.
+    #[cfg(feature = "native")]
+    pub fn restore(&mut self, snapshot: &Snapshot) -> anyhow::Result<()> {
+        let host = self.engine.host();
+        if snapshot.pins.len() != host.pins.len() || snapshot.flash.len() != host.flash.len() {
+            anyhow::bail!("snapshot is of a different device");
+        }
+        self.engine.set_memory(&snapshot.memory)?;
+
+        let globals = self.engine.globals();
+        for (name, value) in &snapshot.globals {
+            if !globals.iter().any(|(n, _)| n == name) {
+                anyhow::bail!("snapshot has a global {name:?} the firmware doesn't export");
+            }
+            self.engine.set_global(name, *value)?;
+        }
+
+        let host = self.engine.host_mut();
+        host.pins.clone_from(&snapshot.pins);
+        host.flash.clone_from(&snapshot.flash);
+        host.char_q.clear();
+        host.events.clear();
+        host.clock.set_ms(snapshot.clock_ms);
+        self.touch = TouchTracker::new(self.touch.thresholds());
+        Ok(())
     }
 
     pub fn reset_storage(&mut self) -> anyhow::Result<()> {
-        self.funcs.js_reset_storage.call(&mut self.store, ())
+        self.engine.call(Export::ResetStorage, &[])?;
+        Ok(())
     }
 
     pub fn get_screen(&mut self) -> anyhow::Result<Screen> {
-        let memory = self
-            .instance
-            .get_memory(&mut self.store, "memory")
-            .ok_or(anyhow::format_err!("failed to find `memory` export"))?;
-
-        let mut screen = Screen::default();
-
-        let mut buf = vec![0u8; 66];
-
-        for y in 0..176 {
-            let base = self.funcs.get_gfx_ptr.call(&mut self.store, y as i32)?;
-            memory.read(&self.store, base as usize, &mut buf)?;
-
-            fn get3(x: usize, buf: &[u8]) -> u8 {
-                let bit = x * 3;
-                let byte = bit >> 3;
-                ((buf[byte] >> (bit & 7))
-                    | if (bit & 7) <= 5 {
-                        0
-                    } else {
-                        buf[byte + 1] << (8 - (bit & 7))
-                    })
-                    & 7
+        let mut screen = Screen::new(self.screen_spec);
+        self.get_screen_into(&mut screen)?;
+        Ok(screen)
+    }
+
+    /// Reads the screen into an existing buffer, for callers that grab frames
+    /// often enough for the allocation to matter.
+    pub fn get_screen_into(&mut self, screen: &mut Screen) -> anyhow::Result<()> {
+        self.profiled("getScreen", |emu| emu.read_screen(screen))
+    }
+
+    /// Copies part of the firmware's memory into `buf`.
+    fn read_memory(&self, addr: usize, buf: &mut [u8]) -> anyhow::Result<()> {
+        let Some(data) = self.engine.memory().get(addr..addr + buf.len()) else {
+            anyhow::bail!("out of bounds memory access");
+        };
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read_screen(&mut self, screen: &mut Screen) -> anyhow::Result<()> {
+        let spec = self.screen_spec;
+        if screen.spec != spec {
+            *screen = Screen::new(spec);
+        }
+        let row_len = spec.row_len();
+        let mut buf = mem::take(&mut self.screen_buf);
+        buf.resize(row_len * spec.height, 0);
+        // The rows are normally laid out one after another, in which case
+        // they can all be read at once instead of asking for each one.
+        let last_row = spec.height as i32 - 1;
+        let mut row_ptr = |y: i32| -> anyhow::Result<usize> {
+            Ok(self.engine.call(Export::GfxGetPtr, &[y])? as usize)
+        };
+        let first = row_ptr(0)?;
+        let second = row_ptr(1)?;
+        let last = row_ptr(last_row)?;
+        if second == first + row_len && last == first + last_row as usize * row_len {
+            self.read_memory(first, &mut buf)?;
+        } else {
+            for (y, row) in buf.chunks_exact_mut(row_len).enumerate() {
+                let base = self.engine.call(Export::GfxGetPtr, &[y as i32])?;
+                self.read_memory(base as usize, row)?;
             }
+        }
+
+        /// Gets the value of an `n`-bit pixel, which may span up to three
+        /// bytes.
+        fn get_bits(x: usize, n: usize, buf: &[u8]) -> u32 {
+            let bit = x * n;
+            let bytes = &buf[bit >> 3..((bit + n).div_ceil(8)).min(buf.len())];
+            let word = bytes
+                .iter()
+                .rev()
+                .fold(0u32, |word, &b| word << 8 | u32::from(b));
+            (word >> (bit & 7)) & ((1 << n) - 1)
+        }
 
-            for x in 0..176 {
-                screen.0[y][x] = Color::new(get3(x, &buf));
+        let bits = spec.format.bits();
+        for (row, buf) in screen
+            .pixels
+            .chunks_exact_mut(spec.width)
+            .zip(buf.chunks_exact(row_len))
+        {
+            for (x, pixel) in row.iter_mut().enumerate() {
+                *pixel = spec.format.color(get_bits(x, bits, buf));
             }
         }
-        Ok(screen)
+        self.screen_buf = buf;
+        Ok(())
     }
 
     pub fn push_string<T, B>(&mut self, chars: T) -> anyhow::Result<()>
+    where
+        B: Borrow<u8>,
+        T: IntoIterator<Item = B>,
+    {
+        self.push_string_to(Device::CONSOLE, chars)
+    }
+
+    pub fn push_string_to<T, B>(&mut self, device: Device, chars: T) -> anyhow::Result<()>
     where
         B: Borrow<u8>,
         T: IntoIterator<Item = B>,
     {
         for ch in chars.into_iter() {
-            self.funcs
-                .js_push_char
-                .call(&mut self.store, (21, *ch.borrow() as i32))?;
+            self.engine
+                .call(Export::PushChar, &[device.0, *ch.borrow() as i32])?;
             self.idle()?;
         }
 
         Ok(())
     }
 
+    pub fn pin(&self, pin: i32) -> Option<bool> {
+        let pins = &self.engine.host().pins;
+        usize::try_from(pin).ok().and_then(|i| pins.get(i).copied())
+    }
+
+    pub fn pins(&self) -> &[bool] {
+        &self.engine.host().pins
+    }
+
+    /// Sets a pin's raw value without telling the firmware.
+    pub fn set_pin(&mut self, pin: i32, value: bool) -> anyhow::Result<()> {
+        let pins = &mut self.engine.host_mut().pins;
+        match usize::try_from(pin).ok().and_then(|i| pins.get_mut(i)) {
+            Some(p) => {
+                *p = value;
+                Ok(())
+            }
+            None => anyhow::bail!("no such pin {pin}"),
+        }
+    }
+
     pub fn send_pin_watch_event(&mut self, pin: i32) -> anyhow::Result<()> {
-        self.funcs
-            .js_send_pin_watch_event
-            .call(&mut self.store, pin)
+        self.engine.call(Export::SendPinWatchEvent, &[pin])?;
+        Ok(())
+    }
+
+    /// Applies the parts of a device profile that the emulator handles itself,
+    /// rather than the firmware.
+    pub fn set_profile(&mut self, profile: &DeviceProfile) {
+        self.touch = TouchTracker::new(profile.gestures);
+        self.touch_mode = profile.touch_mode;
+        self.clock().set_drift_ppm(profile.clock_drift_ppm);
+        self.screen_spec = profile.screen_spec();
+        if profile.board != Board::Banglejs2 {
+            let pins = &mut self.engine.host_mut().pins;
+            pins.fill(false);
+            for &pin in profile.board.buttons() {
+                pins[pin as usize] = profile.board.buttons_active_low();
+            }
+        }
+        self.buttons = profile.board.buttons();
+        self.buttons_active_low = profile.board.buttons_active_low();
+        self.charge_pin = profile.board.charge_pin();
+    }
+
+    /// The charge-detect pin and whether it's active low, if the board has one.
+    pub fn charge_pin(&self) -> Option<(i32, bool)> {
+        self.charge_pin
+    }
+
+    /// Attaches a simulated peripheral, which sees the firmware's pin and I2C
+    /// accesses from then on.
+    pub fn add_peripheral(&mut self, peripheral: Box<dyn Peripheral>) {
+        self.engine.host_mut().peripherals.push(peripheral);
+    }
+
+    /// How many buttons the device has.
+    pub fn button_count(&self) -> usize {
+        self.buttons.len()
+    }
+
+    /// The size and format of the screen the firmware draws to.
+    pub fn screen_spec(&self) -> ScreenSpec {
+        self.screen_spec
+    }
+
+    /// Switches where gestures come from, abandoning any touch in progress.
+    pub fn set_touch_mode(&mut self, mode: TouchMode) {
+        self.touch = TouchTracker::new(self.touch.thresholds());
+        self.touch_mode = mode;
     }
 
     pub fn send_touch(&mut self, x: u8, y: u8, on: bool) -> anyhow::Result<()> {
-        for gesture in self.touch.add_touch((x, y), on) {
-            self.funcs.js_send_touch_event.call(
-                &mut self.store,
-                (x as i32, y as i32, on as i32, gesture as i32),
-            )?;
+        self.send_touch_with_pressure(x, y, on, DEFAULT_TOUCH_PRESSURE)
+    }
+
+    /// Sends a touch with a pressure from 0 to 255. Only firmware builds that
+    /// export `jsSendTouchEventPressure` get the pressure; it's dropped for
+    /// the rest, since the real controller doesn't measure it.
+    pub fn send_touch_with_pressure(
+        &mut self,
+        x: u8,
+        y: u8,
+        on: bool,
+        pressure: u8,
+    ) -> anyhow::Result<()> {
+        let gestures = match self.touch_mode {
+            TouchMode::Gestures => self.touch.add_touch((x, y), on),
+            TouchMode::Raw => vec![Gesture::Drag],
+        };
+        let pressure = if on { pressure } else { 0 };
+        if !self.engine.has_export(Export::SendTouchEvent) {
+            debug!("ignoring touch on a device without a touchscreen");
+            return Ok(());
+        }
+        if self.touch_mode == TouchMode::Gestures {
+            let recognized = gestures.iter().filter(|&&g| g != Gesture::Drag);
+            self.gestures.extend(recognized);
+        }
+        let with_pressure = self.engine.has_export(Export::SendTouchEventPressure);
+        for gesture in gestures {
+            let args = [
+                x as i32,
+                y as i32,
+                on as i32,
+                gesture as i32,
+                pressure.into(),
+            ];
+            if with_pressure {
+                self.engine.call(Export::SendTouchEventPressure, &args)?;
+            } else {
+                self.engine.call(Export::SendTouchEvent, &args[..4])?;
+            }
         }
         Ok(())
     }
 
-    pub fn press_button(&mut self, on: bool) -> anyhow::Result<()> {
-        // Pin values are expected to be inverted.
-        self.store.data_mut().pins[BTN1 as usize] = !on;
-        self.send_pin_watch_event(BTN1)
+    /// Presses or releases a button, numbered from 1 like `BTN1`.
+    pub fn press_button(&mut self, button: usize, on: bool) -> anyhow::Result<()> {
+        let Some(&pin) = button.checked_sub(1).and_then(|i| self.buttons.get(i)) else {
+            anyhow::bail!("no such button {button}");
+        };
+        self.engine.host_mut().pins[pin as usize] = on != self.buttons_active_low;
+        self.send_pin_watch_event(pin)
     }
 
     pub fn flags(&self) -> Flags {
         self.flags.clone()
     }
+
+    pub fn clock(&self) -> Clock {
+        self.engine.host().clock.clone()
+    }
+
+    /// Replaces the firmware's clock. The new clock takes on the old one's
+    /// drift, since that belongs to the device rather than the clock.
+    pub fn set_clock(&mut self, clock: Clock) {
+        clock.set_drift_ppm(self.clock().drift_ppm());
+        self.engine.host_mut().clock = clock;
+    }
 }
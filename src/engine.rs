@@ -0,0 +1,107 @@
+use crate::emu::{Device, Host};
+
+/// A value passed to or from the firmware, with floats kept as their bits the
+/// way WebAssembly stores them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Val {
+    I32(i32),
+    I64(i64),
+    F32(u32),
+    F64(u64),
+}
+
+/// The functions the firmware exports that the emulator calls.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Export {
+    /// `jsInit()`
+    Init,
+    /// `jsIdle() -> i32`
+    Idle,
+    /// `jsGfxChanged() -> i32`
+    GfxChanged,
+    /// `jsGfxGetPtr(row: i32) -> i32`
+    GfxGetPtr,
+    /// `jshPushIOCharEvent(device: i32, ch: i32)`
+    PushChar,
+    /// `jsfResetStorage()`
+    ResetStorage,
+    /// `jsSendPinWatchEvent(pin: i32)`
+    SendPinWatchEvent,
+    /// `jsSendTouchEvent(x: i32, y: i32, on: i32, gesture: i32)`, only in
+    /// firmware builds for boards with a touchscreen.
+    SendTouchEvent,
+    /// `jsSendTouchEventPressure(x: i32, y: i32, on: i32, gesture: i32,
+    /// pressure: i32)`, only in firmware builds that can report touch
+    /// pressure to apps.
+    SendTouchEventPressure,
+    /// `jshGetDeviceToTransmit() -> i32`
+    GetDeviceToTransmit,
+    /// `jshGetCharToTransmit(device: i32) -> i32`
+    GetCharToTransmit,
+}
+
+impl Export {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Init => "jsInit",
+            Self::Idle => "jsIdle",
+            Self::GfxChanged => "jsGfxChanged",
+            Self::GfxGetPtr => "jsGfxGetPtr",
+            Self::PushChar => "jshPushIOCharEvent",
+            Self::ResetStorage => "jsfResetStorage",
+            Self::SendPinWatchEvent => "jsSendPinWatchEvent",
+            Self::SendTouchEvent => "jsSendTouchEvent",
+            Self::SendTouchEventPressure => "jsSendTouchEventPressure",
+            Self::GetDeviceToTransmit => "jshGetDeviceToTransmit",
+            Self::GetCharToTransmit => "jshGetCharToTransmit",
+        }
+    }
+}
+
+/// A WebAssembly runtime running the firmware for an [`Emulator`]. The engine
+/// owns the emulator's [`Host`], and answers the firmware's calls to the host
+/// functions it imports with the host's methods; the native build runs it
+/// under wasmtime ([`WasmtimeEngine`]), and a browser build could use the
+/// browser's own WebAssembly support.
+///
+/// [`Emulator`]: crate::emu::Emulator
+/// [`WasmtimeEngine`]: crate::wasmtime_engine::WasmtimeEngine
+pub trait Engine: Send {
+    fn host(&self) -> &Host;
+    fn host_mut(&mut self) -> &mut Host;
+
+    /// Whether the firmware has an export, for the ones only some builds do.
+    fn has_export(&self, export: Export) -> bool;
+    /// Calls one of the firmware's exports, returning its result, or 0 for
+    /// the ones without one.
+    fn call(&mut self, export: Export, args: &[i32]) -> anyhow::Result<i32>;
+
+    /// The firmware's memory.
+    fn memory(&self) -> &[u8];
+    /// Replaces the firmware's memory, growing it if need be and zeroing
+    /// anything past the end of `data`.
+    fn set_memory(&mut self, data: &[u8]) -> anyhow::Result<()>;
+    /// The values of the firmware's exported mutable globals.
+    fn globals(&mut self) -> Vec<(String, Val)>;
+    fn set_global(&mut self, name: &str, value: Val) -> anyhow::Result<()>;
+}
+
+/// Moves the output the firmware has waiting to go out onto `char_q`, calling
+/// its exports through `call`. This is what the firmware's `jsHandleIO` import
+/// does, as well as the emulator collecting its output.
+pub fn transmit(
+    char_q: &mut Vec<(Device, u8)>,
+    mut call: impl FnMut(Export, &[i32]) -> anyhow::Result<i32>,
+) -> anyhow::Result<()> {
+    loop {
+        let device = call(Export::GetDeviceToTransmit, &[])?;
+        if device == 0 {
+            return Ok(());
+        }
+        let ch = call(Export::GetCharToTransmit, &[device])?;
+        let Ok(ch) = ch.try_into() else {
+            return Ok(());
+        };
+        char_q.push((Device(device), ch));
+    }
+}
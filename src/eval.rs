@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+
+use base64::{engine::general_purpose, Engine};
+use log::{debug, error};
+use serde_json::Value;
+use tokio::sync::{mpsc::Sender, oneshot};
+
+use crate::emu::{Device, Emulator, Input};
+
+pub type EvalResult = Result<Value, String>;
+
+// Results are printed by the firmware wrapped in these markers, which are
+// stripped from the console output before anything else sees it.
+const START: &[u8] = b"\x02eval:";
+const END: u8 = b'\x03';
+
+/// Builds the console line that evaluates `expr` and prints its JSON-encoded
+/// result tagged with `id`. The leading DLE suppresses echo of the line.
+fn command(id: u64, expr: &str) -> Vec<u8> {
+    let expr = general_purpose::STANDARD.encode(expr.as_bytes());
+    format!(
+        "\x10(function(){{var r;try{{r={{ok:eval(atob('{expr}'))}};}}catch(e){{r={{err:''+e}};}}\
+         print('\\x02eval:{id}:'+btoa(JSON.stringify(r))+'\\x03');}})();\n"
+    )
+    .into_bytes()
+}
+
+fn parse_result(payload: &[u8]) -> anyhow::Result<(u64, EvalResult)> {
+    let payload = std::str::from_utf8(payload)?;
+    let (id, data) = payload
+        .split_once(':')
+        .ok_or_else(|| anyhow::format_err!("missing eval id"))?;
+    let data = general_purpose::STANDARD.decode(data)?;
+    let mut value: Value = serde_json::from_slice(&data)?;
+    let result = match value.get_mut("err") {
+        Some(Value::String(err)) => Err(std::mem::take(err)),
+        _ => Ok(value.get_mut("ok").map(Value::take).unwrap_or_default()),
+    };
+    Ok((id.parse()?, result))
+}
+
+#[derive(Default)]
+struct DeviceFilter {
+    partial: Vec<u8>,
+    strip_newline: bool,
+}
+
+/// Tracks outstanding hidden evaluations and extracts their results from the
+/// console output.
+#[derive(Default)]
+pub struct EvalChannel {
+    next_id: u64,
+    pending: HashMap<u64, oneshot::Sender<EvalResult>>,
+    filters: HashMap<Device, DeviceFilter>,
+}
+
+impl EvalChannel {
+    /// Registers an evaluation and returns the console input that runs it.
+    pub fn request(&mut self, expr: &str, reply: oneshot::Sender<EvalResult>) -> Vec<u8> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.insert(id, reply);
+        command(id, expr)
+    }
+
+    /// Whether any evaluations are still waiting for their results.
+    pub fn has_pending(&self) -> bool {
+        self.pending.values().any(|reply| !reply.is_closed())
+    }
+
+    /// Removes eval results from console output, delivering them to their
+    /// requesters, and returns what remains to be shown to the user.
+    pub fn filter(&mut self, device: Device, data: Vec<u8>) -> Vec<u8> {
+        // Requesters that gave up, such as on timing out, won't take their
+        // results, and would otherwise keep the output being scanned forever.
+        self.pending.retain(|_, reply| !reply.is_closed());
+        if self.pending.is_empty() && !self.filters.contains_key(&device) {
+            return data;
+        }
+
+        let filter = self.filters.entry(device).or_default();
+        let mut buf = std::mem::take(&mut filter.partial);
+        buf.extend(data);
+        let mut out = vec![];
+        let mut rest = &buf[..];
+
+        loop {
+            if filter.strip_newline {
+                if let Some(r) = rest.strip_prefix(b"\r") {
+                    rest = r;
+                }
+                if let Some(r) = rest.strip_prefix(b"\n") {
+                    rest = r;
+                    filter.strip_newline = false;
+                } else if !rest.is_empty() {
+                    filter.strip_newline = false;
+                }
+            }
+
+            match rest.windows(START.len()).position(|w| w == START) {
+                Some(start) => {
+                    out.extend(&rest[..start]);
+                    let body = &rest[start + START.len()..];
+                    match body.iter().position(|&b| b == END) {
+                        Some(end) => {
+                            match parse_result(&body[..end]) {
+                                Ok((id, result)) => {
+                                    debug!("eval {id} finished: {result:?}");
+                                    if let Some(reply) = self.pending.remove(&id) {
+                                        let _ = reply.send(result);
+                                    }
+                                }
+                                Err(err) => error!("malformed eval result: {err}"),
+                            }
+                            filter.strip_newline = true;
+                            rest = &body[end + 1..];
+                        }
+                        None => {
+                            filter.partial = rest[start..].to_vec();
+                            break;
+                        }
+                    }
+                }
+                None => {
+                    // Hold back anything that might be the beginning of a
+                    // marker split across reads.
+                    let keep = (1..START.len())
+                        .rev()
+                        .find(|&n| rest.ends_with(&START[..n]))
+                        .unwrap_or(0);
+                    out.extend(&rest[..rest.len() - keep]);
+                    filter.partial = rest[rest.len() - keep..].to_vec();
+                    break;
+                }
+            }
+        }
+
+        if filter.partial.is_empty() && !filter.strip_newline {
+            self.filters.remove(&device);
+        }
+        out
+    }
+}
+
+/// Evaluates `expr` in the firmware without it appearing on the console,
+/// returning the JSON-converted result.
+pub async fn eval(tx: &Sender<Input>, expr: &str) -> anyhow::Result<EvalResult> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    tx.send(Input::Eval(expr.to_owned(), reply_tx))
+        .await
+        .map_err(|_| anyhow::format_err!("emulator is not running"))?;
+    Ok(reply_rx.await?)
+}
+
+/// A JavaScript expression that evaluates to the numbers of the pins the
+/// firmware has watches on (as set with `setWatch`), for checking a watch
+/// actually registered. Espruino keeps its watches in a hidden list, which has
+/// moved between versions, so both places are looked in.
+pub const WATCHED_PINS_EXPR: &str = "(function(){var h=global['\\xFF'],\
+     w=global['\\xFFwatches']||(h&&h.watches)||[];\
+     return w.filter(function(x){return x;}).map(function(x){return 0|x.pin;});})()";
+
+/// Parses the result of [`WATCHED_PINS_EXPR`] into sorted pin numbers.
+pub fn parse_watched_pins(value: Value) -> anyhow::Result<Vec<i32>> {
+    let mut pins: Vec<i32> = serde_json::from_value(value)?;
+    pins.sort_unstable();
+    pins.dedup();
+    Ok(pins)
+}
+
+/// Evaluates `expr` directly on an emulator that isn't being driven by a
+/// runner, idling it until the result arrives.
+pub fn eval_sync(emu: &mut Emulator, expr: &str) -> anyhow::Result<EvalResult> {
+    const MAX_IDLES: usize = 1000;
+
+    let mut evals = EvalChannel::default();
+    let (reply_tx, mut reply_rx) = oneshot::channel();
+    emu.push_string(evals.request(expr, reply_tx))?;
+    for _ in 0..MAX_IDLES {
+        emu.idle()?;
+        for (device, chars) in emu.handle_io()? {
+            evals.filter(device, chars);
+        }
+        if let Ok(result) = reply_rx.try_recv() {
+            return Ok(result);
+        }
+    }
+    anyhow::bail!("no eval result after {MAX_IDLES} idle calls")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn abandoned_evals_stop_being_pending() {
+        let mut evals = EvalChannel::default();
+        let (reply_tx, reply_rx) = oneshot::channel();
+        evals.request("1", reply_tx);
+        assert!(evals.has_pending());
+        drop(reply_rx);
+        assert!(!evals.has_pending());
+        assert_eq!(evals.filter(Device::CONSOLE, b"hi".to_vec()), b"hi");
+        assert!(evals.pending.is_empty());
+    }
+
+    #[test]
+    fn results_are_stripped_and_delivered() {
+        let mut evals = EvalChannel::default();
+        let (reply_tx, mut reply_rx) = oneshot::channel();
+        evals.request("1", reply_tx);
+        let result = general_purpose::STANDARD.encode(br#"{"ok":1}"#);
+        // Split partway through the marker, as reads can be.
+        let line = format!("a\x02eval:0:{result}\x03\r\nb").into_bytes();
+        let mut out = evals.filter(Device::CONSOLE, line[..5].to_vec());
+        out.extend(evals.filter(Device::CONSOLE, line[5..].to_vec()));
+        assert_eq!(out, b"ab");
+        assert_eq!(reply_rx.try_recv().unwrap(), Ok(Value::from(1)));
+        assert!(!evals.has_pending());
+    }
+}
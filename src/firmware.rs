@@ -0,0 +1,172 @@
+use std::{
+    fmt::Display,
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use log::{info, warn};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+/// The name of the firmware build for the Bangle.js 2 emulator.
+const FIRMWARE_FILE: &str = "emulator_banglejs2.wasm";
+const RELEASES_URL: &str = "https://github.com/dzhu/Espruino/releases";
+
+/// Gets the URL to download a firmware version from: `url_template` with
+/// `{version}` replaced, or by default the matching GitHub release asset.
+pub fn firmware_url(version: &str, url_template: Option<&str>) -> String {
+    match url_template {
+        Some(t) => t.replace("{version}", version),
+        None if version == "latest" => format!("{RELEASES_URL}/latest/download/{FIRMWARE_FILE}"),
+        None => format!("{RELEASES_URL}/download/{version}/{FIRMWARE_FILE}"),
+    }
+}
+
+pub fn cache_dir() -> anyhow::Result<PathBuf> {
+    let dir = dirs::cache_dir()
+        .ok_or_else(|| anyhow::format_err!("no cache directory available"))?
+        .join("banglejs-emu")
+        .join("firmware");
+    Ok(dir)
+}
+
+/// Downloads the contents of a URL, checking that they look like a
+/// WebAssembly module.
+pub fn download(url: &str) -> anyhow::Result<Vec<u8>> {
+    info!("downloading {url}");
+    let resp = ureq::get(url)
+        .call()
+        .with_context(|| format!("Failed to download {url}"))?;
+    let mut data = vec![];
+    resp.into_reader().read_to_end(&mut data)?;
+    if !data.starts_with(b"\0asm") {
+        anyhow::bail!("{url} is not a WebAssembly module");
+    }
+    Ok(data)
+}
+
+fn write_cached(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    fs::create_dir_all(path.parent().unwrap())?;
+    // Write to a temporary file first so that an interrupted download doesn't
+    // leave a truncated firmware in the cache.
+    let tmp = path.with_extension("part");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Downloads a firmware version into the local cache unless it's already
+/// there, returning its path. `latest` is always downloaded again, since it
+/// changes over time.
+pub fn fetch(version: &str, url_template: Option<&str>, force: bool) -> anyhow::Result<PathBuf> {
+    if version.contains(['/', '\\']) {
+        anyhow::bail!("invalid firmware version {version:?}");
+    }
+    let path = cache_dir()?.join(format!("{version}.wasm"));
+    if path.exists() && !force && version != "latest" {
+        info!("using cached firmware {path:?}");
+        return Ok(path);
+    }
+
+    let data = download(&firmware_url(version, url_template))?;
+    write_cached(&path, &data).with_context(|| format!("Failed to write {path:?}"))?;
+    Ok(path)
+}
+
+/// Formats the SHA-256 of some data as lowercase hex.
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Downloads a firmware from a URL into the cache, returning its path. A URL
+/// ending in `#sha256=<hex>` pins the firmware's checksum: the download has to
+/// match it, and once it's cached it's never downloaded again. Other URLs are
+/// downloaded every time, since what they point to can change, with the
+/// cached copy used if that fails.
+fn fetch_url(url: &str) -> anyhow::Result<PathBuf> {
+    let (url, expected) = match url.split_once("#sha256=") {
+        Some((url, hex)) => {
+            let hex = hex.to_ascii_lowercase();
+            if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+                anyhow::bail!("invalid SHA-256 {hex:?} in firmware URL");
+            }
+            (url, Some(hex))
+        }
+        None => (url, None),
+    };
+    let name = match &expected {
+        Some(hex) => format!("sha256-{hex}.wasm"),
+        None => format!("url-{}.wasm", &sha256_hex(url.as_bytes())[..16]),
+    };
+    let path = cache_dir()?.join(name);
+
+    if let Some(hex) = &expected {
+        if fs::read(&path).is_ok_and(|data| sha256_hex(&data) == *hex) {
+            info!("using cached firmware {path:?}");
+            return Ok(path);
+        }
+    }
+    let data = match download(url) {
+        Ok(data) => data,
+        Err(err) if expected.is_none() && path.exists() => {
+            warn!("{err:#}; using cached firmware {path:?}");
+            return Ok(path);
+        }
+        Err(err) => return Err(err),
+    };
+    let actual = sha256_hex(&data);
+    if let Some(hex) = &expected {
+        if actual != *hex {
+            anyhow::bail!("{url} has SHA-256 {actual}, not the expected {hex}");
+        }
+    }
+    info!("downloaded {url} with SHA-256 {actual}");
+    write_cached(&path, &data).with_context(|| format!("Failed to write {path:?}"))?;
+    Ok(path)
+}
+
+/// Gets the local path of a firmware given either as a path or as an
+/// `http://` or `https://` URL, which is downloaded into the cache.
+pub fn resolve(path: &Path) -> anyhow::Result<PathBuf> {
+    match path.to_str() {
+        Some(url) if url.starts_with("http://") || url.starts_with("https://") => fetch_url(url),
+        _ => Ok(path.to_owned()),
+    }
+}
+
+/// The expression evaluated to get [`FirmwareInfo`].
+pub const INFO_EXPR: &str = "process.env";
+
+/// Identifying details of a running firmware, from `process.env`.
+#[derive(Clone, Debug, Default)]
+pub struct FirmwareInfo {
+    pub version: String,
+    pub board: String,
+    pub git_commit: String,
+}
+
+impl FirmwareInfo {
+    pub fn from_env(env: &Value) -> Self {
+        let field = |name: &str| env[name].as_str().unwrap_or("?").to_owned();
+        Self {
+            version: field("VERSION"),
+            board: field("BOARD"),
+            git_commit: field("GIT_COMMIT"),
+        }
+    }
+}
+
+impl Display for FirmwareInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Espruino {} ({}, commit {})",
+            self.version, self.board, self.git_commit
+        )
+    }
+}
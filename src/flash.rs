@@ -0,0 +1,41 @@
+use std::{fs, io::Read, path::Path};
+
+use anyhow::Context;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+/// Flash images are mostly erased bytes, so even fast compression shrinks them
+/// enormously.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Decompresses a flash image if it's compressed with gzip or zstd, detected
+/// from its contents, and otherwise returns it unchanged.
+pub fn decompress(data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    if data.starts_with(GZIP_MAGIC) {
+        let mut out = vec![];
+        GzDecoder::new(&data[..])
+            .read_to_end(&mut out)
+            .context("Failed to decompress gzip flash image")?;
+        Ok(out)
+    } else if data.starts_with(ZSTD_MAGIC) {
+        zstd::decode_all(&data[..]).context("Failed to decompress zstd flash image")
+    } else {
+        Ok(data)
+    }
+}
+
+/// Writes a flash image as raw binary, compressing it if the path ends in `.gz`
+/// or `.zst`.
+pub fn write_image(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let data = match path.extension().and_then(|e| e.to_str()) {
+        Some("gz") => {
+            let mut encoder = GzEncoder::new(vec![], Compression::default());
+            std::io::Write::write_all(&mut encoder, data)?;
+            encoder.finish()?
+        }
+        Some("zst") => zstd::encode_all(data, ZSTD_LEVEL)?,
+        _ => data.to_vec(),
+    };
+    fs::write(path, data).with_context(|| format!("Failed to write {path:?}"))
+}
@@ -0,0 +1,202 @@
+//! Support for fuzzing the emulator with arbitrary console input and touch and
+//! button sequences, and for checking gesture recognition against generated
+//! touch traces; see the `fuzz` directory for the `cargo fuzz` targets.
+
+use crate::{
+    emu::{Clock, Emulator, BTN1},
+    pool::EmulatorPool,
+    touch::{Gesture, GestureThresholds, TouchTracker},
+};
+
+/// The environment variable giving the firmware to fuzz.
+pub const WASM_PATH_VAR: &str = "BANGLEJS_EMU_FUZZ_WASM";
+
+#[derive(Debug)]
+enum Action {
+    Console(Vec<u8>),
+    Touch(u8, u8, bool),
+    Button(bool),
+    /// Move virtual time forward by this many milliseconds and idle.
+    Idle(u8),
+}
+
+/// Decodes fuzzer input into actions: each starts with an opcode byte followed
+/// by its arguments, and a truncated action at the end is dropped.
+fn decode(mut data: &[u8]) -> Vec<Action> {
+    let mut actions = vec![];
+    while let Some((&op, rest)) = data.split_first() {
+        data = rest;
+        let action = match op % 4 {
+            0 => {
+                let Some((&len, rest)) = data.split_first() else {
+                    break;
+                };
+                let len = (len as usize).min(rest.len());
+                let (chars, rest) = rest.split_at(len);
+                data = rest;
+                Action::Console(chars.to_vec())
+            }
+            1 => {
+                let [x, y, on, ..] = *data else {
+                    break;
+                };
+                data = &data[3..];
+                Action::Touch(x.min(175), y.min(175), on & 1 != 0)
+            }
+            2 => Action::Button(op & 4 != 0),
+            _ => Action::Idle(op >> 2),
+        };
+        actions.push(action);
+    }
+    actions
+}
+
+fn apply(emu: &mut Emulator, clock: &Clock, action: &Action) -> anyhow::Result<()> {
+    match action {
+        Action::Console(chars) => emu.push_string(chars)?,
+        Action::Touch(x, y, on) => emu.send_touch(*x, *y, *on)?,
+        Action::Button(on) => emu.press_button(1, *on)?,
+        Action::Idle(ms) => {
+            clock.advance(f64::from(*ms));
+            emu.idle()?;
+        }
+    }
+    emu.handle_io()?;
+    Ok(())
+}
+
+/// Runs one fuzzer input against a fresh emulator on a virtual clock. Traps
+/// and other errors from the firmware are turned into panics, so that the
+/// fuzzer records them as crashes.
+pub fn run_one(pool: &EmulatorPool, data: &[u8]) {
+    let actions = decode(data);
+
+    let mut emu = pool.instantiate().expect("failed to instantiate emulator");
+    let clock = Clock::stopped_at(0.0);
+    emu.set_clock(clock.clone());
+    emu.init().expect("failed to initialize emulator");
+    emu.send_pin_watch_event(BTN1).unwrap();
+
+    for action in &actions {
+        if let Err(err) = apply(&mut emu, &clock, action) {
+            panic!("{action:?} failed: {err:?}");
+        }
+    }
+}
+
+/// The furthest a finger moves along each axis between two touch samples.
+const MAX_STEP: i16 = 12;
+/// The most samples in one stroke.
+const MAX_STROKE_LEN: u8 = 40;
+
+/// A source of choices for generating inputs from fuzzer data.
+struct Entropy<'a>(&'a [u8]);
+
+impl Entropy<'_> {
+    fn byte(&mut self) -> Option<u8> {
+        let (&b, rest) = self.0.split_first()?;
+        self.0 = rest;
+        Some(b)
+    }
+
+    /// Picks a value in `-max..=max`.
+    fn step(&mut self, max: i16) -> Option<i16> {
+        Some(i16::from(self.byte()?) % (2 * max + 1) - max)
+    }
+}
+
+/// A single touch sample: position and whether the screen is being touched.
+pub type TouchSample = (u8, u8, bool);
+
+/// Generates a physically plausible touch trace from fuzzer data: a series of
+/// strokes, each starting anywhere on the screen and moving in small steps
+/// like a finger between touch controller samples before lifting, with the
+/// occasional stray release while nothing is touching.
+pub fn touch_trace(data: &[u8]) -> Vec<TouchSample> {
+    let mut entropy = Entropy(data);
+    let mut trace = vec![];
+    while let (Some(x), Some(y), Some(len)) = (entropy.byte(), entropy.byte(), entropy.byte()) {
+        let (mut x, mut y) = (x.min(175), y.min(175));
+        if len & 0x80 != 0 {
+            trace.push((x, y, false));
+        }
+        trace.push((x, y, true));
+        for _ in 0..len % MAX_STROKE_LEN {
+            let (Some(dx), Some(dy)) = (entropy.step(MAX_STEP), entropy.step(MAX_STEP)) else {
+                break;
+            };
+            x = (i16::from(x) + dx).clamp(0, 175) as u8;
+            y = (i16::from(y) + dy).clamp(0, 175) as u8;
+            trace.push((x, y, true));
+        }
+        trace.push((x, y, false));
+    }
+    trace
+}
+
+/// Feeds a touch trace through a [`TouchTracker`] with the given thresholds and
+/// checks the gestures it reports against the invariants the firmware relies
+/// on:
+///
+/// - every sample while touching reports a drag, and nothing else until the
+///   release;
+/// - a release reports a drag first, followed by at most one tap or swipe;
+/// - a release with nothing touching reports nothing;
+/// - a swipe's direction matches how the stroke moved overall, and a tap
+///   only comes from a stroke that ended near where it started.
+pub fn check_gestures(trace: &[TouchSample], thresholds: GestureThresholds) -> Result<(), String> {
+    let mut tracker = TouchTracker::new(thresholds);
+    let near = |a: u8, b: u8| u64::from(a.abs_diff(b)) < thresholds.tap_max;
+    let mut start = (0, 0);
+    for (ind, &(x, y, on)) in trace.iter().enumerate() {
+        let was_touching = tracker.is_touching();
+        if on && !was_touching {
+            start = (x, y);
+        }
+        let gestures = tracker.add_touch((x, y), on);
+        let fail = |what: &str| {
+            Err(format!(
+                "sample {ind} ({x}, {y}, {on}): {what}: {gestures:?}"
+            ))
+        };
+
+        if !on && !was_touching {
+            if !gestures.is_empty() {
+                return fail("gestures reported without a touch");
+            }
+            continue;
+        }
+        if gestures.first() != Some(&Gesture::Drag) {
+            return fail("no leading drag");
+        }
+        if on {
+            if gestures.len() != 1 {
+                return fail("gestures reported before release");
+            }
+            continue;
+        }
+
+        match gestures[1..] {
+            [] => {}
+            [Gesture::Touch] if near(x, start.0) && near(y, start.1) => {}
+            [Gesture::Right] if x > start.0 => {}
+            [Gesture::Left] if x <= start.0 => {}
+            [Gesture::Down] if y > start.1 => {}
+            [Gesture::Up] if y <= start.1 => {}
+            _ => return fail("inconsistent gestures on release"),
+        }
+        if tracker.is_touching() {
+            return fail("still touching after release");
+        }
+    }
+    Ok(())
+}
+
+/// Checks gesture recognition on the touch trace generated from one fuzzer
+/// input, panicking if any invariant is broken.
+pub fn run_gestures(data: &[u8]) {
+    let trace = touch_trace(data);
+    if let Err(err) = check_gestures(&trace, GestureThresholds::default()) {
+        panic!("{err}\ntrace: {trace:?}");
+    }
+}
@@ -0,0 +1,54 @@
+/// The window and lookahead sizes, as powers of two, that Espruino builds
+/// heatshrink with, and so that the App Loader compresses files with.
+const WINDOW_BITS: u32 = 8;
+const LOOKAHEAD_BITS: u32 = 4;
+
+/// Reads a stream of bits, most significant first.
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Bits<'_> {
+    /// Reads `n` bits, or `None` if the data runs out first, as it does in the
+    /// padding at the end.
+    fn read(&mut self, n: u32) -> Option<usize> {
+        let mut val = 0;
+        for _ in 0..n {
+            let byte = *self.data.get(self.pos / 8)?;
+            val = val << 1 | usize::from(byte >> (7 - self.pos % 8) & 1);
+            self.pos += 1;
+        }
+        Some(val)
+    }
+}
+
+/// Decompresses heatshrink data, as `require("heatshrink").decompress` does on
+/// the watch. Back-references to before the start of the output read zeros,
+/// as they do from the firmware's zeroed window.
+pub fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut bits = Bits { data, pos: 0 };
+    let mut out = vec![];
+    while let Some(tag) = bits.read(1) {
+        if tag == 1 {
+            let Some(byte) = bits.read(8) else { break };
+            out.push(byte as u8);
+        } else {
+            let Some(index) = bits.read(WINDOW_BITS) else {
+                break;
+            };
+            let Some(count) = bits.read(LOOKAHEAD_BITS) else {
+                break;
+            };
+            let offset = index + 1;
+            for _ in 0..=count {
+                let byte = match out.len().checked_sub(offset) {
+                    Some(i) => out[i],
+                    None => 0,
+                };
+                out.push(byte);
+            }
+        }
+    }
+    out
+}
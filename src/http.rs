@@ -0,0 +1,210 @@
+use std::fmt::Debug;
+
+use anyhow::Context;
+use log::{debug, error, info};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    select,
+    sync::{broadcast::Receiver, watch},
+};
+
+use crate::{
+    emu::{IdleStats, ScreenWatch},
+    screenshot::encode_png,
+};
+
+const INDEX: &str = r#"<!DOCTYPE html>
+<html>
+<head><title>Bangle.js 2 emulator</title></head>
+<body style="background: #444">
+<img src="/stream" width="352" height="352" style="image-rendering: pixelated">
+</body>
+</html>
+"#;
+
+const BOUNDARY: &str = "frame";
+
+async fn read_request_path(socket: &mut TcpStream) -> anyhow::Result<Option<String>> {
+    let mut buf = vec![];
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend(&chunk[..n]);
+        if buf.len() > 16 * 1024 {
+            anyhow::bail!("request header too large");
+        }
+    }
+
+    let head = String::from_utf8_lossy(&buf);
+    let mut parts = head.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => Ok(Some(path.to_owned())),
+        _ => Ok(None),
+    }
+}
+
+async fn write_response(
+    socket: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> anyhow::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(body).await?;
+    Ok(())
+}
+
+async fn stream_screen(socket: &mut TcpStream, mut screens: ScreenWatch) -> anyhow::Result<()> {
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary={BOUNDARY}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"
+    );
+    socket.write_all(head.as_bytes()).await?;
+
+    loop {
+        // Only the latest frame is sent; frames produced while a previous one
+        // was being written are skipped.
+        let screen = screens.borrow_and_update().clone();
+        if let Some(screen) = screen {
+            let png = encode_png(&screen)?;
+            let part = format!(
+                "--{BOUNDARY}\r\nContent-Type: image/png\r\nContent-Length: {}\r\n\r\n",
+                png.len()
+            );
+            socket.write_all(part.as_bytes()).await?;
+            socket.write_all(&png).await?;
+            socket.write_all(b"\r\n").await?;
+        }
+        if screens.changed().await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Streams a line of JSON for each screen update, with the frame's number and
+/// the hash of its contents (as `region-hash` gives it), so a client can tell
+/// when the screen has settled without fetching any images.
+async fn stream_frames(socket: &mut TcpStream, mut screens: ScreenWatch) -> anyhow::Result<()> {
+    let head = "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n";
+    socket.write_all(head.as_bytes()).await?;
+
+    loop {
+        let screen = screens.borrow_and_update().clone();
+        if let Some(screen) = screen {
+            let line = format!(
+                "{{\"frame\":{},\"hash\":\"{:016x}\"}}\n",
+                screen.frame(),
+                screen.hash()
+            );
+            socket.write_all(line.as_bytes()).await?;
+        }
+        if screens.changed().await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Formats idle statistics for Prometheus.
+fn metrics(stats: &IdleStats) -> String {
+    format!(
+        "# TYPE banglejs_idle_calls_total counter\n\
+         banglejs_idle_calls_total {}\n\
+         # TYPE banglejs_idle_seconds_total counter\n\
+         banglejs_idle_seconds_total {}\n\
+         # TYPE banglejs_idle_max_seconds gauge\n\
+         banglejs_idle_max_seconds {}\n\
+         # TYPE banglejs_idle_bursts_total counter\n\
+         banglejs_idle_bursts_total {}\n\
+         # TYPE banglejs_idle_busy_bursts_total counter\n\
+         banglejs_idle_busy_bursts_total {}\n",
+        stats.calls,
+        stats.total.as_secs_f64(),
+        stats.max.as_secs_f64(),
+        stats.bursts,
+        stats.busy_bursts
+    )
+}
+
+async fn handle_connection(
+    mut socket: TcpStream,
+    screens: ScreenWatch,
+    idle_stats: watch::Receiver<Option<IdleStats>>,
+) -> anyhow::Result<()> {
+    let path = match read_request_path(&mut socket).await? {
+        Some(path) => path,
+        None => {
+            return write_response(&mut socket, "400 Bad Request", "text/plain", b"").await;
+        }
+    };
+    debug!("http request: {path}");
+
+    // Browsers add queries (usually to get around caching) and fragments that
+    // don't change what's served.
+    let route = path.split(['?', '#']).next().unwrap_or_default();
+    match route {
+        "/" => write_response(&mut socket, "200 OK", "text/html", INDEX.as_bytes()).await,
+        "/screen.png" => {
+            let screen = screens.borrow().clone();
+            match screen {
+                Some(screen) => {
+                    let png = encode_png(&screen)?;
+                    write_response(&mut socket, "200 OK", "image/png", &png).await
+                }
+                None => {
+                    write_response(&mut socket, "503 Service Unavailable", "text/plain", b"").await
+                }
+            }
+        }
+        "/stream" => stream_screen(&mut socket, screens).await,
+        "/frames" => stream_frames(&mut socket, screens).await,
+        "/metrics" => {
+            let stats = idle_stats.borrow().clone().unwrap_or_default();
+            let body = metrics(&stats);
+            write_response(&mut socket, "200 OK", "text/plain", body.as_bytes()).await
+        }
+        _ => write_response(&mut socket, "404 Not Found", "text/plain", b"").await,
+    }
+}
+
+/// Serves the emulated screen over HTTP: `/screen.png` is the current frame,
+/// `/stream` is a `multipart/x-mixed-replace` stream of PNG frames (viewable
+/// directly in a browser or as an MJPEG-style source), and `/` is a page
+/// embedding the stream. `/frames` streams the number and hash of each frame
+/// as lines of JSON. `/metrics` has the firmware's idle statistics in
+/// Prometheus's text format.
+pub async fn run_http(
+    bind: impl ToSocketAddrs + Debug,
+    screens: ScreenWatch,
+    idle_stats: watch::Receiver<Option<IdleStats>>,
+    mut quit: Receiver<()>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(&bind)
+        .await
+        .with_context(|| format!("Failed to bind {bind:?}"))?;
+
+    loop {
+        select! {
+            _ = quit.recv() => break,
+            conn = listener.accept() => {
+                let (socket, addr) = conn?;
+                info!("http connection from {addr}");
+                let screens = screens.clone();
+                let idle_stats = idle_stats.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = handle_connection(socket, screens, idle_stats).await {
+                        error!("http connection from {addr} failed: {err}");
+                    }
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
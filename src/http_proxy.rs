@@ -0,0 +1,219 @@
+use std::{io::Read, time::Duration};
+
+use anyhow::Context;
+use log::{info, warn};
+use serde_derive::Deserialize;
+use serde_json::{json, Map, Value};
+use tokio::sync::mpsc::WeakSender;
+use url::Url;
+
+use crate::{
+    device::gb_message_line,
+    emu::{Device, Input},
+};
+
+/// The largest response body passed on to the watch, which has to hold it in
+/// its small heap.
+const BODY_LIMIT: u64 = 64 << 10;
+
+/// Settings for answering the watch's requests to Gadgetbridge's HTTP proxy
+/// (what `Bangle.http` sends) by making them from the host.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct HttpProxyConfig {
+    /// The URLs the watch may fetch under, such as
+    /// `"https://api.open-meteo.com/v1"`: the scheme, host, and port have to
+    /// match exactly, and the path has to start with the same segments.
+    /// Requests for anything else get an error back, without going out.
+    pub allow: Vec<String>,
+    /// How long to wait for a response, in seconds.
+    pub timeout: f64,
+}
+
+impl Default for HttpProxyConfig {
+    fn default() -> Self {
+        Self {
+            allow: vec![],
+            timeout: 10.0,
+        }
+    }
+}
+
+/// Makes the watch's HTTP requests from the host, as Gadgetbridge's proxy
+/// does from the phone, for the URLs the config allows.
+#[derive(Clone, Debug)]
+pub struct HttpProxy {
+    allow: Vec<Url>,
+    agent: ureq::Agent,
+}
+
+impl HttpProxy {
+    pub fn new(config: &HttpProxyConfig) -> anyhow::Result<Self> {
+        let timeout = Duration::try_from_secs_f64(config.timeout)
+            .with_context(|| format!("invalid timeout {}", config.timeout))?;
+        let agent = ureq::AgentBuilder::new()
+            .timeout(timeout)
+            // A redirect could lead anywhere, so it's passed back as a failed
+            // request rather than followed past the allowlist.
+            .redirects(0)
+            .build();
+        let allow = config
+            .allow
+            .iter()
+            .map(|allow| {
+                let url = Url::parse(allow).with_context(|| format!("invalid URL {allow:?}"))?;
+                if url.cannot_be_a_base() || url.host().is_none() {
+                    anyhow::bail!("{allow:?} has no host");
+                }
+                if has_userinfo(&url) || url.query().is_some() || url.fragment().is_some() {
+                    anyhow::bail!("{allow:?} should only have a scheme, host, port, and path");
+                }
+                Ok(url)
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { allow, agent })
+    }
+
+    /// Whether a URL is under one of the allowed ones.
+    fn allows(&self, url: &Url) -> bool {
+        let segments = |url: &Url| -> Vec<String> {
+            // A trailing slash leaves an empty last segment, which doesn't
+            // restrict anything.
+            let mut segments: Vec<_> = url.path_segments().unwrap().map(str::to_owned).collect();
+            if segments.last().is_some_and(String::is_empty) {
+                segments.pop();
+            }
+            segments
+        };
+        !has_userinfo(url)
+            && self.allow.iter().any(|allow| {
+                url.scheme() == allow.scheme()
+                    && url.host() == allow.host()
+                    && url.port_or_known_default() == allow.port_or_known_default()
+                    && segments(url).starts_with(&segments(allow))
+            })
+    }
+
+    /// Starts making a request, sending the watch the reply on `tx` once it's
+    /// done.
+    pub fn spawn(&self, request: Map<String, Value>, tx: WeakSender<Input>) {
+        let proxy = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut reply = match proxy.fetch(&request) {
+                Ok(body) => json!({ "t": "http", "resp": body }),
+                Err(err) => {
+                    warn!("watch's HTTP request failed: {err:#}");
+                    json!({ "t": "http", "err": format!("{err:#}") })
+                }
+            };
+            if let Some(id) = request.get("id") {
+                reply["id"] = id.clone();
+            }
+            let line = gb_message_line(&reply);
+            if let Some(tx) = tx.upgrade() {
+                let _ = tx.blocking_send(Input::Console(Device::CONSOLE, line));
+            }
+        });
+    }
+
+    /// Makes a request given in the form `Bangle.http` sends it, returning
+    /// the body of the response.
+    fn fetch(&self, request: &Map<String, Value>) -> anyhow::Result<String> {
+        let field = |name| request.get(name).and_then(Value::as_str);
+        let Some(url) = field("url") else {
+            anyhow::bail!("no URL given");
+        };
+        let url = Url::parse(url).with_context(|| format!("invalid URL {url:?}"))?;
+        if url.cannot_be_a_base() || !self.allows(&url) {
+            anyhow::bail!("URL not allowed: {url}");
+        }
+        if request.contains_key("xpath") {
+            anyhow::bail!("XPath queries aren't supported");
+        }
+        let method = field("method").unwrap_or("GET").to_uppercase();
+        info!("fetching {method} {url} for the watch");
+        let mut req = self.agent.request_url(&method, &url);
+        if let Some(headers) = request.get("headers").and_then(Value::as_object) {
+            for (name, value) in headers {
+                let value = match value {
+                    Value::String(s) => s.clone(),
+                    value => value.to_string(),
+                };
+                req = req.set(name, &value);
+            }
+        }
+        let resp = match field("body") {
+            Some(body) => req.send_string(body),
+            None => req.call(),
+        };
+        // Error statuses come back as errors, but redirects, which aren't
+        // followed, don't.
+        let resp = match resp {
+            Ok(resp) if resp.status() < 300 => resp,
+            Ok(resp) | Err(ureq::Error::Status(_, resp)) => {
+                anyhow::bail!("{url} returned {} {}", resp.status(), resp.status_text())
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let mut body = vec![];
+        resp.into_reader()
+            .take(BODY_LIMIT + 1)
+            .read_to_end(&mut body)
+            .with_context(|| format!("Failed to read the response from {url}"))?;
+        if body.len() as u64 > BODY_LIMIT {
+            anyhow::bail!("the response from {url} is over {BODY_LIMIT} bytes");
+        }
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+}
+
+fn has_userinfo(url: &Url) -> bool {
+    !url.username().is_empty() || url.password().is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allows(allow: &str, url: &str) -> bool {
+        let proxy = HttpProxy::new(&HttpProxyConfig {
+            allow: vec![allow.to_owned()],
+            ..Default::default()
+        })
+        .unwrap();
+        proxy.allows(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn allowlist_matches_whole_hosts_and_path_segments() {
+        let allow = "https://api.open-meteo.com";
+        assert!(allows(allow, "https://api.open-meteo.com/v1/forecast?x=1"));
+        assert!(allows(allow, "https://api.open-meteo.com:443/"));
+        assert!(!allows(allow, "https://api.open-meteo.com.evil.example/"));
+        assert!(!allows(allow, "https://api.open-meteo.com@evil.example/"));
+        assert!(!allows(allow, "https://user@api.open-meteo.com/"));
+        assert!(!allows(allow, "http://api.open-meteo.com/"));
+        assert!(!allows(allow, "https://api.open-meteo.com:8443/"));
+
+        let allow = "https://example.com/api/";
+        assert!(allows(allow, "https://example.com/api"));
+        assert!(allows(allow, "https://example.com/api/weather"));
+        assert!(!allows(allow, "https://example.com/apiary"));
+        assert!(!allows(allow, "https://example.com/api/../admin"));
+    }
+
+    #[test]
+    fn allowlist_entries_are_checked() {
+        for allow in [
+            "example.com",
+            "https://u:p@example.com/",
+            "https://example.com/?q",
+        ] {
+            let config = HttpProxyConfig {
+                allow: vec![allow.to_owned()],
+                ..Default::default()
+            };
+            assert!(HttpProxy::new(&config).is_err(), "{allow}");
+        }
+    }
+}
@@ -0,0 +1,92 @@
+#[cfg(feature = "native")]
+pub mod advertising;
+#[cfg(feature = "native")]
+pub mod ancs;
+#[cfg(feature = "native")]
+pub mod app;
+#[cfg(feature = "native")]
+pub mod battery;
+#[cfg(feature = "native")]
+pub mod bench;
+#[cfg(feature = "native")]
+pub mod bundle;
+#[cfg(feature = "native")]
+pub mod config;
+#[cfg(feature = "native")]
+pub mod control;
+#[cfg(feature = "native")]
+pub mod daemon;
+pub mod device;
+#[cfg(feature = "native")]
+pub mod dst;
+pub mod emu;
+pub mod engine;
+#[cfg(feature = "native")]
+pub mod eval;
+#[cfg(feature = "native")]
+pub mod firmware;
+#[cfg(feature = "native")]
+pub mod flash;
+#[cfg(feature = "native")]
+pub mod futures_extras;
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
+pub mod heatshrink;
+#[cfg(feature = "native")]
+pub mod http;
+#[cfg(feature = "native")]
+pub mod http_proxy;
+#[cfg(feature = "native")]
+pub mod lockstep;
+#[cfg(feature = "native")]
+pub mod logfile;
+#[cfg(feature = "native")]
+pub mod machine;
+#[cfg(feature = "native")]
+pub mod mock;
+#[cfg(feature = "native")]
+pub mod mqtt;
+#[cfg(feature = "native")]
+pub mod outbox;
+#[cfg(feature = "native")]
+pub mod overlay;
+pub mod peripheral;
+#[cfg(feature = "native")]
+pub mod plugin;
+#[cfg(feature = "native")]
+pub mod pool;
+#[cfg(feature = "native")]
+pub mod power;
+pub mod profile;
+#[cfg(feature = "native")]
+pub mod responder;
+#[cfg(feature = "native")]
+pub mod runner;
+#[cfg(feature = "native")]
+pub mod screenshot;
+#[cfg(feature = "native")]
+pub mod script;
+#[cfg(feature = "native")]
+pub mod shm;
+#[cfg(feature = "native")]
+pub mod snapshot;
+pub mod storage;
+#[cfg(feature = "native")]
+pub mod suite;
+#[cfg(feature = "native")]
+pub mod text;
+#[cfg(feature = "native")]
+pub mod timeline;
+pub mod touch;
+#[cfg(feature = "native")]
+pub mod tsclient;
+#[cfg(feature = "native")]
+pub mod tui_extras;
+#[cfg(feature = "native")]
+pub mod ui;
+#[cfg(feature = "native")]
+pub mod wasmtime_engine;
+#[cfg(feature = "web")]
+pub mod web;
+#[cfg(feature = "native")]
+pub mod widgets;
@@ -0,0 +1,173 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::info;
+
+use crate::{
+    control::{Command, TAP_DURATION},
+    emu::{Clock, Emulator, Screen, BTN1},
+    runner::{apply_input, TICK_MS},
+    screenshot::encode_png,
+    script::ScriptLine,
+};
+
+struct Side {
+    emu: Emulator,
+    clock: Clock,
+    console: Vec<u8>,
+    screen: Option<Screen>,
+}
+
+impl Side {
+    fn new(mut emu: Emulator, start_ms: f64) -> anyhow::Result<Self> {
+        let clock = Clock::stopped_at(start_ms);
+        emu.set_clock(clock.clone());
+        emu.send_pin_watch_event(BTN1)?;
+        Ok(Self {
+            emu,
+            clock,
+            console: vec![],
+            screen: None,
+        })
+    }
+
+    fn tick(&mut self) -> anyhow::Result<()> {
+        self.clock.advance(TICK_MS);
+        self.emu.idle()?;
+        if self.emu.gfx_changed()? {
+            let screen = self.screen.get_or_insert_with(Screen::default);
+            self.emu.get_screen_into(screen)?;
+        }
+        for (_, chars) in self.emu.handle_io()? {
+            self.console.extend(chars);
+        }
+        Ok(())
+    }
+
+    /// Carries out a command that goes straight to the firmware.
+    fn apply(&mut self, command: &Command) -> anyhow::Result<()> {
+        let input = command.firmware_input().unwrap();
+        apply_input(&mut self.emu, input)
+    }
+}
+
+/// Runs two emulators side by side on identical virtual clocks, feeding both
+/// the inputs from a script, and reports where their console output and screen
+/// contents diverge.
+pub struct Lockstep {
+    a: Side,
+    b: Side,
+    elapsed_ms: f64,
+    console_diverged: bool,
+    screens_differ: bool,
+    frames_dir: Option<PathBuf>,
+    divergences: usize,
+}
+
+impl Lockstep {
+    pub fn new(a: Emulator, b: Emulator, frames_dir: Option<&Path>) -> anyhow::Result<Self> {
+        let start_ms = a.clock().now_ms();
+        Ok(Self {
+            a: Side::new(a, start_ms)?,
+            b: Side::new(b, start_ms)?,
+            elapsed_ms: 0.0,
+            console_diverged: false,
+            screens_differ: false,
+            frames_dir: frames_dir.map(Into::into),
+            divergences: 0,
+        })
+    }
+
+    fn report(&mut self, what: String) {
+        println!("t={:.0}ms: {what}", self.elapsed_ms);
+        self.divergences += 1;
+    }
+
+    fn compare(&mut self) -> anyhow::Result<()> {
+        if !self.console_diverged {
+            let (a, b) = (&self.a.console, &self.b.console);
+            if let Some(pos) = a.iter().zip(b).position(|(a, b)| a != b) {
+                self.console_diverged = true;
+                let context =
+                    |c: &[u8]| String::from_utf8_lossy(&c[pos..c.len().min(pos + 40)]).into_owned();
+                let (a, b) = (context(a), context(b));
+                self.report(format!(
+                    "console output diverged at byte {pos}: {a:?} vs. {b:?}"
+                ));
+            }
+        }
+
+        let diff = match (&self.a.screen, &self.b.screen) {
+            (Some(a), Some(b)) => a.differing_pixels(b),
+            (None, None) => 0,
+            (Some(screen), None) | (None, Some(screen)) => screen.width() * screen.height(),
+        };
+        if diff > 0 && !self.screens_differ {
+            self.report(format!("screens differ in {diff} pixels"));
+            if let (Some(dir), Some(a), Some(b)) =
+                (self.frames_dir.take(), &self.a.screen, &self.b.screen)
+            {
+                fs::create_dir_all(&dir)?;
+                fs::write(dir.join("a.png"), encode_png(a)?)?;
+                fs::write(dir.join("b.png"), encode_png(b)?)?;
+                info!("wrote differing frames to {dir:?}");
+            }
+        } else if diff == 0 && self.screens_differ {
+            println!("t={:.0}ms: screens match again", self.elapsed_ms);
+        }
+        self.screens_differ = diff > 0;
+        Ok(())
+    }
+
+    /// Moves both emulators forward by the given amount of virtual time.
+    pub fn run_for(&mut self, ms: f64) -> anyhow::Result<()> {
+        let end = self.elapsed_ms + ms;
+        while self.elapsed_ms < end {
+            self.a.tick()?;
+            self.b.tick()?;
+            self.elapsed_ms += TICK_MS;
+            self.compare()?;
+        }
+        Ok(())
+    }
+
+    pub fn run_script(&mut self, script: &[ScriptLine]) -> anyhow::Result<()> {
+        for line in script {
+            info!("diff script line {}: {}", line.lineno, line.text);
+            match &line.command {
+                Command::Wait(d) => self.run_for(d.as_secs_f64() * 1000.0)?,
+                Command::Tap(x, y) => {
+                    for on in [true, false] {
+                        let touch = Command::Touch(*x, *y, on, None);
+                        self.a.apply(&touch)?;
+                        self.b.apply(&touch)?;
+                        if on {
+                            self.run_for(TAP_DURATION.as_secs_f64() * 1000.0)?;
+                        }
+                    }
+                }
+                c if c.firmware_input().is_some() => {
+                    self.a.apply(c)?;
+                    self.b.apply(c)?;
+                }
+                _ => anyhow::bail!(
+                    "line {}: `{}` isn't supported when diffing",
+                    line.lineno,
+                    line.text
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// Finishes the comparison, returning the number of divergences found.
+    pub fn finish(mut self) -> usize {
+        if !self.console_diverged && self.a.console.len() != self.b.console.len() {
+            let (a, b) = (self.a.console.len(), self.b.console.len());
+            self.report(format!("console output lengths differ: {a} vs. {b} bytes"));
+        }
+        self.divergences
+    }
+}
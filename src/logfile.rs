@@ -0,0 +1,99 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// When to start a new log file, and how many old ones to keep.
+#[derive(Clone, Copy, Debug)]
+pub struct Rotation {
+    /// Rotate before the file grows past this many bytes.
+    pub max_size: Option<u64>,
+    /// Rotate once the file has been written to for this long.
+    pub max_age: Option<Duration>,
+    /// How many rotated files to keep, as `<name>.1` (the newest) to `<name>.<keep>`.
+    pub keep: usize,
+}
+
+/// A log file that's appended to, and moved aside for a fresh one when it gets
+/// too big or too old. Rotation only happens between writes, so as long as each
+/// log record is written at once, no record is split across files.
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+    opened: Instant,
+}
+
+fn open(path: &Path) -> io::Result<(File, u64)> {
+    let file = File::options().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
+
+impl RotatingFile {
+    pub fn open(path: impl Into<PathBuf>, rotation: Rotation) -> io::Result<Self> {
+        let path = path.into();
+        let (file, size) = open(&path)?;
+        Ok(Self {
+            path,
+            rotation,
+            file,
+            size,
+            opened: Instant::now(),
+        })
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{n}"));
+        name.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let keep = self.rotation.keep;
+        if keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            // The oldest is simply overwritten.
+            for n in (1..keep).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        (self.file, self.size) = open(&self.path)?;
+        self.opened = Instant::now();
+        Ok(())
+    }
+
+    fn due(&self, len: usize) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let Rotation {
+            max_size, max_age, ..
+        } = self.rotation;
+        max_size.is_some_and(|max| self.size + len as u64 > max)
+            || max_age.is_some_and(|max| self.opened.elapsed() >= max)
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.due(buf.len()) {
+            self.rotate()?;
+        }
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
@@ -0,0 +1,151 @@
+use crate::{
+    emu::{Clock, Device, Emulator, Event, Flags, Screen},
+    snapshot::Snapshot,
+    touch::{Gesture, TouchMode},
+};
+
+/// What the runner needs from an emulated watch. [`Emulator`] implements it by
+/// running firmware on an [`Engine`]; anything else implementing it, such as a
+/// mock, can be driven by an [`AsyncRunner`] just the same.
+///
+/// [`AsyncRunner`]: crate::runner::AsyncRunner
+/// [`Engine`]: crate::engine::Engine
+pub trait Machine: Send + 'static {
+    /// Runs the firmware's idle loop once, returning how many milliseconds it
+    /// wants to be left alone for (0 to run again at once).
+    fn idle(&mut self) -> anyhow::Result<i32>;
+    /// Whether the screen has changed since the last call.
+    fn gfx_changed(&mut self) -> anyhow::Result<bool>;
+    fn get_screen(&mut self) -> anyhow::Result<Screen>;
+    fn get_screen_into(&mut self, screen: &mut Screen) -> anyhow::Result<()>;
+    /// Takes the output the firmware has written to each device.
+    fn handle_io(&mut self) -> anyhow::Result<Vec<(Device, Vec<u8>)>>;
+    fn take_events(&mut self) -> Vec<Event>;
+    /// Takes the gestures recognized in touches since the last call.
+    fn take_gestures(&mut self) -> Vec<Gesture>;
+
+    fn push_string_to(&mut self, device: Device, chars: &[u8]) -> anyhow::Result<()>;
+    fn send_touch_with_pressure(
+        &mut self,
+        x: u8,
+        y: u8,
+        on: bool,
+        pressure: u8,
+    ) -> anyhow::Result<()>;
+    fn set_touch_mode(&mut self, mode: TouchMode);
+    fn button_count(&self) -> usize;
+    /// Presses or releases a button, numbered from 1.
+    fn press_button(&mut self, button: usize, on: bool) -> anyhow::Result<()>;
+
+    fn pin(&self, pin: i32) -> Option<bool>;
+    fn pins(&self) -> &[bool];
+    /// Sets a pin's raw value without telling the firmware.
+    fn set_pin(&mut self, pin: i32, value: bool) -> anyhow::Result<()>;
+    fn send_pin_watch_event(&mut self, pin: i32) -> anyhow::Result<()>;
+    /// The pin that tells the firmware whether the charger is connected, and
+    /// whether it reads low while it is, if there's a charger.
+    fn charge_pin(&self) -> Option<(i32, bool)>;
+
+    fn flash(&self) -> &[u8];
+    fn snapshot(&mut self) -> anyhow::Result<Snapshot>;
+    fn restore(&mut self, snapshot: &Snapshot) -> anyhow::Result<()>;
+
+    fn clock(&self) -> Clock;
+    fn flags(&self) -> Flags;
+}
+
+impl Machine for Emulator {
+    fn idle(&mut self) -> anyhow::Result<i32> {
+        Emulator::idle(self)
+    }
+
+    fn gfx_changed(&mut self) -> anyhow::Result<bool> {
+        Emulator::gfx_changed(self)
+    }
+
+    fn get_screen(&mut self) -> anyhow::Result<Screen> {
+        Emulator::get_screen(self)
+    }
+
+    fn get_screen_into(&mut self, screen: &mut Screen) -> anyhow::Result<()> {
+        Emulator::get_screen_into(self, screen)
+    }
+
+    fn handle_io(&mut self) -> anyhow::Result<Vec<(Device, Vec<u8>)>> {
+        Emulator::handle_io(self)
+    }
+
+    fn take_events(&mut self) -> Vec<Event> {
+        Emulator::take_events(self)
+    }
+
+    fn take_gestures(&mut self) -> Vec<Gesture> {
+        Emulator::take_gestures(self)
+    }
+
+    fn push_string_to(&mut self, device: Device, chars: &[u8]) -> anyhow::Result<()> {
+        Emulator::push_string_to(self, device, chars)
+    }
+
+    fn send_touch_with_pressure(
+        &mut self,
+        x: u8,
+        y: u8,
+        on: bool,
+        pressure: u8,
+    ) -> anyhow::Result<()> {
+        Emulator::send_touch_with_pressure(self, x, y, on, pressure)
+    }
+
+    fn set_touch_mode(&mut self, mode: TouchMode) {
+        Emulator::set_touch_mode(self, mode)
+    }
+
+    fn button_count(&self) -> usize {
+        Emulator::button_count(self)
+    }
+
+    fn press_button(&mut self, button: usize, on: bool) -> anyhow::Result<()> {
+        Emulator::press_button(self, button, on)
+    }
+
+    fn pin(&self, pin: i32) -> Option<bool> {
+        Emulator::pin(self, pin)
+    }
+
+    fn pins(&self) -> &[bool] {
+        Emulator::pins(self)
+    }
+
+    fn set_pin(&mut self, pin: i32, value: bool) -> anyhow::Result<()> {
+        Emulator::set_pin(self, pin, value)
+    }
+
+    fn send_pin_watch_event(&mut self, pin: i32) -> anyhow::Result<()> {
+        Emulator::send_pin_watch_event(self, pin)
+    }
+
+    fn charge_pin(&self) -> Option<(i32, bool)> {
+        Emulator::charge_pin(self)
+    }
+
+    fn flash(&self) -> &[u8] {
+        Emulator::flash(self)
+    }
+
+    fn snapshot(&mut self) -> anyhow::Result<Snapshot> {
+        Emulator::snapshot(self)
+    }
+
+    fn restore(&mut self, snapshot: &Snapshot) -> anyhow::Result<()> {
+        Emulator::restore(self, snapshot)
+    }
+
+    fn clock(&self) -> Clock {
+        Emulator::clock(self)
+    }
+
+    fn flags(&self) -> Flags {
+        Emulator::flags(self)
+    }
+}
@@ -1,145 +1,70 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     fmt::Debug,
     fs::{self, File},
-    io::{BufRead, BufReader, Read},
+    future::Future,
+    io::{self, IsTerminal, Write},
     path::{Path, PathBuf},
     str,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
-use base64::{engine::general_purpose, Engine};
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use env_logger::{Builder, Target};
+use futures::future::select_all;
 use log::{debug, error, info};
-use serde_derive::Deserialize;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream, ToSocketAddrs},
     select,
     sync::{
         broadcast::{self, Receiver},
-        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        mpsc::{self, Sender},
+        oneshot, watch,
     },
 };
 
-mod emu;
-mod futures_extras;
-mod runner;
-mod tui_extras;
-mod ui;
-
-use crate::{
-    emu::{Emulator, Input, Output},
+use banglejs_emu::{
+    battery::Battery,
+    bench::Bench,
+    bundle,
+    config::{Config, UploadProgress},
+    control::{self, Controller},
+    daemon,
+    dst::{self, DstZone},
+    emu::{Clock, Device, Event, Input, Output},
+    eval, firmware,
     futures_extras::{OptionFuture, Task},
-    runner::AsyncRunner,
-    ui::UIInput,
+    http,
+    http_proxy::HttpProxy,
+    lockstep::Lockstep,
+    logfile::{RotatingFile, Rotation},
+    mqtt,
+    outbox::Outbox,
+    pool::EmulatorPool,
+    power::PowerMonitor,
+    profile::CallProfile,
+    runner::{self, AsyncRunner},
+    script,
+    shm::SharedScreen,
+    snapshot::{self, Snapshot},
+    suite,
+    touch::TouchMode,
+    tsclient,
+    tui_extras::{LcdStyle, Lighting, Palette},
+    ui::{self, TuiOptions, UIInput, UIOutput},
 };
 
-#[derive(Clone, Debug, Deserialize)]
-enum FileContents {
-    #[serde(rename = "path")]
-    Path(PathBuf),
-    #[serde(rename = "contents")]
-    Contents(String),
-}
-
-#[derive(Clone, Debug, Deserialize)]
-struct FileSpec {
-    #[serde(default)]
-    evaluate: bool,
-
-    #[serde(flatten)]
-    contents: FileContents,
-}
-
-#[derive(Clone, Debug, Default, Deserialize)]
-struct Config {
-    #[serde(default)]
-    factory_reset: bool,
-    flash_initial_contents_file: Option<String>,
-    #[serde(default)]
-    storage: HashMap<String, FileSpec>,
-    startup: Option<String>,
-}
-
-impl Config {
-    fn read<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
-        let mut f = File::open(path)?;
-        let mut buf = String::new();
-        f.read_to_string(&mut buf)?;
-        let config: Config = toml::from_str(&buf)?;
-        Ok(config)
-    }
-
-    fn build<P: AsRef<Path>>(&self, wasm_path: P) -> anyhow::Result<Emulator> {
-        let mut emu = if let Some(f) = &self.flash_initial_contents_file {
-            let flash = get_flash_initial_contents(f)?;
-            Emulator::new_with_flash(&wasm_path, &flash)?
-        } else {
-            Emulator::new(&wasm_path)?
-        };
-
-        if self.factory_reset {
-            emu.reset_storage()?;
-        }
-
-        emu.init()?;
-
-        // Set up initial emulator state as specified by config.
-        let mut send_string = |s: Vec<u8>| {
-            emu.push_string(s.iter()).unwrap();
-        };
-        fn b64(b: &[u8]) -> String {
-            general_purpose::STANDARD_NO_PAD.encode(b)
-        }
-
-        for (path, spec) in &self.storage {
-            let contents = match &spec.contents {
-                FileContents::Path(p) => {
-                    fs::read(p).with_context(|| format!("Failed to load file {p:?}"))?
-                }
-                FileContents::Contents(s) => s.clone().into_bytes(),
-            };
-            info!("writing {} bytes to {}", contents.len(), path);
-            let s = if spec.evaluate {
-                format!(
-                    "\x10require('Storage').write(atob('{}'), eval(atob('{}')));\n",
-                    b64(path.as_bytes()),
-                    b64(&contents),
-                )
-            } else {
-                const CHUNK_SIZE: usize = 1 << 15;
-                contents
-                    .chunks(CHUNK_SIZE)
-                    .enumerate()
-                    .map(|(ind, chunk)| {
-                        format!(
-                            "\x10require('Storage').write(atob('{}'), atob('{}'), {}, {});\n",
-                            b64(path.as_bytes()),
-                            b64(chunk),
-                            ind * CHUNK_SIZE,
-                            contents.len(),
-                        )
-                    })
-                    .collect::<Vec<_>>()
-                    .join("")
-            };
-            send_string(s.into_bytes())
-        }
-
-        if let Some(s) = &self.startup {
-            send_string(s.clone().into_bytes());
-        }
-
-        Ok(emu)
-    }
-}
-
 #[derive(Debug, Parser)]
+#[command(version, args_conflicts_with_subcommands = true)]
 struct Args {
     // These comments should not end in periods due to how they are presented in
     // the CLI help output.
+    #[command(subcommand)]
+    command: Option<Commands>,
+
     /// The TCP port to bind to
     #[arg(short = 'b', default_value_t = String::from("localhost:37026"))]
     bind: String,
@@ -148,39 +73,440 @@ struct Args {
     #[arg(short = 'c')]
     config_path: Option<PathBuf>,
 
+    /// A scenario from the config file to apply on top of the rest of it
+    #[arg(short = 'S', long = "scenario", requires = "config_path")]
+    scenario: Option<String>,
+
+    /// A directory to write a bundle for reproducing the run to if the firmware
+    /// fails or the script does
+    #[arg(short = 'x')]
+    bundle_dir: Option<PathBuf>,
+
+    /// Start the emulator over instead of exiting if it fails, from the config
+    /// or from a recent snapshot
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "config")]
+    restart_on_crash: Option<Restart>,
+
+    /// An installed app to load after startup, by its id, instead of the
+    /// config's `boot_app`
+    #[arg(short = 'L', long = "launch")]
+    launch: Option<String>,
+
+    /// A JavaScript file to upload to RAM and run after startup, as the
+    /// Espruino IDE does, without writing it to storage (may be repeated)
+    #[arg(short = 'u')]
+    uploads: Vec<PathBuf>,
+
     /// A file to send emulator logging output to
     #[arg(short = 'o')]
     log_file: Option<PathBuf>,
 
-    /// The compiled firmware
-    wasm_path: PathBuf,
+    /// Start a new log file before the current one grows past this many bytes
+    #[arg(long)]
+    log_max_size: Option<u64>,
+
+    /// Start a new log file after this many seconds
+    #[arg(long)]
+    log_max_age: Option<u64>,
+
+    /// How many old log files to keep when starting new ones
+    #[arg(long, default_value_t = 5)]
+    log_keep: usize,
+
+    /// A directory to keep each run's log, console history, reproducer bundle,
+    /// and script output files in, under a subdirectory named for when it
+    /// started
+    #[arg(long)]
+    workdir: Option<PathBuf>,
+
+    /// This run's directory under the workdir
+    #[arg(skip)]
+    run_dir: Option<PathBuf>,
+
+    /// The most console output to keep in the TUI's scrollback, in bytes
+    #[arg(short = 'm', default_value_t = 1 << 20)]
+    console_limit: usize,
+
+    /// Don't make links in the TUI's console clickable
+    #[arg(long)]
+    no_hyperlinks: bool,
+
+    /// Draw the TUI with only ASCII characters, as is done by default if the
+    /// locale isn't UTF-8
+    #[arg(long)]
+    ascii: bool,
+
+    /// A file to append all console output to
+    #[arg(short = 'H')]
+    history_file: Option<PathBuf>,
+
+    /// An address to serve a live view of the screen over HTTP on
+    #[arg(short = 'w')]
+    http_bind: Option<String>,
+
+    /// An address to serve the control interface on
+    #[arg(short = 'C')]
+    control_bind: Option<String>,
+
+    /// An MQTT broker, as HOST:PORT, to bridge Gadgetbridge messages to and
+    /// from the watch through
+    #[arg(long)]
+    mqtt: Option<String>,
+
+    /// The prefix of the topics the MQTT bridge uses
+    #[arg(long, default_value_t = String::from("banglejs"), requires = "mqtt")]
+    mqtt_prefix: String,
+
+    /// A script of control commands to run, exiting when it finishes
+    #[arg(short = 's')]
+    script: Option<PathBuf>,
+
+    /// Run without the TUI, printing console output to stdout
+    #[arg(short = 'n')]
+    headless: bool,
+
+    /// Render the screen in 24-bit color approximating the look of the real LCD
+    #[arg(short = 'l')]
+    lcd: bool,
+
+    /// The gamma to apply when approximating the LCD
+    #[arg(short = 'g', default_value_t = 1.0, requires = "lcd")]
+    gamma: f64,
+
+    /// The brightness to apply when approximating the LCD
+    #[arg(short = 'B', default_value_t = 1.0, requires = "lcd")]
+    brightness: f64,
+
+    /// The light to approximate the LCD in (`sunlight`, `indoor`, or `night`),
+    /// which `a` cycles through in the TUI
+    #[arg(long, default_value = "indoor", requires = "lcd")]
+    lighting: Lighting,
+
+    /// The colors to show the screen with when not approximating the LCD
+    /// (`ansi`, `high-contrast`, or `red-green`), overriding the config
+    #[arg(long)]
+    palette: Option<Palette>,
+
+    /// Pass touches straight to the firmware instead of recognizing taps and
+    /// swipes in the emulator
+    #[arg(short = 'R')]
+    raw_touch: bool,
+
+    /// Serve a console device's traffic on its own TCP address instead of the
+    /// main console, given as DEVICE=ADDRESS (may be repeated)
+    #[arg(short = 'd', value_parser = parse_route)]
+    routes: Vec<(Device, String)>,
+
+    /// Count and time calls to host functions and into the firmware, and print
+    /// a summary on exit
+    #[arg(short = 'P')]
+    profile: bool,
+
+    /// Track how long the LCD, backlight, GPS, HRM, compass, and CPU are on,
+    /// and print a summary on exit
+    #[arg(long)]
+    power_report: bool,
+
+    /// A file to keep the screen in for other programs to map into memory
+    #[arg(short = 'F')]
+    shared_screen: Option<PathBuf>,
+
+    /// The shortest time to leave the firmware alone between idle calls, in
+    /// milliseconds
+    #[arg(long, default_value_t = 10)]
+    min_tick: u64,
+
+    /// Sleep for exactly as long as the firmware asks between idle calls
+    #[arg(long)]
+    exact_idle: bool,
+
+    /// Idle the firmware only once a second after a while without input,
+    /// output, or screen changes, to save power
+    #[arg(long)]
+    nap: bool,
+
+    /// Run in the background without the TUI, detached from the terminal,
+    /// serving the control interface (on localhost:37027 unless -C is given)
+    #[arg(long)]
+    daemon: bool,
+
+    /// The file to record the daemon's pid in
+    #[arg(long, default_value = DEFAULT_PIDFILE)]
+    pidfile: PathBuf,
+
+    /// Set on the background copy started by --daemon
+    #[arg(long, hide = true)]
+    detached: bool,
+
+    /// The compiled firmware, as a path or an http(s) URL
+    #[arg(required = true)]
+    wasm_path: Option<PathBuf>,
 }
 
-fn get_flash_initial_contents<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<u8>> {
-    let f = File::open(path)?;
-    let f = BufReader::new(f);
-
-    let mut ret = vec![];
-
-    for line in f.lines() {
-        let line = line?;
-        let fields = line.split(',');
-        let row: Result<Vec<u8>, _> = fields
-            .filter(|f| !f.is_empty())
-            .map(|f| f.parse())
-            .collect();
-        if let Ok(row) = row {
-            ret.extend(row);
-        }
+#[derive(Debug, Subcommand)]
+enum Commands {
+    /// Report whether an emulator started with --daemon is running
+    Status {
+        /// The file the daemon's pid was recorded in
+        #[arg(long, default_value = DEFAULT_PIDFILE)]
+        pidfile: PathBuf,
+    },
+
+    /// Stop an emulator started with --daemon, waiting for it to exit
+    Stop {
+        /// The file the daemon's pid was recorded in
+        #[arg(long, default_value = DEFAULT_PIDFILE)]
+        pidfile: PathBuf,
+    },
+
+    /// Download a compiled firmware into the local cache and print its path
+    FetchFirmware {
+        /// The firmware version to download
+        #[arg(default_value_t = String::from("latest"))]
+        version: String,
+
+        /// A URL to download from instead of the GitHub releases, in which
+        /// `{version}` is replaced by the version
+        #[arg(short = 'u')]
+        url: Option<String>,
+
+        /// Download again even if the version is already cached
+        #[arg(short = 'f')]
+        force: bool,
+    },
+
+    /// Run two firmwares side by side with identical inputs and virtual time,
+    /// reporting where their console output and screens diverge
+    Diff {
+        /// The first compiled firmware
+        wasm_a: PathBuf,
+
+        /// The second compiled firmware
+        wasm_b: PathBuf,
+
+        /// A config file to use for setting up both emulators
+        #[arg(short = 'c')]
+        config_path: Option<PathBuf>,
+
+        /// A script of inputs to send to both emulators
+        #[arg(short = 's')]
+        script: Option<PathBuf>,
+
+        /// How long to keep running after the script finishes, in milliseconds
+        #[arg(short = 't', default_value_t = 1000)]
+        duration: u64,
+
+        /// A directory to write the first pair of differing frames to
+        #[arg(short = 'f')]
+        frames_dir: Option<PathBuf>,
+    },
+
+    /// Run a firmware as fast as possible on virtual time with a scripted
+    /// workload and report how long emulating it took
+    Bench {
+        /// The compiled firmware
+        wasm_path: PathBuf,
+
+        /// A config file to use for setting up the emulator
+        #[arg(short = 'c')]
+        config_path: Option<PathBuf>,
+
+        /// A script of inputs to send as the workload
+        #[arg(short = 's')]
+        script: Option<PathBuf>,
+
+        /// How long to run for, in virtual seconds
+        #[arg(short = 't', default_value_t = 10.0)]
+        seconds: f64,
+
+        /// Also count and time calls to host functions and into the firmware
+        #[arg(short = 'P')]
+        profile: bool,
+    },
+
+    /// Run the firmware across a year's daylight saving time transitions on
+    /// virtual time, saving screenshots before and after each
+    Dst {
+        /// The compiled firmware
+        wasm_path: PathBuf,
+
+        /// A config file to use for setting up the emulator
+        #[arg(short = 'c')]
+        config_path: Option<PathBuf>,
+
+        /// The zone, e.g. `Europe/Berlin`, or `eu:<hours>` or `us:<hours>` for
+        /// the EU or US rules with any standard offset
+        #[arg(short = 'z', default_value_t = String::from("Europe/London"))]
+        zone: String,
+
+        /// The year of the transitions (the current one by default)
+        #[arg(short = 'y')]
+        year: Option<i32>,
+
+        /// How long to run before and after each transition, in virtual seconds
+        #[arg(short = 'm', default_value_t = 120.0)]
+        margin: f64,
+
+        /// The directory to save screenshots to
+        #[arg(short = 'o', default_value = ".")]
+        dir: PathBuf,
+    },
+
+    /// Run every scenario script (`<name>.script`) in a directory, each with
+    /// `<name>.toml` or the given config, and report the results
+    TestSuite {
+        /// The compiled firmware
+        wasm_path: PathBuf,
+
+        /// The directory containing the scenarios
+        dir: PathBuf,
+
+        /// A config file for scenarios without their own
+        #[arg(short = 'c')]
+        config_path: Option<PathBuf>,
+
+        /// How many scenarios to run at once
+        #[arg(short = 'j', default_value_t = 1)]
+        jobs: usize,
+
+        /// Save the screen to golden screenshots instead of comparing
+        #[arg(short = 'u')]
+        update: bool,
+
+        /// A file to write a JUnit XML report to
+        #[arg(short = 'r')]
+        report: Option<PathBuf>,
+
+        /// A directory to write reproducer bundles for failed scenarios to,
+        /// in a subdirectory for each
+        #[arg(short = 'x')]
+        bundle_dir: Option<PathBuf>,
+    },
+
+    /// Run a firmware from a reproducer bundle, replaying its inputs
+    Replay {
+        /// The bundle directory
+        bundle: PathBuf,
+
+        /// The compiled firmware
+        wasm_path: PathBuf,
+    },
+
+    /// Check a config file for problems without starting the emulator
+    CheckConfig {
+        /// The config file to check
+        config_path: PathBuf,
+
+        /// A compiled firmware to boot for also checking files that are
+        /// evaluated before being stored
+        wasm_path: Option<PathBuf>,
+    },
+
+    /// Print the version details of a compiled firmware
+    FirmwareInfo {
+        /// The compiled firmware
+        wasm_path: PathBuf,
+    },
+
+    /// Write an npm package with a TypeScript client for the control interface
+    TsClient {
+        /// The directory to write the package to
+        dir: PathBuf,
+    },
+}
+
+fn parse_route(s: &str) -> anyhow::Result<(Device, String)> {
+    let (device, addr) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::format_err!("expected DEVICE=ADDRESS"))?;
+    let device = device
+        .parse()
+        .with_context(|| format!("Invalid device number {device:?}"))?;
+    Ok((Device(device), addr.to_owned()))
+}
+
+/// Draws a progress bar on stderr for a file being written into storage, if
+/// stderr is a terminal.
+fn show_upload_progress(progress: UploadProgress) {
+    const WIDTH: usize = 30;
+
+    let mut stderr = io::stderr();
+    if !stderr.is_terminal() || progress.total == 0 {
+        return;
     }
+    let filled = WIDTH * progress.sent / progress.total;
+    let _ = write!(
+        stderr,
+        "\r{} [{}{}] {:3}%",
+        progress.file,
+        "#".repeat(filled),
+        " ".repeat(WIDTH - filled),
+        100 * progress.sent / progress.total
+    );
+    if progress.sent == progress.total {
+        let _ = writeln!(stderr);
+    }
+}
 
-    Ok(ret)
+/// Saves the emulator's state to a numbered slot kept for its firmware.
+async fn save_slot(to_emu: Sender<Input>, wasm_path: PathBuf, slot: u8) -> anyhow::Result<()> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    to_emu
+        .send(Input::Snapshot(reply_tx))
+        .await
+        .map_err(|_| anyhow::format_err!("emulator is not running"))?;
+    let snapshot = reply_rx.await?;
+    tokio::task::spawn_blocking(move || {
+        snapshot.write(&snapshot::slot_path(
+            &snapshot::slots_dir(&wasm_path)?,
+            slot,
+        ))
+    })
+    .await?
+}
+
+/// Restores the emulator's state from a numbered slot kept for its firmware.
+async fn load_slot(to_emu: Sender<Input>, wasm_path: PathBuf, slot: u8) -> anyhow::Result<()> {
+    let snapshot = tokio::task::spawn_blocking(move || {
+        Snapshot::read(&snapshot::slot_path(
+            &snapshot::slots_dir(&wasm_path)?,
+            slot,
+        ))
+    })
+    .await??;
+    to_emu
+        .send(Input::Restore(Box::new(snapshot)))
+        .await
+        .map_err(|_| anyhow::format_err!("emulator is not running"))
+}
+
+/// Shows the outcome of saving or loading a slot on the TUI's timeline.
+async fn report_slot(
+    action: impl Future<Output = anyhow::Result<()>>,
+    done: String,
+    failed: String,
+    to_ui: Sender<UIOutput>,
+) {
+    let description = match action.await {
+        Ok(()) => {
+            info!("{done}");
+            done
+        }
+        Err(err) => {
+            error!("{failed}: {err:#}");
+            format!("{failed}: {err:#}")
+        }
+    };
+    let _ = to_ui
+        .send(UIOutput::Emu(Output::Event(Event::Status(description))))
+        .await;
 }
 
 async fn run_net(
     bind: impl ToSocketAddrs + Debug,
-    mut rx: UnboundedReceiver<Vec<u8>>,
-    tx: UnboundedSender<Input>,
+    device: Device,
+    mut rx: mpsc::Receiver<Vec<u8>>,
+    tx: Sender<Input>,
     mut quit: Receiver<()>,
 ) -> anyhow::Result<()> {
     let listener = TcpListener::bind(&bind)
@@ -217,8 +543,12 @@ async fn run_net(
                         debug!("socket connection closed");
                         socket = None;
                     }
+                    // Waiting for room in the emulator's input queue holds up
+                    // reading from the socket.
                     Ok(n) => {
-                        tx.send(Input::Console(buf[..n].to_owned())).unwrap();
+                        if tx.send(Input::Console(device, buf[..n].to_owned())).await.is_err() {
+                            break;
+                        }
                     }
                     Err(err) => {
                         error!("socket err: {err}");
@@ -232,84 +562,685 @@ async fn run_net(
     Ok(())
 }
 
+/// How to start the emulator over after it fails.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+enum Restart {
+    /// Set it up from the config again
+    Config,
+    /// Go back to the latest of the snapshots taken periodically while it
+    /// runs, or set it up from the config if there isn't one yet
+    Snapshot,
+}
+
+/// How many outputs can be queued for the UI or a network client before more
+/// have to wait in an [`Outbox`].
+const OUTPUT_QUEUE_LEN: usize = 64;
+/// How much output can wait for a consumer that isn't keeping up before the
+/// oldest of it is dropped, in bytes.
+const OUTPUT_BUFFER_LIMIT: usize = 1 << 20;
+
+/// How often the emulator's state is saved for restarting from.
+const RESTART_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Where a daemon's pid is recorded unless told otherwise.
+const DEFAULT_PIDFILE: &str = "banglejs-emu.pid";
+/// Where a daemon serves the control interface unless told otherwise, since
+/// without the TUI it's the only way to drive the emulator other than the
+/// console.
+const DEFAULT_DAEMON_CONTROL_BIND: &str = "localhost:37027";
+
+/// Creates an emulator set up from the config.
+type BuildRunner = Arc<dyn Fn() -> anyhow::Result<AsyncRunner> + Send + Sync>;
+
 async fn run_emu(
-    emu: Emulator,
-    rx: UnboundedReceiver<Input>,
-    tx: UnboundedSender<Output>,
+    mut emu: AsyncRunner,
+    build: BuildRunner,
+    restart: Option<Restart>,
+    mut rx: mpsc::Receiver<Input>,
+    tx: Sender<Output>,
     mut quit: Receiver<()>,
 ) -> anyhow::Result<()> {
-    let emu = AsyncRunner::new(emu);
-    select! {
-        _ = quit.recv() => Ok(()),
-        ret = emu.run(rx, tx) => ret,
+    let mut snapshot: Option<Box<Snapshot>> = None;
+    loop {
+        // Each run gets its own input channel, so that the inputs can go to a
+        // replacement after a failure. An input waits here for room in it,
+        // leaving the rest waiting in `rx`.
+        let (run_tx, run_rx) = mpsc::channel(runner::QUEUE_LEN);
+        if let (Some(Restart::Snapshot), Some(snapshot)) = (restart, snapshot.take()) {
+            run_tx.try_send(Input::Restore(snapshot)).unwrap();
+        }
+        let mut held = None;
+        let run = emu.run(run_rx, tx.clone());
+        tokio::pin!(run);
+        let mut ticks = tokio::time::interval(RESTART_SNAPSHOT_INTERVAL);
+        let mut pending_snapshot = None;
+        let mut rx_open = true;
+        let err = loop {
+            select! {
+                _ = quit.recv() => return Ok(()),
+                ret = &mut run => match ret {
+                    Ok(()) => return Ok(()),
+                    Err(err) => break err,
+                },
+                input = rx.recv(), if rx_open && held.is_none() => match input {
+                    Some(input) => held = Some(input),
+                    None => rx_open = false,
+                },
+                permit = run_tx.reserve(), if held.is_some() => {
+                    if let Ok(permit) = permit {
+                        permit.send(held.take().unwrap());
+                    }
+                }
+                _ = ticks.tick(), if restart == Some(Restart::Snapshot) && pending_snapshot.is_none() => {
+                    let (reply_tx, reply_rx) = oneshot::channel();
+                    if run_tx.try_send(Input::Snapshot(reply_tx)).is_ok() {
+                        pending_snapshot = Some(reply_rx);
+                    }
+                }
+                s = OptionFuture::from(pending_snapshot.as_mut()) => {
+                    pending_snapshot = None;
+                    if let Ok(s) = s {
+                        snapshot = Some(s);
+                    }
+                }
+            }
+        };
+        if restart.is_none() {
+            return Err(err);
+        }
+        error!("emulator failed, restarting: {err:?}");
+        let _ = tx
+            .send(Output::Event(Event::Status(format!(
+                "restarted after failure: {err:#}"
+            ))))
+            .await;
+        emu = tokio::task::spawn_blocking({
+            let build = Arc::clone(&build);
+            move || build()
+        })
+        .await??;
     }
 }
 
+/// Creates a directory for a run's outputs under the workdir, named for the
+/// current time.
+fn create_run_dir(workdir: &Path) -> anyhow::Result<PathBuf> {
+    let stamp = dst::format_utc_stamp(Clock::default().now_ms());
+    let mut dir = workdir.join(&stamp);
+    // Runs started within the same second get numbered.
+    for n in 2.. {
+        if !dir.exists() {
+            break;
+        }
+        dir = workdir.join(format!("{stamp}-{n}"));
+    }
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {dir:?}"))?;
+    Ok(fs::canonicalize(dir)?)
+}
+
 async fn _main() -> anyhow::Result<()> {
-    let args = Args::parse();
+    let mut args = Args::parse();
+
+    if args.daemon {
+        let pid = daemon::detach(&args.pidfile)?;
+        println!("started with pid {pid}");
+        return Ok(());
+    }
+    let _pidfile = args
+        .detached
+        .then(|| daemon::PidfileGuard(args.pidfile.clone()));
+    if args.detached {
+        args.headless = true;
+        args.control_bind
+            .get_or_insert_with(|| DEFAULT_DAEMON_CONTROL_BIND.to_owned());
+    }
+
+    // Outputs that weren't given their own paths go in the run's directory.
+    if let Some(workdir) = &args.workdir {
+        let dir = create_run_dir(workdir)?;
+        eprintln!("writing outputs to {}", dir.display());
+        args.log_file
+            .get_or_insert_with(|| dir.join("emulator.log"));
+        args.history_file
+            .get_or_insert_with(|| dir.join("console.log"));
+        args.bundle_dir.get_or_insert_with(|| dir.join("bundle"));
+        args.run_dir = Some(dir);
+    }
 
-    if let Some(log_file) = args.log_file {
+    if let Some(log_file) = &args.log_file {
+        let rotation = Rotation {
+            max_size: args.log_max_size,
+            max_age: args.log_max_age.map(Duration::from_secs),
+            keep: args.log_keep,
+        };
         Builder::from_default_env()
             .format_timestamp_micros()
             .target(Target::Pipe(Box::new(
-                File::options()
-                    .create(true)
-                    .append(true)
-                    .open(&log_file)
+                RotatingFile::open(log_file, rotation)
                     .with_context(|| format!("Failed to create log file {log_file:?}"))?,
             )))
             .init();
     }
 
+    match args.command.take() {
+        Some(Commands::Status { pidfile }) => match daemon::running_pid(&pidfile)? {
+            Some(pid) => {
+                println!("running with pid {pid}");
+                Ok(())
+            }
+            None => anyhow::bail!("not running"),
+        },
+        Some(Commands::Stop { pidfile }) => {
+            tokio::task::spawn_blocking(move || daemon::stop(&pidfile)).await??;
+            println!("stopped");
+            Ok(())
+        }
+        Some(Commands::TsClient { dir }) => {
+            tsclient::write_package(&dir)?;
+            println!("wrote the client package to {}", dir.display());
+            Ok(())
+        }
+        Some(Commands::FetchFirmware {
+            version,
+            url,
+            force,
+        }) => {
+            let path = tokio::task::spawn_blocking(move || {
+                firmware::fetch(&version, url.as_deref(), force)
+            })
+            .await??;
+            println!("{}", path.display());
+            Ok(())
+        }
+        Some(Commands::Diff {
+            wasm_a,
+            wasm_b,
+            config_path,
+            script,
+            duration,
+            frames_dir,
+        }) => {
+            let config = match &config_path {
+                Some(path) => Config::read(path)
+                    .with_context(|| format!("Failed to open config file {path:?}"))?,
+                None => Config::default(),
+            };
+            let script = match &script {
+                Some(path) => script::load_script(path, None)?,
+                None => vec![],
+            };
+            let mut lockstep = Lockstep::new(
+                config.build(firmware_path(wasm_a).await?)?,
+                config.build(firmware_path(wasm_b).await?)?,
+                frames_dir.as_deref(),
+            )?;
+            lockstep.run_script(&script)?;
+            lockstep.run_for(duration as f64)?;
+            match lockstep.finish() {
+                0 => {
+                    println!("no divergences found");
+                    Ok(())
+                }
+                n => anyhow::bail!("found {n} divergences"),
+            }
+        }
+        Some(Commands::Bench {
+            wasm_path,
+            config_path,
+            script,
+            seconds,
+            profile,
+        }) => {
+            let config = match &config_path {
+                Some(path) => Config::read(path)
+                    .with_context(|| format!("Failed to open config file {path:?}"))?,
+                None => Config::default(),
+            };
+            let script = match &script {
+                Some(path) => script::load_script(path, None)?,
+                None => vec![],
+            };
+            let wasm_path = firmware_path(wasm_path).await?;
+            let start = Instant::now();
+            let mut emu = config.build(&wasm_path)?;
+            let boot = start.elapsed();
+            let profile = profile.then(CallProfile::shared);
+            if let Some(profile) = &profile {
+                emu.set_call_profile(Arc::clone(profile));
+            }
+            let bench = Bench::new(emu, boot, config.schedule()?, seconds * 1000.0)?;
+            println!("{}", bench.run(&script)?);
+            if let Some(profile) = profile {
+                print!("\n{}", profile.lock().unwrap());
+            }
+            Ok(())
+        }
+        Some(Commands::Dst {
+            wasm_path,
+            config_path,
+            zone,
+            year,
+            margin,
+            dir,
+        }) => {
+            let zone: DstZone = zone.parse()?;
+            let config = match &config_path {
+                Some(path) => Config::read(path)
+                    .with_context(|| format!("Failed to open config file {path:?}"))?,
+                None => Config::default(),
+            };
+            let emu = config.build(firmware_path(wasm_path).await?)?;
+            let year = year.unwrap_or_else(|| dst::current_year(&emu.clock()));
+            dst::capture_transitions(emu, &zone, year, margin * 1000.0, &dir)
+        }
+        Some(Commands::TestSuite {
+            wasm_path,
+            dir,
+            config_path,
+            jobs,
+            update,
+            report,
+            bundle_dir,
+        }) => {
+            let failures = suite::run_suite(
+                &firmware_path(wasm_path).await?,
+                &dir,
+                config_path.as_deref(),
+                jobs,
+                update,
+                report.as_deref(),
+                bundle_dir.as_deref(),
+            )
+            .await?;
+            if failures > 0 {
+                anyhow::bail!("{failures} scenarios failed");
+            }
+            Ok(())
+        }
+        Some(Commands::CheckConfig {
+            config_path,
+            wasm_path,
+        }) => {
+            let config = Config::read(&config_path)
+                .with_context(|| format!("Failed to open config file {config_path:?}"))?;
+            let mut problems = config.check();
+            if let Some(wasm_path) = wasm_path {
+                let pool = EmulatorPool::new(firmware_path(wasm_path).await?)?;
+                problems.extend(config.check_evaluated(&pool)?);
+            }
+            for problem in &problems {
+                println!("{problem}");
+            }
+            match problems.len() {
+                0 => {
+                    println!("no problems found");
+                    Ok(())
+                }
+                n => anyhow::bail!("found {n} problems in {config_path:?}"),
+            }
+        }
+        Some(Commands::FirmwareInfo { wasm_path }) => {
+            let mut emu = Config::default().build(firmware_path(wasm_path).await?)?;
+            let env = eval::eval_sync(&mut emu, firmware::INFO_EXPR)?
+                .map_err(|e| anyhow::format_err!("{e}"))?;
+            println!("{}", firmware::FirmwareInfo::from_env(&env));
+            Ok(())
+        }
+        Some(Commands::Replay { bundle, wasm_path }) => {
+            args.config_path = Some(bundle.join(bundle::CONFIG_FILE));
+            args.wasm_path = Some(wasm_path);
+            run(args, Some(bundle)).await
+        }
+        None => run(args, None).await,
+    }
+}
+
+/// Gets the local path of a firmware, downloading it first if it's a URL.
+async fn firmware_path(path: PathBuf) -> anyhow::Result<PathBuf> {
+    tokio::task::spawn_blocking(move || firmware::resolve(&path)).await?
+}
+
+/// Runs the emulator, replaying the inputs from a reproducer bundle if given
+/// one.
+async fn run(args: Args, replay: Option<PathBuf>) -> anyhow::Result<()> {
+    let wasm_path = firmware_path(args.wasm_path.unwrap()).await?;
+
     // Initialize emulator from arguments.
-    let emu = match &args.config_path {
+    let mut config = match &args.config_path {
         Some(path) => Config::read(path)
             .with_context(|| format!("Failed to open config file {:?}", args.config_path))?,
         None => Config::default(),
+    };
+    if let Some(name) = &args.scenario {
+        config.select_scenario(name)?;
+    }
+    if args.raw_touch {
+        config.device.touch_mode = TouchMode::Raw;
+    }
+    if let Some(id) = &args.launch {
+        config.set_boot_app(format!("{id}.app.js"));
+    }
+    for path in &args.uploads {
+        config.add_upload(path);
+    }
+    let pool = EmulatorPool::new(&wasm_path)?;
+    let profile = args.profile.then(CallProfile::shared);
+    let mut emu = config.build_with_progress(&pool, show_upload_progress)?;
+    if let Some(profile) = &profile {
+        emu.set_call_profile(Arc::clone(profile));
+    }
+    let mut schedule = config.schedule()?;
+    if let Some(dir) = &replay {
+        schedule.extend(bundle::load_inputs(dir)?);
+    }
+    let min_tick = Duration::from_millis(args.min_tick);
+    let exact_idle = args.exact_idle;
+    let nap = args.nap;
+    let mut emu = AsyncRunner::new(emu)
+        .schedule(schedule)
+        .respond(config.responses()?)
+        .http_proxy(config.http_proxy.as_ref().map(HttpProxy::new).transpose()?)
+        .min_tick(min_tick)
+        .exact_idle(exact_idle)
+        .nap(nap);
+    let bundle = match &args.bundle_dir {
+        Some(dir) => Some((dir.clone(), config.bundle_config()?)),
+        None => None,
+    };
+    if let Some((dir, bundle_config)) = bundle.clone() {
+        emu = emu.bundle(dir, bundle_config);
     }
-    .build(&args.wasm_path)?;
+    let device = config.device.clone();
+    let screen_spec = device.screen_spec();
+    let palette = args.palette.or(config.palette).unwrap_or_default();
+    let board = device.board;
+    let battery = config.battery.clone().map(Battery::new);
+    let power = (args.power_report || battery.is_some()).then(|| PowerMonitor::new(battery));
+    let build_profile = profile.clone();
+    let build: BuildRunner = Arc::new(move || {
+        let mut emu = config.build_from(&pool)?;
+        if let Some(profile) = &build_profile {
+            emu.set_call_profile(Arc::clone(profile));
+        }
+        let mut emu = AsyncRunner::new(emu)
+            .schedule(config.schedule()?)
+            .respond(config.responses()?)
+            .http_proxy(config.http_proxy.as_ref().map(HttpProxy::new).transpose()?)
+            .min_tick(min_tick)
+            .exact_idle(exact_idle)
+            .nap(nap);
+        if let Some((dir, bundle_config)) = bundle.clone() {
+            emu = emu.bundle(dir, bundle_config);
+        }
+        Ok(emu)
+    });
+
+    let mut shared_screen = match &args.shared_screen {
+        Some(path) => Some(SharedScreen::create(path, screen_spec)?),
+        None => None,
+    };
+
+    let mut history = match &args.history_file {
+        Some(path) => Some(
+            File::options()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open history file {path:?}"))?,
+        ),
+        None => None,
+    };
 
     // Set up independent tasks and channels between them.
-    let (to_emu_tx, to_emu_rx) = mpsc::unbounded_channel();
-    let (from_emu_tx, mut from_emu_rx) = mpsc::unbounded_channel();
-    let (to_ui_tx, to_ui_rx) = mpsc::unbounded_channel();
+    // Network clients send their input straight to the emulator, so that they
+    // wait for it to keep up; input from the UI, which the main loop can't
+    // wait on, waits in `to_emu_pending` instead.
+    let (to_emu_tx, to_emu_rx) = mpsc::channel(runner::QUEUE_LEN);
+    let mut to_emu_pending = VecDeque::new();
+    let (from_emu_tx, mut from_emu_rx) = mpsc::channel(runner::QUEUE_LEN);
+    let (to_ui_tx, to_ui_rx) = mpsc::channel(OUTPUT_QUEUE_LEN);
     let (from_ui_tx, mut from_ui_rx) = mpsc::unbounded_channel();
-    let (to_net_tx, to_net_rx) = mpsc::unbounded_channel();
-    let (from_net_tx, mut from_net_rx) = mpsc::unbounded_channel();
+    let (to_net_tx, to_net_rx) = mpsc::channel(OUTPUT_QUEUE_LEN);
+    let (screen_tx, screen_rx) = watch::channel(None);
+    let (idle_stats_tx, idle_stats_rx) = watch::channel(None);
+    let mut to_ui = Outbox::new(to_ui_tx.clone(), OUTPUT_BUFFER_LIMIT);
+    let mut to_net = Outbox::new(to_net_tx, OUTPUT_BUFFER_LIMIT);
 
     let (quit_tx, _) = broadcast::channel(1);
 
     let q = || quit_tx.subscribe();
-    let mut emu = Task::spawn(run_emu(emu, to_emu_rx, from_emu_tx, q()));
-    let mut net = Task::spawn(run_net(args.bind, to_net_rx, from_net_tx, q()));
-    let mut ui = Task::spawn(ui::run_tui(to_ui_rx, from_ui_tx, q()));
+    let mut emu = Task::spawn(run_emu(
+        emu,
+        build,
+        args.restart_on_crash,
+        to_emu_rx,
+        from_emu_tx,
+        q(),
+    ));
+    let mut net = Task::spawn(run_net(
+        args.bind,
+        Device::CONSOLE,
+        to_net_rx,
+        to_emu_tx.clone(),
+        q(),
+    ));
+    // Devices with their own address each get an independent listener; their
+    // traffic bypasses the main console and the UI.
+    let mut routes = HashMap::new();
+    let mut route_tasks = vec![];
+    for (device, bind) in args.routes {
+        let (tx, rx) = mpsc::channel(OUTPUT_QUEUE_LEN);
+        routes.insert(device, Outbox::new(tx, OUTPUT_BUFFER_LIMIT));
+        route_tasks.push(Task::spawn(run_net(
+            bind,
+            device,
+            rx,
+            to_emu_tx.clone(),
+            q(),
+        )));
+    }
+    let (mut to_mqtt, mut mqtt) = match args.mqtt {
+        Some(broker) => {
+            let (tx, rx) = mpsc::channel(OUTPUT_QUEUE_LEN);
+            let task = Task::spawn(mqtt::run_mqtt(
+                broker,
+                args.mqtt_prefix,
+                rx,
+                to_emu_tx.clone(),
+                q(),
+            ));
+            (Some(Outbox::new(tx, OUTPUT_BUFFER_LIMIT)), Some(task))
+        }
+        None => (None, None),
+    };
+    let mut ui = if args.headless {
+        // Nothing reads what's sent to the UI, so it's dropped rather than left
+        // to fill the queue.
+        drop(to_ui_rx);
+        None
+    } else {
+        let options = TuiOptions {
+            lcd: args.lcd.then_some(LcdStyle {
+                gamma: args.gamma,
+                brightness: args.brightness,
+                lighting: args.lighting,
+            }),
+            palette,
+            console_limit: args.console_limit,
+            hyperlinks: !args.no_hyperlinks,
+            ascii: args.ascii || !ui::unicode_locale(),
+            buttons: board.button_positions(),
+        };
+        Some(Task::spawn(ui::run_tui(
+            to_ui_rx,
+            screen_rx.clone(),
+            from_ui_tx.clone(),
+            options,
+            q(),
+        )))
+    };
+    if let Some(power) = &power {
+        let controller = Controller::new(to_emu_tx.clone(), screen_rx.clone());
+        tokio::spawn(power.clone().run(controller, idle_stats_rx.clone()));
+    }
+    let mut script = args.script.map(|path| {
+        let controller = Controller::new(to_emu_tx.clone(), screen_rx.clone())
+            .device(&device)
+            .outputs_in(args.run_dir.clone())
+            .power(power.clone());
+        let to_emu_tx = to_emu_tx.clone();
+        Task::spawn(async move {
+            let result = script::run_script(path, controller).await;
+            if let Err(err) = &result {
+                if let Err(err) = bundle::request(&to_emu_tx, format!("{err:?}")).await {
+                    error!("failed to write reproducer bundle: {err:#}");
+                }
+            }
+            result
+        })
+    });
+    tokio::spawn({
+        let controller = Controller::new(to_emu_tx.clone(), screen_rx.clone());
+        let to_ui_tx = to_ui_tx.clone();
+        async move {
+            if let Err(err) = controller.install_overlay_hook().await {
+                error!("failed to install overlay hook: {err:#}");
+            }
+            match controller.eval(firmware::INFO_EXPR).await {
+                Ok(env) => {
+                    let info = firmware::FirmwareInfo::from_env(&env);
+                    info!("firmware: {info}");
+                    let _ = to_ui_tx.send(UIOutput::Firmware(info)).await;
+                }
+                Err(err) => error!("failed to get firmware info: {err:#}"),
+            }
+        }
+    });
+    let mut control = args.control_bind.map(|bind| {
+        let controller = Controller::new(to_emu_tx.clone(), screen_rx.clone())
+            .device(&device)
+            .outputs_in(args.run_dir.clone())
+            .power(power.clone());
+        Task::spawn(control::run_control(bind, controller, q()))
+    });
+    let mut http = args
+        .http_bind
+        .map(|bind| Task::spawn(http::run_http(bind, screen_rx, idle_stats_rx, q())));
+
+    let mut terminated = Box::pin(daemon::terminated());
 
     // Run main loop.
     loop {
+        // Output to routes that aren't keeping up waits for room in any of them.
+        let route_room: OptionFuture<_> = {
+            let waiting: Vec<_> = routes
+                .iter()
+                .filter(|(_, outbox)| outbox.has_pending())
+                .map(|(&device, outbox)| {
+                    let room = outbox.reserve();
+                    Box::pin(async move { (device, room.await) })
+                })
+                .collect();
+            (!waiting.is_empty()).then(|| select_all(waiting)).into()
+        };
         select! {
             output = from_emu_rx.recv() => {
                 let output = output.unwrap();
-                if let Output::Console(data) = &output {
-                    info!("output: {:?}", str::from_utf8(data));
-                    let _ = to_net_tx.send(data.to_owned());
+                match &output {
+                    Output::Console(device, data) => {
+                        info!("output ({}): {:?}", device.0, str::from_utf8(data));
+                        if let Some(route) = routes.get_mut(device) {
+                            route.send(data.to_owned());
+                            continue;
+                        }
+                        to_net.send(data.to_owned());
+                        if let Some(to_mqtt) = &mut to_mqtt {
+                            to_mqtt.send(data.to_owned());
+                        }
+                        if let Some(history) = &mut history {
+                            if let Err(err) = history.write_all(data) {
+                                error!("failed to write console history: {err}");
+                            }
+                        }
+                        if args.headless {
+                            let mut stdout = io::stdout().lock();
+                            let _ = stdout.write_all(data);
+                            let _ = stdout.flush();
+                        }
+                    }
+                    Output::Screen(_) => {}
+                    Output::Event(event) => debug!("event: {event:?}"),
+                    Output::Gesture(gesture) => debug!("gesture: {}", gesture.name()),
+                    Output::Paused(paused) => info!("paused: {paused}"),
+                    Output::IdleStats(stats) => {
+                        debug!("{stats}");
+                        idle_stats_tx.send_replace(Some(stats.clone()));
+                    }
+                }
+                match output {
+                    // Everything showing the screen, the UI included, watches
+                    // for the newest one rather than queueing them up, so a
+                    // viewer that can't keep up skips frames instead of
+                    // falling behind.
+                    Output::Screen(screen) => {
+                        if let Some(shared) = &mut shared_screen {
+                            shared.write(&screen);
+                        }
+                        screen_tx.send_replace(Some(Arc::new(*screen)));
+                    }
+                    output => to_ui.send(UIOutput::Emu(output)),
                 }
-                let _ = to_ui_tx.send(output);
             }
-            data = from_net_rx.recv() => {
-                if let Some(data) = data {
-                    let _ = to_emu_tx.send(data);
+            permit = to_ui.reserve(), if to_ui.has_pending() => to_ui.send_pending(permit),
+            permit = to_net.reserve(), if to_net.has_pending() => to_net.send_pending(permit),
+            permit = OptionFuture::from(
+                to_mqtt.as_ref().filter(|o| o.has_pending()).map(Outbox::reserve)
+            ) => to_mqtt.as_mut().unwrap().send_pending(permit),
+            ((device, permit), _, _) = route_room => {
+                routes.get_mut(&device).unwrap().send_pending(permit);
+            }
+            permit = to_emu_tx.reserve(), if !to_emu_pending.is_empty() => {
+                if let Ok(permit) = permit {
+                    permit.send(to_emu_pending.pop_front().unwrap());
                 }
             }
             input = from_ui_rx.recv() => {
                 match input.unwrap() {
                     UIInput::Quit => break,
-                    UIInput::EmuInput(input) => to_emu_tx.send(input).unwrap(),
+                    UIInput::EmuInput(input) => to_emu_pending.push_back(input),
+                    UIInput::SaveState(slot) => {
+                        tokio::spawn(report_slot(
+                            save_slot(to_emu_tx.clone(), wasm_path.clone(), slot),
+                            format!("saved state to slot {slot}"),
+                            format!("failed to save state to slot {slot}"),
+                            to_ui_tx.clone(),
+                        ));
+                    }
+                    UIInput::LoadState(slot) => {
+                        tokio::spawn(report_slot(
+                            load_slot(to_emu_tx.clone(), wasm_path.clone(), slot),
+                            format!("loaded state from slot {slot}"),
+                            format!("failed to load state from slot {slot}"),
+                            to_ui_tx.clone(),
+                        ));
+                    }
                 }
             }
 
+            _ = &mut terminated => {
+                info!("asked to terminate");
+                break;
+            }
             _ = &mut emu => break,
             _ = &mut net => break,
-            _ = &mut ui => break,
+            _ = OptionFuture::from(ui.as_mut()) => break,
+            _ = OptionFuture::from(script.as_mut()) => break,
+            _ = OptionFuture::from(control.as_mut()) => break,
+            _ = OptionFuture::from(http.as_mut()) => break,
+            _ = OptionFuture::from(mqtt.as_mut()) => break,
+            _ = OptionFuture::from(
+                (!route_tasks.is_empty()).then(|| select_all(route_tasks.iter_mut()))
+            ) => break,
         }
     }
 
@@ -330,9 +1261,36 @@ async fn _main() -> anyhow::Result<()> {
         }
     }
 
-    wait("ui", ui).await;
+    if let Some(ui) = ui {
+        wait("ui", ui).await;
+    }
     wait("emu", emu).await;
     wait("net", net).await;
+    for route in route_tasks {
+        wait("route", route).await;
+    }
+    if let Some(control) = control {
+        wait("control", control).await;
+    }
+    if let Some(http) = http {
+        wait("http", http).await;
+    }
+    if let Some(mqtt) = mqtt {
+        wait("mqtt", mqtt).await;
+    }
+
+    if let Some(profile) = profile {
+        eprint!("{}", profile.lock().unwrap());
+    }
+    if let (true, Some(power)) = (args.power_report, &power) {
+        eprint!("{}", power.usage());
+    }
+
+    // A failing script is what makes the whole run fail.
+    if let Some(script) = script {
+        info!("waiting for script...");
+        script.output().await??;
+    }
 
     info!("done, exiting!");
     Ok(())
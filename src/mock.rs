@@ -0,0 +1,334 @@
+use std::{
+    collections::VecDeque,
+    mem,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    emu::{Clock, Device, Event, Flags, Screen, ScreenSpec, PIN_COUNT},
+    machine::Machine,
+    snapshot::Snapshot,
+    touch::{Gesture, TouchMode},
+};
+
+/// Something the mock does when its clock reaches a set time.
+#[derive(Clone)]
+pub enum MockOutput {
+    /// Print to a device.
+    Console(Device, Vec<u8>),
+    /// Change the screen.
+    Screen(Screen),
+    /// Report a hardware event, such as a pin changing.
+    Event(Event),
+}
+
+/// An input the mock received, for checking what a runner delivered.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MockInput {
+    Console(Device, Vec<u8>),
+    Touch(u8, u8, bool, u8),
+    Button(usize, bool),
+    TouchMode(TouchMode),
+    SetPin(i32, bool),
+    PinWatchEvent(i32),
+}
+
+type LineHandler = Box<dyn FnMut(Device, &str) -> Option<String> + Send>;
+
+struct MockState {
+    screen: Screen,
+    screen_changed: bool,
+    output: Vec<(Device, Vec<u8>)>,
+    events: Vec<Event>,
+    /// Outputs waiting for the clock to reach their time, in order.
+    timed: VecDeque<(f64, MockOutput)>,
+    inputs: Vec<MockInput>,
+    /// What was sent to each device since its last newline.
+    partial_lines: Vec<(Device, Vec<u8>)>,
+    on_line: Option<LineHandler>,
+    idle_delay: i32,
+    idle_calls: usize,
+    touch_mode: TouchMode,
+    buttons: usize,
+}
+
+/// A stand-in for [`Emulator`] that runs no firmware, for exercising the
+/// runner, the TUI, and the network code without a firmware file. Its outputs
+/// are scripted up front with [`MockEmulator::at`] or in response to console
+/// lines with [`MockEmulator::on_line`], and it records every input it gets.
+/// Clones share the same scripted outputs, recorded inputs, and clock, so a
+/// test can keep one to inspect while a runner owns another; each has its own
+/// pins and flash.
+///
+/// [`Emulator`]: crate::emu::Emulator
+#[derive(Clone)]
+pub struct MockEmulator {
+    state: Arc<Mutex<MockState>>,
+    pins: Vec<bool>,
+    flash: Vec<u8>,
+    clock: Clock,
+    flags: Flags,
+}
+
+impl Default for MockEmulator {
+    fn default() -> Self {
+        Self::new(ScreenSpec::default())
+    }
+}
+
+impl MockEmulator {
+    pub fn new(spec: ScreenSpec) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(MockState {
+                screen: Screen::new(spec),
+                screen_changed: true,
+                output: vec![],
+                events: vec![],
+                timed: VecDeque::new(),
+                inputs: vec![],
+                partial_lines: vec![],
+                on_line: None,
+                idle_delay: 10,
+                idle_calls: 0,
+                touch_mode: TouchMode::default(),
+                buttons: 1,
+            })),
+            pins: vec![false; PIN_COUNT],
+            flash: vec![],
+            clock: Clock::default(),
+            flags: Flags::default(),
+        }
+    }
+
+    /// Gives the mock a flash image, which is otherwise empty.
+    pub fn with_flash(mut self, flash: Vec<u8>) -> Self {
+        self.flash = flash;
+        self
+    }
+
+    /// Schedules an output for when the clock reaches `ms` after it does now.
+    pub fn at(&self, ms: f64, output: MockOutput) -> &Self {
+        let at = self.clock.now_ms() + ms;
+        let mut state = self.state.lock().unwrap();
+        let ind = state.timed.partition_point(|(t, _)| *t <= at);
+        state.timed.insert(ind, (at, output));
+        self
+    }
+
+    /// Prints to the console on the next idle call.
+    pub fn print(&self, text: &str) -> &Self {
+        self.at(0.0, MockOutput::Console(Device::CONSOLE, text.into()))
+    }
+
+    /// Calls `handler` with each complete line sent to a device, printing what
+    /// it returns to that device, as a stand-in for the firmware's
+    /// interpreter.
+    pub fn on_line(
+        &self,
+        handler: impl FnMut(Device, &str) -> Option<String> + Send + 'static,
+    ) -> &Self {
+        self.state.lock().unwrap().on_line = Some(Box::new(handler));
+        self
+    }
+
+    /// Sets how many milliseconds each idle call asks to be left alone for.
+    pub fn idle_delay(&self, ms: i32) -> &Self {
+        self.state.lock().unwrap().idle_delay = ms;
+        self
+    }
+
+    /// Sets how many buttons the mock has.
+    pub fn buttons(&self, count: usize) -> &Self {
+        self.state.lock().unwrap().buttons = count;
+        self
+    }
+
+    /// Takes the inputs received since the last call.
+    pub fn take_inputs(&self) -> Vec<MockInput> {
+        mem::take(&mut self.state.lock().unwrap().inputs)
+    }
+
+    /// How many times the mock has been idled.
+    pub fn idle_calls(&self) -> usize {
+        self.state.lock().unwrap().idle_calls
+    }
+
+    pub fn touch_mode(&self) -> TouchMode {
+        self.state.lock().unwrap().touch_mode
+    }
+
+    fn record(&self, input: MockInput) {
+        self.state.lock().unwrap().inputs.push(input);
+    }
+}
+
+impl Machine for MockEmulator {
+    fn idle(&mut self) -> anyhow::Result<i32> {
+        let now = self.clock.now_ms();
+        let mut state = self.state.lock().unwrap();
+        state.idle_calls += 1;
+        while state.timed.front().is_some_and(|(at, _)| *at <= now) {
+            match state.timed.pop_front().unwrap().1 {
+                MockOutput::Console(device, data) => state.output.push((device, data)),
+                MockOutput::Screen(screen) => {
+                    state.screen = screen;
+                    state.screen_changed = true;
+                }
+                MockOutput::Event(event) => {
+                    // The firmware setting a pin changes it for reads too.
+                    if let Event::Pin(pin, value) = event {
+                        if let Some(p) =
+                            usize::try_from(pin).ok().and_then(|i| self.pins.get_mut(i))
+                        {
+                            *p = value;
+                        }
+                    }
+                    state.events.push(event);
+                }
+            }
+        }
+        Ok(state.idle_delay)
+    }
+
+    fn gfx_changed(&mut self) -> anyhow::Result<bool> {
+        Ok(mem::take(&mut self.state.lock().unwrap().screen_changed))
+    }
+
+    fn get_screen(&mut self) -> anyhow::Result<Screen> {
+        Ok(self.state.lock().unwrap().screen.clone())
+    }
+
+    fn get_screen_into(&mut self, screen: &mut Screen) -> anyhow::Result<()> {
+        screen.clone_from(&self.state.lock().unwrap().screen);
+        Ok(())
+    }
+
+    fn handle_io(&mut self) -> anyhow::Result<Vec<(Device, Vec<u8>)>> {
+        Ok(mem::take(&mut self.state.lock().unwrap().output))
+    }
+
+    fn take_events(&mut self) -> Vec<Event> {
+        mem::take(&mut self.state.lock().unwrap().events)
+    }
+
+    /// The mock recognizes no gestures.
+    fn take_gestures(&mut self) -> Vec<Gesture> {
+        vec![]
+    }
+
+    fn push_string_to(&mut self, device: Device, chars: &[u8]) -> anyhow::Result<()> {
+        self.record(MockInput::Console(device, chars.to_vec()));
+        let mut state = self.state.lock().unwrap();
+        let ind = match state.partial_lines.iter().position(|(d, _)| *d == device) {
+            Some(ind) => ind,
+            None => {
+                state.partial_lines.push((device, vec![]));
+                state.partial_lines.len() - 1
+            }
+        };
+        let mut lines = vec![];
+        for &ch in chars {
+            let line = &mut state.partial_lines[ind].1;
+            if ch == b'\n' {
+                lines.push(String::from_utf8_lossy(&mem::take(line)).into_owned());
+            } else {
+                line.push(ch);
+            }
+        }
+        for line in lines {
+            if let Some(reply) = state.on_line.as_mut().and_then(|f| f(device, &line)) {
+                state.output.push((device, reply.into_bytes()));
+            }
+        }
+        Ok(())
+    }
+
+    fn send_touch_with_pressure(
+        &mut self,
+        x: u8,
+        y: u8,
+        on: bool,
+        pressure: u8,
+    ) -> anyhow::Result<()> {
+        self.record(MockInput::Touch(x, y, on, pressure));
+        Ok(())
+    }
+
+    fn set_touch_mode(&mut self, mode: TouchMode) {
+        self.record(MockInput::TouchMode(mode));
+        self.state.lock().unwrap().touch_mode = mode;
+    }
+
+    fn button_count(&self) -> usize {
+        self.state.lock().unwrap().buttons
+    }
+
+    fn press_button(&mut self, button: usize, on: bool) -> anyhow::Result<()> {
+        if button == 0 || button > self.button_count() {
+            anyhow::bail!("no such button {button}");
+        }
+        self.record(MockInput::Button(button, on));
+        Ok(())
+    }
+
+    fn pin(&self, pin: i32) -> Option<bool> {
+        usize::try_from(pin)
+            .ok()
+            .and_then(|i| self.pins.get(i).copied())
+    }
+
+    fn pins(&self) -> &[bool] {
+        &self.pins
+    }
+
+    fn set_pin(&mut self, pin: i32, value: bool) -> anyhow::Result<()> {
+        self.record(MockInput::SetPin(pin, value));
+        match usize::try_from(pin).ok().and_then(|i| self.pins.get_mut(i)) {
+            Some(p) => {
+                *p = value;
+                Ok(())
+            }
+            None => anyhow::bail!("no such pin {pin}"),
+        }
+    }
+
+    fn send_pin_watch_event(&mut self, pin: i32) -> anyhow::Result<()> {
+        self.record(MockInput::PinWatchEvent(pin));
+        Ok(())
+    }
+
+    /// The mock has no charger.
+    fn charge_pin(&self) -> Option<(i32, bool)> {
+        None
+    }
+
+    fn flash(&self) -> &[u8] {
+        &self.flash
+    }
+
+    fn snapshot(&mut self) -> anyhow::Result<Snapshot> {
+        Ok(Snapshot {
+            memory: vec![],
+            globals: vec![],
+            pins: self.pins.clone(),
+            flash: self.flash.clone(),
+            clock_ms: self.clock.now_ms(),
+        })
+    }
+
+    fn restore(&mut self, snapshot: &Snapshot) -> anyhow::Result<()> {
+        self.pins.clone_from(&snapshot.pins);
+        self.flash.clone_from(&snapshot.flash);
+        self.clock.set_ms(snapshot.clock_ms);
+        self.state.lock().unwrap().screen_changed = true;
+        Ok(())
+    }
+
+    fn clock(&self) -> Clock {
+        self.clock.clone()
+    }
+
+    fn flags(&self) -> Flags {
+        self.flags.clone()
+    }
+}
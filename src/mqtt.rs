@@ -0,0 +1,305 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use log::{debug, error, info, warn};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    select,
+    sync::{broadcast::Receiver, mpsc},
+    time::{interval, sleep, timeout},
+};
+
+use crate::{
+    device::{gb_message_line, gb_watch_message},
+    emu::{Device, Input},
+};
+
+/// How often the broker expects to hear from the bridge, which pings it at
+/// half that.
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+/// How long to give the broker to accept a connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait before reconnecting after losing the broker.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const PINGREQ: u8 = 0xc0;
+
+/// Appends an MQTT string: its length as two bytes, then its bytes.
+fn put_str(buf: &mut Vec<u8>, s: &[u8]) {
+    buf.extend((s.len() as u16).to_be_bytes());
+    buf.extend(s);
+}
+
+/// Frames a packet of some type with its remaining length, which MQTT encodes
+/// seven bits at a time, least significant first.
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![kind];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        out.push(if len > 0 { byte | 0x80 } else { byte });
+        if len == 0 {
+            break;
+        }
+    }
+    out.extend(body);
+    out
+}
+
+/// Splits the first whole packet off the front of `buf`, if there is one,
+/// into its first byte and its body.
+fn take_packet(buf: &mut Vec<u8>) -> anyhow::Result<Option<(u8, Vec<u8>)>> {
+    let mut len = 0;
+    let mut header = 1;
+    loop {
+        let Some(&byte) = buf.get(header) else {
+            return Ok(None);
+        };
+        len |= usize::from(byte & 0x7f) << (7 * (header - 1));
+        header += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if header > 4 {
+            anyhow::bail!("malformed packet length from the broker");
+        }
+    }
+    if buf.len() < header + len {
+        return Ok(None);
+    }
+    let kind = buf[0];
+    let body = buf[header..header + len].to_vec();
+    buf.drain(..header + len);
+    Ok(Some((kind, body)))
+}
+
+/// Reads the next whole packet from the broker, keeping anything after it in
+/// `buf` for next time.
+async fn read_packet(read: &mut OwnedReadHalf, buf: &mut Vec<u8>) -> anyhow::Result<(u8, Vec<u8>)> {
+    let mut chunk = [0; 4096];
+    loop {
+        if let Some(packet) = take_packet(buf)? {
+            return Ok(packet);
+        }
+        let n = read.read(&mut chunk).await?;
+        if n == 0 {
+            anyhow::bail!("the broker closed the connection");
+        }
+        buf.extend(&chunk[..n]);
+    }
+}
+
+/// Turns a message published to one of the bridge's topics into the line
+/// Gadgetbridge would send the watch: a whole message on `<prefix>/gb`, or its
+/// fields on `<prefix>/gb/<type>`.
+fn gb_line(prefix: &str, topic: &str, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut message: Value =
+        serde_json::from_slice(payload).context("the payload isn't valid JSON")?;
+    let Some(fields) = message.as_object_mut() else {
+        anyhow::bail!("the payload isn't a JSON object");
+    };
+    let rest = topic.strip_prefix(prefix).unwrap_or(topic);
+    if let Some(kind) = rest.strip_prefix("/gb/") {
+        fields.insert("t".to_owned(), kind.into());
+    }
+    Ok(gb_message_line(&message))
+}
+
+struct Session {
+    read: OwnedReadHalf,
+    write: OwnedWriteHalf,
+    buf: Vec<u8>,
+}
+
+impl Session {
+    async fn connect(broker: &str, prefix: &str) -> anyhow::Result<Self> {
+        let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(broker))
+            .await
+            .context("Timed out connecting")??;
+        let (read, write) = stream.into_split();
+        let mut session = Self {
+            read,
+            write,
+            buf: vec![],
+        };
+
+        let mut body = vec![];
+        put_str(&mut body, b"MQTT");
+        // Protocol level 4 (3.1.1), with a clean session.
+        body.extend([4, 0x02]);
+        body.extend((KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+        put_str(
+            &mut body,
+            format!("banglejs-emu-{}", std::process::id()).as_bytes(),
+        );
+        session.write.write_all(&packet(CONNECT, &body)).await?;
+        let (kind, body) = timeout(
+            CONNECT_TIMEOUT,
+            read_packet(&mut session.read, &mut session.buf),
+        )
+        .await
+        .context("Timed out waiting for the broker to accept the connection")??;
+        match (kind & 0xf0, body.get(1)) {
+            (CONNACK, Some(0)) => {}
+            (CONNACK, code) => anyhow::bail!("the broker refused the connection (code {code:?})"),
+            _ => anyhow::bail!("expected a CONNACK from the broker, got {kind:#04x}"),
+        }
+
+        let mut body = vec![];
+        body.extend(1u16.to_be_bytes());
+        for filter in [format!("{prefix}/gb"), format!("{prefix}/gb/+")] {
+            put_str(&mut body, filter.as_bytes());
+            body.push(0);
+        }
+        session.write.write_all(&packet(SUBSCRIBE, &body)).await?;
+        Ok(session)
+    }
+
+    async fn publish(&mut self, topic: &str, payload: &[u8]) -> anyhow::Result<()> {
+        let mut body = vec![];
+        put_str(&mut body, topic.as_bytes());
+        body.extend(payload);
+        self.write.write_all(&packet(PUBLISH, &body)).await?;
+        Ok(())
+    }
+
+    /// Handles a packet from the broker, returning the topic and payload if
+    /// it's a message.
+    async fn handle(
+        &mut self,
+        kind: u8,
+        body: Vec<u8>,
+    ) -> anyhow::Result<Option<(String, Vec<u8>)>> {
+        if kind & 0xf0 != PUBLISH {
+            debug!("mqtt packet {kind:#04x} ({} bytes)", body.len());
+            return Ok(None);
+        }
+        let qos = (kind >> 1) & 3;
+        let topic = match &body[..] {
+            [hi, lo, rest @ ..] => rest.get(..usize::from(u16::from_be_bytes([*hi, *lo]))),
+            _ => None,
+        };
+        let Some(topic) = topic else {
+            anyhow::bail!("malformed PUBLISH from the broker");
+        };
+        let topic_end = 2 + topic.len();
+        let topic = String::from_utf8_lossy(topic).into_owned();
+        let mut payload = &body[topic_end..];
+        if qos > 0 {
+            // The subscriptions are at QoS 0, so the broker shouldn't do this,
+            // but one that does would keep resending without an ack.
+            let Some(id) = payload.get(..2) else {
+                anyhow::bail!("malformed PUBLISH from the broker");
+            };
+            if qos == 1 {
+                self.write.write_all(&packet(PUBACK, id)).await?;
+            }
+            payload = &payload[2..];
+        }
+        Ok(Some((topic, payload.to_vec())))
+    }
+}
+
+/// Bridges Gadgetbridge messages between the watch and an MQTT broker:
+/// messages published to `<prefix>/gb` (a whole message) or `<prefix>/gb/<type>`
+/// (its other fields) are sent to the watch as Gadgetbridge would, and the
+/// messages the watch sends back, recognized in the console output on `rx`,
+/// are published to `<prefix>/watch/<type>`. Losing the broker only pauses the
+/// bridge until it can reconnect.
+pub async fn run_mqtt(
+    broker: String,
+    prefix: String,
+    mut rx: mpsc::Receiver<Vec<u8>>,
+    tx: mpsc::Sender<Input>,
+    mut quit: Receiver<()>,
+) -> anyhow::Result<()> {
+    let mut session = Session::connect(&broker, &prefix)
+        .await
+        .with_context(|| format!("Failed to connect to MQTT broker {broker:?}"))?;
+    info!("connected to MQTT broker {broker}");
+    let mut bridge = Bridge {
+        prefix,
+        line: vec![],
+    };
+    loop {
+        match bridge.run(&mut session, &mut rx, &tx, &mut quit).await {
+            Ok(()) => return Ok(()),
+            Err(err) => error!("lost MQTT broker {broker}: {err:#}"),
+        }
+        session = loop {
+            select! {
+                _ = quit.recv() => return Ok(()),
+                _ = sleep(RECONNECT_DELAY) => {}
+            }
+            match Session::connect(&broker, &bridge.prefix).await {
+                Ok(session) => break session,
+                Err(err) => error!("failed to reconnect to MQTT broker {broker}: {err:#}"),
+            }
+        };
+        info!("reconnected to MQTT broker {broker}");
+    }
+}
+
+struct Bridge {
+    prefix: String,
+    /// Console output since the last newline.
+    line: Vec<u8>,
+}
+
+impl Bridge {
+    /// Passes messages back and forth until told to quit, or until something
+    /// goes wrong with the session.
+    async fn run(
+        &mut self,
+        session: &mut Session,
+        rx: &mut mpsc::Receiver<Vec<u8>>,
+        tx: &mpsc::Sender<Input>,
+        quit: &mut Receiver<()>,
+    ) -> anyhow::Result<()> {
+        let mut pings = interval(KEEP_ALIVE / 2);
+        loop {
+            select! {
+                _ = quit.recv() => return Ok(()),
+                packet = read_packet(&mut session.read, &mut session.buf) => {
+                    let (kind, body) = packet?;
+                    let Some((topic, payload)) = session.handle(kind, body).await? else {
+                        continue;
+                    };
+                    match gb_line(&self.prefix, &topic, &payload) {
+                        Ok(data) => {
+                            let _ = tx.send(Input::Console(Device::CONSOLE, data)).await;
+                        }
+                        Err(err) => warn!("ignoring MQTT message on {topic}: {err:#}"),
+                    }
+                }
+                data = rx.recv() => {
+                    let Some(data) = data else {
+                        return Ok(());
+                    };
+                    self.line.extend(data);
+                    while let Some(end) = self.line.iter().position(|&c| c == b'\n') {
+                        let text = String::from_utf8_lossy(&self.line[..end]).into_owned();
+                        self.line.drain(..=end);
+                        if let Some(message) = gb_watch_message(&text) {
+                            let kind = message["t"].as_str().unwrap();
+                            let topic = format!("{}/watch/{kind}", self.prefix);
+                            session.publish(&topic, text.trim().as_bytes()).await?;
+                        }
+                    }
+                }
+                _ = pings.tick() => session.write.write_all(&packet(PINGREQ, &[])).await?,
+            }
+        }
+    }
+}
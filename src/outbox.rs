@@ -0,0 +1,164 @@
+use std::{collections::VecDeque, future::Future};
+
+use log::warn;
+use tokio::sync::mpsc::{error::TrySendError, OwnedPermit, Sender};
+
+/// The largest chunk console output is merged into while it waits.
+const CHUNK_LEN: usize = 4096;
+
+/// Output that can wait in an [`Outbox`].
+pub trait Item: Sized {
+    /// Merges `next` into this item if one item can stand for both, or hands
+    /// it back otherwise.
+    fn merge(&mut self, next: Self) -> Option<Self>;
+
+    /// How many bytes this counts for against the outbox's limit; items that
+    /// count for nothing are never dropped.
+    fn size(&self) -> usize;
+}
+
+impl Item for Vec<u8> {
+    fn merge(&mut self, next: Self) -> Option<Self> {
+        if self.len() + next.len() > CHUNK_LEN {
+            return Some(next);
+        }
+        self.extend(next);
+        None
+    }
+
+    fn size(&self) -> usize {
+        self.len()
+    }
+}
+
+/// The sending end of a bounded channel to a consumer that might not keep up
+/// with the emulator, like a slow terminal or network client.
+///
+/// Sending never waits: whatever doesn't fit in the channel waits here instead,
+/// merged where possible, and once that reaches the limit the oldest of it is
+/// dropped, like a serial port without flow control would. The owner has to
+/// [`reserve`](Self::reserve) room and pass it to [`send_pending`](Self::send_pending)
+/// to move waiting items along.
+pub struct Outbox<T> {
+    tx: Sender<T>,
+    pending: VecDeque<T>,
+    pending_size: usize,
+    limit: usize,
+    /// How many bytes have been dropped since that was last logged.
+    dropped: usize,
+}
+
+impl<T: Item + Send + 'static> Outbox<T> {
+    pub fn new(tx: Sender<T>, limit: usize) -> Self {
+        Self {
+            tx,
+            pending: VecDeque::new(),
+            pending_size: 0,
+            limit,
+            dropped: 0,
+        }
+    }
+
+    pub fn send(&mut self, item: T) {
+        let item = if self.pending.is_empty() {
+            match self.tx.try_send(item) {
+                Ok(()) | Err(TrySendError::Closed(_)) => return,
+                Err(TrySendError::Full(item)) => item,
+            }
+        } else {
+            item
+        };
+        let item = match self.pending.back_mut() {
+            Some(back) => {
+                let before = back.size();
+                let rest = back.merge(item);
+                self.pending_size = self.pending_size - before + back.size();
+                rest
+            }
+            None => Some(item),
+        };
+        if let Some(item) = item {
+            self.pending_size += item.size();
+            self.pending.push_back(item);
+        }
+
+        while self.pending_size > self.limit {
+            let Some(i) = self.pending.iter().position(|item| item.size() > 0) else {
+                break;
+            };
+            let size = self.pending.remove(i).unwrap().size();
+            self.pending_size -= size;
+            self.dropped += size;
+        }
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Waits for room in the channel, without borrowing the outbox so that it
+    /// can be sent to in the meantime.
+    pub fn reserve(&self) -> impl Future<Output = Option<OwnedPermit<T>>> {
+        let tx = self.tx.clone();
+        async move { tx.reserve_owned().await.ok() }
+    }
+
+    /// Sends the oldest waiting item, if any, with room from [`reserve`](Self::reserve).
+    pub fn send_pending(&mut self, permit: Option<OwnedPermit<T>>) {
+        if self.dropped > 0 {
+            warn!(
+                "dropped {} bytes of output that wasn't keeping up",
+                self.dropped
+            );
+            self.dropped = 0;
+        }
+        let Some(item) = self.pending.pop_front() else {
+            return;
+        };
+        self.pending_size -= item.size();
+        match permit {
+            Some(permit) => {
+                permit.send(item);
+            }
+            // Nobody is listening any more.
+            None => {
+                self.pending.clear();
+                self.pending_size = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn waiting_output_merges_or_drops() {
+        // Neither consumer reads until everything has been sent, and the slow
+        // one has too little room to hold the backlog.
+        let (fast_tx, mut fast_rx) = mpsc::channel(1);
+        let (slow_tx, mut slow_rx) = mpsc::channel(1);
+        let mut fast = Outbox::new(fast_tx, 1 << 20);
+        let mut slow = Outbox::new(slow_tx, 8);
+        for chunk in ["one\n", "two\n", "three\n"] {
+            fast.send(chunk.as_bytes().to_vec());
+            slow.send(chunk.as_bytes().to_vec());
+        }
+
+        let mut received = vec![fast_rx.recv().await.unwrap()];
+        while fast.has_pending() {
+            fast.send_pending(fast.reserve().await);
+            received.push(fast_rx.recv().await.unwrap());
+        }
+        // What was waiting was merged into one chunk.
+        assert_eq!(received, [&b"one\n"[..], b"two\nthree\n"]);
+
+        assert_eq!(slow_rx.recv().await.unwrap(), b"one\n");
+        assert!(!slow.has_pending());
+        drop(slow);
+        assert!(slow_rx.recv().await.is_none());
+    }
+}
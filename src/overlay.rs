@@ -0,0 +1,88 @@
+use serde_json::Value;
+
+use crate::emu::{Color, Screen, ScreenSpec};
+
+/// A JavaScript expression that wraps `Bangle.setLCDOverlay` so that the
+/// current overlay can be read back with [`CAPTURE_EXPR`]. The firmware
+/// composes the overlay while sending the framebuffer to the LCD, so it doesn't
+/// otherwise appear in the framebuffer the emulator reads the screen from.
+/// Installing the wrapper is idempotent, but it's lost when the firmware
+/// resets its JavaScript state (e.g. on `load()`). On boards without `Bangle`,
+/// which have no overlay, it evaluates to `false` and [`CAPTURE_EXPR`] to
+/// `null`.
+pub const HOOK_EXPR: &str = "(function(){var B=global.Bangle,f=B&&B.setLCDOverlay;\
+     if(!f||f.emu)return false;B.setLCDOverlay=function(i,x,y,o){var c=B._emuOverlay;\
+     if(!i){if(!o||!o.id||!c||c.id==o.id)delete B._emuOverlay;}\
+     else B._emuOverlay={i:i,x:x|0,y:y|0,id:o&&o.id};\
+     return f.apply(B,arguments);};B.setLCDOverlay.emu=1;return true;})()";
+
+/// A JavaScript expression that renders the current overlay into an
+/// off-screen buffer and evaluates to its position and pixels, for passing to
+/// [`Overlay::from_capture`]. Pixels the overlay image leaves transparent are
+/// left as a color that doesn't otherwise come up in 3-bit images.
+pub const CAPTURE_EXPR: &str = "(function(){var o=global.Bangle&&Bangle._emuOverlay;\
+     if(!o)return null;var i=o.i;if(i.asImage)i=i.asImage();\
+     var m=g.imageMetrics(i),w=m.width,h=m.height,K=0x0821,b=Graphics.createArrayBuffer(w,h,16),p='';\
+     b.setBgColor(K).clear();b.drawImage(i,0,0);\
+     for(var y=0;y<h;y++)for(var x=0;x<w;x++){var c=b.getPixel(x,y);\
+     p+=c==K?'.':(c>>15&1|c>>9&2|c>>2&4);}\
+     return {x:o.x,y:o.y,width:w,height:h,pixels:p};})()";
+
+/// The image shown on the overlay layer and where it is.
+pub struct Overlay {
+    pub x: i64,
+    pub y: i64,
+    pub width: usize,
+    pub height: usize,
+    /// The overlay's pixels row by row, with `None` for transparent ones.
+    pixels: Vec<Option<Color>>,
+}
+
+impl Overlay {
+    pub fn from_capture(value: &Value) -> anyhow::Result<Option<Self>> {
+        if value.is_null() {
+            return Ok(None);
+        }
+        let bad = || anyhow::format_err!("unexpected overlay capture result");
+        let pixels: Vec<Option<Color>> = value["pixels"]
+            .as_str()
+            .ok_or_else(bad)?
+            .chars()
+            .map(|c| c.to_digit(8).map(|v| Color::new(v as u8)))
+            .collect();
+        let overlay = Self {
+            x: value["x"].as_i64().ok_or_else(bad)?,
+            y: value["y"].as_i64().ok_or_else(bad)?,
+            width: value["width"].as_u64().ok_or_else(bad)? as usize,
+            height: value["height"].as_u64().ok_or_else(bad)? as usize,
+            pixels,
+        };
+        if overlay.pixels.len() != overlay.width * overlay.height {
+            return Err(bad());
+        }
+        Ok(Some(overlay))
+    }
+
+    /// Draws the overlay's opaque pixels onto a screen, clipping it to the
+    /// screen's bounds.
+    pub fn compose(&self, screen: &mut Screen) {
+        for (ind, pixel) in self.pixels.iter().enumerate() {
+            let Some(color) = pixel else {
+                continue;
+            };
+            let x = self.x + (ind % self.width) as i64;
+            let y = self.y + (ind / self.width) as i64;
+            if (0..screen.width() as i64).contains(&x) && (0..screen.height() as i64).contains(&y) {
+                screen.set(x as usize, y as usize, *color);
+            }
+        }
+    }
+
+    /// Gets the overlay layer alone, at its position on an otherwise black
+    /// screen.
+    pub fn layer(&self, spec: ScreenSpec) -> Screen {
+        let mut screen = Screen::new(spec);
+        self.compose(&mut screen);
+        screen
+    }
+}
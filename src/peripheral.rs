@@ -0,0 +1,43 @@
+use crate::engine::Val;
+
+/// A simulated piece of hardware attached to the emulated chip. Every method
+/// has a default that ignores the access, so a peripheral only implements the
+/// ones it cares about. Peripherals are asked in the order they were added, and
+/// the first one to handle a read or a call wins.
+pub trait Peripheral: Send {
+    /// Called when the firmware sets a GPIO pin.
+    fn pin_written(&mut self, _pin: i32, _value: bool) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called when the firmware reads a GPIO pin; returning `None` leaves it to
+    /// the emulator's own pin state.
+    fn read_pin(&mut self, _pin: i32) -> anyhow::Result<Option<bool>> {
+        Ok(None)
+    }
+
+    /// Called when the firmware writes to an I2C device, returning whether the
+    /// device at `addr` acknowledged it.
+    fn i2c_write(&mut self, _bus: i32, _addr: i32, _data: &[u8]) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    /// Called when the firmware reads from an I2C device, returning whether the
+    /// device at `addr` acknowledged it (and filled `buf`).
+    fn i2c_read(&mut self, _bus: i32, _addr: i32, _buf: &mut [u8]) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    /// Whether this peripheral provides the host function `name`, so that a
+    /// firmware importing it can be loaded even though the emulator doesn't
+    /// provide it itself.
+    fn provides(&self, _name: &str) -> bool {
+        false
+    }
+
+    /// Called when the firmware calls a host function that the emulator
+    /// doesn't provide itself, returning whether this peripheral handled it.
+    fn call(&mut self, _name: &str, _params: &[Val], _results: &mut [Val]) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+}
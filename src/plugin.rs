@@ -0,0 +1,142 @@
+use std::path::Path;
+
+use anyhow::Context;
+use wasmtime::{Engine, Instance, Linker, Memory, Module, Store, TypedFunc};
+use wasmtime_wasi::{sync::WasiCtxBuilder, WasiCtx};
+
+use crate::{engine::Val, peripheral::Peripheral, wasmtime_engine::from_wasmtime};
+
+/// A peripheral implemented by a WebAssembly module. The module can export any
+/// of:
+///
+/// - `pin_written(pin: i32, value: i32)`
+/// - `read_pin(pin: i32) -> i32`, returning -1 to leave the pin alone
+/// - `i2c_buffer() -> i32`, the address of a buffer in its `memory` for I2C
+///   data, along with `i2c_write(bus: i32, addr: i32, len: i32) -> i32` and
+///   `i2c_read(bus: i32, addr: i32, len: i32) -> i32`, returning nonzero to
+///   acknowledge
+/// - `host_<name>`, with the same signature as the firmware's import `name`,
+///   to provide that host function
+///
+/// Modules built for WASI can print to stderr for debugging.
+pub struct WasmPlugin {
+    store: Store<WasiCtx>,
+    instance: Instance,
+    pin_written: Option<TypedFunc<(i32, i32), ()>>,
+    read_pin: Option<TypedFunc<i32, i32>>,
+    i2c: Option<I2cFuncs>,
+    /// The host functions it exports, without the `host_` prefix.
+    hosts: Vec<String>,
+}
+
+struct I2cFuncs {
+    memory: Memory,
+    buffer: TypedFunc<(), i32>,
+    write: TypedFunc<(i32, i32, i32), i32>,
+    read: TypedFunc<(i32, i32, i32), i32>,
+}
+
+impl WasmPlugin {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let engine = Engine::default();
+        let module = Module::from_file(&engine, path)
+            .with_context(|| format!("Failed to load plugin {path:?}"))?;
+        let mut linker = Linker::new(&engine);
+        wasmtime_wasi::add_to_linker(&mut linker, |s| s)?;
+        let mut store = Store::new(&engine, WasiCtxBuilder::new().inherit_stderr().build());
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .with_context(|| format!("Failed to instantiate plugin {path:?}"))?;
+
+        let pin_written = instance.get_typed_func(&mut store, "pin_written").ok();
+        let read_pin = instance.get_typed_func(&mut store, "read_pin").ok();
+        let i2c = (|| {
+            Some(I2cFuncs {
+                memory: instance.get_memory(&mut store, "memory")?,
+                buffer: instance.get_typed_func(&mut store, "i2c_buffer").ok()?,
+                write: instance.get_typed_func(&mut store, "i2c_write").ok()?,
+                read: instance.get_typed_func(&mut store, "i2c_read").ok()?,
+            })
+        })();
+        let hosts = module
+            .exports()
+            .filter_map(|export| export.name().strip_prefix("host_"))
+            .map(str::to_owned)
+            .collect();
+        Ok(Self {
+            store,
+            instance,
+            pin_written,
+            read_pin,
+            i2c,
+            hosts,
+        })
+    }
+}
+
+impl Peripheral for WasmPlugin {
+    fn pin_written(&mut self, pin: i32, value: bool) -> anyhow::Result<()> {
+        if let Some(f) = &self.pin_written {
+            f.call(&mut self.store, (pin, value.into()))?;
+        }
+        Ok(())
+    }
+
+    fn read_pin(&mut self, pin: i32) -> anyhow::Result<Option<bool>> {
+        let Some(f) = &self.read_pin else {
+            return Ok(None);
+        };
+        Ok(match f.call(&mut self.store, pin)? {
+            -1 => None,
+            value => Some(value != 0),
+        })
+    }
+
+    fn i2c_write(&mut self, bus: i32, addr: i32, data: &[u8]) -> anyhow::Result<bool> {
+        let Some(i2c) = &self.i2c else {
+            return Ok(false);
+        };
+        let ptr = i2c.buffer.call(&mut self.store, ())?;
+        i2c.memory.write(&mut self.store, ptr as usize, data)?;
+        Ok(i2c
+            .write
+            .call(&mut self.store, (bus, addr, data.len() as i32))?
+            != 0)
+    }
+
+    fn i2c_read(&mut self, bus: i32, addr: i32, buf: &mut [u8]) -> anyhow::Result<bool> {
+        let Some(i2c) = &self.i2c else {
+            return Ok(false);
+        };
+        if i2c
+            .read
+            .call(&mut self.store, (bus, addr, buf.len() as i32))?
+            == 0
+        {
+            return Ok(false);
+        }
+        let ptr = i2c.buffer.call(&mut self.store, ())?;
+        i2c.memory.read(&self.store, ptr as usize, buf)?;
+        Ok(true)
+    }
+
+    fn provides(&self, name: &str) -> bool {
+        self.hosts.iter().any(|host| host == name)
+    }
+
+    fn call(&mut self, name: &str, params: &[Val], results: &mut [Val]) -> anyhow::Result<bool> {
+        let Some(f) = self
+            .instance
+            .get_func(&mut self.store, &format!("host_{name}"))
+        else {
+            return Ok(false);
+        };
+        let params: Vec<_> = params.iter().map(|&v| v.into()).collect();
+        let mut vals: Vec<_> = results.iter().map(|&v| v.into()).collect();
+        f.call(&mut self.store, &params, &mut vals)?;
+        for (result, val) in results.iter_mut().zip(&vals) {
+            *result = from_wasmtime(val)?;
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,57 @@
+use std::{fs, path::Path};
+
+use anyhow::Context;
+use wasmtime::{Engine, Module};
+
+use crate::{emu::Emulator, firmware::sha256_hex, peripheral::Peripheral};
+
+/// A compiled firmware that any number of isolated emulators can be created
+/// from, without compiling the firmware again for each one. The compiled code
+/// is shared between the emulators, and their memories start out as
+/// copy-on-write mappings of the module's initial memory.
+#[derive(Clone)]
+pub struct EmulatorPool {
+    engine: Engine,
+    module: Module,
+    sha256: String,
+}
+
+impl EmulatorPool {
+    pub fn new<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let data = fs::read(path).with_context(|| format!("Failed to read firmware {path:?}"))?;
+        let engine = Engine::default();
+        let module = Module::new(&engine, &data)?;
+        Ok(Self {
+            engine,
+            module,
+            sha256: sha256_hex(&data),
+        })
+    }
+
+    /// The SHA-256 of the firmware file, as lowercase hex.
+    pub fn sha256(&self) -> &str {
+        &self.sha256
+    }
+
+    /// Creates a fresh emulator with empty flash.
+    pub fn instantiate(&self) -> anyhow::Result<Emulator> {
+        Emulator::from_module(&self.engine, &self.module)
+    }
+
+    /// Creates a fresh emulator with empty flash and peripherals attached,
+    /// which can provide host functions the firmware imports.
+    pub fn instantiate_with_peripherals(
+        &self,
+        peripherals: Vec<Box<dyn Peripheral>>,
+    ) -> anyhow::Result<Emulator> {
+        Emulator::from_module_with(&self.engine, &self.module, peripherals)
+    }
+
+    /// Creates a fresh emulator with the given initial flash contents.
+    pub fn instantiate_with_flash(&self, data: &[u8]) -> anyhow::Result<Emulator> {
+        let mut emu = self.instantiate()?;
+        emu.write_flash(data);
+        Ok(emu)
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+use tokio::{sync::watch, time::interval};
+
+use crate::{battery::Battery, control::Controller, emu::IdleStats};
+
+/// How often the monitor checks what's powered on.
+const TICK: Duration = Duration::from_secs(1);
+
+/// Which power-hungry parts of the watch are on.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub struct Powered {
+    pub lcd: bool,
+    pub backlight: bool,
+    pub gps: bool,
+    pub hrm: bool,
+    pub compass: bool,
+}
+
+/// What the firmware reports each tick: its time, in seconds, what's on, and
+/// whether it's charging.
+#[derive(Deserialize)]
+struct Sample {
+    time: f64,
+    #[serde(flatten)]
+    on: Powered,
+    charging: bool,
+}
+
+/// Gets a [`Sample`], first replacing `E.getBattery` to report a level if
+/// there's a battery model, since reloading the firmware undoes that. Boards
+/// without the functions for checking the LCD and backlight have them on.
+fn sample_expr(level: Option<f64>) -> String {
+    let battery = match level {
+        Some(level) => format!("E.getBattery=function(){{return {};}};", level.round()),
+        None => String::new(),
+    };
+    format!(
+        "(function(){{{battery}var B=global.Bangle||{{}};\
+         function on(f){{return !!(B[f]&&B[f]());}}\
+         var lcd=B.isLCDOn?B.isLCDOn():true;\
+         return {{time:getTime(),lcd:lcd,backlight:B.isBacklightOn?B.isBacklightOn():lcd,\
+         gps:on('isGPSOn'),hrm:on('isHRMOn'),compass:on('isCompassOn'),\
+         charging:on('isCharging')}};}})()"
+    )
+}
+
+/// How long, in seconds, each part of the watch has been powered, and the CPU
+/// running rather than asleep, out of the time passed on the watch's clock.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PowerUsage {
+    pub elapsed: f64,
+    pub lcd: f64,
+    pub backlight: f64,
+    pub gps: f64,
+    pub hrm: f64,
+    pub compass: f64,
+    /// Going by how long the firmware's idle calls took.
+    pub cpu: f64,
+}
+
+impl fmt::Display for PowerUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<12} {:>12} {:>8}", "powered", "seconds", "percent")?;
+        for (name, secs) in [
+            ("LCD", self.lcd),
+            ("backlight", self.backlight),
+            ("GPS", self.gps),
+            ("HRM", self.hrm),
+            ("compass", self.compass),
+            ("CPU", self.cpu),
+        ] {
+            let percent = if self.elapsed > 0.0 {
+                secs / self.elapsed * 100.0
+            } else {
+                0.0
+            };
+            writeln!(f, "{name:<12} {secs:>12.1} {percent:>8.1}")?;
+        }
+        writeln!(f, "{:<12} {:>12.1}", "elapsed", self.elapsed)
+    }
+}
+
+/// Tracks what's powered on a running emulator, sampling it every second and
+/// taking it to have stayed that way until the next sample, and drains the
+/// battery model, if there is one, to match.
+#[derive(Clone)]
+pub struct PowerMonitor {
+    usage: Arc<Mutex<PowerUsage>>,
+    battery: Option<Battery>,
+}
+
+impl PowerMonitor {
+    pub fn new(battery: Option<Battery>) -> Self {
+        Self {
+            usage: Default::default(),
+            battery,
+        }
+    }
+
+    pub fn usage(&self) -> PowerUsage {
+        self.usage.lock().unwrap().clone()
+    }
+
+    pub fn battery(&self) -> Option<&Battery> {
+        self.battery.as_ref()
+    }
+
+    /// Monitors the emulator until it stops.
+    pub async fn run(self, controller: Controller, idle_stats: watch::Receiver<Option<IdleStats>>) {
+        let mut ticks = interval(TICK);
+        let mut last: Option<Sample> = None;
+        // How long the firmware had spent in idle calls at the last sample.
+        let mut last_busy = Duration::ZERO;
+        loop {
+            ticks.tick().await;
+            let level = self.battery.as_ref().map(|b| b.report().level);
+            let sample = match controller.eval(&sample_expr(level)).await {
+                Ok(sample) => sample,
+                Err(err) if controller.is_running() => {
+                    debug!("power monitor skipping a tick: {err:#}");
+                    continue;
+                }
+                Err(_) => break,
+            };
+            let sample: Sample = match serde_json::from_value(sample) {
+                Ok(sample) => sample,
+                Err(err) => {
+                    debug!("power monitor got an unexpected sample: {err}");
+                    continue;
+                }
+            };
+
+            // The idle statistics start over if the emulator is restarted.
+            let busy = idle_stats.borrow().as_ref().map_or(last_busy, |s| s.total);
+            let cpu = busy.saturating_sub(last_busy).as_secs_f64();
+            last_busy = busy;
+
+            if let Some(last) = &last {
+                // The clock can be set back, which doesn't undo anything.
+                let secs = (sample.time - last.time).max(0.0);
+                let on = last.on;
+                let during = |on| if on { secs } else { 0.0 };
+                let mut usage = self.usage.lock().unwrap();
+                usage.elapsed += secs;
+                usage.lcd += during(on.lcd);
+                usage.backlight += during(on.backlight);
+                usage.gps += during(on.gps);
+                usage.hrm += during(on.hrm);
+                usage.compass += during(on.compass);
+                usage.cpu += cpu;
+                if let Some(battery) = &self.battery {
+                    battery.drain(secs / 3600.0, on, last.charging, cpu / 3600.0);
+                }
+            }
+            last = Some(sample);
+        }
+    }
+}
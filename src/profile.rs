@@ -0,0 +1,64 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// A call profile shared between the emulators it's collected from, which outlives
+/// any of them (e.g. across restarts).
+pub type SharedCallProfile = Arc<Mutex<CallProfile>>;
+
+#[derive(Clone, Copy, Default)]
+struct CallStats {
+    count: u64,
+    total: Duration,
+    max: Duration,
+}
+
+/// How many times each host function and call into the firmware was made and
+/// how long they took.
+#[derive(Default)]
+pub struct CallProfile {
+    calls: HashMap<&'static str, CallStats>,
+}
+
+impl CallProfile {
+    pub fn shared() -> SharedCallProfile {
+        Arc::default()
+    }
+
+    pub fn record(&mut self, name: &'static str, elapsed: Duration) {
+        let stats = self.calls.entry(name).or_default();
+        stats.count += 1;
+        stats.total += elapsed;
+        stats.max = stats.max.max(elapsed);
+    }
+}
+
+// Calls into the firmware include the time of any host functions they call, so
+// the totals overlap.
+impl fmt::Display for CallProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut calls: Vec<_> = self.calls.iter().collect();
+        calls.sort_by_key(|(_, stats)| Reverse(stats.total));
+        writeln!(
+            f,
+            "{:<24} {:>12} {:>12} {:>10} {:>10}",
+            "function", "calls", "total ms", "mean us", "max us"
+        )?;
+        for (name, stats) in calls {
+            let total = stats.total.as_secs_f64();
+            writeln!(
+                f,
+                "{name:<24} {:>12} {:>12.1} {:>10.2} {:>10.0}",
+                stats.count,
+                total * 1e3,
+                total * 1e6 / stats.count as f64,
+                stats.max.as_secs_f64() * 1e6
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+use log::info;
+use serde_json::{Map, Value};
+use tokio::sync::mpsc::WeakSender;
+
+use crate::{
+    device::{gb_message_line, gb_watch_message},
+    emu::{Device, Input},
+    http_proxy::HttpProxy,
+};
+
+/// A canned reply to a kind of message the watch sends Gadgetbridge, such as
+/// a request to its HTTP proxy.
+#[derive(Clone, Debug)]
+pub struct Response {
+    /// The fields a message has to have, with these values, to be replied to.
+    pub when: Map<String, Value>,
+    /// How long the phone takes to reply, on the watch's clock.
+    pub after: Duration,
+    /// The message to send back. The request's `id`, if it has one, is copied
+    /// into it unless it has its own, the way replies are matched up with
+    /// requests.
+    pub reply: Map<String, Value>,
+}
+
+impl Response {
+    fn matches(&self, message: &Map<String, Value>) -> bool {
+        self.when
+            .iter()
+            .all(|(field, value)| message.get(field) == Some(value))
+    }
+}
+
+/// Replies to the messages the watch sends Gadgetbridge, picked out of its
+/// console output, with the first of the responses that matches each, or
+/// failing that, for HTTP requests, from the proxy if there is one.
+#[derive(Clone, Debug, Default)]
+pub struct Responder {
+    responses: Vec<Response>,
+    proxy: Option<HttpProxy>,
+    /// Console output since the last newline.
+    line: Vec<u8>,
+}
+
+impl Responder {
+    pub fn new(responses: Vec<Response>, proxy: Option<HttpProxy>) -> Self {
+        Self {
+            responses,
+            proxy,
+            line: vec![],
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.responses.is_empty() && self.proxy.is_none()
+    }
+
+    /// Takes some console output, returning the canned replies to any
+    /// messages it finishes, each with how long to wait before sending it.
+    /// Replies from the HTTP proxy are sent on `tx` when they come in.
+    pub fn feed(&mut self, chars: &[u8], tx: &WeakSender<Input>) -> Vec<(Duration, Input)> {
+        let mut replies = vec![];
+        self.line.extend(chars);
+        while let Some(end) = self.line.iter().position(|&c| c == b'\n') {
+            let text = String::from_utf8_lossy(&self.line[..end]).into_owned();
+            self.line.drain(..=end);
+            let Some(message) = gb_watch_message(&text) else {
+                continue;
+            };
+            let Some(response) = self.responses.iter().find(|r| r.matches(&message)) else {
+                if let Some(proxy) = &self.proxy {
+                    if message["t"] == "http" {
+                        proxy.spawn(message, tx.clone());
+                    }
+                }
+                continue;
+            };
+            let mut reply = response.reply.clone();
+            if let Some(id) = message.get("id") {
+                reply.entry("id").or_insert_with(|| id.clone());
+            }
+            info!(
+                "replying to the watch's {} message in {:?}",
+                message["t"].as_str().unwrap(),
+                response.after
+            );
+            let line = gb_message_line(&Value::Object(reply));
+            replies.push((response.after, Input::Console(Device::CONSOLE, line)));
+        }
+        replies
+    }
+}
@@ -1,22 +1,48 @@
 use std::{
+    collections::VecDeque,
+    path::PathBuf,
     sync::{Arc, Mutex},
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use futures::future::Either;
 use futures_timer::Delay;
-use log::info;
+use log::{debug, error, info};
+use regex::Regex;
 use tokio::{
     select,
-    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    sync::{
+        mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender, WeakSender},
+        oneshot,
+    },
 };
 
 use crate::{
-    emu::{Emulator, Flags, Input, Output, BTN1},
+    bundle::Recorder,
+    device::{bluetooth_expr, gb_set_time_line},
+    emu::{
+        Buzz, ClockAdjustment, Device, Emulator, Event, Flags, IdleStats, Input, Output, Screen,
+        Step, WaitResult, BTN1, DEFAULT_TOUCH_PRESSURE, VIBRATE,
+    },
+    eval::EvalChannel,
     futures_extras::OptionFuture,
+    http_proxy::HttpProxy,
+    machine::Machine,
+    responder::{Responder, Response},
+    touch::Gesture,
 };
 
-pub struct AsyncRunner {
-    emu: Emulator,
+/// Runs an emulated watch in real time, feeding it inputs and sending on its
+/// outputs; normally an [`Emulator`], but any [`Machine`] will do.
+pub struct AsyncRunner<M = Emulator> {
+    emu: M,
+    schedule: Vec<(Duration, Input)>,
+    responses: Vec<Response>,
+    http_proxy: Option<HttpProxy>,
+    bundle: Option<(PathBuf, String)>,
+    min_tick: Duration,
+    exact_idle: bool,
+    nap: bool,
 }
 
 async fn watchdog(
@@ -33,7 +59,11 @@ async fn watchdog(
     loop {
         select! {
             button = button_rx.recv() => {
-                if button.unwrap() {
+                // The input channel closing means the runner has gone away.
+                let Some(button) = button else {
+                    break;
+                };
+                if button {
                     let now = Instant::now();
                     reset_deadline = Some(now + Duration::from_millis(1500));
                     interrupt_deadline = Some(now + Duration::from_millis(2000));
@@ -61,92 +91,949 @@ async fn watchdog(
     }
 }
 
-impl AsyncRunner {
-    pub fn new(emu: Emulator) -> Self {
-        Self { emu }
+impl<M: Machine> AsyncRunner<M> {
+    pub fn new(emu: M) -> Self {
+        Self {
+            emu,
+            schedule: vec![],
+            responses: vec![],
+            http_proxy: None,
+            bundle: None,
+            min_tick: DEFAULT_MIN_TICK,
+            exact_idle: false,
+            nap: false,
+        }
+    }
+
+    /// Once nothing has happened for a while and the firmware is only asking
+    /// to be idled now and then, idles it at most every [`NAP_TICK`] until
+    /// something does, to save power. Its timers may fire late while napping.
+    pub fn nap(mut self, nap: bool) -> Self {
+        self.nap = nap;
+        self
+    }
+
+    /// Sets the shortest time the firmware is left alone between bursts of
+    /// idle calls, when it isn't waiting for an input. Raising it trades
+    /// timer accuracy for CPU time on machines with coarse timers.
+    pub fn min_tick(mut self, min_tick: Duration) -> Self {
+        self.min_tick = min_tick;
+        self
+    }
+
+    /// Sleeps for exactly as long as the firmware asks between bursts of idle
+    /// calls, on the runtime's own timer, with the minimum tick only applying
+    /// when it has more to do straight away.
+    pub fn exact_idle(mut self, exact_idle: bool) -> Self {
+        self.exact_idle = exact_idle;
+        self
+    }
+
+    /// Delivers inputs at the given times on the firmware's clock, relative to
+    /// when the runner starts.
+    pub fn schedule(mut self, inputs: Vec<(Duration, Input)>) -> Self {
+        self.schedule = inputs;
+        self
+    }
+
+    /// Replies to messages the watch sends Gadgetbridge with canned
+    /// responses, delivering each reply as a scheduled input.
+    pub fn respond(mut self, responses: Vec<Response>) -> Self {
+        self.responses = responses;
+        self
     }
 
+    /// Makes the watch's requests to Gadgetbridge's HTTP proxy from the host,
+    /// for those that no canned response matches, delivering the replies as
+    /// inputs.
+    pub fn http_proxy(mut self, proxy: Option<HttpProxy>) -> Self {
+        self.http_proxy = proxy;
+        self
+    }
+
+    /// Records the run, to write a reproducer bundle to `dir` if the firmware
+    /// fails or one is requested with [`Input::WriteBundle`]. `config` is the
+    /// text of the bundle's config file, from [`Config::bundle_config`].
+    ///
+    /// [`Config::bundle_config`]: crate::config::Config::bundle_config
+    pub fn bundle(mut self, dir: PathBuf, config: String) -> Self {
+        self.bundle = Some((dir, config));
+        self
+    }
+
+    /// Runs the emulator until `input` closes while it's paused or it fails.
+    /// Outputs wait in the runner, with only the newest screen kept, until
+    /// there's room for them in `output`, holding up the emulator: a consumer
+    /// that doesn't keep up slows it down rather than have outputs pile up.
     pub async fn run(
         self,
-        mut input: UnboundedReceiver<Input>,
-        output: UnboundedSender<Output>,
+        mut input: Receiver<Input>,
+        output: Sender<Output>,
     ) -> anyhow::Result<()> {
-        let (input2_tx, mut input2_rx) = mpsc::unbounded_channel();
+        let (input2_tx, mut input2_rx) = mpsc::channel(QUEUE_LEN);
+        let replies_tx = input2_tx.downgrade();
         let (to_watchdog_tx, to_watchdog_rx) = mpsc::unbounded_channel();
         let (wake_tx, mut wake_rx) = mpsc::unbounded_channel();
 
         tokio::spawn(async move {
             while let Some(x) = input.recv().await {
-                if let Input::Button(b) = x {
+                // Holding BTN1 is what resets the watch.
+                if let Input::Button(1, b) = x {
                     to_watchdog_tx.send(b).unwrap();
                 }
-                input2_tx.send(x).unwrap();
+                input2_tx.send(x).await.unwrap();
             }
         });
         tokio::spawn(watchdog(to_watchdog_rx, self.emu.flags(), wake_tx));
 
+        let recorder = self
+            .bundle
+            .map(|(dir, config)| Recorder::new(dir, config, &self.emu));
         let emu = Arc::new(Mutex::new(self.emu));
-        let send_output = |chars: Vec<u8>| {
-            if !chars.is_empty() {
-                let _ = output.send(Output::Console(chars));
-            }
+        let clock = emu.lock().unwrap().clock();
+        let mut schedule = Schedule::new(self.schedule, clock.now_ms());
+        let mut sink = Sink {
+            tx: output,
+            pending: VecDeque::new(),
+            evals: EvalChannel::default(),
+            waits: vec![],
+            idle_waits: vec![],
+            recorder,
+            responder: Responder::new(self.responses, self.http_proxy),
+            replies: vec![],
+            inputs: replies_tx,
+            buzzes: vec![],
+            buzz_start: None,
+            gestures: vec![],
+            frames: 0,
         };
 
-        {
-            let mut emu = emu.lock().unwrap();
-            emu.send_pin_watch_event(BTN1)?;
-            send_output(emu.handle_io()?);
-        }
-
-        loop {
-            let mut delay = 1;
-            for _ in 0..5 {
-                let d = tokio::task::spawn_blocking({
-                    let emu = Arc::clone(&emu);
-                    move || emu.lock().unwrap().idle()
-                })
-                .await??;
-                if d > 0 {
-                    delay = d as u64;
-                    break;
-                }
-            }
+        let result: anyhow::Result<()> = async {
             {
                 let mut emu = emu.lock().unwrap();
-                if emu.gfx_changed()? {
-                    let screen = emu.get_screen()?;
-                    let _ = output.send(Output::Screen(Box::new(screen)));
-                }
-                send_output(emu.handle_io()?);
+                emu.send_pin_watch_event(BTN1)?;
+                sink.flush(&mut *emu, false)?;
             }
 
-            let mut first = true;
+            let mut paused = false;
+            let mut stats = IdleStats::default();
+            let mut stats_sent = Instant::now();
+            // When an input, output, or screen change last happened.
+            let mut active = Instant::now();
+            let mut napping = false;
             loop {
-                let timeout =
-                    Delay::new(Duration::from_millis(if first { delay.max(10) } else { 1 }));
-                first = false;
-                select! {
-                    _ = timeout => {
-                        break;
+                // Zero means the firmware wants to run again right away.
+                let mut delay = 0;
+                if !paused {
+                    // Keep idling while the firmware has more to do, but only
+                    // for so long, so that an app that never lets up still
+                    // gets its output and screen flushed and inputs handled.
+                    let start = Instant::now();
+                    stats.bursts += 1;
+                    loop {
+                        let call_start = Instant::now();
+                        let d = idle(&emu).await?;
+                        let elapsed = call_start.elapsed();
+                        stats.calls += 1;
+                        stats.total += elapsed;
+                        stats.max = stats.max.max(elapsed);
+                        stats.recent_max = stats.recent_max.max(elapsed);
+                        if d > 0 {
+                            delay = d as u64;
+                            break;
+                        }
+                        if start.elapsed() >= IDLE_BUDGET {
+                            stats.busy_bursts += 1;
+                            break;
+                        }
+                    }
+                    if stats_sent.elapsed() >= STATS_INTERVAL {
+                        sink.send(Output::IdleStats(stats.clone()));
+                        stats.recent_max = Duration::ZERO;
+                        stats_sent = Instant::now();
+                    }
+                    {
+                        let mut emu = emu.lock().unwrap();
+                        schedule.run_due(&mut *emu, &mut sink, clock.now_ms())?;
+                        if sink.flush(&mut *emu, true)? {
+                            active = Instant::now();
+                        }
                     }
-                    _ = wake_rx.recv() => {}
-                    s = input2_rx.recv() => {
-                        if let Some(s) = s {
+                    sink.check_idle(delay as f64);
+                    sink.check_deadlines(clock.now_ms());
+                }
+
+                let nap = self.nap
+                    && active.elapsed() >= NAP_AFTER
+                    && Duration::from_millis(delay) >= NAP_THRESHOLD;
+                if nap != napping {
+                    napping = nap;
+                    debug!("{}", if napping { "napping" } else { "done napping" });
+                }
+
+                // Inputs are handled as they arrive, each one bringing the
+                // next idle forward so the firmware can respond to it, but
+                // never pushing it back: however fast inputs come in, the
+                // firmware runs again by the deadline.
+                let wait = match Duration::from_millis(delay) {
+                    Duration::ZERO => self.min_tick,
+                    wait if self.exact_idle => wait,
+                    wait => wait.max(self.min_tick),
+                };
+                let wait = if napping { wait.max(NAP_TICK) } else { wait };
+                let mut deadline = Instant::now() + wait;
+                loop {
+                    sink.drain().await;
+                    // While paused, only inputs move things along.
+                    let timeout: OptionFuture<_> = (!paused)
+                        .then(|| {
+                            if self.exact_idle {
+                                Either::Left(Box::pin(tokio::time::sleep_until(deadline.into())))
+                            } else {
+                                Either::Right(Delay::new(
+                                    deadline.saturating_duration_since(Instant::now()),
+                                ))
+                            }
+                        })
+                        .into();
+                    select! {
+                        _ = timeout => {
+                            break;
+                        }
+                        _ = wake_rx.recv() => {}
+                        s = input2_rx.recv() => {
+                            let Some(s) = s else {
+                                if paused {
+                                    // Nothing can resume us now.
+                                    return Ok(());
+                                }
+                                continue;
+                            };
+                            active = Instant::now();
+                            napping = false;
+                            deadline = deadline.min(Instant::now() + INPUT_RESPONSE);
+                            if let Some(description) = describe(&s) {
+                                sink.send(Output::Event(Event::Input(description)));
+                            }
+                            if let Some(event) = input_event(&s) {
+                                sink.send(Output::Event(event));
+                            }
+                            if let Some(recorder) = &mut sink.recorder {
+                                recorder.record_input(clock.now_ms(), &s);
+                            }
+                            let s = match s {
+                                Input::Pause(p) => {
+                                    if p != paused {
+                                        paused = p;
+                                        if paused {
+                                            clock.stop();
+                                        } else {
+                                            clock.start();
+                                        }
+                                        info!("{}", if paused { "paused" } else { "resumed" });
+                                        sink.send(Output::Paused(paused));
+                                    }
+                                    if paused {
+                                        continue;
+                                    }
+                                    break;
+                                }
+                                Input::Step(step) => {
+                                    if paused {
+                                        step_paused(&emu, step, &mut sink, &mut schedule).await?;
+                                    } else {
+                                        info!("ignoring step while running");
+                                    }
+                                    continue;
+                                }
+                                Input::WaitFor(regex, ms, reply) => {
+                                    sink.waits.push(ConsoleWait {
+                                        regex,
+                                        deadline_ms: clock.now_ms() + ms,
+                                        text: String::new(),
+                                        reply,
+                                    });
+                                    if paused {
+                                        // Run on virtual time until the wait is
+                                        // over, as if stepping.
+                                        while !sink.waits.is_empty() {
+                                            let step = Step::Millis(TICK_MS);
+                                            step_paused(&emu, step, &mut sink, &mut schedule).await?;
+                                            sink.check_deadlines(clock.now_ms());
+                                            sink.drain().await;
+                                        }
+                                    }
+                                    continue;
+                                }
+                                Input::WaitIdle(quiet_ms, ms, reply) => {
+                                    sink.idle_waits.push(IdleWait {
+                                        quiet_ms,
+                                        deadline_ms: clock.now_ms() + ms,
+                                        reply,
+                                    });
+                                    if paused {
+                                        while !sink.idle_waits.is_empty() {
+                                            let step = Step::Millis(TICK_MS);
+                                            let d = step_paused(&emu, step, &mut sink, &mut schedule).await?;
+                                            sink.check_idle(d as f64);
+                                            sink.check_deadlines(clock.now_ms());
+                                            sink.drain().await;
+                                        }
+                                    }
+                                    continue;
+                                }
+                                Input::Eval(expr, reply) => {
+                                    Input::Console(Device::CONSOLE, sink.evals.request(&expr, reply))
+                                }
+                                Input::ReadPin(pin, reply) => {
+                                    let _ = reply.send(emu.lock().unwrap().pin(pin));
+                                    continue;
+                                }
+                                Input::TakeBuzzes(reply) => {
+                                    let _ = reply.send(std::mem::take(&mut sink.buzzes));
+                                    continue;
+                                }
+                                Input::TakeGestures(reply) => {
+                                    let _ = reply.send(std::mem::take(&mut sink.gestures));
+                                    continue;
+                                }
+                                Input::ReadPins(reply) => {
+                                    let _ = reply.send(emu.lock().unwrap().pins().to_vec());
+                                    continue;
+                                }
+                                Input::ReadFlash(reply) => {
+                                    let _ = reply.send(emu.lock().unwrap().flash().to_vec());
+                                    continue;
+                                }
+                                Input::Snapshot(reply) => {
+                                    let snapshot = emu.lock().unwrap().snapshot()?;
+                                    let _ = reply.send(Box::new(snapshot));
+                                    continue;
+                                }
+                                Input::WriteBundle(reason, reply) => {
+                                    let result = match &sink.recorder {
+                                        Some(recorder) => {
+                                            recorder.write(&mut *emu.lock().unwrap(), &reason)
+                                        }
+                                        None => Ok(()),
+                                    };
+                                    let _ = reply.send(result);
+                                    continue;
+                                }
+                                Input::Restore(snapshot) => {
+                                    let mut emu = emu.lock().unwrap();
+                                    emu.restore(&snapshot)?;
+                                    // Show the restored screen even if the
+                                    // firmware doesn't think it has changed.
+                                    sink.send_screen(Box::new(emu.get_screen()?));
+                                    continue;
+                                }
+                                s => s,
+                            };
                             tokio::task::spawn_blocking({
                                 let emu = Arc::clone(&emu);
-                                move || -> anyhow::Result<()> {
-                                    let mut emu = emu.lock().unwrap();
-                                    match s {
-                                        Input::Console(s) => emu.push_string(&s),
-                                        Input::Touch(x, y, on) => emu.send_touch(x, y, on),
-                                        Input::Button(on) => emu.press_button(on),
+                                move || apply_input(&mut *emu.lock().unwrap(), s)
+                            }).await??;
+
+                            // Evaluations still need the firmware to run, but with
+                            // its clock stopped nothing else should change.
+                            if paused {
+                                for _ in 0..PAUSED_EVAL_IDLES {
+                                    if !sink.evals.has_pending() {
+                                        break;
                                     }
+                                    idle(&emu).await?;
+                                    sink.flush(&mut *emu.lock().unwrap(), true)?;
                                 }
-                            }).await??;
+                            }
                         }
                     }
                 }
             }
         }
+        .await;
+        sink.drain().await;
+        if let Err(err) = &result {
+            if let Some(recorder) = &sink.recorder {
+                if let Err(err) = recorder.write(&mut *emu.lock().unwrap(), &format!("{err:?}")) {
+                    error!("failed to write reproducer bundle: {err:#}");
+                }
+            }
+        }
+        result
+    }
+}
+
+/// How many inputs can wait for the runner, and how many outputs for whatever
+/// takes them, before more have to wait.
+pub const QUEUE_LEN: usize = 64;
+/// The default for [`AsyncRunner::min_tick`].
+pub const DEFAULT_MIN_TICK: Duration = Duration::from_millis(10);
+/// The longest the firmware is idled back to back while it has work pending.
+const IDLE_BUDGET: Duration = Duration::from_millis(20);
+/// How long nothing has to happen before napping.
+const NAP_AFTER: Duration = Duration::from_secs(5);
+/// How long the firmware has to ask to be left alone for before napping.
+const NAP_THRESHOLD: Duration = Duration::from_millis(100);
+/// The shortest time between idles while napping.
+pub const NAP_TICK: Duration = Duration::from_secs(1);
+/// How often idle statistics are reported.
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+/// How soon after an input the firmware is idled to handle it.
+const INPUT_RESPONSE: Duration = Duration::from_millis(1);
+/// How many times the firmware is idled to answer an evaluation while paused.
+const PAUSED_EVAL_IDLES: usize = 1000;
+/// How far the clock moves between idle calls when stepping by time, or when
+/// running flat out on a virtual clock.
+pub(crate) const TICK_MS: f64 = 10.0;
+/// How much console output a wait keeps to match against.
+const WAIT_TEXT_LIMIT: usize = 1 << 16;
+/// The most buzzes kept for taking, dropping the oldest beyond that.
+const BUZZ_LIMIT: usize = 1000;
+/// The most gestures kept for taking, dropping the oldest beyond that.
+const GESTURE_LIMIT: usize = 1000;
+
+/// A pending request to wait for the firmware to have nothing to do for a
+/// while.
+struct IdleWait {
+    quiet_ms: f64,
+    deadline_ms: f64,
+    reply: oneshot::Sender<bool>,
+}
+
+/// A pending request to wait for console output matching a pattern.
+struct ConsoleWait {
+    regex: Regex,
+    deadline_ms: f64,
+    /// The console output since the wait started.
+    text: String,
+    reply: oneshot::Sender<WaitResult>,
+}
+
+/// Where the emulator's outputs go: eval results are picked out of the console
+/// output, which is checked against any waits, and the rest is sent on.
+struct Sink {
+    tx: Sender<Output>,
+    /// Outputs waiting for room in `tx`.
+    pending: VecDeque<Output>,
+    evals: EvalChannel,
+    waits: Vec<ConsoleWait>,
+    idle_waits: Vec<IdleWait>,
+    recorder: Option<Recorder>,
+    responder: Responder,
+    /// Replies from the responder, with when to deliver them on the
+    /// firmware's clock, waiting to be added to the schedule.
+    replies: Vec<(f64, Input)>,
+    /// Where replies that take a while, like the HTTP proxy's, are sent.
+    inputs: WeakSender<Input>,
+    /// Buzzes of the vibration motor that haven't been taken yet.
+    buzzes: Vec<Buzz>,
+    /// When the vibration motor turned on, if it's on.
+    buzz_start: Option<f64>,
+    /// Recognized gestures that haven't been taken yet.
+    gestures: Vec<Gesture>,
+    /// How many screen updates have been sent.
+    frames: u64,
+}
+
+impl Sink {
+    /// Queues an output to be sent, replacing any screen still waiting with a
+    /// new one.
+    fn send(&mut self, output: Output) {
+        if let Output::Screen(_) = output {
+            self.pending.retain(|o| !matches!(o, Output::Screen(_)));
+        }
+        self.pending.push_back(output);
+    }
+
+    /// Sends the waiting outputs, waiting for room for them.
+    async fn drain(&mut self) {
+        while let Some(output) = self.pending.pop_front() {
+            if self.tx.send(output).await.is_err() {
+                // Nothing's listening any more.
+                self.pending.clear();
+            }
+        }
+    }
+
+    /// Sends a screen update, numbering it as the next frame.
+    fn send_screen(&mut self, mut screen: Box<Screen>) {
+        self.frames += 1;
+        screen.set_frame(self.frames);
+        self.send(Output::Screen(screen));
+    }
+
+    /// Sends the emulator's console output, hardware events, and, if
+    /// requested, any screen update, returning whether there were any.
+    fn flush(&mut self, emu: &mut impl Machine, screen: bool) -> anyhow::Result<bool> {
+        let mut any = false;
+        if screen && emu.gfx_changed()? {
+            let mut screen = Box::<Screen>::default();
+            emu.get_screen_into(&mut screen)?;
+            self.send_screen(screen);
+            any = true;
+        }
+        for (device, chars) in emu.handle_io()? {
+            let chars = self.evals.filter(device, chars);
+            if !chars.is_empty() {
+                if let Some(recorder) = &mut self.recorder {
+                    recorder.record_console(&chars);
+                }
+                self.check_waits(&chars);
+                if device == Device::CONSOLE && !self.responder.is_empty() {
+                    let now_ms = emu.clock().now_ms();
+                    for (after, input) in self.responder.feed(&chars, &self.inputs) {
+                        self.replies
+                            .push((now_ms + after.as_secs_f64() * 1000.0, input));
+                    }
+                }
+                self.send(Output::Console(device, chars));
+                any = true;
+            }
+        }
+        for event in emu.take_events() {
+            if let Event::Pin(VIBRATE, on) = event {
+                self.record_buzz(emu.clock().now_ms(), on);
+            }
+            self.send(Output::Event(event));
+            any = true;
+        }
+        for gesture in emu.take_gestures() {
+            if self.gestures.len() >= GESTURE_LIMIT {
+                self.gestures.remove(0);
+            }
+            self.gestures.push(gesture);
+            self.send(Output::Gesture(gesture));
+            any = true;
+        }
+        Ok(any)
+    }
+
+    fn record_buzz(&mut self, now_ms: f64, on: bool) {
+        match (on, self.buzz_start) {
+            (true, None) => self.buzz_start = Some(now_ms),
+            (false, Some(start_ms)) => {
+                if self.buzzes.len() >= BUZZ_LIMIT {
+                    self.buzzes.remove(0);
+                }
+                self.buzzes.push(Buzz {
+                    start_ms,
+                    duration_ms: now_ms - start_ms,
+                });
+                self.buzz_start = None;
+            }
+            _ => {}
+        }
+    }
+
+    fn check_waits(&mut self, chars: &[u8]) {
+        let chars = String::from_utf8_lossy(chars);
+        let mut waits = vec![];
+        for mut wait in self.waits.drain(..) {
+            wait.text.push_str(&chars);
+            if wait.text.len() > WAIT_TEXT_LIMIT {
+                let mut cut = wait.text.len() - WAIT_TEXT_LIMIT;
+                while !wait.text.is_char_boundary(cut) {
+                    cut += 1;
+                }
+                wait.text.drain(..cut);
+            }
+            match wait.regex.find(&wait.text) {
+                Some(m) => {
+                    let _ = wait.reply.send(WaitResult::Matched(m.as_str().to_owned()));
+                }
+                None => waits.push(wait),
+            }
+        }
+        self.waits = waits;
+    }
+
+    /// Finishes the waits for the firmware to go idle that are satisfied by
+    /// it having just asked to be left alone for `delay_ms`.
+    fn check_idle(&mut self, delay_ms: f64) {
+        if self.evals.has_pending() {
+            return;
+        }
+        let (done, waits) = self
+            .idle_waits
+            .drain(..)
+            .partition(|w| delay_ms >= w.quiet_ms);
+        self.idle_waits = waits;
+        for wait in done {
+            let _ = wait.reply.send(true);
+        }
+    }
+
+    fn check_deadlines(&mut self, now_ms: f64) {
+        let (expired, waits) = self
+            .waits
+            .drain(..)
+            .partition(|w| now_ms >= w.deadline_ms || w.reply.is_closed());
+        self.waits = waits;
+        for wait in expired {
+            let _ = wait.reply.send(WaitResult::Elapsed);
+        }
+        let (expired, waits) = self
+            .idle_waits
+            .drain(..)
+            .partition(|w| now_ms >= w.deadline_ms || w.reply.is_closed());
+        self.idle_waits = waits;
+        for wait in expired {
+            let _ = wait.reply.send(false);
+        }
+    }
+}
+
+/// Inputs waiting to be delivered at set times, each with what it's from for
+/// describing it.
+struct Schedule(VecDeque<(f64, Input, &'static str)>);
+
+impl Schedule {
+    fn new(mut inputs: Vec<(Duration, Input)>, start_ms: f64) -> Self {
+        inputs.sort_by_key(|(at, _)| *at);
+        Self(
+            inputs
+                .into_iter()
+                .map(|(at, input)| (start_ms + at.as_secs_f64() * 1000.0, input, "scheduled"))
+                .collect(),
+        )
+    }
+
+    fn run_due(
+        &mut self,
+        emu: &mut impl Machine,
+        sink: &mut Sink,
+        now_ms: f64,
+    ) -> anyhow::Result<()> {
+        for (at, input) in sink.replies.drain(..) {
+            let i = self.0.partition_point(|(t, ..)| *t <= at);
+            self.0.insert(i, (at, input, "reply"));
+        }
+        while self.0.front().is_some_and(|(at, ..)| *at <= now_ms) {
+            let (_, input, from) = self.0.pop_front().unwrap();
+            if let Some(description) = describe(&input) {
+                sink.send(Output::Event(Event::Input(format!(
+                    "{description} ({from})"
+                ))));
+            }
+            if let Some(event) = input_event(&input) {
+                sink.send(Output::Event(event));
+            }
+            if let Some(recorder) = &mut sink.recorder {
+                recorder.record_input(now_ms, &input);
+            }
+            apply_input(emu, input)?;
+        }
+        Ok(())
+    }
+}
+
+/// Delivers an input that goes straight to the firmware.
+pub(crate) fn apply_input(emu: &mut impl Machine, input: Input) -> anyhow::Result<()> {
+    match input {
+        Input::Console(d, s) => emu.push_string_to(d, &s),
+        Input::Touch(x, y, on, pressure) => {
+            emu.send_touch_with_pressure(x, y, on, pressure.unwrap_or(DEFAULT_TOUCH_PRESSURE))
+        }
+        Input::Button(button, on) => {
+            if button == 0 || button > emu.button_count() {
+                error!("ignoring input for nonexistent button {button}");
+                return Ok(());
+            }
+            emu.press_button(button, on)
+        }
+        Input::TouchMode(mode) => {
+            emu.set_touch_mode(mode);
+            Ok(())
+        }
+        Input::AdjustClock(ClockAdjustment::Drift(ppm)) => {
+            emu.clock().set_drift_ppm(ppm);
+            Ok(())
+        }
+        Input::AdjustClock(ClockAdjustment::Skew(ms)) => {
+            emu.clock().skew(ms);
+            Ok(())
+        }
+        Input::TimeSync(offset_ms, timezone) => {
+            let phone_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs_f64()
+                * 1000.0
+                + offset_ms;
+            // Jumping the clock as well means the sync takes even with
+            // firmware builds that don't implement `setTime`.
+            let clock = emu.clock();
+            clock.skew(phone_ms - clock.now_ms());
+            emu.push_string_to(
+                Device::CONSOLE,
+                gb_set_time_line(phone_ms, timezone).as_bytes(),
+            )
+        }
+        Input::Pin(pin, value) => {
+            // Scheduled and replayed inputs aren't checked up front.
+            if emu.pin(pin).is_none() {
+                error!("ignoring input for nonexistent pin {pin}");
+                return Ok(());
+            }
+            if let Some(value) = value {
+                emu.set_pin(pin, value)?;
+            }
+            emu.send_pin_watch_event(pin)
+        }
+        Input::Charger(on) => {
+            let Some((pin, active_low)) = emu.charge_pin() else {
+                error!("ignoring charger input for a board without a charger");
+                return Ok(());
+            };
+            let on = on.unwrap_or_else(|| emu.pin(pin) == Some(active_low));
+            emu.set_pin(pin, on != active_low)?;
+            emu.send_pin_watch_event(pin)
+        }
+        Input::Bluetooth(on) => emu.push_string_to(
+            Device::CONSOLE,
+            format!("\x10{};\n", bluetooth_expr(on)).as_bytes(),
+        ),
+        Input::Pause(_)
+        | Input::Step(_)
+        | Input::WaitFor(..)
+        | Input::WaitIdle(..)
+        | Input::Eval(..)
+        | Input::ReadFlash(_)
+        | Input::ReadPin(..)
+        | Input::ReadPins(_)
+        | Input::TakeBuzzes(_)
+        | Input::TakeGestures(_)
+        | Input::Snapshot(_)
+        | Input::Restore(_)
+        | Input::WriteBundle(..) => unreachable!(),
+    }
+}
+
+async fn idle<M: Machine>(emu: &Arc<Mutex<M>>) -> anyhow::Result<i32> {
+    let emu = Arc::clone(emu);
+    tokio::task::spawn_blocking(move || emu.lock().unwrap().idle()).await?
+}
+
+/// Runs the firmware while paused, returning how many milliseconds its last
+/// idle call asked to be left alone for.
+async fn step_paused<M: Machine>(
+    emu: &Arc<Mutex<M>>,
+    step: Step,
+    sink: &mut Sink,
+    schedule: &mut Schedule,
+) -> anyhow::Result<i32> {
+    let clock = emu.lock().unwrap().clock();
+    let mut delay = 0;
+    match step {
+        Step::Idle => {
+            delay = idle(emu).await?;
+            sink.flush(&mut *emu.lock().unwrap(), true)?;
+        }
+        Step::Millis(ms) => {
+            let mut remaining = ms;
+            while remaining > 0.0 {
+                let tick = remaining.min(TICK_MS);
+                clock.advance(tick);
+                remaining -= tick;
+                schedule.run_due(&mut *emu.lock().unwrap(), sink, clock.now_ms())?;
+                delay = idle(emu).await?;
+                sink.flush(&mut *emu.lock().unwrap(), true)?;
+            }
+        }
+    }
+    Ok(delay)
+}
+
+/// The event to report for an input that a display might show, beyond its
+/// description.
+fn input_event(input: &Input) -> Option<Event> {
+    match *input {
+        Input::Button(button, on) => Some(Event::Button(button, on)),
+        Input::Touch(x, y, on, _) => Some(Event::Touch(x, y, on)),
+        _ => None,
+    }
+}
+
+/// Describes an input for the timeline, unless it's an internal one.
+fn describe(input: &Input) -> Option<String> {
+    match input {
+        Input::Console(d, data) => Some(format!("{} bytes to device {}", data.len(), d.0)),
+        Input::Touch(x, y, true, None) => Some(format!("touch {x},{y}")),
+        Input::Touch(x, y, true, Some(p)) => Some(format!("touch {x},{y} pressure {p}")),
+        Input::Touch(x, y, false, _) => Some(format!("release {x},{y}")),
+        Input::Button(1, on) => Some(format!("button {}", if *on { "down" } else { "up" })),
+        Input::Button(n, on) => Some(format!("button {n} {}", if *on { "down" } else { "up" })),
+        Input::TouchMode(mode) => Some(format!("touch mode {mode:?}")),
+        Input::Pin(pin, Some(value)) => Some(format!("pin {pin} set to {}", *value as u8)),
+        Input::Pin(pin, None) => Some(format!("pin {pin} watch event")),
+        Input::Charger(Some(true)) => Some("charger connected".to_owned()),
+        Input::Charger(Some(false)) => Some("charger disconnected".to_owned()),
+        Input::Charger(None) => Some("charger toggled".to_owned()),
+        Input::Bluetooth(Some(true)) => Some("bluetooth connected".to_owned()),
+        Input::Bluetooth(Some(false)) => Some("bluetooth disconnected".to_owned()),
+        Input::Bluetooth(None) => Some("bluetooth toggled".to_owned()),
+        Input::AdjustClock(ClockAdjustment::Drift(ppm)) => Some(format!("clock drift {ppm}ppm")),
+        Input::AdjustClock(ClockAdjustment::Skew(ms)) => Some(format!("clock skew {ms}ms")),
+        Input::TimeSync(ms, None) => Some(format!("time sync {ms:+}ms")),
+        Input::TimeSync(ms, Some(hours)) => Some(format!("time sync {ms:+}ms UTC{hours:+}")),
+        Input::Pause(_)
+        | Input::Step(_)
+        | Input::WaitFor(..)
+        | Input::WaitIdle(..)
+        | Input::Eval(..)
+        | Input::ReadFlash(_)
+        | Input::ReadPin(..)
+        | Input::ReadPins(_)
+        | Input::TakeBuzzes(_)
+        | Input::TakeGestures(_)
+        | Input::Snapshot(_)
+        | Input::Restore(_)
+        | Input::WriteBundle(..) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        emu::{Color, PixelFormat, ScreenSpec},
+        mock::{MockEmulator, MockOutput},
+    };
+
+    /// Starts a runner on a clone of `mock` and pauses it, returning its
+    /// input channel and an output channel with room for `output_len`
+    /// outputs.
+    async fn start_paused(
+        mock: &MockEmulator,
+        output_len: usize,
+    ) -> (Sender<Input>, Receiver<Output>) {
+        let (to_emu_tx, to_emu_rx) = mpsc::channel(QUEUE_LEN);
+        let (from_emu_tx, mut from_emu_rx) = mpsc::channel(output_len);
+        tokio::spawn(AsyncRunner::new(mock.clone()).run(to_emu_rx, from_emu_tx));
+        to_emu_tx.send(Input::Pause(true)).await.unwrap();
+        while !matches!(from_emu_rx.recv().await.unwrap(), Output::Paused(true)) {}
+        (to_emu_tx, from_emu_rx)
+    }
+
+    /// Waits for the runner to have handled every input sent before.
+    async fn sync(tx: &Sender<Input>) {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        tx.send(Input::ReadPins(reply_tx)).await.unwrap();
+        reply_rx.await.unwrap();
+    }
+
+    async fn wait_idle(tx: &Sender<Input>, quiet_ms: f64, ms: f64) -> bool {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        tx.send(Input::WaitIdle(quiet_ms, ms, reply_tx))
+            .await
+            .unwrap();
+        reply_rx.await.unwrap()
+    }
+
+    /// Takes the console output sent so far.
+    fn console(rx: &mut Receiver<Output>) -> Vec<Vec<u8>> {
+        let mut chunks = vec![];
+        while let Ok(output) = rx.try_recv() {
+            if let Output::Console(Device::CONSOLE, data) = output {
+                chunks.push(data);
+            }
+        }
+        chunks
+    }
+
+    #[tokio::test]
+    async fn paused_runs_move_on_virtual_time() {
+        let mock = MockEmulator::default();
+        let (tx, mut rx) = start_paused(&mock, QUEUE_LEN).await;
+        let clock = mock.clock();
+        let start_ms = clock.now_ms();
+        mock.at(
+            50.0,
+            MockOutput::Console(Device::CONSOLE, b"tick\n".to_vec()),
+        );
+
+        // Real time passing doesn't move a paused clock; only stepping does.
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        tx.send(Input::Step(Step::Millis(40.0))).await.unwrap();
+        sync(&tx).await;
+        assert_eq!(clock.now_ms(), start_ms + 40.0);
+        assert!(console(&mut rx).is_empty());
+
+        // The mock asks to be left alone for 10ms each time, which is quiet
+        // enough for the first wait but not the second.
+        assert!(wait_idle(&tx, 5.0, 1000.0).await);
+        assert_eq!(clock.now_ms(), start_ms + 50.0);
+        assert_eq!(console(&mut rx), [b"tick\n"]);
+        assert!(!wait_idle(&tx, 50.0, 100.0).await);
+        assert_eq!(clock.now_ms(), start_ms + 150.0);
+    }
+
+    #[tokio::test]
+    async fn screens_waiting_to_be_sent_coalesce() {
+        let spec = ScreenSpec {
+            width: 4,
+            height: 4,
+            format: PixelFormat::Rgb3,
+        };
+        let mock = MockEmulator::new(spec);
+        let (tx, mut rx) = start_paused(&mock, QUEUE_LEN).await;
+        for i in 1..=6 {
+            let mut screen = Screen::new(spec);
+            screen.set(0, 0, Color::new(i));
+            mock.at(f64::from(i) * 10.0, MockOutput::Screen(screen));
+        }
+        let screens = |rx: &mut Receiver<Output>| {
+            let mut pixels = vec![];
+            while let Ok(output) = rx.try_recv() {
+                if let Output::Screen(screen) = output {
+                    pixels.push((screen.frame(), screen.pixel(0, 0)));
+                }
+            }
+            pixels
+        };
+
+        // Screens drawn in separate steps are each sent, numbered in order.
+        for _ in 0..3 {
+            tx.send(Input::Step(Step::Millis(10.0))).await.unwrap();
+            sync(&tx).await;
+        }
+        let sent = screens(&mut rx);
+        let colors: Vec<_> = sent.iter().map(|&(_, color)| color).collect();
+        assert_eq!(colors, [1, 2, 3].map(Color::new));
+        assert!(sent.windows(2).all(|w| w[0].0 + 1 == w[1].0), "{sent:?}");
+
+        // Screens drawn within one step only ever waited to be sent together,
+        // so only the newest is.
+        tx.send(Input::Step(Step::Millis(30.0))).await.unwrap();
+        sync(&tx).await;
+        let sent = screens(&mut rx);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1, Color::new(6));
+    }
+
+    #[tokio::test]
+    async fn console_output_waits_for_room_instead_of_dropping() {
+        let mock = MockEmulator::default();
+        // There's only room for one output at a time.
+        let (tx, mut rx) = start_paused(&mock, 1).await;
+        let clock = mock.clock();
+        let start_ms = clock.now_ms();
+        let lines = ["one\n", "two\n", "three\n"];
+        for (i, line) in lines.into_iter().enumerate() {
+            let at = (i + 1) as f64 * 10.0;
+            mock.at(at, MockOutput::Console(Device::CONSOLE, line.into()));
+        }
+        tx.send(Input::Step(Step::Millis(40.0))).await.unwrap();
+
+        let mut received = vec![];
+        while received.len() < lines.len() {
+            if let Output::Console(Device::CONSOLE, data) = rx.recv().await.unwrap() {
+                received.push(String::from_utf8(data).unwrap());
+            }
+        }
+        assert_eq!(received, lines);
+        sync(&tx).await;
+        assert_eq!(clock.now_ms(), start_ms + 40.0);
     }
 }
@@ -0,0 +1,53 @@
+use std::io::Read;
+
+use crate::emu::{PixelFormat, Screen, ScreenSpec};
+
+/// Encodes the screen as an 8-bit RGB PNG image.
+pub fn encode_png(screen: &Screen) -> anyhow::Result<Vec<u8>> {
+    let mut out = vec![];
+    {
+        let mut encoder =
+            png::Encoder::new(&mut out, screen.width() as u32, screen.height() as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        let data: Vec<u8> = screen
+            .rows()
+            .flat_map(|row| row.iter().flat_map(|c| c.rgb8()))
+            .collect();
+        writer.write_image_data(&data)?;
+    }
+    Ok(out)
+}
+
+/// Decodes a PNG image of the screen, such as one written by [`encode_png`],
+/// mapping each pixel to the nearest color a screen format can show.
+pub fn decode_png(data: impl Read, format: PixelFormat) -> anyhow::Result<Screen> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    let channels = match info.color_type {
+        png::ColorType::Rgb => 3,
+        png::ColorType::Rgba => 4,
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::Indexed => anyhow::bail!("unexpected indexed image"),
+    };
+
+    let width = info.width as usize;
+    let mut screen = Screen::new(ScreenSpec {
+        width,
+        height: info.height as usize,
+        format,
+    });
+    for (ind, px) in buf[..info.buffer_size()].chunks(channels).enumerate() {
+        let rgb = match channels {
+            1 | 2 => [px[0], px[0], px[0]],
+            _ => [px[0], px[1], px[2]],
+        };
+        screen.set(ind % width, ind / width, format.quantize(rgb));
+    }
+    Ok(screen)
+}
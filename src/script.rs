@@ -0,0 +1,154 @@
+use std::{fs, mem, path::Path};
+
+use anyhow::Context;
+use log::info;
+
+use crate::control::{Command, Controller};
+
+/// The most commands a script can expand to, so that nested `repeat` blocks
+/// can't use up all of memory before anything has run. Tests use a small cap
+/// so that reaching it is cheap.
+const MAX_COMMANDS: usize = if cfg!(test) { 100 } else { 1_000_000 };
+
+/// A command from a script, along with where it came from for reporting.
+#[derive(Clone, Debug)]
+pub struct ScriptLine {
+    pub lineno: usize,
+    pub text: String,
+    pub command: Command,
+}
+
+/// Reads a script of control commands, one per line. Blank lines and lines
+/// starting with `#` are ignored, and paths are relative to the script, or to
+/// `output_dir` if given for files that are only written. Lines between
+/// `repeat <n>` and `end` (which can nest) are run `n` times; they're expanded
+/// here, so every runner sees plain commands, and a script can expand to at
+/// most a million of them.
+pub fn load_script(
+    path: impl AsRef<Path>,
+    output_dir: Option<&Path>,
+) -> anyhow::Result<Vec<ScriptLine>> {
+    let path = path.as_ref();
+    let script =
+        fs::read_to_string(path).with_context(|| format!("Failed to read script {path:?}"))?;
+
+    // The lines of each enclosing `repeat` block so far, with its count and
+    // where it started.
+    let mut blocks: Vec<(Vec<ScriptLine>, usize, usize)> = vec![];
+    let mut lines = vec![];
+    for (ind, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let lineno = ind + 1;
+        if let Some(count) = line.strip_prefix("repeat ") {
+            let count = count
+                .trim()
+                .parse()
+                .with_context(|| format!("{}:{lineno}: invalid repeat count", path.display()))?;
+            blocks.push((mem::take(&mut lines), count, lineno));
+            continue;
+        }
+        if line == "end" {
+            let Some((outer, count, start)) = blocks.pop() else {
+                anyhow::bail!("{}:{lineno}: `end` without `repeat`", path.display());
+            };
+            let body = mem::replace(&mut lines, outer);
+            let total = body
+                .len()
+                .checked_mul(count)
+                .and_then(|n| n.checked_add(lines.len()))
+                .filter(|&n| n <= MAX_COMMANDS);
+            if total.is_none() {
+                anyhow::bail!(
+                    "{}:{start}: `repeat` block expands the script past {MAX_COMMANDS} commands",
+                    path.display()
+                );
+            }
+            for _ in 0..count {
+                lines.extend(body.iter().cloned());
+            }
+            continue;
+        }
+        let mut command = Command::parse(line)
+            .with_context(|| format!("{}:{lineno}: invalid command", path.display()))?;
+        let base = path.parent().unwrap_or(Path::new("."));
+        command.resolve_paths(base, output_dir.unwrap_or(base));
+        lines.push(ScriptLine {
+            lineno,
+            text: line.to_owned(),
+            command,
+        });
+    }
+    if let Some((_, _, lineno)) = blocks.pop() {
+        anyhow::bail!("{}:{lineno}: `repeat` without `end`", path.display());
+    }
+    Ok(lines)
+}
+
+/// Runs a script of control commands, stopping at the first failure.
+pub async fn run_script(path: impl AsRef<Path>, controller: Controller) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    for line in load_script(path, controller.output_dir())? {
+        let ScriptLine {
+            lineno,
+            text,
+            command,
+        } = line;
+        info!("script line {lineno}: {text}");
+        let reply = controller
+            .execute(command)
+            .await
+            .with_context(|| format!("{}:{lineno}: `{text}` failed", path.display()))?;
+        if !reply.is_empty() {
+            info!("script line {lineno} replied: {reply}");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, script: &str) -> anyhow::Result<Vec<ScriptLine>> {
+        let path = std::env::temp_dir().join(format!("banglejs-emu-{}-{name}", std::process::id()));
+        fs::write(&path, script).unwrap();
+        let result = load_script(&path, None);
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn repeat_blocks_expand() {
+        let lines = load(
+            "expand",
+            "repeat 2\neval 1\nrepeat 3\neval 2\nend\nend\npause\n",
+        )
+        .unwrap();
+        let texts: Vec<_> = lines.iter().map(|l| l.text.as_str()).collect();
+        let mut expected = vec![];
+        for _ in 0..2 {
+            expected.extend(["eval 1", "eval 2", "eval 2", "eval 2"]);
+        }
+        expected.push("pause");
+        assert_eq!(texts, expected);
+    }
+
+    #[test]
+    fn repeat_blocks_are_capped() {
+        let lines = load("full", "repeat 10\nrepeat 10\neval 1\nend\nend\n").unwrap();
+        assert_eq!(lines.len(), MAX_COMMANDS);
+
+        let err = load("capped", "repeat 10\nrepeat 10\neval 1\nend\neval 2\nend\n")
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string()
+                .ends_with(":1: `repeat` block expands the script past 100 commands"),
+            "{err}"
+        );
+    }
+}
@@ -0,0 +1,78 @@
+use std::{
+    fs::OpenOptions,
+    path::Path,
+    sync::atomic::{fence, AtomicU64, Ordering},
+};
+
+use anyhow::Context;
+use memmap2::MmapMut;
+
+use crate::emu::{Screen, ScreenSpec};
+
+const MAGIC: &[u8; 8] = b"BJSFRAME";
+const VERSION: u32 = 1;
+/// Where the sequence number is in the header.
+const SEQ_OFFSET: usize = 24;
+/// Where the pixels start, after the header.
+const PIXELS_OFFSET: usize = 32;
+
+/// The screen, kept up to date in a memory-mapped file for other programs to
+/// read directly.
+///
+/// The file starts with a header of little-endian fields: the magic
+/// `BJSFRAME`, then a `u32` format version (1), width and height, a padding
+/// `u32`, and a `u64` sequence number. After the header comes one byte per
+/// pixel, by rows, holding its 3-bit color (1 is red, 2 green, 4 blue), or the
+/// nearest one on screens with other colors.
+///
+/// The sequence number works as a lock: it's odd while a frame is being
+/// written and even otherwise, going up by 2 each frame. A reader should read
+/// it, copy the pixels, and read it again, retrying if it was odd or changed.
+pub struct SharedScreen {
+    map: MmapMut,
+    spec: ScreenSpec,
+}
+
+impl SharedScreen {
+    pub fn create(path: &Path, spec: ScreenSpec) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .with_context(|| format!("Failed to create {path:?}"))?;
+        file.set_len((PIXELS_OFFSET + spec.width * spec.height) as u64)?;
+        // Safety: the file is only written through this mapping; other
+        // programs changing it could corrupt the frames they read but can't
+        // affect us, since nothing is read back from it.
+        let mut map = unsafe { MmapMut::map_mut(&file) }
+            .with_context(|| format!("Failed to map {path:?}"))?;
+        map[..8].copy_from_slice(MAGIC);
+        map[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        map[12..16].copy_from_slice(&(spec.width as u32).to_le_bytes());
+        map[16..20].copy_from_slice(&(spec.height as u32).to_le_bytes());
+        Ok(Self { map, spec })
+    }
+
+    fn seq(&self) -> &AtomicU64 {
+        // Safety: the mapping is page-aligned, so the offset is suitably
+        // aligned, and it's only accessed atomically.
+        unsafe { &*(self.map.as_ptr().add(SEQ_OFFSET) as *const AtomicU64) }
+    }
+
+    /// Writes a frame, unless it's not the size the file was made for.
+    pub fn write(&mut self, screen: &Screen) {
+        if (screen.width(), screen.height()) != (self.spec.width, self.spec.height) {
+            return;
+        }
+        let seq = self.seq().load(Ordering::Relaxed);
+        self.seq().store(seq + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        let pixels = &mut self.map[PIXELS_OFFSET..];
+        for (dst, c) in pixels.iter_mut().zip(screen.rows().flatten()) {
+            *dst = c.value();
+        }
+        self.seq().store(seq + 2, Ordering::Release);
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::{
+    emu::{FLASH_SIZE, PIN_COUNT},
+    engine::Val,
+};
+
+const MAGIC: &[u8] = b"BJSSTATE";
+const VERSION: u32 = 1;
+/// Most of a snapshot is empty flash and heap, so fast compression is plenty.
+const ZSTD_LEVEL: i32 = 3;
+
+/// The complete state of an emulator, taken between calls into the firmware,
+/// which can be restored into an emulator running the same firmware.
+#[derive(Clone)]
+pub struct Snapshot {
+    pub(crate) memory: Vec<u8>,
+    pub(crate) globals: Vec<(String, Val)>,
+    pub(crate) pins: Vec<bool>,
+    pub(crate) flash: Vec<u8>,
+    /// The firmware's time when the snapshot was taken.
+    pub(crate) clock_ms: f64,
+}
+
+// The memory and flash are far too big to print.
+impl fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Snapshot")
+            .field("memory_len", &self.memory.len())
+            .field("clock_ms", &self.clock_ms)
+            .finish_non_exhaustive()
+    }
+}
+
+fn put_bytes(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend((data.len() as u64).to_le_bytes());
+    out.extend(data);
+}
+
+/// Reads the pieces of a serialized snapshot in order.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        if self.0.len() < n {
+            anyhow::bail!("snapshot is truncated");
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> anyhow::Result<&'a [u8]> {
+        let len = self.u64()? as usize;
+        self.take(len)
+    }
+}
+
+impl Snapshot {
+    fn serialize(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend(VERSION.to_le_bytes());
+        out.extend(self.clock_ms.to_bits().to_le_bytes());
+        put_bytes(
+            &mut out,
+            &self.pins.iter().map(|&p| p as u8).collect::<Vec<_>>(),
+        );
+        put_bytes(&mut out, &self.flash);
+        put_bytes(&mut out, &self.memory);
+        out.extend((self.globals.len() as u64).to_le_bytes());
+        for (name, value) in &self.globals {
+            put_bytes(&mut out, name.as_bytes());
+            let (tag, bits) = match *value {
+                Val::I32(v) => (0u8, v as u32 as u64),
+                Val::I64(v) => (1, v as u64),
+                Val::F32(v) => (2, v as u64),
+                Val::F64(v) => (3, v),
+            };
+            out.push(tag);
+            out.extend(bits.to_le_bytes());
+        }
+        out
+    }
+
+    fn deserialize(data: &[u8]) -> anyhow::Result<Self> {
+        let mut r = Reader(data);
+        if r.take(MAGIC.len())? != MAGIC {
+            anyhow::bail!("not an emulator snapshot");
+        }
+        let version = u32::from_le_bytes(r.take(4)?.try_into().unwrap());
+        if version != VERSION {
+            anyhow::bail!("unsupported snapshot version {version}");
+        }
+        let clock_ms = f64::from_bits(r.u64()?);
+        let pins: Vec<_> = r.bytes()?.iter().map(|&p| p != 0).collect();
+        if pins.len() != PIN_COUNT {
+            anyhow::bail!("snapshot has {} pins, not {PIN_COUNT}", pins.len());
+        }
+        let flash = r.bytes()?.to_vec();
+        if flash.len() != FLASH_SIZE {
+            anyhow::bail!(
+                "snapshot has {} bytes of flash, not {FLASH_SIZE}",
+                flash.len()
+            );
+        }
+        let memory = r.bytes()?.to_vec();
+        let mut globals = vec![];
+        for _ in 0..r.u64()? {
+            let name = String::from_utf8(r.bytes()?.to_vec())?;
+            let tag = r.take(1)?[0];
+            let bits = r.u64()?;
+            let value = match tag {
+                0 => Val::I32(bits as u32 as i32),
+                1 => Val::I64(bits as i64),
+                2 => Val::F32(bits as u32),
+                3 => Val::F64(bits),
+                _ => anyhow::bail!("bad global type in snapshot"),
+            };
+            globals.push((name, value));
+        }
+        Ok(Self {
+            memory,
+            globals,
+            pins,
+            flash,
+            clock_ms,
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let data = zstd::encode_all(&self.serialize()[..], ZSTD_LEVEL)?;
+        fs::write(path, data).with_context(|| format!("Failed to write {path:?}"))
+    }
+
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let data = fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+        let data = zstd::decode_all(&data[..]).context("Failed to decompress snapshot")?;
+        Self::deserialize(&data)
+    }
+}
+
+/// The directory holding save-state slots for a firmware. Snapshots only make
+/// sense for the firmware build they were taken from, so each build (told
+/// apart by a hash of its contents) gets its own slots.
+pub fn slots_dir(wasm_path: &Path) -> anyhow::Result<PathBuf> {
+    let wasm = fs::read(wasm_path).with_context(|| format!("Failed to read {wasm_path:?}"))?;
+    let mut hash = 0xcbf29ce484222325u64;
+    for b in wasm {
+        hash ^= u64::from(b);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    let dir = dirs::data_local_dir()
+        .ok_or_else(|| anyhow::format_err!("no data directory available"))?
+        .join("banglejs-emu")
+        .join("states")
+        .join(format!("{hash:016x}"));
+    Ok(dir)
+}
+
+pub fn slot_path(dir: &Path, slot: u8) -> PathBuf {
+    dir.join(format!("slot{slot}.state"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            memory: vec![1, 2, 3],
+            globals: vec![("__stack_pointer".to_owned(), Val::I32(-16))],
+            pins: vec![true; PIN_COUNT],
+            flash: vec![0xff; FLASH_SIZE],
+            clock_ms: 1234.5,
+        }
+    }
+
+    #[test]
+    fn snapshots_round_trip() {
+        let loaded = Snapshot::deserialize(&snapshot().serialize()).unwrap();
+        assert_eq!(loaded.memory, [1, 2, 3]);
+        assert_eq!(loaded.globals, snapshot().globals);
+        assert_eq!(loaded.clock_ms, 1234.5);
+    }
+
+    #[test]
+    fn truncated_snapshots_fail_to_load() {
+        let data = snapshot().serialize();
+        assert!(Snapshot::deserialize(&data[..data.len() / 2]).is_err());
+
+        let mut short = snapshot();
+        short.flash.truncate(FLASH_SIZE / 2);
+        assert!(Snapshot::deserialize(&short.serialize()).is_err());
+
+        let mut short = snapshot();
+        short.pins.pop();
+        assert!(Snapshot::deserialize(&short.serialize()).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+use std::ops::Range;
+
+/// The size of the header before each file's contents: a 32-bit size word (with
+/// flags in its top byte) followed by the name.
+const HEADER_LEN: usize = 32;
+/// The longest file name the firmware's storage allows.
+pub const NAME_LEN: usize = 28;
+/// The flash is erased a page at a time, and the firmware moves files that
+/// won't fit in what remains of a page to the start of the next one, leaving
+/// the rest of the page erased.
+const PAGE_SIZE: usize = 4096;
+const ERASED: u32 = 0xffff_ffff;
+
+fn align(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+/// A file found in storage.
+struct Entry {
+    header: Range<usize>,
+    name: [u8; NAME_LEN],
+    len: usize,
+}
+
+/// Reads and writes files in the firmware's storage format directly in a flash
+/// image, for setting up storage before the firmware starts instead of through
+/// the console.
+pub struct Storage<'a> {
+    flash: &'a mut [u8],
+}
+
+impl<'a> Storage<'a> {
+    pub fn new(flash: &'a mut [u8]) -> Self {
+        Self { flash }
+    }
+
+    fn word(&self, addr: usize) -> u32 {
+        u32::from_le_bytes(self.flash[addr..addr + 4].try_into().unwrap())
+    }
+
+    /// Finds every file header in storage (including those of deleted files,
+    /// whose names are zeroed) and the first address after the last file.
+    fn scan(&self) -> (Vec<Entry>, usize) {
+        let mut entries = vec![];
+        let mut addr = 0;
+        let mut end = 0;
+        while addr + HEADER_LEN <= self.flash.len() {
+            let size = self.word(addr);
+            if size == ERASED {
+                // Either the rest of this page was skipped over or this is the
+                // end of storage, depending on whether the next page is used.
+                let next = (addr / PAGE_SIZE + 1) * PAGE_SIZE;
+                if next + HEADER_LEN > self.flash.len() || self.word(next) == ERASED {
+                    break;
+                }
+                addr = next;
+                continue;
+            }
+            let len = (size & 0x00ff_ffff) as usize;
+            entries.push(Entry {
+                header: addr..addr + HEADER_LEN,
+                name: self.flash[addr + 4..addr + HEADER_LEN].try_into().unwrap(),
+                len,
+            });
+            addr += HEADER_LEN + align(len);
+            end = addr;
+        }
+        (entries, end)
+    }
+
+    /// Reads a file, or `None` if there's no file with the name.
+    pub fn read(&self, name: &str) -> Option<&[u8]> {
+        if name.is_empty() || name.len() > NAME_LEN {
+            return None;
+        }
+        let mut padded = [0; NAME_LEN];
+        padded[..name.len()].copy_from_slice(name.as_bytes());
+        let (entries, _) = self.scan();
+        let entry = entries.iter().rev().find(|e| e.name == padded)?;
+        self.flash
+            .get(entry.header.end..entry.header.end + entry.len)
+    }
+
+    /// Writes a file, deleting any existing file with the same name, unless
+    /// that already has the same contents, in which case the flash is left as
+    /// it is.
+    pub fn write(&mut self, name: &str, contents: &[u8]) -> anyhow::Result<()> {
+        if name.is_empty() || name.len() > NAME_LEN {
+            anyhow::bail!("storage file name {name:?} must be 1 to {NAME_LEN} bytes long");
+        }
+        if contents.len() > 0x00ff_ffff {
+            anyhow::bail!("storage file {name:?} is too large");
+        }
+        let mut padded = [0; NAME_LEN];
+        padded[..name.len()].copy_from_slice(name.as_bytes());
+
+        if self.read(name) == Some(contents) {
+            return Ok(());
+        }
+        let (entries, end) = self.scan();
+        let len = HEADER_LEN + contents.len();
+        let mut start = end;
+        let page_end = (start / PAGE_SIZE + 1) * PAGE_SIZE;
+        if len <= PAGE_SIZE && start + len > page_end {
+            start = page_end;
+        }
+        if start + len > self.flash.len() {
+            anyhow::bail!("not enough space in storage for {name:?}");
+        }
+        // Deleting a file only clears bits, like the firmware does, since
+        // that's all flash allows without erasing a page.
+        for entry in entries.iter().filter(|e| e.name == padded) {
+            self.flash[entry.header.start + 4..entry.header.end].fill(0);
+        }
+        let header = &mut self.flash[start..start + HEADER_LEN];
+        header[..4].copy_from_slice(&(contents.len() as u32).to_le_bytes());
+        header[4..].copy_from_slice(&padded);
+        self.flash[start + HEADER_LEN..start + len].copy_from_slice(contents);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_that_would_cross_a_page_start_the_next() {
+        let mut flash = vec![0xff; 4 * PAGE_SIZE];
+        let mut storage = Storage::new(&mut flash);
+        let first = vec![1; PAGE_SIZE - HEADER_LEN - 100];
+        let second = vec![2; 200];
+        let large = vec![3; PAGE_SIZE + 100];
+        storage.write("first", &first).unwrap();
+        storage.write("second", &second).unwrap();
+        storage.write("large", &large).unwrap();
+
+        let (entries, end) = storage.scan();
+        let starts: Vec<_> = entries.iter().map(|e| e.header.start).collect();
+        // Files larger than a page can't avoid crossing one, so they follow on
+        // directly.
+        let large_start = PAGE_SIZE + HEADER_LEN + 200;
+        assert_eq!(starts, [0, PAGE_SIZE, large_start]);
+        assert_eq!(end, large_start + HEADER_LEN + large.len());
+        assert!(flash[PAGE_SIZE - 100..PAGE_SIZE].iter().all(|&b| b == 0xff));
+
+        let storage = Storage::new(&mut flash);
+        assert_eq!(storage.read("first"), Some(&first[..]));
+        assert_eq!(storage.read("second"), Some(&second[..]));
+        assert_eq!(storage.read("large"), Some(&large[..]));
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use log::{error, info};
+use tokio::sync::{mpsc, watch, Semaphore};
+
+use crate::{
+    bundle,
+    config::Config,
+    control::Controller,
+    emu::Output,
+    http_proxy::HttpProxy,
+    pool::EmulatorPool,
+    runner::{self, AsyncRunner},
+    script::run_script,
+};
+
+/// The extension of scenario scripts in a suite directory.
+const SCRIPT_EXT: &str = "script";
+
+/// A scenario script, with the config it runs under.
+struct Scenario {
+    name: String,
+    script: PathBuf,
+    config: Option<PathBuf>,
+}
+
+struct Outcome {
+    name: String,
+    duration: Duration,
+    result: anyhow::Result<()>,
+}
+
+/// Finds the scenarios in a suite directory: each `<name>.script` is run with
+/// `<name>.toml` as its config if that exists, or else the suite's config.
+fn find_scenarios(dir: &Path, default_config: Option<&Path>) -> anyhow::Result<Vec<Scenario>> {
+    let mut scenarios = vec![];
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {dir:?}"))? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(SCRIPT_EXT) {
+            continue;
+        }
+        let config = path.with_extension("toml");
+        scenarios.push(Scenario {
+            name: path.file_stem().unwrap().to_string_lossy().into_owned(),
+            config: if config.exists() {
+                Some(config)
+            } else {
+                default_config.map(Into::into)
+            },
+            script: path,
+        });
+    }
+    scenarios.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(scenarios)
+}
+
+async fn run_scenario(
+    pool: EmulatorPool,
+    scenario: &Scenario,
+    update_goldens: bool,
+    bundle_dir: Option<PathBuf>,
+) -> anyhow::Result<()> {
+    let config = match &scenario.config {
+        Some(path) => {
+            Config::read(path).with_context(|| format!("Failed to open config file {path:?}"))?
+        }
+        None => Config::default(),
+    };
+    let schedule = config.schedule()?;
+    let device = config.device.clone();
+    let responses = config.responses()?;
+    let http_proxy = config.http_proxy.as_ref().map(HttpProxy::new).transpose()?;
+    let bundle_config = config.bundle_config()?;
+    let emu = tokio::task::spawn_blocking(move || config.build_from(&pool)).await??;
+
+    let (to_emu_tx, to_emu_rx) = mpsc::channel(runner::QUEUE_LEN);
+    let (from_emu_tx, mut from_emu_rx) = mpsc::channel(runner::QUEUE_LEN);
+    let (screen_tx, screen_rx) = watch::channel(None);
+
+    let mut runner = AsyncRunner::new(emu)
+        .schedule(schedule)
+        .respond(responses)
+        .http_proxy(http_proxy);
+    if let Some(dir) = bundle_dir {
+        runner = runner.bundle(dir, bundle_config);
+    }
+    let runner = tokio::spawn(runner.run(to_emu_rx, from_emu_tx));
+    let pump = tokio::spawn(async move {
+        while let Some(output) = from_emu_rx.recv().await {
+            if let Output::Screen(screen) = output {
+                screen_tx.send_replace(Some(Arc::new(*screen)));
+            }
+        }
+    });
+
+    let controller = Controller::new(to_emu_tx.clone(), screen_rx)
+        .device(&device)
+        .update_goldens(update_goldens);
+    let result = run_script(&scenario.script, controller).await;
+    if let Err(err) = &result {
+        if let Err(err) = bundle::request(&to_emu_tx, format!("{err:?}")).await {
+            error!("failed to write reproducer bundle: {err:#}");
+        }
+    }
+    runner.abort();
+    pump.abort();
+    result
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn junit_report(suite: &str, outcomes: &[Outcome]) -> String {
+    let failures = outcomes.iter().filter(|o| o.result.is_err()).count();
+    let total: f64 = outcomes.iter().map(|o| o.duration.as_secs_f64()).sum();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let suite = xml_escape(suite);
+    writeln!(
+        xml,
+        "<testsuite name=\"{suite}\" tests=\"{}\" failures=\"{failures}\" time=\"{total:.3}\">",
+        outcomes.len()
+    )
+    .unwrap();
+    for o in outcomes {
+        write!(
+            xml,
+            "  <testcase classname=\"{suite}\" name=\"{}\" time=\"{:.3}\"",
+            xml_escape(&o.name),
+            o.duration.as_secs_f64()
+        )
+        .unwrap();
+        match &o.result {
+            Ok(()) => xml.push_str("/>\n"),
+            Err(err) => {
+                writeln!(
+                    xml,
+                    ">\n    <failure message=\"{}\">{}</failure>\n  </testcase>",
+                    xml_escape(&err.to_string()),
+                    xml_escape(&format!("{err:?}"))
+                )
+                .unwrap();
+            }
+        }
+    }
+    xml.push_str("</testsuite>\n");
+    xml
+}
+
+/// Runs every scenario in a suite directory, up to `jobs` at a time, printing
+/// the results and optionally writing a JUnit XML report. Returns the number of
+/// failed scenarios.
+pub async fn run_suite(
+    wasm_path: &Path,
+    dir: &Path,
+    config: Option<&Path>,
+    jobs: usize,
+    update_goldens: bool,
+    report: Option<&Path>,
+    bundle_dir: Option<&Path>,
+) -> anyhow::Result<usize> {
+    let scenarios = find_scenarios(dir, config)?;
+    // Compile the firmware once up front rather than once per scenario.
+    let pool = {
+        let wasm_path = wasm_path.to_owned();
+        tokio::task::spawn_blocking(move || EmulatorPool::new(wasm_path)).await??
+    };
+    let semaphore = Arc::new(Semaphore::new(jobs.max(1)));
+
+    let tasks: Vec<_> = scenarios
+        .into_iter()
+        .map(|scenario| {
+            let semaphore = Arc::clone(&semaphore);
+            let pool = pool.clone();
+            let bundle_dir = bundle_dir.map(Path::to_owned);
+            tokio::spawn(async move {
+                let _permit = semaphore.acquire().await.unwrap();
+                info!("running scenario {}", scenario.name);
+                let start = Instant::now();
+                let bundle_dir = bundle_dir.map(|d| d.join(&scenario.name));
+                let result = run_scenario(pool, &scenario, update_goldens, bundle_dir).await;
+                Outcome {
+                    name: scenario.name,
+                    duration: start.elapsed(),
+                    result,
+                }
+            })
+        })
+        .collect();
+
+    let mut outcomes = vec![];
+    for task in tasks {
+        let outcome = task.await?;
+        match &outcome.result {
+            Ok(()) => println!("PASS {}", outcome.name),
+            Err(err) => println!("FAIL {}: {err:#}", outcome.name),
+        }
+        outcomes.push(outcome);
+    }
+
+    let failures = outcomes.iter().filter(|o| o.result.is_err()).count();
+    println!("{} passed, {failures} failed", outcomes.len() - failures);
+
+    if let Some(path) = report {
+        let name = dir
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "banglejs-emu".to_owned());
+        fs::write(path, junit_report(&name, &outcomes))
+            .with_context(|| format!("Failed to write report {path:?}"))?;
+    }
+
+    Ok(failures)
+}
@@ -0,0 +1,149 @@
+use serde_derive::Serialize;
+use serde_json::Value;
+
+use crate::emu::{Color, Screen};
+
+struct Glyph {
+    ch: char,
+    width: usize,
+    bits: Vec<bool>,
+    /// The first set pixel, used to pick the candidate foreground color.
+    anchor: (usize, usize),
+}
+
+/// Bitmaps of the printable ASCII characters of one of the firmware's fonts.
+pub struct Font {
+    height: usize,
+    space_width: usize,
+    glyphs: Vec<Glyph>,
+}
+
+/// A run of text found on the screen; `x` and `y` are the top left corner of
+/// its bounding box.
+#[derive(Debug, Serialize)]
+pub struct TextRun {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    pub color: u8,
+    pub text: String,
+}
+
+impl Font {
+    /// Returns a JavaScript expression that renders each glyph of `font` into
+    /// an off-screen buffer and evaluates to their bitmaps, for passing to
+    /// [`Font::from_probe`].
+    pub fn probe_expr(font: &str) -> String {
+        let font = serde_json::to_string(font).unwrap();
+        format!(
+            "(function(){{var f={font},b=Graphics.createArrayBuffer(8,8,1);b.setFont(f);\
+             var h=b.getFontHeight(),r=[];b=Graphics.createArrayBuffer(4*h,h,1);b.setFont(f);\
+             for(var c=32;c<127;c++){{var s=String.fromCharCode(c),w=b.stringWidth(s),p='';\
+             b.clear();b.drawString(s,0,0);\
+             for(var y=0;y<h;y++)for(var x=0;x<w;x++)p+=b.getPixel(x,y)?'1':'0';\
+             r.push([s,w,p]);}}return {{height:h,glyphs:r}};}})()"
+        )
+    }
+
+    pub fn from_probe(value: &Value) -> anyhow::Result<Self> {
+        let bad = || anyhow::format_err!("unexpected font probe result");
+        let height = value["height"].as_u64().ok_or_else(bad)? as usize;
+        let mut space_width = 1;
+        let mut glyphs = vec![];
+        for g in value["glyphs"].as_array().ok_or_else(bad)? {
+            let ch = g[0]
+                .as_str()
+                .and_then(|s| s.chars().next())
+                .ok_or_else(bad)?;
+            let width = g[1].as_u64().ok_or_else(bad)? as usize;
+            let bits: Vec<bool> = g[2]
+                .as_str()
+                .ok_or_else(bad)?
+                .bytes()
+                .map(|b| b == b'1')
+                .collect();
+            if bits.len() != width * height {
+                return Err(bad());
+            }
+            if ch == ' ' {
+                space_width = width.max(1);
+                continue;
+            }
+            if let Some(ind) = bits.iter().position(|&b| b) {
+                glyphs.push(Glyph {
+                    ch,
+                    width,
+                    bits,
+                    anchor: (ind % width, ind / width),
+                });
+            }
+        }
+        Ok(Self {
+            height,
+            space_width,
+            glyphs,
+        })
+    }
+
+    fn glyph_at(&self, screen: &Screen, x: usize, y: usize) -> Option<(&Glyph, Color)> {
+        self.glyphs.iter().find_map(|glyph| {
+            if !screen.contains(x, y, glyph.width, self.height) {
+                return None;
+            }
+            let fg = screen.pixel(x + glyph.anchor.0, y + glyph.anchor.1);
+            let matches = glyph.bits.iter().enumerate().all(|(ind, &on)| {
+                let c = screen.pixel(x + ind % glyph.width, y + ind / glyph.width);
+                (c == fg) == on
+            });
+            matches.then_some((glyph, fg))
+        })
+    }
+}
+
+/// Finds text drawn on the screen in `font`, as runs of characters of the same
+/// color on the same baseline.
+pub fn read_text(screen: &Screen, font: &Font) -> Vec<TextRun> {
+    let mut runs: Vec<TextRun> = vec![];
+    if font.height == 0 || font.height > screen.height() {
+        return runs;
+    }
+
+    for y in 0..=screen.height() - font.height {
+        let mut x = 0;
+        let mut last: Option<(usize, Color)> = None;
+        while x < screen.width() {
+            let Some((glyph, fg)) = font.glyph_at(screen, x, y) else {
+                x += 1;
+                continue;
+            };
+            let continues = match (last, runs.last_mut()) {
+                (Some((end, color)), Some(run))
+                    if color == fg && x >= end && x - end <= 2 * font.space_width =>
+                {
+                    if x - end >= font.space_width {
+                        run.text.push(' ');
+                    }
+                    run.text.push(glyph.ch);
+                    run.width = x + glyph.width - run.x;
+                    true
+                }
+                _ => false,
+            };
+            if !continues {
+                runs.push(TextRun {
+                    x,
+                    y,
+                    width: glyph.width,
+                    height: font.height,
+                    color: fg.value(),
+                    text: glyph.ch.to_string(),
+                });
+            }
+            x += glyph.width;
+            last = Some((x, fg));
+        }
+    }
+
+    runs
+}
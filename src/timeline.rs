@@ -0,0 +1,103 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::emu::{Event, VIBRATE};
+
+/// The rows of the timeline.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EventKind {
+    Input,
+    Vibration,
+    Screen,
+    Exception,
+    Storage,
+    Status,
+}
+
+impl EventKind {
+    pub const ALL: [Self; 6] = [
+        Self::Input,
+        Self::Vibration,
+        Self::Screen,
+        Self::Exception,
+        Self::Storage,
+        Self::Status,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Input => "input",
+            Self::Vibration => "vibrate",
+            Self::Screen => "screen",
+            Self::Exception => "error",
+            Self::Storage => "storage",
+            Self::Status => "status",
+        }
+    }
+}
+
+pub struct TimelineEntry {
+    pub time: Instant,
+    pub kind: EventKind,
+    pub description: String,
+}
+
+/// The recent events in the emulator, for showing how they relate in time.
+pub struct Timeline {
+    pub window: Duration,
+    entries: VecDeque<TimelineEntry>,
+}
+
+impl Timeline {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            entries: VecDeque::new(),
+        }
+    }
+
+    pub fn record(&mut self, kind: EventKind, description: impl Into<String>) {
+        let now = Instant::now();
+        while let Some(e) = self.entries.front() {
+            if now.duration_since(e.time) <= self.window {
+                break;
+            }
+            self.entries.pop_front();
+        }
+        self.entries.push_back(TimelineEntry {
+            time: now,
+            kind,
+            description: description.into(),
+        });
+    }
+
+    /// Records anything of interest in an emulator event.
+    pub fn record_event(&mut self, event: &Event) {
+        match event {
+            Event::Input(description) => self.record(EventKind::Input, description),
+            Event::Status(description) => self.record(EventKind::Status, description),
+            Event::Pin(VIBRATE, on) => {
+                self.record(EventKind::Vibration, if *on { "on" } else { "off" })
+            }
+            Event::Pin(..) | Event::Button(..) | Event::Touch(..) | Event::Advertising(_) => {}
+            Event::FlashWrite { addr, len } => {
+                self.record(EventKind::Storage, format!("{len} bytes at {addr:#x}"))
+            }
+        }
+    }
+
+    /// Records any exceptions reported in console output.
+    pub fn record_console(&mut self, data: &[u8]) {
+        for line in String::from_utf8_lossy(data).lines() {
+            if line.contains("Uncaught") {
+                self.record(EventKind::Exception, line.trim());
+            }
+        }
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &TimelineEntry> {
+        self.entries.iter()
+    }
+}
@@ -0,0 +1,224 @@
+use std::str::FromStr;
+
+use serde_derive::{Deserialize, Serialize};
+
+/// The gesture codes understood by the firmware's touch handler.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Gesture {
+    Drag = 0,
+    Down = 1,
+    Up = 2,
+    Left = 3,
+    Right = 4,
+    Touch = 5,
+}
+
+impl Gesture {
+    /// What the gesture is called, for display.
+    pub fn name(self) -> &'static str {
+        match self {
+            Gesture::Drag => "drag",
+            Gesture::Down => "swipe down",
+            Gesture::Up => "swipe up",
+            Gesture::Left => "swipe left",
+            Gesture::Right => "swipe right",
+            Gesture::Touch => "tap",
+        }
+    }
+}
+
+impl FromStr for Gesture {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            Self::Drag,
+            Self::Down,
+            Self::Up,
+            Self::Left,
+            Self::Right,
+            Self::Touch,
+        ]
+        .into_iter()
+        .find(|gesture| gesture.name() == s)
+        .ok_or_else(|| anyhow::format_err!("unknown gesture {s:?}"))
+    }
+}
+
+/// How far, in pixels of total movement along each axis, a stroke may or must
+/// travel to count as a tap or a swipe.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GestureThresholds {
+    /// A stroke moving less than this along both axes is a tap.
+    pub tap_max: u64,
+    /// A swipe must move more than this along its axis...
+    pub swipe_min: u64,
+    /// ...and less than this along the other one.
+    pub swipe_max_cross: u64,
+}
+
+impl Default for GestureThresholds {
+    fn default() -> Self {
+        Self {
+            tap_max: 5,
+            swipe_min: 80,
+            swipe_max_cross: 20,
+        }
+    }
+}
+
+/// Where gestures come from.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TouchMode {
+    /// Recognize taps and swipes with a [`TouchTracker`], as the touch
+    /// controller does on a real watch.
+    #[default]
+    Gestures,
+    /// Pass every touch sample through as a plain drag, and leave any gesture
+    /// detection to the firmware.
+    Raw,
+}
+
+impl FromStr for TouchMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gestures" => Ok(Self::Gestures),
+            "raw" => Ok(Self::Raw),
+            _ => anyhow::bail!("expected `gestures` or `raw`"),
+        }
+    }
+}
+
+/// Turns a stream of raw touch points into the gestures the firmware expects,
+/// like the touch controller on a real watch does.
+#[derive(Debug, Default)]
+pub struct TouchTracker {
+    start_last: Option<((u8, u8), (u8, u8))>,
+    dist: (u64, u64),
+    thresholds: GestureThresholds,
+}
+
+impl TouchTracker {
+    pub fn new(thresholds: GestureThresholds) -> Self {
+        Self {
+            thresholds,
+            ..Default::default()
+        }
+    }
+
+    pub fn thresholds(&self) -> GestureThresholds {
+        self.thresholds
+    }
+
+    pub fn is_touching(&self) -> bool {
+        self.start_last.is_some()
+    }
+
+    /// Records a touch sample, returning the gestures to report for it: a drag
+    /// for every sample while touching, plus a tap or swipe on release.
+    pub fn add_touch(&mut self, pt: (u8, u8), on: bool) -> Vec<Gesture> {
+        match (self.start_last, on) {
+            // Start new touch -- record start and emit a drag.
+            (None, true) => {
+                self.start_last = Some((pt, pt));
+                self.dist = (0, 0);
+                vec![Gesture::Drag]
+            }
+            // Continue existing touch -- update state and emit a drag.
+            (Some((start, last)), true) => {
+                self.dist.0 += u64::from(pt.0.abs_diff(last.0));
+                self.dist.1 += u64::from(pt.1.abs_diff(last.1));
+                self.start_last = Some((start, pt));
+                vec![Gesture::Drag]
+            }
+            // Release existing touch -- check stats and see what to emit in
+            // addition to a drag.
+            (Some((start, last)), false) => {
+                self.dist.0 += u64::from(pt.0.abs_diff(last.0));
+                self.dist.1 += u64::from(pt.1.abs_diff(last.1));
+
+                let mut ret = vec![Gesture::Drag];
+
+                let t = &self.thresholds;
+                if self.dist.0 < t.tap_max && self.dist.1 < t.tap_max {
+                    ret.push(Gesture::Touch);
+                }
+                if self.dist.0 > t.swipe_min && self.dist.1 < t.swipe_max_cross {
+                    ret.push(if pt.0 > start.0 {
+                        Gesture::Right
+                    } else {
+                        Gesture::Left
+                    });
+                }
+                if self.dist.0 < t.swipe_max_cross && self.dist.1 > t.swipe_min {
+                    ret.push(if pt.1 > start.1 {
+                        Gesture::Down
+                    } else {
+                        Gesture::Up
+                    });
+                }
+
+                self.start_last = None;
+                ret
+            }
+            // Supposedly end touch when already ended -- ignore.
+            (None, false) => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds a stroke through a tracker, checking that every sample reports a
+    /// drag and nothing else until the release, and returns what the release
+    /// reported beyond its drag.
+    fn stroke(points: &[(u8, u8)]) -> Vec<Gesture> {
+        let mut tracker = TouchTracker::default();
+        for &pt in points {
+            assert_eq!(tracker.add_touch(pt, true), [Gesture::Drag]);
+            assert!(tracker.is_touching());
+        }
+        let gestures = tracker.add_touch(*points.last().unwrap(), false);
+        assert!(!tracker.is_touching());
+        assert_eq!(gestures.first(), Some(&Gesture::Drag), "{gestures:?}");
+        // Releasing again reports nothing.
+        assert!(tracker.add_touch((0, 0), false).is_empty());
+        gestures[1..].to_vec()
+    }
+
+    #[test]
+    fn strokes_give_taps_and_swipes() {
+        assert_eq!(stroke(&[(88, 88)]), [Gesture::Touch]);
+        assert_eq!(stroke(&[(88, 88), (90, 89)]), [Gesture::Touch]);
+
+        let across: Vec<_> = (20..=120).step_by(10).map(|x| (x, 88)).collect();
+        assert_eq!(stroke(&across), [Gesture::Right]);
+        let back: Vec<_> = across.iter().rev().copied().collect();
+        assert_eq!(stroke(&back), [Gesture::Left]);
+        let down: Vec<_> = across.iter().map(|&(x, y)| (y, x)).collect();
+        assert_eq!(stroke(&down), [Gesture::Down]);
+        let up: Vec<_> = back.iter().map(|&(x, y)| (y, x)).collect();
+        assert_eq!(stroke(&up), [Gesture::Up]);
+
+        // A swipe that wanders too far across its axis is neither.
+        let diagonal: Vec<_> = (20..=120).step_by(10).map(|x| (x, x)).collect();
+        assert!(stroke(&diagonal).is_empty());
+    }
+
+    #[test]
+    fn long_presses_only_tap_if_they_stay_put() {
+        assert_eq!(stroke(&[(88, 88); 100]), [Gesture::Touch]);
+
+        // Distance adds up over the whole press, even if it ends where it
+        // started.
+        let jittery: Vec<_> = (0..100).map(|i| (88 + i % 2, 88 + i % 2)).collect();
+        assert!(stroke(&jittery).is_empty());
+    }
+}
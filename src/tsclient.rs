@@ -0,0 +1,507 @@
+//! Generation of a TypeScript client for the control interface, so that
+//! Node-based tooling can drive the emulator.
+
+use std::{fmt::Write, fs, path::Path};
+
+use anyhow::Context;
+
+/// How a command's reply is handed back to the caller.
+enum Reply {
+    /// Nothing useful; the promise just resolves.
+    None,
+    /// JSON, parsed before being returned.
+    Json,
+    /// The reply text as it is.
+    Text,
+}
+
+/// A control command as a client method: its name, its TypeScript parameters,
+/// a template expression building the command line from them, and its reply.
+struct Method {
+    name: &'static str,
+    doc: &'static str,
+    params: &'static str,
+    line: &'static str,
+    reply: Reply,
+}
+
+const METHODS: &[Method] = &[
+    Method {
+        name: "eval",
+        doc: "Evaluates JavaScript on the watch without echoing it to the console.",
+        params: "expression: string",
+        line: "`eval ${expression}`",
+        reply: Reply::Json,
+    },
+    Method {
+        name: "readText",
+        doc: "Finds the runs of text drawn on the screen in one of the firmware's fonts.",
+        params: "font = \"6x8\"",
+        line: "`read-text ${font}`",
+        reply: Reply::Json,
+    },
+    Method {
+        name: "send",
+        doc: "Sends a line of text to the console.",
+        params: "text: string",
+        line: "`send ${text}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "wait",
+        doc: "Does nothing for the given number of milliseconds.",
+        params: "ms: number",
+        line: "`wait ${ms}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "touch",
+        doc: "Starts or continues a touch, optionally with a pressure from 0 to 255.",
+        params: "x: number, y: number, pressure?: number",
+        line: "`touch ${x} ${y}${pressure === undefined ? \"\" : ` ${pressure}`}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "release",
+        doc: "Ends a touch.",
+        params: "x: number, y: number",
+        line: "`release ${x} ${y}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "tap",
+        doc: "Touches and releases at a point.",
+        params: "x: number, y: number",
+        line: "`tap ${x} ${y}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "button",
+        doc: "Presses or releases a button, numbered from 1.",
+        params: "down: boolean, button = 1",
+        line: "`button ${button} ${down ? \"down\" : \"up\"}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "touchMode",
+        doc: "Switches between recognizing gestures in the emulator and passing raw touches through.",
+        params: "mode: \"gestures\" | \"raw\"",
+        line: "`touch-mode ${mode}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "pause",
+        doc: "Stops running the firmware, freezing its clock.",
+        params: "",
+        line: "\"pause\"",
+        reply: Reply::None,
+    },
+    Method {
+        name: "resume",
+        doc: "Starts running the firmware again.",
+        params: "",
+        line: "\"resume\"",
+        reply: Reply::None,
+    },
+    Method {
+        name: "step",
+        doc: "While paused, runs the firmware for one idle call or for some milliseconds of virtual time.",
+        params: "ms?: number",
+        line: "ms === undefined ? \"step\" : `step ${ms}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "runUntil",
+        doc: "Waits until console output matches the regex or the time passes, returning `console <text>` or `timeout`.",
+        params: "pattern: string, ms: number",
+        line: "`run-until /${pattern}/ ${ms}`",
+        reply: Reply::Text,
+    },
+    Method {
+        name: "waitIdle",
+        doc: "Waits until the firmware has nothing due within `quietMs` or `ms` pass, returning `idle` or `timeout`.",
+        params: "quietMs: number, ms: number",
+        line: "`wait-idle ${quietMs} ${ms}`",
+        reply: Reply::Text,
+    },
+    Method {
+        name: "expectPixel",
+        doc: "Fails unless the pixel has the given color (a name, 3-bit value, or `#rrggbb`).",
+        params: "x: number, y: number, color: string | number",
+        line: "`expect-pixel ${x} ${y} ${color}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "regionHash",
+        doc: "Hashes a region of the screen, for use with `expectRegion`.",
+        params: "x: number, y: number, w: number, h: number",
+        line: "`region-hash ${x} ${y} ${w} ${h}`",
+        reply: Reply::Text,
+    },
+    Method {
+        name: "expectRegion",
+        doc: "Fails unless the region has the given hash.",
+        params: "x: number, y: number, w: number, h: number, hash: string",
+        line: "`expect-region ${x} ${y} ${w} ${h} ${hash}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "expectText",
+        doc: "Fails unless the text is drawn on the screen in the default font.",
+        params: "text: string",
+        line: "`expect-text ${text}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "menuSelect",
+        doc: "Scrolls through the menu on screen until an item containing the label is visible, then taps it.",
+        params: "label: string",
+        line: "`menu-select ${label}`",
+        reply: Reply::Text,
+    },
+    Method {
+        name: "screenshot",
+        doc: "Saves the screen as a PNG file, at a path on the emulator's side.",
+        params: "path: string",
+        line: "`screenshot ${quote(path)}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "overlayScreenshot",
+        doc: "Saves just the overlay layer as a PNG file.",
+        params: "path: string",
+        line: "`overlay-screenshot ${quote(path)}`",
+        reply: Reply::Text,
+    },
+    Method {
+        name: "expectScreenshot",
+        doc: "Fails unless the screen matches a PNG file.",
+        params: "path: string",
+        line: "`expect-screenshot ${quote(path)}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "pin",
+        doc: "Gets a pin's raw value.",
+        params: "pin: number",
+        line: "`pin ${pin}`",
+        reply: Reply::Json,
+    },
+    Method {
+        name: "setPin",
+        doc: "Sets a pin's raw value and sends the firmware a pin watch event for it.",
+        params: "pin: number, value: boolean",
+        line: "`set-pin ${pin} ${value ? 1 : 0}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "pinEvent",
+        doc: "Sends the firmware a pin watch event without changing the pin.",
+        params: "pin: number",
+        line: "`pin-event ${pin}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "timezone",
+        doc: "Sets the watch's timezone as an offset from UTC in hours.",
+        params: "hours: number",
+        line: "`timezone ${hours}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "clockDrift",
+        doc: "Makes the watch's clock gain (or, if negative, lose) time at the given parts per million.",
+        params: "ppm: number",
+        line: "`clock-drift ${ppm}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "clockSkew",
+        doc: "Jumps the watch's clock forward (or, if negative, back) by some milliseconds.",
+        params: "ms: number",
+        line: "`clock-skew ${ms}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "timeSync",
+        doc: "Syncs the watch's time to the host's clock plus some milliseconds, and its timezone if given, as Gadgetbridge does.",
+        params: "offsetMs = 0, timezone?: number",
+        line: "timezone === undefined ? `time-sync ${offsetMs}` : `time-sync ${offsetMs} ${timezone}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "buzzes",
+        doc: "Gets the buzzes of the vibration motor since the last `buzzes` or `expectBuzz`.",
+        params: "",
+        line: "\"buzzes\"",
+        reply: Reply::Json,
+    },
+    Method {
+        name: "expectBuzz",
+        doc: "Fails unless the vibration motor has buzzed at least `count` times since the last check.",
+        params: "count = 1",
+        line: "`expect-buzz ${count}`",
+        reply: Reply::Json,
+    },
+    Method {
+        name: "gestures",
+        doc: "Gets the gestures recognized since the last `gestures` or `expectGesture`.",
+        params: "",
+        line: "\"gestures\"",
+        reply: Reply::Json,
+    },
+    Method {
+        name: "expectGesture",
+        doc: "Fails unless the given gesture was recognized since the last check.",
+        params: "name: \"tap\" | \"drag\" | \"swipe up\" | \"swipe down\" | \"swipe left\" | \"swipe right\"",
+        line: "`expect-gesture ${name}`",
+        reply: Reply::Json,
+    },
+    Method {
+        name: "watchedPins",
+        doc: "Gets the pins the firmware has watches on.",
+        params: "",
+        line: "\"watched-pins\"",
+        reply: Reply::Json,
+    },
+    Method {
+        name: "framebuffer",
+        doc: "Gets the firmware's framebuffer as packed pixels in base64, with its size, format, frame number, and whether the LCD is on.",
+        params: "",
+        line: "\"framebuffer\"",
+        reply: Reply::Json,
+    },
+    Method {
+        name: "appList",
+        doc: "Gets the installed apps, each with its id, name, type, and version.",
+        params: "",
+        line: "\"app ls\"",
+        reply: Reply::Json,
+    },
+    Method {
+        name: "appRemove",
+        doc: "Removes an installed app and all its files, as the App Loader does.",
+        params: "id: string",
+        line: "`app rm ${id}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "appSetClock",
+        doc: "Makes an installed clock the one the watch shows.",
+        params: "id: string",
+        line: "`app set-clock ${id}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "ancsNotify",
+        doc: "Sends a notification the way an iPhone does, with the given fields overriding a canned message, returning its UID.",
+        params: "notification: { appId?: string; appName?: string; category?: number; title?: string; subtitle?: string; message?: string; positive?: string; negative?: string; silent?: boolean; important?: boolean } = {}",
+        line: "`ancs notify ${JSON.stringify(notification)}`",
+        reply: Reply::Json,
+    },
+    Method {
+        name: "ancsRemove",
+        doc: "Removes a notification, as dismissing it on the phone does.",
+        params: "uid: number",
+        line: "`ancs rm ${uid}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "ancsActions",
+        doc: "Gets the actions the watch has taken on notifications since the last call, each a UID and whether it was the positive action.",
+        params: "",
+        line: "\"ancs actions\"",
+        reply: Reply::Json,
+    },
+    Method {
+        name: "theme",
+        doc: "Switches to the light or dark theme (or whichever isn't in use) and reloads the app, returning the new theme.",
+        params: "theme?: \"light\" | \"dark\"",
+        line: "theme === undefined ? \"theme\" : `theme ${theme}`",
+        reply: Reply::Text,
+    },
+    Method {
+        name: "themeScreenshots",
+        doc: "Saves the screen in both themes as `<name>.light.png` and `<name>.dark.png`.",
+        params: "path: string",
+        line: "`theme-screenshots ${quote(path)}`",
+        reply: Reply::None,
+    },
+    Method {
+        name: "charger",
+        doc: "Connects or disconnects the charger (or toggles it), returning `charging` or `not charging` as the firmware sees it.",
+        params: "on?: boolean",
+        line: "on === undefined ? \"charger\" : `charger ${on ? \"on\" : \"off\"}`",
+        reply: Reply::Text,
+    },
+    Method {
+        name: "bluetooth",
+        doc: "Connects or disconnects an emulated phone over Bluetooth (or toggles it), returning `connected` or `disconnected` as the firmware sees it.",
+        params: "connected?: boolean",
+        line: "connected === undefined ? \"bluetooth\" : `bluetooth ${connected ? \"on\" : \"off\"}`",
+        reply: Reply::Text,
+    },
+    Method {
+        name: "power",
+        doc: "Gets how many seconds each part of the watch and the CPU have been on.",
+        params: "",
+        line: "\"power\"",
+        reply: Reply::Json,
+    },
+    Method {
+        name: "battery",
+        doc: "Gets the battery model's level and how much each thing has drained, in percent.",
+        params: "",
+        line: "\"battery\"",
+        reply: Reply::Json,
+    },
+    Method {
+        name: "saveFlash",
+        doc: "Saves the whole flash as an image, compressed if the path ends in `.gz` or `.zst`.",
+        params: "path: string",
+        line: "`save-flash ${quote(path)}`",
+        reply: Reply::None,
+    },
+];
+
+const PRELUDE: &str = r#"// Generated by `banglejs-emu ts-client`; regenerate rather than editing.
+
+import { Socket, connect } from "net";
+
+/** A failure reported by the emulator for a command. */
+export class CommandError extends Error {}
+
+function quote(path: string): string {
+  return `"${path}"`;
+}
+
+/** A connection to the control interface of an emulator started with `-C`. */
+export class Emulator {
+  private buffered = "";
+  private pending: { resolve: (reply: string) => void; reject: (err: Error) => void }[] = [];
+
+  private constructor(private socket: Socket) {
+    socket.setEncoding("utf8");
+    socket.on("data", (data: string) => {
+      this.buffered += data;
+      let end;
+      while ((end = this.buffered.indexOf("\n")) >= 0) {
+        const line = this.buffered.slice(0, end);
+        this.buffered = this.buffered.slice(end + 1);
+        const waiter = this.pending.shift();
+        if (!waiter) continue;
+        if (line === "ok" || line.startsWith("ok ")) {
+          waiter.resolve(line.slice(3));
+        } else {
+          waiter.reject(new CommandError(line.replace(/^err /, "")));
+        }
+      }
+    });
+    socket.on("close", () => {
+      for (const waiter of this.pending.splice(0)) {
+        waiter.reject(new Error("connection to the emulator closed"));
+      }
+    });
+  }
+
+  /** Connects to the control interface, by default on localhost:37027. */
+  static connect(port = 37027, host = "localhost"): Promise<Emulator> {
+    return new Promise((resolve, reject) => {
+      const socket = connect(port, host, () => resolve(new Emulator(socket)));
+      socket.once("error", reject);
+    });
+  }
+
+  close(): void {
+    this.socket.end();
+  }
+
+  /** Sends a raw command line, returning the text of the reply. */
+  command(line: string): Promise<string> {
+    return new Promise((resolve, reject) => {
+      this.pending.push({ resolve, reject });
+      this.socket.write(line + "\n");
+    });
+  }
+
+  /** Writes a file into the watch's storage, as the app loader does. */
+  async writeFile(name: string, contents: string | Uint8Array): Promise<void> {
+    const b64 = (data: string | Uint8Array) => Buffer.from(data).toString("base64");
+    await this.command(
+      `eval require("Storage").write(atob("${b64(name)}"), atob("${b64(contents)}"))`,
+    );
+  }
+
+  /** Loads an app, or the default clock if none is given. */
+  async load(file?: string): Promise<void> {
+    await this.command(file === undefined ? "eval load()" : `eval load(${JSON.stringify(file)})`);
+  }
+"#;
+
+/// Generates the TypeScript source of the client.
+pub fn generate() -> String {
+    let mut out = PRELUDE.to_owned();
+    for method in METHODS {
+        let ret = match method.reply {
+            Reply::None => "void",
+            Reply::Json => "unknown",
+            Reply::Text => "string",
+        };
+        writeln!(out, "\n  /** {} */", method.doc).unwrap();
+        writeln!(
+            out,
+            "  async {}({}): Promise<{ret}> {{",
+            method.name, method.params
+        )
+        .unwrap();
+        let line = method.line;
+        match method.reply {
+            Reply::None => writeln!(out, "    await this.command({line});").unwrap(),
+            Reply::Json => {
+                writeln!(out, "    return JSON.parse(await this.command({line}));").unwrap()
+            }
+            Reply::Text => writeln!(out, "    return this.command({line});").unwrap(),
+        }
+        writeln!(out, "  }}").unwrap();
+    }
+    out.push_str("}\n");
+    out
+}
+
+/// Writes an npm package containing the client into a directory.
+pub fn write_package(dir: &Path) -> anyhow::Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {dir:?}"))?;
+    let package = serde_json::json!({
+        "name": "banglejs-emu",
+        "version": env!("CARGO_PKG_VERSION"),
+        "description": "Client for the control interface of banglejs-emu",
+        "main": "index.js",
+        "types": "index.d.ts",
+        "scripts": { "prepare": "tsc" },
+        "devDependencies": { "@types/node": "*", "typescript": "*" },
+    });
+    let tsconfig = serde_json::json!({
+        "compilerOptions": {
+            "target": "es2020",
+            "module": "commonjs",
+            "declaration": true,
+            "strict": true,
+        },
+        "files": ["index.ts"],
+    });
+    for (name, contents) in [
+        (
+            "package.json",
+            serde_json::to_string_pretty(&package)? + "\n",
+        ),
+        (
+            "tsconfig.json",
+            serde_json::to_string_pretty(&tsconfig)? + "\n",
+        ),
+        ("index.ts", generate()),
+    ] {
+        let path = dir.join(name);
+        fs::write(&path, contents).with_context(|| format!("Failed to write {path:?}"))?;
+    }
+    Ok(())
+}
@@ -1,13 +1,23 @@
+use std::{ops::Range, str::FromStr, sync::OnceLock, time::Instant};
+
+use regex::Regex;
+use serde_derive::Deserialize;
 use tui::{
+    backend::TestBackend,
     buffer::Buffer,
     layout::{Alignment, Rect},
-    style::{Color, Style},
-    text::Text,
+    style::{Color, Modifier, Style},
+    text::{Spans, Text},
     widgets::{Block, StatefulWidget, Widget},
+    Terminal,
 };
 use unicode_width::UnicodeWidthStr;
 
-use crate::emu::{self, Screen};
+use crate::{
+    device::ButtonPosition,
+    emu::{self, Screen},
+    timeline::{EventKind, Timeline},
+};
 
 fn get_line_offset(line_width: u16, text_area_width: u16, alignment: Alignment) -> u16 {
     match alignment {
@@ -17,9 +27,72 @@ fn get_line_offset(line_width: u16, text_area_width: u16, alignment: Alignment)
     }
 }
 
+/// Renders a widget to an off-screen terminal of the given size and returns
+/// what it drew, for checking rendering without a real terminal.
+pub fn render_to_buffer(widget: impl Widget, width: u16, height: u16) -> Buffer {
+    render_stateful_to_buffer(Stateless(widget), width, height, &mut ())
+}
+
+/// Like [`render_to_buffer`], for a widget with state.
+pub fn render_stateful_to_buffer<W: StatefulWidget>(
+    widget: W,
+    width: u16,
+    height: u16,
+    state: &mut W::State,
+) -> Buffer {
+    let mut terminal = Terminal::new(TestBackend::new(width, height)).unwrap();
+    terminal
+        .draw(|f| f.render_stateful_widget(widget, f.size(), state))
+        .unwrap();
+    terminal.backend().buffer().clone()
+}
+
+/// The text of each row of a buffer, without styles, for comparing against
+/// expected output. The cells that wide characters cover are left out, as a
+/// terminal would show them.
+pub fn buffer_lines(buf: &Buffer) -> Vec<String> {
+    let area = buf.area();
+    (area.top()..area.bottom())
+        .map(|y| {
+            let mut line = String::new();
+            let mut x = area.left();
+            while x < area.right() {
+                let symbol = &buf.get(x, y).symbol;
+                line.push_str(symbol);
+                x += (symbol.width() as u16).max(1);
+            }
+            line
+        })
+        .collect()
+}
+
+/// Lets a plain widget be rendered as a stateful one.
+struct Stateless<W>(W);
+
+impl<W: Widget> StatefulWidget for Stateless<W> {
+    type State = ();
+
+    fn render(self, area: Rect, buf: &mut Buffer, _state: &mut ()) {
+        self.0.render(area, buf);
+    }
+}
+
+/// Finds the byte ranges of case-insensitive occurrences of `query` in `line`.
+pub fn find_matches(line: &str, query: &str) -> Vec<Range<usize>> {
+    if query.is_empty() {
+        return vec![];
+    }
+    line.to_ascii_lowercase()
+        .match_indices(&query.to_ascii_lowercase())
+        .map(|(ind, m)| ind..ind + m.len())
+        .collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct Console<'a> {
     text: Text<'a>,
+    scroll: usize,
+    highlight: Option<(&'a str, Option<usize>)>,
 }
 
 impl<'a> Console<'a> {
@@ -27,37 +100,383 @@ impl<'a> Console<'a> {
     where
         T: Into<Text<'a>>,
     {
-        Console { text: text.into() }
+        Console {
+            text: text.into(),
+            scroll: 0,
+            highlight: None,
+        }
+    }
+
+    /// Shows the text scrolled up from the bottom by some number of lines.
+    pub fn scroll(mut self, lines: usize) -> Self {
+        self.scroll = lines;
+        self
+    }
+
+    /// Highlights occurrences of a search query, with those on the line with
+    /// the given index standing out further.
+    pub fn highlight(mut self, query: &'a str, current: Option<usize>) -> Self {
+        self.highlight = Some((query, current));
+        self
     }
 }
 
+/// Finds the byte ranges of web links in `line`, leaving out punctuation that
+/// probably ends the sentence they're in rather than the link.
+pub fn find_urls(line: &str) -> Vec<Range<usize>> {
+    static URL: OnceLock<Regex> = OnceLock::new();
+    let url = URL.get_or_init(|| Regex::new(r#"https?://[^\s"'<>`]+"#).unwrap());
+    url.find_iter(line)
+        .map(|m| {
+            let trimmed = m
+                .as_str()
+                .trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']']);
+            m.start()..m.start() + trimmed.len()
+        })
+        .collect()
+}
+
+/// A run of link text drawn on one row, so that it can be made into a
+/// hyperlink after drawing, which tui has no way to express.
+#[derive(Clone, Debug)]
+pub struct Link {
+    pub x: u16,
+    pub y: u16,
+    pub text: String,
+    pub url: String,
+}
+
 impl<'a> Widget for Console<'a> {
     fn render(self, area: Rect, buf: &mut Buffer) {
+        StatefulWidget::render(self, area, buf, &mut vec![]);
+    }
+}
+
+/// Rendering a console with state collects the links it draws, underlined.
+impl<'a> StatefulWidget for Console<'a> {
+    type State = Vec<Link>;
+
+    fn render(self, area: Rect, buf: &mut Buffer, links: &mut Vec<Link>) {
         if area.height < 1 {
             return;
         }
 
         let mut y = area.height - 1;
-        for line in self.text.lines.iter().rev() {
-            let mut x = 0;
-            for ch in line
-                .0
-                .iter()
-                .flat_map(|span| span.styled_graphemes(Style::default()))
-            {
-                let symbol = ch.symbol;
-                buf.get_mut(area.left() + x, area.top() + y)
-                    .set_symbol(if symbol.is_empty() { " " } else { symbol });
-                x += symbol.width() as u16;
-                if x >= area.width {
-                    break;
+        for (ind, line) in self.text.lines.iter().enumerate().rev().skip(self.scroll) {
+            let text: String = line.0.iter().map(|s| s.content.as_ref()).collect();
+            let urls = find_urls(&text);
+            let (matches, style) = match self.highlight {
+                Some((query, current)) => {
+                    let style = if current == Some(ind) {
+                        Style::default().fg(Color::Black).bg(Color::Yellow)
+                    } else {
+                        Style::default().add_modifier(Modifier::REVERSED)
+                    };
+                    (find_matches(&text, query), style)
+                }
+                None => (vec![], Style::default()),
+            };
+
+            let rows = wrap_line(line, area.width);
+            for (row_ind, row) in rows.iter().enumerate().rev() {
+                let mut x = 0;
+                if row_ind > 0 {
+                    buf.get_mut(area.left(), area.top() + y)
+                        .set_symbol(CONTINUATION)
+                        .set_fg(Color::DarkGray);
+                    x = 1;
                 }
+                // The link being drawn, by its index in `urls`.
+                let mut link: Option<(usize, Link)> = None;
+                for &(symbol, ofs, width) in row {
+                    let cell = buf
+                        .get_mut(area.left() + x, area.top() + y)
+                        .set_symbol(symbol);
+                    let url = urls.iter().position(|r| r.contains(&ofs));
+                    if url.is_some() {
+                        cell.modifier.insert(Modifier::UNDERLINED);
+                    }
+                    if matches.iter().any(|m| m.contains(&ofs)) {
+                        cell.set_style(style);
+                    }
+                    // The cells a wide character covers are left blank.
+                    for i in 1..width {
+                        buf.get_mut(area.left() + x + i, area.top() + y).reset();
+                    }
+                    match (&mut link, url) {
+                        (Some((i, link)), Some(j)) if *i == j => link.text.push_str(symbol),
+                        _ => {
+                            links.extend(link.take().map(|(_, link)| link));
+                            link = url.map(|j| {
+                                let link = Link {
+                                    x: area.left() + x,
+                                    y: area.top() + y,
+                                    text: symbol.to_owned(),
+                                    url: text[urls[j].clone()].to_owned(),
+                                };
+                                (j, link)
+                            });
+                        }
+                    }
+                    x += width;
+                }
+                links.extend(link.map(|(_, link)| link));
+
+                match y.checked_sub(1) {
+                    Some(y2) => y = y2,
+                    None => return,
+                }
+            }
+        }
+    }
+}
+
+/// Draws two rows of the screen in one row of the terminal, the top in the
+/// background color and the bottom in the foreground color.
+const HALF_BLOCK: &str = "\u{2584}";
+
+/// Marks the rows a long console line wraps onto after its first.
+const CONTINUATION: &str = "\u{21aa}";
+
+/// Splits a line into the rows it takes up when wrapped at `width` columns,
+/// with room for the continuation marker on every row after the first. Each
+/// character comes with its byte offset in the line, for highlighting, and its
+/// width; characters that take up no space are dropped, and ones that would
+/// straddle the edge move to the next row.
+fn wrap_line<'a>(line: &'a Spans, width: u16) -> Vec<Vec<(&'a str, usize, u16)>> {
+    let mut rows = vec![vec![]];
+    let mut x = 0;
+    let mut ofs = 0;
+    for ch in line
+        .0
+        .iter()
+        .flat_map(|span| span.styled_graphemes(Style::default()))
+    {
+        let symbol = ch.symbol;
+        let w = symbol.width() as u16;
+        ofs += symbol.len();
+        if w == 0 {
+            continue;
+        }
+        // Very narrow panes just cut lines off.
+        if x + w > width && x > 0 {
+            if width <= 2 {
+                break;
             }
+            rows.push(vec![]);
+            x = 1;
+        }
+        rows.last_mut()
+            .unwrap()
+            .push((symbol, ofs - symbol.len(), w.min(width)));
+        x += w;
+    }
+    rows
+}
+
+/// The light the watch's transflective LCD is seen in, which changes how it
+/// looks: it reflects ambient light, so it's at its best in sunlight, while at
+/// night only the dim, bluish backlight shows through it.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Lighting {
+    Sunlight,
+    #[default]
+    Indoor,
+    Night,
+}
+
+/// How the LCD responds under some lighting.
+struct Response {
+    /// The reflectance of a channel that's fully off and fully on, between
+    /// which it goes linearly.
+    levels: (f64, f64),
+    /// How far colors are pulled toward grey.
+    desaturation: f64,
+    /// How much of each channel the light has.
+    tint: (f64, f64, f64),
+}
+
+impl Lighting {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sunlight => "sunlight",
+            Self::Indoor => "indoor",
+            Self::Night => "night",
+        }
+    }
+
+    /// The lighting after this one, for cycling through them.
+    pub fn next(self) -> Self {
+        match self {
+            Self::Sunlight => Self::Indoor,
+            Self::Indoor => Self::Night,
+            Self::Night => Self::Sunlight,
+        }
+    }
+
+    fn response(self) -> Response {
+        match self {
+            Self::Sunlight => Response {
+                levels: (0.06, 0.95),
+                desaturation: 0.15,
+                tint: (1.0, 1.0, 1.0),
+            },
+            Self::Indoor => Response {
+                levels: (0.12, 0.82),
+                desaturation: 0.3,
+                tint: (1.0, 1.0, 1.0),
+            },
+            Self::Night => Response {
+                levels: (0.08, 0.45),
+                desaturation: 0.5,
+                tint: (0.85, 0.95, 1.15),
+            },
+        }
+    }
+}
+
+impl FromStr for Lighting {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sunlight" => Ok(Self::Sunlight),
+            "indoor" => Ok(Self::Indoor),
+            "night" => Ok(Self::Night),
+            _ => anyhow::bail!("expected `sunlight`, `indoor`, or `night`"),
+        }
+    }
+}
+
+/// Renders the screen in 24-bit color approximating the look of the watch's
+/// transflective LCD, whose colors are much less saturated than the pure ANSI
+/// ones and whose white is more of a light grey.
+#[derive(Clone, Copy, Debug)]
+pub struct LcdStyle {
+    pub gamma: f64,
+    pub brightness: f64,
+    pub lighting: Lighting,
+}
+
+impl LcdStyle {
+    fn color(&self, c: emu::Color) -> Color {
+        let Response {
+            levels,
+            desaturation,
+            tint,
+        } = self.lighting.response();
+        let level = |v: u8| levels.0 + (levels.1 - levels.0) * f64::from(v) / 255.0;
+        let [r, g, b] = c.rgb8().map(level);
+        let grey = (r + g + b) / 3.0;
+        let channel = |v: f64, tint: f64| {
+            let v = (v + (grey - v) * desaturation) * tint;
+            let v = (v * self.brightness).clamp(0.0, 1.0).powf(1.0 / self.gamma);
+            (v * 255.0).round() as u8
+        };
+        Color::Rgb(channel(r, tint.0), channel(g, tint.1), channel(b, tint.2))
+    }
+}
+
+/// How the screen's colors are shown in the terminal when not approximating
+/// the LCD.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Palette {
+    /// The terminal's own ANSI colors.
+    #[default]
+    Ansi,
+    /// Fully saturated colors, with the dark ones lightened to stand out
+    /// against black.
+    HighContrast,
+    /// The Okabe-Ito colors, which stay distinct with deuteranopia or
+    /// protanopia (red and green are shown as vermilion and bluish green).
+    #[serde(alias = "deuteranopia", alias = "protanopia")]
+    RedGreen,
+}
+
+impl Palette {
+    /// The colors the 3-bit ones are shown as, by value, or `None` for the
+    /// terminal's own.
+    fn colors(self) -> Option<[[u8; 3]; 8]> {
+        const BLACK: [u8; 3] = [0, 0, 0];
+        const WHITE: [u8; 3] = [255, 255, 255];
+        match self {
+            Palette::Ansi => None,
+            Palette::HighContrast => Some([
+                BLACK,
+                [255, 64, 64],
+                [0, 255, 0],
+                [255, 255, 0],
+                [64, 128, 255],
+                [255, 64, 255],
+                [0, 255, 255],
+                WHITE,
+            ]),
+            Palette::RedGreen => Some([
+                BLACK,
+                [213, 94, 0],
+                [0, 158, 115],
+                [240, 228, 66],
+                [0, 114, 178],
+                [204, 121, 167],
+                [86, 180, 233],
+                WHITE,
+            ]),
+        }
+    }
 
-            match y.checked_sub(1) {
-                Some(y2) => y = y2,
-                None => break,
+    /// Gets how to show a color. The 3-bit colors map straight to the
+    /// palette's; any other color is blended from them by how far each of
+    /// its channels is on, or with the terminal's colors, shown as it is.
+    fn color(self, c: emu::Color) -> Color {
+        const ANSI: [Color; 8] = [
+            Color::Black,
+            Color::Red,
+            Color::Green,
+            Color::Yellow,
+            Color::Blue,
+            Color::Magenta,
+            Color::Cyan,
+            Color::White,
+        ];
+        let is_3bit = emu::Color::new(c.value()) == c;
+        let Some(colors) = self.colors() else {
+            if is_3bit {
+                return ANSI[usize::from(c.value())];
             }
+            let [r, g, b] = c.rgb8();
+            return Color::Rgb(r, g, b);
+        };
+        if is_3bit {
+            let [r, g, b] = colors[usize::from(c.value())];
+            return Color::Rgb(r, g, b);
+        }
+        let on = c.rgb8().map(|v| f64::from(v) / 255.0);
+        let mut mixed = [0.0; 3];
+        for (value, color) in colors.iter().enumerate() {
+            let weight: f64 = on
+                .iter()
+                .enumerate()
+                .map(|(i, &v)| if value & (1 << i) != 0 { v } else { 1.0 - v })
+                .product();
+            for (m, &c) in mixed.iter_mut().zip(color) {
+                *m += weight * f64::from(c);
+            }
+        }
+        let [r, g, b] = mixed.map(|v| v.round() as u8);
+        Color::Rgb(r, g, b)
+    }
+}
+
+impl FromStr for Palette {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ansi" => Ok(Self::Ansi),
+            "high-contrast" => Ok(Self::HighContrast),
+            "red-green" | "deuteranopia" | "protanopia" => Ok(Self::RedGreen),
+            _ => anyhow::bail!("expected `ansi`, `high-contrast`, or `red-green`"),
         }
     }
 }
@@ -65,24 +484,44 @@ impl<'a> Widget for Console<'a> {
 #[derive(Clone)]
 pub struct TuiScreen<'a> {
     screen: &'a Screen,
+    lcd: Option<LcdStyle>,
+    palette: Palette,
+    touches: &'a [(u8, u8)],
 }
 
 impl<'a> TuiScreen<'a> {
     pub fn new(screen: &'a emu::Screen) -> TuiScreen<'a> {
-        TuiScreen { screen }
+        TuiScreen {
+            screen,
+            lcd: None,
+            palette: Palette::default(),
+            touches: &[],
+        }
+    }
+
+    /// Marks recent touches on the screen, oldest first: the latest with a
+    /// cross and the rest, which trace out any drag, with a dot each.
+    pub fn touches(mut self, touches: &'a [(u8, u8)]) -> Self {
+        self.touches = touches;
+        self
+    }
+
+    pub fn lcd(mut self, lcd: Option<LcdStyle>) -> Self {
+        self.lcd = lcd;
+        self
     }
-}
 
-fn color(c: emu::Color) -> Color {
-    match c.rgb() {
-        (false, false, false) => Color::Black,
-        (false, false, true) => Color::Blue,
-        (false, true, false) => Color::Green,
-        (false, true, true) => Color::Cyan,
-        (true, false, false) => Color::Red,
-        (true, false, true) => Color::Magenta,
-        (true, true, false) => Color::Yellow,
-        (true, true, true) => Color::White,
+    /// Sets the colors to use when not approximating the LCD.
+    pub fn palette(mut self, palette: Palette) -> Self {
+        self.palette = palette;
+        self
+    }
+
+    fn color(&self, c: emu::Color) -> Color {
+        match &self.lcd {
+            Some(lcd) => lcd.color(c),
+            None => self.palette.color(c),
+        }
     }
 }
 
@@ -94,26 +533,116 @@ impl<'a> StatefulWidget for TuiScreen<'a> {
             return;
         }
 
-        let x0 = get_line_offset(176, area.width, Alignment::Center);
+        let (width, height) = (self.screen.width() as u16, self.screen.height() as u16);
+        let x0 = get_line_offset(width, area.width, Alignment::Center);
         let y0 = 0;
 
         *state = (x0, y0);
 
-        for y in (0..176.min(2 * area.height)).step_by(2) {
-            for x in 0..176.min(area.width) {
+        // Whether a pixel's column or row is drawn as an ellipsis, to show that
+        // the screen is cut off there.
+        let cut_off = |x: u16, y: u16| {
+            (area.width < width && x == area.width - 1)
+                || (area.height < height.div_ceil(2) && y / 2 == area.height - 1)
+        };
+        for y in (0..height.min(2 * area.height)).step_by(2) {
+            for x in 0..width.min(area.width) {
                 let cell = buf.get_mut(area.left() + x0 + x, area.top() + y0 + y / 2);
 
-                if (area.width < 176 && x == area.width - 1)
-                    || (area.height < 88 && y / 2 == area.height - 1)
-                {
+                if cut_off(x, y) {
                     cell.set_symbol("\u{2026}");
                 } else {
-                    cell.set_symbol("\u{2584}")
-                        .set_bg(color(self.screen.0[y as usize][x as usize]))
-                        .set_fg(color(self.screen.0[y as usize + 1][x as usize]));
+                    let (x, y) = (x as usize, y as usize);
+                    // An odd last row is drawn over black.
+                    let below = self.screen.get(x, y + 1).unwrap_or_default();
+                    cell.set_symbol(HALF_BLOCK)
+                        .set_bg(self.color(self.screen.pixel(x, y)))
+                        .set_fg(self.color(below));
                 };
             }
         }
+
+        const CROSS: [(i16, i16); 9] = [
+            (0, 0),
+            (-1, 0),
+            (-2, 0),
+            (1, 0),
+            (2, 0),
+            (0, -1),
+            (0, -2),
+            (0, 1),
+            (0, 2),
+        ];
+        for (ind, &(tx, ty)) in self.touches.iter().enumerate() {
+            let (shape, color) = if ind + 1 == self.touches.len() {
+                (&CROSS[..], Color::LightMagenta)
+            } else {
+                (&CROSS[..1], Color::Magenta)
+            };
+            for (dx, dy) in shape {
+                let (x, y) = (i16::from(tx) + dx, i16::from(ty) + dy);
+                let (Ok(x), Ok(y)) = (u16::try_from(x), u16::try_from(y)) else {
+                    continue;
+                };
+                if x >= width.min(area.width) || y >= height.min(2 * area.height) || cut_off(x, y) {
+                    continue;
+                }
+                let cell = buf.get_mut(area.left() + x0 + x, area.top() + y0 + y / 2);
+                if y % 2 == 0 {
+                    cell.set_bg(color);
+                } else {
+                    cell.set_fg(color);
+                }
+            }
+        }
+    }
+}
+
+/// Marks where a device's buttons are on the borders around its screen (those
+/// of the area it's rendered in), so that it's clear that they're separate
+/// from the touchscreen, lighting up the ones being held.
+pub struct ButtonMarkers<'a> {
+    positions: &'a [ButtonPosition],
+    held: &'a [usize],
+    screen_rows: u16,
+}
+
+impl<'a> ButtonMarkers<'a> {
+    /// Marks buttons at `positions`, of which those numbered (from 1) in
+    /// `held` are held, beside a screen drawn in the top `screen_rows` rows
+    /// inside the borders.
+    pub fn new(positions: &'a [ButtonPosition], held: &'a [usize], screen_rows: u16) -> Self {
+        Self {
+            positions,
+            held,
+            screen_rows,
+        }
+    }
+}
+
+impl<'a> Widget for ButtonMarkers<'a> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        if area.width < 2 || area.height < 3 {
+            return;
+        }
+        let rows = self.screen_rows.clamp(1, area.height - 2);
+        for (ind, pos) in self.positions.iter().enumerate() {
+            let x = if pos.right {
+                area.right() - 1
+            } else {
+                area.left()
+            };
+            let middle = area.top() + 1 + (f32::from(rows - 1) * pos.height).round() as u16;
+            let color = if self.held.contains(&(ind + 1)) {
+                Color::Yellow
+            } else {
+                Color::DarkGray
+            };
+            let (top, bottom) = (area.top() + 1, area.top() + rows);
+            for y in middle.saturating_sub(1).max(top)..=(middle + 1).min(bottom) {
+                buf.get_mut(x, y).set_symbol("\u{2588}").set_fg(color);
+            }
+        }
     }
 }
 
@@ -145,3 +674,239 @@ impl<'a, W: StatefulWidget> StatefulWidget for Blocked<'a, W> {
         self.inner.render(inner, buf, state);
     }
 }
+
+/// Shows recent events on a shared time axis, one row per kind of event, with
+/// the present at the right edge.
+pub struct TimelineView<'a> {
+    timeline: &'a Timeline,
+    now: Instant,
+}
+
+impl<'a> TimelineView<'a> {
+    pub fn new(timeline: &'a Timeline, now: Instant) -> Self {
+        Self { timeline, now }
+    }
+}
+
+impl<'a> Widget for TimelineView<'a> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        const LABEL_WIDTH: u16 = 8;
+        if area.width <= LABEL_WIDTH {
+            return;
+        }
+        let axis_width = area.width - LABEL_WIDTH;
+        let window = self.timeline.window.as_secs_f64();
+
+        for (row, kind) in EventKind::ALL.iter().enumerate() {
+            let row = row as u16;
+            if row >= area.height {
+                break;
+            }
+            let y = area.top() + row;
+            buf.set_string(area.left(), y, kind.label(), Style::default());
+            for x in 0..axis_width {
+                buf.get_mut(area.left() + LABEL_WIDTH + x, y)
+                    .set_symbol("\u{b7}")
+                    .set_fg(Color::DarkGray);
+            }
+
+            let color = match kind {
+                EventKind::Input => Color::Cyan,
+                EventKind::Vibration => Color::Magenta,
+                EventKind::Screen => Color::Green,
+                EventKind::Exception => Color::Red,
+                EventKind::Storage => Color::Yellow,
+                EventKind::Status => Color::White,
+            };
+            for entry in self.timeline.entries().filter(|e| e.kind == *kind) {
+                let age = self.now.saturating_duration_since(entry.time).as_secs_f64();
+                if age > window {
+                    continue;
+                }
+                let col = ((1.0 - age / window) * f64::from(axis_width - 1)).round() as u16;
+                buf.get_mut(area.left() + LABEL_WIDTH + col, y)
+                    .set_symbol("\u{2503}")
+                    .set_fg(color);
+            }
+        }
+    }
+}
+
+/// The raw values of the pins in a grid, with the ones the firmware has
+/// watches on highlighted.
+pub struct PinsView<'a> {
+    values: &'a [bool],
+    watched: &'a [i32],
+}
+
+impl<'a> PinsView<'a> {
+    /// How wide each pin's cell is, e.g. `D17 1` and a gap.
+    pub const CELL_WIDTH: u16 = 8;
+
+    pub fn new(values: &'a [bool], watched: &'a [i32]) -> Self {
+        Self { values, watched }
+    }
+
+    /// How many rows the grid takes at the given width.
+    pub fn rows(pins: usize, width: u16) -> u16 {
+        let per_row = usize::from((width / Self::CELL_WIDTH).max(1));
+        pins.div_ceil(per_row) as u16
+    }
+}
+
+impl<'a> Widget for PinsView<'a> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let per_row = (area.width / Self::CELL_WIDTH).max(1);
+        for (pin, &value) in self.values.iter().enumerate() {
+            let (row, col) = (pin as u16 / per_row, pin as u16 % per_row);
+            if row >= area.height {
+                break;
+            }
+            let style = if self.watched.contains(&(pin as i32)) {
+                Style::default().fg(Color::Black).bg(Color::Yellow)
+            } else if value {
+                Style::default().fg(Color::Green)
+            } else {
+                Style::default().fg(Color::DarkGray)
+            };
+            buf.set_stringn(
+                area.left() + col * Self::CELL_WIDTH,
+                area.top() + row,
+                format!("D{pin:<2} {}", value as u8),
+                usize::from(area.width),
+                style,
+            );
+        }
+    }
+}
+
+/// The ASCII character to draw in place of one that isn't, for terminals
+/// without Unicode: box-drawing characters become their usual ASCII
+/// stand-ins, and anything else without one becomes `?`.
+pub fn ascii_symbol(symbol: &str) -> &str {
+    if symbol.is_ascii() {
+        return symbol;
+    }
+    match symbol {
+        "\u{2502}" | "\u{2503}" | "\u{2551}" => "|",
+        "\u{2500}" | "\u{2550}" => "-",
+        "\u{2501}" => "=",
+        "\u{250c}" | "\u{2510}" | "\u{2514}" | "\u{2518}" | "\u{256d}" | "\u{256e}"
+        | "\u{256f}" | "\u{2570}" | "\u{2554}" | "\u{2557}" | "\u{255a}" | "\u{255d}" => "+",
+        "\u{250f}" | "\u{2513}" | "\u{2517}" | "\u{251b}" => "#",
+        "\u{2588}" => "#",
+        "\u{2026}" => "~",
+        CONTINUATION => ">",
+        "\u{b7}" => ".",
+        _ => "?",
+    }
+}
+
+/// Replaces everything drawn in its area that isn't ASCII, for terminals that
+/// can't show anything else; rendered last, after the widgets it covers. The
+/// half blocks that draw two rows of the screen per row of the terminal become
+/// a space in the top pixel's color where both pixels match, or otherwise a
+/// `#` in the bottom one's color over the top one's.
+pub struct AsciiOnly;
+
+impl Widget for AsciiOnly {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        for y in area.top()..area.bottom() {
+            for x in area.left()..area.right() {
+                let cell = buf.get_mut(x, y);
+                if cell.symbol == HALF_BLOCK {
+                    let symbol = if cell.fg == cell.bg { " " } else { "#" };
+                    cell.set_symbol(symbol);
+                } else if !cell.symbol.is_ascii() {
+                    let symbol = ascii_symbol(&cell.symbol).to_owned();
+                    cell.set_symbol(&symbol);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tui::widgets::Borders;
+
+    use super::*;
+    use crate::emu::{PixelFormat, ScreenSpec};
+
+    fn screen(width: usize, height: usize) -> Screen {
+        Screen::new(ScreenSpec {
+            width,
+            height,
+            format: PixelFormat::Rgb3,
+        })
+    }
+
+    #[test]
+    fn screen_is_centered_two_rows_per_line() {
+        let mut screen = screen(4, 3);
+        screen.set(0, 0, emu::Color::new(1));
+        screen.set(0, 1, emu::Color::new(2));
+        screen.set(1, 2, emu::Color::WHITE);
+        let mut origin = (0, 0);
+        let buf = render_stateful_to_buffer(TuiScreen::new(&screen), 8, 3, &mut origin);
+        assert_eq!(origin, (2, 0));
+        assert_eq!(buffer_lines(&buf), ["  ▄▄▄▄  ", "  ▄▄▄▄  ", "        "]);
+        assert_eq!(
+            (buf.get(2, 0).bg, buf.get(2, 0).fg),
+            (Color::Red, Color::Green)
+        );
+        // The odd last row is drawn over black.
+        assert_eq!(
+            (buf.get(3, 1).bg, buf.get(3, 1).fg),
+            (Color::White, Color::Black)
+        );
+    }
+
+    #[test]
+    fn cut_off_screen_ends_in_ellipses() {
+        let screen = screen(6, 6);
+        let buf = render_stateful_to_buffer(TuiScreen::new(&screen), 4, 2, &mut (0, 0));
+        assert_eq!(buffer_lines(&buf), ["▄▄▄…", "…………"]);
+    }
+
+    #[test]
+    fn console_wraps_from_the_bottom() {
+        let console = Console::new("hello\nwrapped line\n世界");
+        let buf = render_to_buffer(console, 6, 4);
+        assert_eq!(buffer_lines(&buf), ["wrappe", "↪d lin", "↪e    ", "世界  "]);
+
+        let console = Console::new("hello\nwrapped line\n世界").scroll(1);
+        let buf = render_to_buffer(console, 6, 4);
+        assert_eq!(buffer_lines(&buf), ["hello ", "wrappe", "↪d lin", "↪e    "]);
+    }
+
+    #[test]
+    fn colors_keep_their_shades() {
+        let grey = emu::Color::from_rgb565(0x8410);
+        assert_eq!(Palette::Ansi.color(grey), Color::Rgb(132, 130, 132));
+        assert_eq!(Palette::Ansi.color(emu::Color::new(1)), Color::Red);
+        assert_eq!(
+            Palette::HighContrast.color(emu::Color::new(1)),
+            Color::Rgb(255, 64, 64)
+        );
+        let dark_red = emu::Color::from_rgb565(0x8000);
+        assert_ne!(
+            Palette::RedGreen.color(dark_red),
+            Palette::RedGreen.color(emu::Color::new(1))
+        );
+        let lcd = LcdStyle {
+            gamma: 1.0,
+            brightness: 1.0,
+            lighting: Lighting::Indoor,
+        };
+        assert_ne!(lcd.color(grey), lcd.color(emu::Color::WHITE));
+        assert_ne!(lcd.color(grey), lcd.color(emu::Color::BLACK));
+    }
+
+    #[test]
+    fn blocked_draws_inside_its_borders() {
+        let block = Block::default().borders(Borders::ALL).title("log");
+        let buf = render_to_buffer(Blocked::new(block, Console::new("a\nb")), 6, 4);
+        assert_eq!(buffer_lines(&buf), ["┌log─┐", "│a   │", "│b   │", "└────┘"]);
+    }
+}
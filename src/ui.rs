@@ -1,44 +1,436 @@
 use std::{
-    io,
+    collections::VecDeque,
+    env, fs,
+    io::{self, Write},
+    path::PathBuf,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, EventStream},
-    execute,
-    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    cursor::MoveTo,
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, EventStream, KeyModifiers},
+    execute, queue,
+    style::{Attribute, Print, SetAttribute},
+    terminal::{
+        disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen, SetTitle,
+    },
 };
-use futures::StreamExt;
+use futures::{future::BoxFuture, FutureExt, StreamExt};
 use futures_timer::Delay;
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
 use tokio::{
     select,
     sync::{
         broadcast::Receiver,
-        mpsc::{UnboundedReceiver, UnboundedSender},
+        mpsc::{Receiver as MpscReceiver, UnboundedSender},
+        oneshot,
     },
+    time::timeout,
 };
 use tui::{
     backend::{Backend, CrosstermBackend},
     layout::{Alignment, Rect},
-    widgets::{Block, Borders},
+    widgets::{Block, BorderType, Borders, Paragraph},
     Terminal,
 };
 
 use crate::{
-    emu::{Input, Output, Screen},
+    advertising,
+    device::ButtonPosition,
+    emu::{self, Device, IdleStats, Input, Output, Screen, ScreenWatch, Step},
+    eval::{parse_watched_pins, WATCHED_PINS_EXPR},
+    firmware::FirmwareInfo,
     futures_extras::OptionFuture,
-    tui_extras::{Blocked, Console, TuiScreen},
+    outbox::Item,
+    timeline::{EventKind, Timeline},
+    tui_extras::{
+        find_matches, AsciiOnly, Blocked, ButtonMarkers, Console, LcdStyle, Palette, PinsView,
+        TimelineView, TuiScreen,
+    },
 };
 
 #[derive(Debug)]
 pub enum UIInput {
     Quit,
     EmuInput(Input),
+    /// Save the emulator's state to a numbered slot.
+    SaveState(u8),
+    /// Go back to the state saved in a numbered slot.
+    LoadState(u8),
+}
+
+/// Things for the UI to display.
+pub enum UIOutput {
+    Emu(Output),
+    Firmware(FirmwareInfo),
+}
+
+// Console output waiting for the UI runs together. (Screens don't come this
+// way; the UI watches for the newest one instead.)
+impl Item for UIOutput {
+    fn merge(&mut self, next: Self) -> Option<Self> {
+        match (self, next) {
+            (
+                UIOutput::Emu(Output::Console(device, data)),
+                UIOutput::Emu(Output::Console(next_device, next_data)),
+            ) if *device == next_device => data
+                .merge(next_data)
+                .map(|rest| UIOutput::Emu(Output::Console(next_device, rest))),
+            (_, next) => Some(next),
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            UIOutput::Emu(Output::Console(_, data)) => data.len(),
+            _ => 0,
+        }
+    }
+}
+
+/// Settings for the TUI.
+pub struct TuiOptions {
+    pub lcd: Option<LcdStyle>,
+    pub palette: Palette,
+    /// The most console output to keep for display, in bytes.
+    pub console_limit: usize,
+    /// Whether to make links in the console clickable.
+    pub hyperlinks: bool,
+    /// Whether to draw only ASCII characters.
+    pub ascii: bool,
+    /// Where the device's buttons are, for marking them beside the screen.
+    pub buttons: &'static [ButtonPosition],
+}
+
+/// Whether the locale says the terminal can show Unicode, as far as can be
+/// told.
+pub fn unicode_locale() -> bool {
+    if cfg!(windows) {
+        return true;
+    }
+    // The first of these that's set decides, as in the C library.
+    ["LC_ALL", "LC_CTYPE", "LANG"]
+        .iter()
+        .find_map(|var| env::var(var).ok().filter(|v| !v.is_empty()))
+        .is_some_and(|locale| {
+            let locale = locale.to_ascii_lowercase();
+            locale.contains("utf-8") || locale.contains("utf8")
+        })
+}
+
+/// How far back the timeline goes.
+const TIMELINE_WINDOW: Duration = Duration::from_secs(10);
+/// How often the timeline is redrawn while it's shown.
+const TIMELINE_TICK: Duration = Duration::from_millis(200);
+/// How many inputs the input log keeps.
+const INPUT_LOG_LEN: usize = 100;
+/// How many inputs the input log shows at once.
+const INPUT_LOG_ROWS: u16 = 8;
+/// How long touches stay marked on the screen.
+const TOUCH_TRAIL: Duration = Duration::from_millis(1000);
+/// How often the pin panel is refreshed while it's shown.
+const PIN_POLL: Duration = Duration::from_secs(1);
+/// How long to wait for the firmware to list its watches, in case it's busy.
+const PIN_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// The narrowest the console is given by default, in columns.
+const CONSOLE_WIDTH: u16 = 80;
+/// How many columns each press of `<` or `>` moves the divider between the
+/// screen and the console.
+const RESIZE_STEP: u16 = 2;
+
+/// Pane sizes chosen at runtime, kept between runs.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct PaneSizes {
+    /// The fraction of the terminal's width the screen pane takes, or `None`
+    /// to fit the screen.
+    screen_share: Option<f64>,
+}
+
+impl PaneSizes {
+    fn path() -> Option<PathBuf> {
+        Some(dirs::config_dir()?.join("banglejs-emu").join("panes.toml"))
+    }
+
+    /// Reads the saved sizes, falling back to the defaults if there are none
+    /// or they can't be read.
+    fn load() -> Self {
+        Self::path()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|text| toml::from_str(&text).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self) {
+        let result = (|| -> anyhow::Result<()> {
+            let path =
+                Self::path().ok_or_else(|| anyhow::format_err!("no config directory available"))?;
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, toml::to_string(self)?)?;
+            Ok(())
+        })();
+        if let Err(e) = result {
+            warn!("failed to save pane sizes: {e:#}");
+        }
+    }
+
+    /// How wide the screen and console panes are in a terminal `width` columns
+    /// wide, where the screen pane needs `screen_width` columns to fit.
+    fn widths(&self, width: u16, screen_width: u16) -> (u16, u16) {
+        let w1 = match self.screen_share {
+            Some(share) => (f64::from(width) * share).round() as u16,
+            None if width >= screen_width + CONSOLE_WIDTH => screen_width,
+            None => {
+                (u32::from(width) * u32::from(screen_width)
+                    / u32::from(screen_width + CONSOLE_WIDTH)) as u16
+            }
+        };
+        let w1 = w1.min(width);
+        (w1, width - w1)
+    }
+
+    /// Moves the divider so that the screen pane is `w1` columns wide, keeping
+    /// a little of each pane.
+    fn set_screen_width(&mut self, w1: u16, width: u16) {
+        if width > 0 {
+            let w1 = w1.clamp(2.min(width), width.saturating_sub(2));
+            self.screen_share = Some(f64::from(w1) / f64::from(width));
+        }
+    }
+}
+
+/// The pins as last probed for the pin panel.
+struct PinStatus {
+    values: Vec<bool>,
+    /// The pins the firmware has watches on, or `None` if it didn't say.
+    watched: Option<Vec<i32>>,
+}
+
+/// Reads the pins from the emulator and asks the firmware which of them it's
+/// watching.
+fn probe_pins(tx: &UnboundedSender<UIInput>) -> BoxFuture<'static, Option<PinStatus>> {
+    let (values_tx, values_rx) = oneshot::channel();
+    let (eval_tx, eval_rx) = oneshot::channel();
+    let _ = tx.send(UIInput::EmuInput(Input::ReadPins(values_tx)));
+    let _ = tx.send(UIInput::EmuInput(Input::Eval(
+        WATCHED_PINS_EXPR.to_owned(),
+        eval_tx,
+    )));
+    async move {
+        let values = values_rx.await.ok()?;
+        let watched = match timeout(PIN_PROBE_TIMEOUT, eval_rx).await {
+            Ok(Ok(Ok(value))) => parse_watched_pins(value).ok(),
+            _ => None,
+        };
+        Some(PinStatus { values, watched })
+    }
+    .boxed()
+}
+
+/// How many columns the screen pane needs to fit the screen and its borders.
+fn screen_pane_width(screen: &Option<Arc<Screen>>) -> u16 {
+    screen.as_ref().map_or(178, |s| s.width() as u16 + 2)
+}
+
+/// Everything the TUI displays.
+struct TuiState {
+    screen: Option<Arc<Screen>>,
+    output_buf: Vec<u8>,
+    firmware: Option<FirmwareInfo>,
+    console: ConsoleView,
+    focus: Focus,
+    timeline: Timeline,
+    show_timeline: bool,
+    show_pins: bool,
+    pins: Option<PinStatus>,
+    show_inputs: bool,
+    /// Descriptions of recent inputs and the gestures recognized in them,
+    /// oldest first, and when they arrived.
+    input_log: VecDeque<(Instant, String)>,
+    show_advertising: bool,
+    /// The last advertising payload the firmware set, and when.
+    advertising: Option<(Instant, Vec<u8>)>,
+    started: Instant,
+    paused: bool,
+    idle_stats: Option<IdleStats>,
+    panes: PaneSizes,
+    /// The buttons being held, numbered from 1.
+    buttons_held: Vec<usize>,
+    /// Recent touches, oldest first, and when they were made.
+    touches: VecDeque<(Instant, u8, u8)>,
+}
+
+/// The pane that keys which mean different things in each go to.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+enum Focus {
+    /// Arrow keys swipe.
+    #[default]
+    Screen,
+    /// Arrow keys scroll, and `f`, `n`, and `N` work.
+    Console,
+}
+
+impl Focus {
+    fn next(self) -> Self {
+        match self {
+            Focus::Screen => Focus::Console,
+            Focus::Console => Focus::Screen,
+        }
+    }
+
+    /// How to draw a pane's borders, to show which one has focus.
+    fn border(self, pane: Focus) -> BorderType {
+        if self == pane {
+            BorderType::Thick
+        } else {
+            BorderType::Plain
+        }
+    }
+}
+
+impl TuiState {
+    fn log_input(&mut self, description: String) {
+        self.input_log.push_back((Instant::now(), description));
+        if self.input_log.len() > INPUT_LOG_LEN {
+            self.input_log.pop_front();
+        }
+    }
+}
+
+/// A search through the console scrollback.
+struct Search {
+    query: String,
+    /// Whether the query is still being typed.
+    editing: bool,
+    /// The line of the current match, if any.
+    line: Option<usize>,
+    found: bool,
+}
+
+impl Search {
+    /// Moves to the nearest match older or newer than the current one (or,
+    /// before the first move, than the end of the scrollback).
+    fn step(&mut self, lines: &[&str], older: bool) {
+        let from = self.line.unwrap_or(lines.len());
+        let found = if older {
+            (0..from)
+                .rev()
+                .find(|&ind| !find_matches(lines[ind], &self.query).is_empty())
+        } else {
+            (from + 1..lines.len()).find(|&ind| !find_matches(lines[ind], &self.query).is_empty())
+        };
+        self.found = found.is_some();
+        if found.is_some() {
+            self.line = found;
+        }
+    }
+
+    fn title(&self) -> String {
+        let status = match (self.editing, self.found, self.line) {
+            (true, ..) => "_".to_owned(),
+            (false, true, Some(line)) => format!(" (line {})", line + 1),
+            (false, ..) => " (not found)".to_owned(),
+        };
+        format!("Console: /{}{status}", self.query)
+    }
+}
+
+/// What part of the console scrollback is shown.
+#[derive(Default)]
+struct ConsoleView {
+    /// The line shown at the bottom of the view, or `None` to follow new
+    /// output.
+    bottom: Option<usize>,
+    search: Option<Search>,
+}
+
+impl ConsoleView {
+    /// Accounts for lines being dropped from the start of the scrollback.
+    fn drop_lines(&mut self, n: usize) {
+        self.bottom = self.bottom.map(|b| b.saturating_sub(n));
+        if let Some(search) = &mut self.search {
+            search.line = search.line.map(|l| l.saturating_sub(n));
+        }
+    }
+
+    /// How many lines up from the end of the scrollback the view is.
+    fn scroll(&self, total: usize) -> usize {
+        self.bottom.map_or(0, |b| total.saturating_sub(b + 1))
+    }
+
+    /// Handles a key press, returning whether it was used. Only keys that
+    /// don't mean anything elsewhere are used when the console isn't focused.
+    fn handle_key(
+        &mut self,
+        code: event::KeyCode,
+        output: &[u8],
+        page: usize,
+        focused: bool,
+    ) -> bool {
+        use event::KeyCode::*;
+        let text = String::from_utf8_lossy(output);
+        let lines: Vec<&str> = text.lines().collect();
+
+        let last = lines.len().saturating_sub(1);
+        let bottom = self.bottom.unwrap_or(last);
+        match (&mut self.search, code) {
+            (Some(search), _) if search.editing => match code {
+                Char(c) => search.query.push(c),
+                Backspace => {
+                    search.query.pop();
+                }
+                Enter => {
+                    search.editing = false;
+                    search.step(&lines, true);
+                }
+                Esc => self.search = None,
+                _ => {}
+            },
+            (_, Char('/')) => {
+                self.search = Some(Search {
+                    query: String::new(),
+                    editing: true,
+                    line: None,
+                    found: false,
+                });
+            }
+            (Some(search), Char('n')) if focused => search.step(&lines, true),
+            (Some(search), Char('N')) if focused => search.step(&lines, false),
+            (Some(_), Esc) => {
+                self.search = None;
+                self.bottom = None;
+            }
+            (_, PageUp) => self.bottom = Some(bottom.saturating_sub(page)),
+            (_, PageDown) => self.bottom = Some(bottom + page).filter(|&b| b < last),
+            (_, Up) if focused => self.bottom = Some(bottom.saturating_sub(1)),
+            (_, Down) if focused => self.bottom = Some(bottom + 1).filter(|&b| b < last),
+            // Stops following where the view is, or jumps back to the end.
+            (_, Char('f')) if focused => {
+                self.bottom = match self.bottom {
+                    Some(_) => None,
+                    None => Some(last),
+                }
+            }
+            (_, End) if focused => self.bottom = None,
+            _ => return false,
+        }
+
+        if let (Char('n' | 'N') | Enter, Some(line)) =
+            (code, self.search.as_ref().and_then(|s| s.line))
+        {
+            self.bottom = Some(line);
+        }
+        true
+    }
 }
 
 pub async fn run_tui(
-    mut rx: UnboundedReceiver<Output>,
+    mut rx: MpscReceiver<UIOutput>,
+    mut screens: ScreenWatch,
     tx: UnboundedSender<UIInput>,
+    mut options: TuiOptions,
     mut quit: Receiver<()>,
 ) -> anyhow::Result<()> {
     // Set up terminal.
@@ -48,94 +440,492 @@ pub async fn run_tui(
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-    fn draw<B: Backend>(
+    fn draw<B: Backend + Write>(
         terminal: &mut Terminal<B>,
-        screen: &Option<Screen>,
-        output: &[u8],
+        state: &TuiState,
+        options: &TuiOptions,
     ) -> io::Result<(u16, u16)> {
+        let TuiOptions {
+            lcd,
+            palette,
+            hyperlinks,
+            ascii,
+            buttons,
+            ..
+        } = *options;
+        let TuiState {
+            screen,
+            output_buf: output,
+            firmware,
+            console,
+            focus,
+            timeline,
+            show_timeline,
+            show_pins,
+            pins,
+            show_inputs,
+            input_log,
+            show_advertising,
+            advertising,
+            started,
+            paused,
+            idle_stats,
+            panes,
+            buttons_held,
+            touches,
+        } = state;
         let mut screen_ofs = (0, 0);
+        let mut links = vec![];
         terminal.draw(|f| {
-            let w1 = 178;
-            let w2 = 80;
-
+            // The screen and its borders.
             let width = f.size().width;
             let height = f.size().height;
-
-            let (w1, w2) = if width >= w1 + w2 {
-                (w1, width - w1)
-            } else {
-                (width * w1 / (w1 + w2), width * w2 / (w1 + w2))
-            };
+            let (w1, w2) = panes.widths(width, screen_pane_width(screen));
 
             if let Some(screen) = screen {
+                let mut title = match firmware {
+                    Some(info) => format!("Screen: Espruino {} ({})", info.version, info.board),
+                    None => "Screen".to_owned(),
+                };
+                if *paused {
+                    title.push_str(" [PAUSED]");
+                } else if let Some(stats) = idle_stats {
+                    title.push_str(&format!(" [{stats}]"));
+                }
+                let screen_rows = (screen.height() as u16).div_ceil(2);
+                let trail: Vec<_> = touches.iter().map(|&(_, x, y)| (x, y)).collect();
                 let screen = Blocked::new(
                     Block::default()
-                        .title("Screen")
+                        .title(title)
                         .title_alignment(Alignment::Center)
-                        .borders(Borders::ALL),
-                    TuiScreen::new(screen),
+                        .borders(Borders::ALL)
+                        .border_type(focus.border(Focus::Screen)),
+                    TuiScreen::new(screen)
+                        .lcd(lcd)
+                        .palette(palette)
+                        .touches(&trail),
                 );
                 f.render_stateful_widget(screen, Rect::new(0, 0, w1, height), &mut screen_ofs);
+                f.render_widget(
+                    ButtonMarkers::new(buttons, buttons_held, screen_rows),
+                    Rect::new(0, 0, w1, height),
+                );
             }
 
+            let text = String::from_utf8_lossy(output);
+            let scroll = console.scroll(text.lines().count());
+            let mut widget = Console::new(text).scroll(scroll);
+            let title = match &console.search {
+                Some(search) => {
+                    widget = widget.highlight(&search.query, search.line);
+                    search.title()
+                }
+                None if console.bottom.is_none() => "Console [following]".to_owned(),
+                None if scroll > 0 => format!("Console (scrolled up {scroll})"),
+                None => "Console (not following)".to_owned(),
+            };
             let output = Blocked::new(
                 Block::default()
-                    .title("Console")
+                    .title(title)
                     .title_alignment(Alignment::Center)
-                    .borders(Borders::ALL),
-                Console::new(String::from_utf8_lossy(output)),
+                    .borders(Borders::ALL)
+                    .border_type(focus.border(Focus::Console)),
+                widget,
             );
-            f.render_widget(output, Rect::new(w1, 0, w2, height));
+            let timeline_height = if *show_timeline {
+                (EventKind::ALL.len() as u16 + 2).min(height / 2)
+            } else {
+                0
+            };
+            let pins_height = match pins.as_ref().filter(|_| *show_pins) {
+                Some(pins) => (PinsView::rows(pins.values.len(), w2.saturating_sub(2)) + 2)
+                    .min((height - timeline_height) / 2),
+                None => 0,
+            };
+            let inputs_height = if *show_inputs {
+                (INPUT_LOG_ROWS + 2).min((height - timeline_height - pins_height) / 2)
+            } else {
+                0
+            };
+            let advertising_fields = advertising.as_ref().map(|(_, a)| advertising::parse(a));
+            let advertising_height = if *show_advertising {
+                let rows = advertising_fields.as_ref().map_or(1, |f| f.len().max(1));
+                (rows as u16 + 2).min((height - timeline_height - pins_height - inputs_height) / 2)
+            } else {
+                0
+            };
+            let console_height =
+                height - timeline_height - pins_height - inputs_height - advertising_height;
+            f.render_stateful_widget(output, Rect::new(w1, 0, w2, console_height), &mut links);
+
+            if let Some(pins) = pins.as_ref().filter(|_| pins_height > 0) {
+                let watched = pins.watched.as_deref().unwrap_or_default();
+                let title = match &pins.watched {
+                    Some(w) if w.is_empty() => "Pins: no watches".to_owned(),
+                    Some(w) => {
+                        let names: Vec<_> = w.iter().map(|p| format!("D{p}")).collect();
+                        format!("Pins: watching {}", names.join(", "))
+                    }
+                    None => "Pins: watches unknown".to_owned(),
+                };
+                let view = Blocked::new(
+                    Block::default()
+                        .title(title)
+                        .title_alignment(Alignment::Center)
+                        .borders(Borders::ALL),
+                    PinsView::new(&pins.values, watched),
+                );
+                f.render_widget(view, Rect::new(w1, console_height, w2, pins_height));
+            }
+
+            if inputs_height > 0 {
+                let rows = usize::from(inputs_height.saturating_sub(2));
+                let lines: Vec<_> = input_log
+                    .iter()
+                    .skip(input_log.len().saturating_sub(rows))
+                    .map(|(at, description)| {
+                        let secs = at.duration_since(*started).as_secs_f64();
+                        format!("{secs:8.3}s  {description}")
+                    })
+                    .collect();
+                let view = Paragraph::new(lines.join("\n")).block(
+                    Block::default()
+                        .title("Inputs")
+                        .title_alignment(Alignment::Center)
+                        .borders(Borders::ALL),
+                );
+                f.render_widget(
+                    view,
+                    Rect::new(w1, console_height + pins_height, w2, inputs_height),
+                );
+            }
+
+            if advertising_height > 0 {
+                let (title, lines) = match (advertising, &advertising_fields) {
+                    (Some((at, payload)), Some(fields)) => {
+                        let secs = at.duration_since(*started).as_secs_f64();
+                        let title =
+                            format!("Advertising: {} bytes, set at {secs:.3}s", payload.len());
+                        let lines: Vec<_> = fields.iter().map(|f| f.to_string()).collect();
+                        (title, lines)
+                    }
+                    _ => ("Advertising".to_owned(), vec!["nothing set yet".to_owned()]),
+                };
+                let view = Paragraph::new(lines.join("\n")).block(
+                    Block::default()
+                        .title(title)
+                        .title_alignment(Alignment::Center)
+                        .borders(Borders::ALL),
+                );
+                f.render_widget(
+                    view,
+                    Rect::new(
+                        w1,
+                        console_height + pins_height + inputs_height,
+                        w2,
+                        advertising_height,
+                    ),
+                );
+            }
+
+            if timeline_height > 0 {
+                let now = Instant::now();
+                let title = match timeline.entries().last() {
+                    Some(e) => format!(
+                        "Timeline: {} {} ({:.1}s ago)",
+                        e.kind.label(),
+                        e.description,
+                        now.duration_since(e.time).as_secs_f64()
+                    ),
+                    None => "Timeline".to_owned(),
+                };
+                let timeline = Blocked::new(
+                    Block::default()
+                        .title(title)
+                        .title_alignment(Alignment::Center)
+                        .borders(Borders::ALL),
+                    TimelineView::new(timeline, now),
+                );
+                f.render_widget(
+                    timeline,
+                    Rect::new(w1, height - timeline_height, w2, timeline_height),
+                );
+            }
+
+            if ascii {
+                f.render_widget(AsciiOnly, f.size());
+            }
         })?;
+        // Going back over the links with OSC 8 escapes makes them clickable in
+        // terminals that support it; the rest ignore the escapes, though
+        // terminals too minimal for Unicode may well not.
+        if hyperlinks && !ascii && !links.is_empty() {
+            let backend = terminal.backend_mut();
+            for link in links {
+                queue!(
+                    backend,
+                    MoveTo(link.x, link.y),
+                    SetAttribute(Attribute::Underlined),
+                    Print(format!(
+                        "\x1b]8;;{}\x1b\\{}\x1b]8;;\x1b\\",
+                        link.url, link.text
+                    )),
+                    SetAttribute(Attribute::Reset),
+                )?;
+            }
+            Write::flush(backend)?;
+        }
         Ok(screen_ofs)
     }
 
-    let send_string = |data: Vec<u8>| tx.send(UIInput::EmuInput(Input::Console(data))).unwrap();
+    let send_string = |data: Vec<u8>| {
+        tx.send(UIInput::EmuInput(Input::Console(Device::CONSOLE, data)))
+            .unwrap()
+    };
 
     let mut screen_ofs = (0, 0);
-    let mut output_buf = vec![];
-    let mut screen: Option<Screen> = None;
+    let mut state = TuiState {
+        screen: None,
+        output_buf: vec![],
+        firmware: None,
+        console: ConsoleView::default(),
+        focus: Focus::default(),
+        timeline: Timeline::new(TIMELINE_WINDOW),
+        show_timeline: false,
+        show_pins: false,
+        pins: None,
+        show_inputs: false,
+        show_advertising: false,
+        advertising: None,
+        input_log: VecDeque::new(),
+        started: Instant::now(),
+        paused: false,
+        idle_stats: None,
+        panes: PaneSizes::load(),
+        buttons_held: vec![],
+        touches: VecDeque::new(),
+    };
     let mut events = EventStream::new();
-    let mut button_deadline = None;
+    // The button being held, and until when.
+    let mut button_deadline: Option<(usize, Instant)> = None;
+    let mut dragging_divider = false;
+    let mut pin_probe = OptionFuture::default();
+    let mut next_pin_probe = None;
 
     loop {
         let button_timeout: OptionFuture<_> = button_deadline
+            .map(|(_, d)| Delay::new(d - Instant::now()))
+            .into();
+        // Keep the timeline moving even when nothing is happening.
+        let timeline_tick: OptionFuture<_> = state
+            .show_timeline
+            .then(|| Delay::new(TIMELINE_TICK))
+            .into();
+        // Take touches off the screen as they get old.
+        let touch_tick: OptionFuture<_> = state
+            .touches
+            .front()
+            .map(|&(at, ..)| {
+                Delay::new((at + TOUCH_TRAIL).saturating_duration_since(Instant::now()))
+            })
+            .into();
+        let pin_tick: OptionFuture<_> = next_pin_probe
             .map(|d| Delay::new(d - Instant::now()))
             .into();
         select! {
             _ = quit.recv() => break,
+            changed = screens.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                state.timeline.record(EventKind::Screen, "redraw");
+                state.screen = screens.borrow_and_update().clone();
+                screen_ofs = draw(&mut terminal, &state, &options)?;
+            }
             output = rx.recv() => {
                 match output {
-                    Some(Output::Screen(s)) => {
-                        screen = Some(*s);
-                        screen_ofs = draw(&mut terminal, &screen, &output_buf)?;
-                    }
-                    Some(Output::Console(data)) => {
+                    // Screens come from the watch instead.
+                    Some(UIOutput::Emu(Output::Screen(_))) => continue,
+                    Some(UIOutput::Emu(Output::Console(_, data))) => {
+                        state.timeline.record_console(&data);
+                        let output_buf = &mut state.output_buf;
                         output_buf.extend(data);
-                        screen_ofs = draw(&mut terminal, &screen, &output_buf)?;
+                        if output_buf.len() > options.console_limit {
+                            // Drop whole lines from the start to get back
+                            // under the limit.
+                            let excess = output_buf.len() - options.console_limit;
+                            let cut = output_buf[excess..]
+                                .iter()
+                                .position(|&c| c == b'\n')
+                                .map_or(output_buf.len(), |ind| excess + ind + 1);
+                            let dropped = output_buf[..cut].iter().filter(|&&c| c == b'\n').count();
+                            output_buf.drain(..cut);
+                            state.console.drop_lines(dropped);
+                        }
+                    }
+                    Some(UIOutput::Emu(Output::Event(emu::Event::Touch(x, y, _)))) => {
+                        state.touches.push_back((Instant::now(), x, y));
+                    }
+                    Some(UIOutput::Emu(Output::Event(emu::Event::Button(button, on)))) => {
+                        state.buttons_held.retain(|&b| b != button);
+                        if on {
+                            state.buttons_held.push(button);
+                        }
+                    }
+                    Some(UIOutput::Emu(Output::Event(emu::Event::Advertising(payload)))) => {
+                        state.advertising = Some((Instant::now(), payload));
+                        if !state.show_advertising {
+                            continue;
+                        }
+                    }
+                    Some(UIOutput::Emu(Output::Event(event))) => {
+                        state.timeline.record_event(&event);
+                        let logged = match event {
+                            emu::Event::Input(description) => {
+                                state.log_input(description);
+                                true
+                            }
+                            _ => false,
+                        };
+                        // Events are only shown on the timeline and the input
+                        // log, so there's nothing to redraw if those are
+                        // hidden.
+                        let shown = state.show_timeline || state.show_inputs && logged;
+                        if !shown {
+                            continue;
+                        }
+                    }
+                    Some(UIOutput::Emu(Output::Gesture(gesture))) => {
+                        let description = format!("gesture {}", gesture.name());
+                        state.timeline.record(EventKind::Input, description.clone());
+                        state.log_input(description);
+                        if !state.show_timeline && !state.show_inputs {
+                            continue;
+                        }
+                    }
+                    Some(UIOutput::Emu(Output::Paused(paused))) => {
+                        state.paused = paused;
+                    }
+                    Some(UIOutput::Emu(Output::IdleStats(stats))) => {
+                        state.idle_stats = Some(stats);
+                    }
+                    Some(UIOutput::Firmware(info)) => {
+                        execute!(terminal.backend_mut(), SetTitle(format!("banglejs-emu: {info}")))?;
+                        state.firmware = Some(info);
                     }
                     None => break,
                 }
+                screen_ofs = draw(&mut terminal, &state, &options)?;
             }
             ev = events.next() => {
                 match ev.unwrap().unwrap() {
                     Event::Key(k) => {
                         use event::KeyCode::*;
+                        let page = usize::from(terminal.size()?.height / 2);
+                        let focused = state.focus == Focus::Console;
+                        if state.console.handle_key(k.code, &state.output_buf, page, focused) {
+                            // A search is for looking through the console.
+                            if state.console.search.is_some() {
+                                state.focus = Focus::Console;
+                            }
+                            screen_ofs =
+                                draw(&mut terminal, &state, &options)?;
+                            continue;
+                        }
+                        // Alt with a number presses one of the other buttons on
+                        // boards that have them.
+                        let button = match k.code {
+                            Enter => Some(1),
+                            Char(c @ '2'..='4') if k.modifiers.contains(KeyModifiers::ALT) => {
+                                Some(usize::from(c as u8 - b'0'))
+                            }
+                            _ => None,
+                        };
+                        if let Some(button) = button {
+                            // Since we don't get key-up events in the
+                            // terminal, hold the button for a fixed amount of
+                            // time after we get a key event; key repeat will
+                            // make holding the key down act like holding the
+                            // button down.
+                            match button_deadline {
+                                Some((held, _)) if held == button => {}
+                                held => {
+                                    if let Some((held, _)) = held {
+                                        tx.send(UIInput::EmuInput(Input::Button(held, false)))?;
+                                    }
+                                    tx.send(UIInput::EmuInput(Input::Button(button, true)))?;
+                                }
+                            }
+                            button_deadline =
+                                Some((button, Instant::now() + Duration::from_millis(300)));
+                            continue;
+                        }
+                        let swipes = state.focus == Focus::Screen;
                         match k.code {
-                            Left => send_string(b"\x10Bangle.emit('swipe', -1, 0);\n".to_vec()),
-                            Right => send_string(b"\x10Bangle.emit('swipe', 1, 0);\n".to_vec()),
-                            Up => send_string(b"\x10Bangle.emit('swipe', 0, -1);\n".to_vec()),
-                            Down => send_string(b"\x10Bangle.emit('swipe', 0, 1);\n".to_vec()),
-                            Enter => {
-                                // Since we don't get key-up events in the
-                                // terminal, hold the button for a fixed amount
-                                // of time after we get a key event; key repeat
-                                // will make holding the key down act like
-                                // holding the button down.
-                                if button_deadline.is_none() {
-                                    tx.send(UIInput::EmuInput(Input::Button(true))).unwrap();
+                            Left if swipes => send_string(b"\x10Bangle.emit('swipe', -1, 0);\n".to_vec()),
+                            Right if swipes => send_string(b"\x10Bangle.emit('swipe', 1, 0);\n".to_vec()),
+                            Up if swipes => send_string(b"\x10Bangle.emit('swipe', 0, -1);\n".to_vec()),
+                            Down if swipes => send_string(b"\x10Bangle.emit('swipe', 0, 1);\n".to_vec()),
+                            Char('p') => {
+                                tx.send(UIInput::EmuInput(Input::Pause(!state.paused)))?
+                            }
+                            Char('s') if state.paused => {
+                                tx.send(UIInput::EmuInput(Input::Step(Step::Idle)))?
+                            }
+                            Char('S') if state.paused => {
+                                tx.send(UIInput::EmuInput(Input::Step(Step::Millis(100.0))))?
+                            }
+                            Tab => {
+                                state.focus = state.focus.next();
+                                screen_ofs = draw(&mut terminal, &state, &options)?;
+                            }
+                            Char('t') => {
+                                state.show_timeline = !state.show_timeline;
+                                screen_ofs = draw(&mut terminal, &state, &options)?;
+                            }
+                            Char('i') => {
+                                state.show_pins = !state.show_pins;
+                                if state.show_pins {
+                                    pin_probe = Some(probe_pins(&tx)).into();
+                                } else {
+                                    pin_probe = OptionFuture::default();
+                                    next_pin_probe = None;
+                                }
+                                screen_ofs = draw(&mut terminal, &state, &options)?;
+                            }
+                            Char('l') => {
+                                state.show_inputs = !state.show_inputs;
+                                screen_ofs = draw(&mut terminal, &state, &options)?;
+                            }
+                            Char('v') => {
+                                state.show_advertising = !state.show_advertising;
+                                screen_ofs = draw(&mut terminal, &state, &options)?;
+                            }
+                            Char('c') => tx.send(UIInput::EmuInput(Input::Charger(None)))?,
+                            Char('b') => tx.send(UIInput::EmuInput(Input::Bluetooth(None)))?,
+                            Char('a') => {
+                                if let Some(lcd) = &mut options.lcd {
+                                    lcd.lighting = lcd.lighting.next();
+                                    screen_ofs = draw(&mut terminal, &state, &options)?;
+                                }
+                            }
+                            Char(c @ ('<' | '>' | '=')) => {
+                                let width = terminal.size()?.width;
+                                let (w1, _) =
+                                    state.panes.widths(width, screen_pane_width(&state.screen));
+                                match c {
+                                    '<' => state
+                                        .panes
+                                        .set_screen_width(w1.saturating_sub(RESIZE_STEP), width),
+                                    '>' => state.panes.set_screen_width(w1 + RESIZE_STEP, width),
+                                    _ => state.panes.screen_share = None,
                                 }
-                                button_deadline = Some(Instant::now() + Duration::from_millis(300));
+                                state.panes.save();
+                                screen_ofs = draw(&mut terminal, &state, &options)?;
+                            }
+                            F(n @ 1..=9) => tx.send(UIInput::SaveState(n))?,
+                            Char(c @ '1'..='9') => {
+                                tx.send(UIInput::LoadState(c as u8 - b'0'))?
                             }
                             Char('q') | Esc => tx.send(UIInput::Quit)?,
                             _ => {}
@@ -143,26 +933,73 @@ pub async fn run_tui(
                     }
                     Event::Mouse(m) => {
                         use event::MouseEventKind::*;
-                        let x = m.column.saturating_sub(screen_ofs.0).clamp(0, 175) as u8;
-                        let y = (m.row * 2).saturating_sub(screen_ofs.1).clamp(0, 175) as u8;
+                        // Dragging either side of the divider between the
+                        // screen and the console moves it.
+                        let width = terminal.size()?.width;
+                        let (w1, _) = state.panes.widths(width, screen_pane_width(&state.screen));
                         match m.kind {
-                            Down(_) => tx.send(UIInput::EmuInput(Input::Touch(x, y, true)))?,
-                            Up(_) => tx.send(UIInput::EmuInput(Input::Touch(x, y, false)))?,
-                            Drag(_) => tx.send(UIInput::EmuInput(Input::Touch(x, y, true)))?,
+                            Down(_) if (w1.saturating_sub(1)..=w1).contains(&m.column) => {
+                                dragging_divider = true;
+                                continue;
+                            }
+                            Drag(_) if dragging_divider => {
+                                state.panes.set_screen_width(m.column + 1, width);
+                                screen_ofs = draw(&mut terminal, &state, &options)?;
+                                continue;
+                            }
+                            Up(_) if dragging_divider => {
+                                dragging_divider = false;
+                                state.panes.save();
+                                continue;
+                            }
+                            _ => {}
+                        }
+                        let Some(screen) = &state.screen else {
+                            continue;
+                        };
+                        let (w, h) = (screen.width() as u16, screen.height() as u16);
+                        let x = m.column.saturating_sub(screen_ofs.0).min(w - 1) as u8;
+                        let y = (m.row * 2).saturating_sub(screen_ofs.1).min(h - 1) as u8;
+                        match m.kind {
+                            Down(_) => tx.send(UIInput::EmuInput(Input::Touch(x, y, true, None)))?,
+                            Up(_) => tx.send(UIInput::EmuInput(Input::Touch(x, y, false, None)))?,
+                            Drag(_) => tx.send(UIInput::EmuInput(Input::Touch(x, y, true, None)))?,
                             Moved => {}
                             ScrollDown => {}
                             ScrollUp => {}
                         }
                     }
                     Event::Resize(..) => {
-                        screen_ofs = draw(&mut terminal, &screen, &output_buf)?;
+                        screen_ofs = draw(&mut terminal, &state, &options)?;
                     }
                     _ => {}
                 }
             }
+            _ = touch_tick => {
+                while state.touches.front().is_some_and(|&(at, ..)| at.elapsed() >= TOUCH_TRAIL) {
+                    state.touches.pop_front();
+                }
+                screen_ofs = draw(&mut terminal, &state, &options)?;
+            }
+            _ = timeline_tick => {
+                screen_ofs = draw(&mut terminal, &state, &options)?;
+            }
+            status = &mut pin_probe => {
+                pin_probe = OptionFuture::default();
+                if let Some(status) = status {
+                    state.pins = Some(status);
+                    next_pin_probe = Some(Instant::now() + PIN_POLL);
+                }
+                screen_ofs = draw(&mut terminal, &state, &options)?;
+            }
+            _ = pin_tick => {
+                next_pin_probe = None;
+                pin_probe = Some(probe_pins(&tx)).into();
+            }
             _ = button_timeout => {
-                tx.send(UIInput::EmuInput(Input::Button(false))).unwrap();
-                button_deadline = None;
+                if let Some((button, _)) = button_deadline.take() {
+                    tx.send(UIInput::EmuInput(Input::Button(button, false))).unwrap();
+                }
             }
 
         }
@@ -0,0 +1,463 @@
+use std::mem;
+
+use anyhow::Context;
+use log::{debug, trace};
+use wasmtime::{
+    AsContextMut, Caller, ExternType, Instance, Linker, Memory, Module, Mutability, Store,
+    TypedFunc, ValType,
+};
+use wasmtime_wasi::{sync::WasiCtxBuilder, WasiCtx};
+
+use crate::{
+    emu::Host,
+    engine::{transmit, Engine, Export, Val},
+};
+
+/// The most bytes an I2C read fills in. Real transfers are a few bytes long,
+/// and this stops a bad length from allocating without bound.
+const I2C_READ_MAX: usize = 1 << 16;
+
+/// What the firmware's host functions have to work with.
+struct Data {
+    host: Host,
+    wasi_ctx: WasiCtx,
+    /// Set once the firmware is instantiated, for host functions that call
+    /// back into it.
+    funcs: Option<ModuleFuncs>,
+}
+
+#[derive(Clone, Copy)]
+struct ModuleFuncs {
+    get_gfx_ptr: TypedFunc<i32, i32>,
+    js_gfx_changed: TypedFunc<(), i32>,
+    js_idle: TypedFunc<(), i32>,
+    js_init: TypedFunc<(), ()>,
+    js_push_char: TypedFunc<(i32, i32), ()>,
+    js_reset_storage: TypedFunc<(), ()>,
+    js_send_pin_watch_event: TypedFunc<i32, ()>,
+    js_send_touch_event: Option<TypedFunc<(i32, i32, i32, i32), ()>>,
+    js_send_touch_event_pressure: Option<TouchPressureFunc>,
+    get_device_to_transmit: TypedFunc<(), i32>,
+    get_char_to_transmit: TypedFunc<i32, i32>,
+}
+
+/// `jsSendTouchEventPressure(x, y, on, gesture, pressure)`.
+type TouchPressureFunc = TypedFunc<(i32, i32, i32, i32, i32), ()>;
+
+impl ModuleFuncs {
+    fn call(
+        &self,
+        mut store: impl AsContextMut<Data = Data>,
+        export: Export,
+        args: &[i32],
+    ) -> anyhow::Result<i32> {
+        let arg = |i: usize| {
+            args.get(i)
+                .copied()
+                .with_context(|| format!("too few arguments for {}", export.name()))
+        };
+        let missing = || anyhow::format_err!("the firmware doesn't export {}", export.name());
+        let store = &mut store;
+        Ok(match export {
+            Export::Init => self.js_init.call(store, ()).map(|()| 0)?,
+            Export::Idle => self.js_idle.call(store, ())?,
+            Export::GfxChanged => self.js_gfx_changed.call(store, ())?,
+            Export::GfxGetPtr => self.get_gfx_ptr.call(store, arg(0)?)?,
+            Export::PushChar => self
+                .js_push_char
+                .call(store, (arg(0)?, arg(1)?))
+                .map(|()| 0)?,
+            Export::ResetStorage => self.js_reset_storage.call(store, ()).map(|()| 0)?,
+            Export::SendPinWatchEvent => self
+                .js_send_pin_watch_event
+                .call(store, arg(0)?)
+                .map(|()| 0)?,
+            Export::SendTouchEvent => self
+                .js_send_touch_event
+                .ok_or_else(missing)?
+                .call(store, (arg(0)?, arg(1)?, arg(2)?, arg(3)?))
+                .map(|()| 0)?,
+            Export::SendTouchEventPressure => self
+                .js_send_touch_event_pressure
+                .ok_or_else(missing)?
+                .call(store, (arg(0)?, arg(1)?, arg(2)?, arg(3)?, arg(4)?))
+                .map(|()| 0)?,
+            Export::GetDeviceToTransmit => self.get_device_to_transmit.call(store, ())?,
+            Export::GetCharToTransmit => self.get_char_to_transmit.call(store, arg(0)?)?,
+        })
+    }
+}
+
+/// Runs the firmware under wasmtime, with WASI for anything it prints.
+pub struct WasmtimeEngine {
+    store: Store<Data>,
+    instance: Instance,
+    memory: Memory,
+    funcs: ModuleFuncs,
+}
+
+impl WasmtimeEngine {
+    /// Instantiates a firmware module, with its host functions answered by
+    /// `host`. Host functions the firmware imports that the emulator doesn't
+    /// provide are left to `host`'s peripherals; if none of them provide one
+    /// either, the firmware fails to load, since it's most likely not a build
+    /// for the emulator.
+    pub fn new(engine: &wasmtime::Engine, module: &Module, host: Host) -> anyhow::Result<Self> {
+        let mut linker = Linker::new(engine);
+
+        wasmtime_wasi::add_to_linker(&mut linker, |d: &mut Data| &mut d.wasi_ctx)?;
+
+        linker.func_wrap(
+            "env",
+            "jsHandleIO",
+            |mut caller: Caller<'_, Data>| -> anyhow::Result<()> {
+                trace!("jsHandleIO");
+                let start = caller.data().host.profile_start();
+                let funcs = caller.data().funcs.unwrap();
+                let mut char_q = mem::take(caller.data_mut().host.char_q());
+                let result = transmit(&mut char_q, |export, args| {
+                    funcs.call(&mut caller, export, args)
+                });
+                *caller.data_mut().host.char_q() = char_q;
+                caller.data().host.profile_finish("jsHandleIO", start);
+                result
+            },
+        )?;
+
+        linker.func_wrap(
+            "env",
+            "hostIsInterrupted",
+            |caller: Caller<'_, Data>| -> i32 { caller.data().host.is_interrupted().into() },
+        )?;
+
+        linker.func_wrap("env", "hostClearInterrupted", |caller: Caller<'_, Data>| {
+            caller.data().host.clear_interrupted()
+        })?;
+
+        linker.func_wrap("env", "hostIsReset", |caller: Caller<'_, Data>| -> i32 {
+            caller.data().host.is_reset().into()
+        })?;
+
+        linker.func_wrap("env", "hostClearReset", |caller: Caller<'_, Data>| {
+            caller.data().host.clear_reset()
+        })?;
+
+        linker.func_wrap(
+            "env",
+            "hwFlashRead",
+            |caller: Caller<'_, Data>, ind: i32| -> i32 {
+                caller.data().host.flash_read(ind).into()
+            },
+        )?;
+
+        linker.func_wrap(
+            "env",
+            "hwFlashWritePtr",
+            |mut caller: Caller<'_, Data>,
+             flash_addr: i32,
+             base: i32,
+             len: i32|
+             -> anyhow::Result<()> {
+                debug!("hwFlashWritePtr {flash_addr} {base} {len}");
+                let data = read(&mut caller, base, len)?;
+                caller.data_mut().host.flash_write(flash_addr, &data);
+                Ok(())
+            },
+        )?;
+
+        linker.func_wrap(
+            "env",
+            "hwGetPinValue",
+            |mut caller: Caller<'_, Data>, ind: i32| -> anyhow::Result<i32> {
+                Ok(caller.data_mut().host.read_pin(ind)?.into())
+            },
+        )?;
+
+        linker.func_wrap(
+            "env",
+            "hwSetPinValue",
+            |mut caller: Caller<'_, Data>, ind: i32, val: i32| -> anyhow::Result<()> {
+                caller.data_mut().host.write_pin(ind, val != 0)
+            },
+        )?;
+
+        // Builds of the firmware that forward I2C to the host call these,
+        // which return 1 if some peripheral acknowledged the transfer.
+        linker.func_wrap(
+            "env",
+            "hwI2CWrite",
+            |mut caller: Caller<'_, Data>,
+             bus: i32,
+             addr: i32,
+             ptr: i32,
+             len: i32|
+             -> anyhow::Result<i32> {
+                debug!("hwI2CWrite {bus} {addr} {ptr} {len}");
+                let data = read(&mut caller, ptr, len)?;
+                Ok(caller.data_mut().host.i2c_write(bus, addr, &data)?.into())
+            },
+        )?;
+
+        linker.func_wrap(
+            "env",
+            "hwI2CRead",
+            |mut caller: Caller<'_, Data>,
+             bus: i32,
+             addr: i32,
+             ptr: i32,
+             len: i32|
+             -> anyhow::Result<i32> {
+                debug!("hwI2CRead {bus} {addr} {ptr} {len}");
+                let len = usize::try_from(len)
+                    .with_context(|| format!("invalid I2C read length {len}"))?;
+                let mut data = vec![0; len.min(I2C_READ_MAX)];
+                let acked = caller.data_mut().host.i2c_read(bus, addr, &mut data)?;
+                if acked {
+                    memory(&mut caller)?.write(&mut caller, ptr as usize, &data)?;
+                }
+                Ok(acked.into())
+            },
+        )?;
+
+        // Builds of the firmware that forward Bluetooth to the host call this
+        // with the raw advertising payload whenever `NRF.setAdvertising`
+        // changes it.
+        linker.func_wrap(
+            "env",
+            "hwBleSetAdvertising",
+            |mut caller: Caller<'_, Data>, ptr: i32, len: i32| -> anyhow::Result<()> {
+                debug!("hwBleSetAdvertising {ptr} {len}");
+                let data = read(&mut caller, ptr, len)?;
+                caller.data_mut().host.set_advertising(data);
+                Ok(())
+            },
+        )?;
+
+        linker.func_wrap("env", "nowMillis", |caller: Caller<'_, Data>| -> f64 {
+            caller.data().host.now_ms()
+        })?;
+
+        let data = Data {
+            host,
+            wasi_ctx: WasiCtxBuilder::new().build(),
+            funcs: None,
+        };
+        let mut store = Store::new(engine, data);
+
+        // Anything else the firmware imports is left to the peripherals that
+        // provide it, so that builds with extra hardware can still run.
+        let mut missing = vec![];
+        for import in module.imports() {
+            let ExternType::Func(ty) = import.ty() else {
+                continue;
+            };
+            if linker
+                .get(&mut store, import.module(), import.name())
+                .is_some()
+            {
+                continue;
+            }
+            let name = import.name().to_owned();
+            if !store.data().host.provides(&name) {
+                missing.push(format!("{}.{name}", import.module()));
+                continue;
+            }
+            let result_types: Vec<_> = ty.results().collect();
+            linker.func_new(
+                import.module(),
+                import.name(),
+                ty,
+                move |mut caller: Caller<'_, Data>, params, results| {
+                    let params = params
+                        .iter()
+                        .map(from_wasmtime)
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let mut vals = result_types
+                        .iter()
+                        .map(zero)
+                        .collect::<Result<Vec<_>, _>>()?;
+                    caller.data_mut().host.call(&name, &params, &mut vals)?;
+                    for (result, val) in results.iter_mut().zip(vals) {
+                        *result = val.into();
+                    }
+                    Ok(())
+                },
+            )?;
+        }
+        if !missing.is_empty() {
+            anyhow::bail!(
+                "the firmware imports host functions that neither the emulator nor any \
+                 peripheral provides ({}); is it an emulator build?",
+                missing.join(", ")
+            );
+        }
+
+        let instance = linker.instantiate(&mut store, module)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .context("failed to find `memory` export")?;
+
+        let funcs = ModuleFuncs {
+            get_gfx_ptr: instance.get_typed_func(&mut store, "jsGfxGetPtr")?,
+            js_gfx_changed: instance.get_typed_func(&mut store, "jsGfxChanged")?,
+            js_idle: instance.get_typed_func(&mut store, "jsIdle")?,
+            js_init: instance.get_typed_func(&mut store, "jsInit")?,
+            js_push_char: instance.get_typed_func(&mut store, "jshPushIOCharEvent")?,
+            js_reset_storage: instance.get_typed_func(&mut store, "jsfResetStorage")?,
+            js_send_pin_watch_event: instance.get_typed_func(&mut store, "jsSendPinWatchEvent")?,
+            js_send_touch_event: instance.get_typed_func(&mut store, "jsSendTouchEvent").ok(),
+            js_send_touch_event_pressure: instance
+                .get_typed_func(&mut store, "jsSendTouchEventPressure")
+                .ok(),
+            get_device_to_transmit: instance
+                .get_typed_func(&mut store, "jshGetDeviceToTransmit")?,
+            get_char_to_transmit: instance.get_typed_func(&mut store, "jshGetCharToTransmit")?,
+        };
+        store.data_mut().funcs = Some(funcs);
+        Ok(Self {
+            store,
+            instance,
+            memory,
+            funcs,
+        })
+    }
+
+    fn mutable_globals(&mut self) -> Vec<(String, wasmtime::Global)> {
+        let globals: Vec<_> = self
+            .instance
+            .exports(&mut self.store)
+            .filter_map(|e| {
+                let name = e.name().to_owned();
+                e.into_global().map(|g| (name, g))
+            })
+            .collect();
+        globals
+            .into_iter()
+            .filter(|(_, g)| g.ty(&self.store).mutability() == Mutability::Var)
+            .collect()
+    }
+}
+
+impl Engine for WasmtimeEngine {
+    fn host(&self) -> &Host {
+        &self.store.data().host
+    }
+
+    fn host_mut(&mut self) -> &mut Host {
+        &mut self.store.data_mut().host
+    }
+
+    fn has_export(&self, export: Export) -> bool {
+        match export {
+            Export::SendTouchEvent => self.funcs.js_send_touch_event.is_some(),
+            Export::SendTouchEventPressure => self.funcs.js_send_touch_event_pressure.is_some(),
+            _ => true,
+        }
+    }
+
+    fn call(&mut self, export: Export, args: &[i32]) -> anyhow::Result<i32> {
+        self.funcs.call(&mut self.store, export, args)
+    }
+
+    fn memory(&self) -> &[u8] {
+        self.memory.data(&self.store)
+    }
+
+    fn set_memory(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let size = self.memory.data_size(&self.store);
+        if size < data.len() {
+            const PAGE: usize = 1 << 16;
+            self.memory
+                .grow(&mut self.store, (data.len() - size).div_ceil(PAGE) as u64)?;
+        }
+        let memory = self.memory.data_mut(&mut self.store);
+        memory[..data.len()].copy_from_slice(data);
+        memory[data.len()..].fill(0);
+        Ok(())
+    }
+
+    fn globals(&mut self) -> Vec<(String, Val)> {
+        self.mutable_globals()
+            .into_iter()
+            .filter_map(|(name, g)| Some((name, from_wasmtime(&g.get(&mut self.store)).ok()?)))
+            .collect()
+    }
+
+    fn set_global(&mut self, name: &str, value: Val) -> anyhow::Result<()> {
+        let Some((_, global)) = self.mutable_globals().into_iter().find(|(n, _)| n == name) else {
+            anyhow::bail!("the firmware has no mutable global {name:?}");
+        };
+        global.set(&mut self.store, value.into())
+    }
+}
+
+/// The firmware's memory, from inside a host function.
+fn memory(caller: &mut Caller<'_, Data>) -> anyhow::Result<Memory> {
+    caller
+        .get_export("memory")
+        .and_then(|e| e.into_memory())
+        .context("failed to find `memory` export")
+}
+
+/// Copies `len` bytes at `ptr` out of the firmware's memory, from inside a
+/// host function.
+fn read(caller: &mut Caller<'_, Data>, ptr: i32, len: i32) -> anyhow::Result<Vec<u8>> {
+    let len = usize::try_from(len).with_context(|| format!("invalid length {len}"))?;
+    let mut data = vec![0; len];
+    memory(caller)?.read(&*caller, ptr as usize, &mut data)?;
+    Ok(data)
+}
+
+impl From<Val> for wasmtime::Val {
+    fn from(val: Val) -> Self {
+        match val {
+            Val::I32(v) => Self::I32(v),
+            Val::I64(v) => Self::I64(v),
+            Val::F32(v) => Self::F32(v),
+            Val::F64(v) => Self::F64(v),
+        }
+    }
+}
+
+/// Converts a value from wasmtime, if it's a number.
+pub(crate) fn from_wasmtime(val: &wasmtime::Val) -> anyhow::Result<Val> {
+    Ok(match *val {
+        wasmtime::Val::I32(v) => Val::I32(v),
+        wasmtime::Val::I64(v) => Val::I64(v),
+        wasmtime::Val::F32(v) => Val::F32(v),
+        wasmtime::Val::F64(v) => Val::F64(v),
+        ref val => anyhow::bail!("unsupported value type {}", val.ty()),
+    })
+}
+
+/// The zero of a numeric type.
+fn zero(ty: &ValType) -> anyhow::Result<Val> {
+    Ok(match ty {
+        ValType::I32 => Val::I32(0),
+        ValType::I64 => Val::I64(0),
+        ValType::F32 => Val::F32(0),
+        ValType::F64 => Val::F64(0),
+        ty => anyhow::bail!("unsupported value type {ty}"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_imports_fail_to_load() {
+        let engine = wasmtime::Engine::default();
+        let module = Module::new(
+            &engine,
+            r#"(module (import "env" "frobnicate" (func)) (import "env" "nowMillis" (func (result f64))))"#,
+        )
+        .unwrap();
+        let err = WasmtimeEngine::new(&engine, &module, Host::default())
+            .err()
+            .unwrap();
+        let err = format!("{err:#}");
+        assert!(err.contains("env.frobnicate"), "{err}");
+        assert!(!err.contains("nowMillis"), "{err}");
+    }
+}
@@ -0,0 +1,96 @@
+//! A C-style API over the portable core, for JavaScript that runs the firmware
+//! with the browser's own WebAssembly support to call into when built for
+//! wasm32. Buffers are passed as pointers into this module's memory, allocated
+//! with `bjs_alloc`.
+
+use std::{mem, slice};
+
+use crate::{
+    storage::Storage,
+    touch::{GestureThresholds, TouchTracker},
+};
+
+/// Allocates a buffer of `len` bytes for passing data in.
+#[no_mangle]
+pub extern "C" fn bjs_alloc(len: usize) -> *mut u8 {
+    let mut buf = Vec::<u8>::with_capacity(len);
+    let ptr = buf.as_mut_ptr();
+    mem::forget(buf);
+    ptr
+}
+
+/// Frees a buffer from `bjs_alloc`.
+///
+/// # Safety
+///
+/// `ptr` must have come from `bjs_alloc` with the same `len`.
+#[no_mangle]
+pub unsafe extern "C" fn bjs_free(ptr: *mut u8, len: usize) {
+    drop(Vec::from_raw_parts(ptr, 0, len));
+}
+
+/// Creates a touch tracker with the given gesture thresholds.
+#[no_mangle]
+pub extern "C" fn bjs_touch_new(
+    tap_max: u32,
+    swipe_min: u32,
+    swipe_max_cross: u32,
+) -> *mut TouchTracker {
+    Box::into_raw(Box::new(TouchTracker::new(GestureThresholds {
+        tap_max: tap_max.into(),
+        swipe_min: swipe_min.into(),
+        swipe_max_cross: swipe_max_cross.into(),
+    })))
+}
+
+/// Frees a touch tracker.
+///
+/// # Safety
+///
+/// `tracker` must have come from `bjs_touch_new` and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn bjs_touch_free(tracker: *mut TouchTracker) {
+    drop(Box::from_raw(tracker));
+}
+
+/// Records a touch sample, returning the gestures to send to the firmware's
+/// `jsSendTouchEvent` as a bit mask of gesture codes (bit 0 for a drag, which
+/// is always sent first if set).
+///
+/// # Safety
+///
+/// `tracker` must have come from `bjs_touch_new` and not have been freed.
+#[no_mangle]
+pub unsafe extern "C" fn bjs_touch_add(tracker: *mut TouchTracker, x: u8, y: u8, on: bool) -> u32 {
+    (*tracker)
+        .add_touch((x, y), on)
+        .into_iter()
+        .fold(0, |mask, gesture| mask | 1 << gesture as u8)
+}
+
+/// Writes a file into storage in a flash image (such as the firmware's flash
+/// buffer before it starts), returning 0 on success or -1 if the name is
+/// invalid or the file doesn't fit.
+///
+/// # Safety
+///
+/// Each pointer must point to a buffer of the given length.
+#[no_mangle]
+pub unsafe extern "C" fn bjs_storage_write(
+    flash: *mut u8,
+    flash_len: usize,
+    name: *const u8,
+    name_len: usize,
+    contents: *const u8,
+    contents_len: usize,
+) -> i32 {
+    let flash = slice::from_raw_parts_mut(flash, flash_len);
+    let Ok(name) = std::str::from_utf8(slice::from_raw_parts(name, name_len)) else {
+        return -1;
+    };
+    let contents = slice::from_raw_parts(contents, contents_len);
+    match Storage::new(flash).write(name, contents) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
@@ -0,0 +1,55 @@
+//! Stand-ins for common widgets, for seeing how a clock face sits alongside
+//! the widget bar without installing the real widgets. They draw roughly what
+//! the BangleApps widgets of the same ids do, and react to the same events.
+
+/// A canned widget: the files to write into storage for it, as the App Loader
+/// would for the real one.
+pub struct Fixture {
+    /// What the fixture is called in a config's `widgets` list.
+    pub name: &'static str,
+    pub files: &'static [(&'static str, &'static str)],
+}
+
+pub const FIXTURES: &[Fixture] = &[
+    Fixture {
+        name: "battery",
+        files: &[
+            ("widbat.wid.js", include_str!("widgets/widbat.wid.js")),
+            (
+                "widbat.info",
+                r#"{"id":"widbat","name":"Battery Level Widget","type":"widget","files":"widbat.info,widbat.wid.js"}"#,
+            ),
+        ],
+    },
+    Fixture {
+        name: "bluetooth",
+        files: &[
+            ("widbt.wid.js", include_str!("widgets/widbt.wid.js")),
+            (
+                "widbt.info",
+                r#"{"id":"widbt","name":"Bluetooth Widget","type":"widget","files":"widbt.info,widbt.wid.js"}"#,
+            ),
+        ],
+    },
+    Fixture {
+        name: "lock",
+        files: &[
+            ("widlock.wid.js", include_str!("widgets/widlock.wid.js")),
+            (
+                "widlock.info",
+                r#"{"id":"widlock","name":"Lock Widget","type":"widget","files":"widlock.info,widlock.wid.js"}"#,
+            ),
+        ],
+    },
+];
+
+/// Looks up a widget fixture by name.
+pub fn fixture(name: &str) -> anyhow::Result<&'static Fixture> {
+    FIXTURES.iter().find(|f| f.name == name).ok_or_else(|| {
+        let names: Vec<_> = FIXTURES.iter().map(|f| f.name).collect();
+        anyhow::anyhow!(
+            "no widget fixture named {name:?} (available: {})",
+            names.join(", ")
+        )
+    })
+}
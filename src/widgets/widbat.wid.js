@@ -0,0 +1,37 @@
+(function () {
+  function setWidth() {
+    WIDGETS.bat.width = Bangle.isCharging() ? 44 : 28;
+  }
+  Bangle.on("charging", function (charging) {
+    setWidth();
+    Bangle.drawWidgets();
+    if (charging) Bangle.buzz();
+  });
+  WIDGETS.bat = {
+    area: "tr",
+    width: 28,
+    draw: function () {
+      var x = this.x,
+        y = this.y;
+      g.reset();
+      if (Bangle.isCharging()) {
+        g.setColor("#0f0").fillPoly([
+          x + 8, y + 2, x + 3, y + 13, x + 7, y + 13,
+          x + 5, y + 21, x + 12, y + 9, x + 8, y + 9, x + 11, y + 2,
+        ]);
+        x += 16;
+      }
+      g.setColor(g.theme.fg)
+        .fillRect(x + 1, y + 6, x + 22, y + 17)
+        .fillRect(x + 23, y + 9, x + 25, y + 14)
+        .clearRect(x + 3, y + 8, x + 20, y + 15);
+      var level = E.getBattery();
+      g.setColor(level < 20 ? "#f00" : "#0f0")
+        .fillRect(x + 4, y + 9, x + 4 + Math.round((15 * level) / 100), y + 14);
+    },
+  };
+  setWidth();
+  setInterval(function () {
+    WIDGETS.bat.draw();
+  }, 60000);
+})();
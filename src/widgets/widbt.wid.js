@@ -0,0 +1,22 @@
+(function () {
+  function redraw() {
+    WIDGETS.bluetooth.draw();
+  }
+  NRF.on("connect", redraw);
+  NRF.on("disconnect", redraw);
+  WIDGETS.bluetooth = {
+    area: "tr",
+    width: 15,
+    draw: function () {
+      var x = this.x,
+        y = this.y;
+      g.reset();
+      if (NRF.getSecurityStatus().connected) g.setColor("#07f");
+      else g.setColor(g.theme.dark ? "#666" : "#999");
+      g.drawPoly([
+        x + 3, y + 7, x + 11, y + 15, x + 7, y + 19, x + 7, y + 3,
+        x + 11, y + 7, x + 3, y + 15,
+      ]);
+    },
+  };
+})();
@@ -0,0 +1,22 @@
+(function () {
+  Bangle.on("lock", function (locked) {
+    WIDGETS.lock.width = locked ? 16 : 0;
+    Bangle.drawWidgets();
+  });
+  WIDGETS.lock = {
+    area: "tl",
+    sortorder: 10,
+    width: Bangle.isLocked() ? 16 : 0,
+    draw: function (w) {
+      if (!w.width) return;
+      var x = w.x,
+        y = w.y;
+      g.reset()
+        .fillRect(x + 3, y + 11, x + 12, y + 19)
+        .drawPoly([
+          x + 5, y + 11, x + 5, y + 7, x + 7, y + 5, x + 8, y + 5,
+          x + 10, y + 7, x + 10, y + 11,
+        ]);
+    },
+  };
+})();